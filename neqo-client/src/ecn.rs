@@ -0,0 +1,137 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Setting and reading the ECN codepoint of datagrams, for `--ecn`. This needs the IP_TOS
+// and IPV6_TCLASS socket options, so it only works on Linux; elsewhere datagrams are sent
+// and received as Not-ECT.
+
+use neqo_common::Ecn;
+use std::io;
+use std::net::UdpSocket;
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use neqo_common::Ecn;
+    use std::convert::TryFrom;
+    use std::io;
+    use std::mem;
+    use std::net::UdpSocket;
+    use std::os::unix::io::AsRawFd;
+
+    fn setsockopt(
+        socket: &UdpSocket,
+        level: libc::c_int,
+        name: libc::c_int,
+        value: libc::c_int,
+    ) -> io::Result<()> {
+        let res = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if res == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    fn is_ipv6(socket: &UdpSocket) -> io::Result<bool> {
+        Ok(socket.local_addr()?.is_ipv6())
+    }
+
+    pub fn enable_recv(socket: &UdpSocket) -> io::Result<()> {
+        if is_ipv6(socket)? {
+            setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, 1)
+        } else {
+            setsockopt(socket, libc::IPPROTO_IP, libc::IP_RECVTOS, 1)
+        }
+    }
+
+    pub fn set(socket: &UdpSocket, ecn: Ecn) -> io::Result<()> {
+        let tos = libc::c_int::from(u8::from(ecn));
+        if is_ipv6(socket)? {
+            setsockopt(socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos)
+        } else {
+            setsockopt(socket, libc::IPPROTO_IP, libc::IP_TOS, tos)
+        }
+    }
+
+    pub fn recv(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, Ecn)> {
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // Room for one control message with an int, the larger of the TOS and TCLASS values.
+        let mut control = [0u64; 8];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control);
+
+        let res = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) };
+        let sz = usize::try_from(res).map_err(|_| io::Error::last_os_error())?;
+
+        let mut ecn = Ecn::NotEct;
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            let hdr = unsafe { &*cmsg };
+            let is_tos = (hdr.cmsg_level == libc::IPPROTO_IP && hdr.cmsg_type == libc::IP_TOS)
+                || (hdr.cmsg_level == libc::IPPROTO_IPV6 && hdr.cmsg_type == libc::IPV6_TCLASS);
+            if is_tos {
+                // IP_TOS carries a byte and IPV6_TCLASS an int.
+                let data = unsafe { libc::CMSG_DATA(cmsg) };
+                let tos = if hdr.cmsg_type == libc::IP_TOS {
+                    unsafe { *data }
+                } else {
+                    unsafe { (data as *const libc::c_int).read_unaligned() as u8 }
+                };
+                ecn = Ecn::from(tos);
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+        Ok((sz, ecn))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use neqo_common::Ecn;
+    use std::io;
+    use std::net::UdpSocket;
+
+    pub fn enable_recv(_socket: &UdpSocket) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn set(_socket: &UdpSocket, _ecn: Ecn) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn recv(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, Ecn)> {
+        socket.recv(buf).map(|sz| (sz, Ecn::NotEct))
+    }
+}
+
+/// Ask for the ECN codepoint of received datagrams, which `recv` then reports.
+pub fn enable_recv(socket: &UdpSocket) -> io::Result<()> {
+    sys::enable_recv(socket)
+}
+
+/// Send the datagrams that follow with the codepoint `ecn`.
+pub fn set(socket: &UdpSocket, ecn: Ecn) -> io::Result<()> {
+    sys::set(socket, ecn)
+}
+
+/// Receive a datagram like `UdpSocket::recv`, with its ECN codepoint.
+pub fn recv(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, Ecn)> {
+    sys::recv(socket, buf)
+}
@@ -7,19 +7,34 @@
 #![cfg_attr(feature = "deny-warnings", deny(warnings))]
 #![warn(clippy::use_self)]
 
-use neqo_common::{matches, Datagram};
+mod ecn;
+
+use neqo_common::{hex, matches, Datagram, Ecn};
 use neqo_crypto::{init, AuthenticationStatus};
-use neqo_http3::{Header, Http3Client, Http3ClientEvent, Http3State, Output};
+use neqo_http3::{
+    Error, Header, Http3Client, Http3ClientEvent, Http3Parameters, Http3State, Output,
+};
+#[cfg(feature = "frame-trace")]
+use neqo_http3::{FrameDirection, FrameEvent, FrameTracer};
 use neqo_transport::stream_id::StreamId;
-use neqo_transport::FixedConnectionIdManager;
+use neqo_transport::{
+    CongestionControlAlgorithm, Connection, ConnectionError, Error as TransportError,
+    FixedConnectionIdManager, State, QUIC_VERSION,
+};
 
 use std::cell::RefCell;
-use std::collections::HashSet;
-use std::io::{self, ErrorKind};
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io::{self, ErrorKind, Write};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::rc::Rc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 use url::Url;
 
@@ -32,23 +47,58 @@ pub struct Args {
     #[structopt(short = "a", long, default_value = "h3-24")]
     /// ALPN labels to negotiate.
     ///
-    /// This client still only does HTTP3 no matter what the ALPN says.
+    /// If "http/0.9" is one of them and the server chooses it, the request is
+    /// made with HTTP/0.9, otherwise with HTTP/3. Offering "http/0.9" means
+    /// that there is no 0-RTT, as the protocol is only known once the server
+    /// has answered.
     alpn: Vec<String>,
 
     url: Url,
 
+    #[structopt(name = "more-urls")]
+    /// More URLs to fetch on the same connection, with HTTP/3. They must have
+    /// the same scheme, host and port as the first URL.
+    more_urls: Vec<Url>,
+
+    #[structopt(long)]
+    /// Send at most this many requests at a time. The other requests are
+    /// queued and sent as responses complete. By default requests are only
+    /// limited by the number of streams the server allows.
+    parallel: Option<usize>,
+
     #[structopt(short = "m", default_value = "GET")]
     method: String,
 
     #[structopt(short = "h", long, number_of_values = 2)]
+    /// A request header, given as a name and a value.
     header: Vec<String>,
 
+    #[structopt(
+        short = "H",
+        long = "request-header",
+        parse(try_from_str = "parse_header")
+    )]
+    /// A request header in the form "name: value", e.g. -H "accept: text/html".
+    request_header: Vec<Header>,
+
+    #[structopt(name = "request-file", long, parse(from_os_str))]
+    /// Send the request in this file instead of a GET for the URL. The file
+    /// holds an HTTP/1.1 request line and header lines, e.g. for replaying a
+    /// captured request; Host becomes :authority. It replaces -m, -h and -H,
+    /// and the URL is only used to connect.
+    request_file: Option<PathBuf>,
+
     #[structopt(name = "max-table-size", short = "t", long, default_value = "128")]
     max_table_size: u32,
 
     #[structopt(name = "max-blocked-streams", short = "b", long, default_value = "128")]
     max_blocked_streams: u16,
 
+    #[structopt(name = "no-qpack-dynamic", long)]
+    /// Do not insert into the server's QPACK dynamic table; encode request
+    /// headers with static table references and literals only.
+    no_qpack_dynamic: bool,
+
     #[structopt(name = "use-old-http", short = "o", long)]
     /// Use http 0.9 instead of HTTP/3
     use_old_http: bool,
@@ -56,90 +106,615 @@ pub struct Args {
     #[structopt(name = "omit-read-data", long)]
     /// Do not print received data
     omit_read_data: bool,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Write the response body to this file instead of printing it. The bytes
+    /// are written as they are received, so binary content is kept intact.
+    /// Only one URL can be fetched with this.
+    output: Option<PathBuf>,
+
+    #[structopt(name = "resumption-token-file", long, parse(from_os_str))]
+    /// Load a resumption token from this file to attempt 0-RTT and
+    /// save a new token to it once the server provides one.
+    resumption_token_file: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// The server name to send in the TLS SNI extension.
+    ///
+    /// This takes precedence over the host of the URL, which is still used
+    /// for DNS resolution and the :authority header. If it is empty, the
+    /// host of the URL is used.
+    sni: Option<String>,
+
+    #[structopt(long, default_value = "newreno", parse(try_from_str = "parse_cc"))]
+    /// The congestion control algorithm to use. Only "newreno" is available.
+    cc: CongestionControlAlgorithm,
+
+    #[structopt(long, parse(try_from_str = "parse_timeout"))]
+    /// Cancel the request and exit with an error if the response is not
+    /// complete within this time, e.g. "5s" or "500ms".
+    timeout: Option<Duration>,
+
+    #[structopt(short = "4", long = "ipv4", raw(conflicts_with = r#""ipv6""#))]
+    /// Only connect to IPv4 addresses of the server.
+    ipv4: bool,
+
+    #[structopt(short = "6", long = "ipv6")]
+    /// Only connect to IPv6 addresses of the server.
+    ipv6: bool,
+
+    #[structopt(name = "keep-alive", long)]
+    /// Send a PING when the connection has been idle for half of the idle
+    /// timeout, so that it stays open during long pauses in a download.
+    keep_alive: bool,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Write the TLS secrets to this file in the NSS key log format, so that
+    /// Wireshark can decrypt a capture. This overrides SSLKEYLOGFILE.
+    keylog: Option<PathBuf>,
+
+    #[structopt(name = "max-data", long)]
+    /// The connection flow control limit in bytes, i.e. how much the server
+    /// may send on all streams together before the client reads it. The
+    /// default is 2^62-1.
+    max_data: Option<u64>,
+
+    #[structopt(name = "max-stream-data", long)]
+    /// The stream flow control limit in bytes, i.e. how much the server may
+    /// send on a stream before it gets more credit. The default is 65535.
+    max_stream_data: Option<u64>,
+
+    #[structopt(name = "dump-packets", long, parse(from_os_str))]
+    /// Write every datagram that is sent or received to this file, so that
+    /// the exchange can be replayed. See the README for the format.
+    dump_packets: Option<PathBuf>,
+
+    #[structopt(name = "connect-to", long, parse(try_from_str = "parse_connect_to"))]
+    /// Connect to this HOST:PORT instead of the host and port of the URL,
+    /// e.g. "192.0.2.1:4433" or "[::1]:4433". The host of the URL is still
+    /// used for the TLS SNI (unless --sni is given) and the :authority header.
+    connect_to: Option<String>,
+
+    #[structopt(long)]
+    /// Send datagrams with the ECT(0) codepoint and count the ECN codepoints
+    /// of received datagrams. This only has an effect on Linux.
+    ecn: bool,
+
+    #[structopt(long)]
+    /// Move the connection to a new local port once this many datagrams have
+    /// been received, by binding a new socket. This tests connection migration,
+    /// which the server has to allow, e.g. neqo-http3-server --allow-migration.
+    migrate: Option<usize>,
+
+    #[structopt(name = "frame-trace", long)]
+    /// Print every HTTP/3 frame that is sent or received and every QPACK
+    /// table insertion. This needs the "frame-trace" feature.
+    frame_trace: bool,
+}
+
+fn parse_timeout(value: &str) -> Result<Duration, String> {
+    let (num, to_duration): (&str, fn(u64) -> Duration) = if value.ends_with("ms") {
+        (&value[..value.len() - 2], Duration::from_millis)
+    } else if value.ends_with('s') {
+        (&value[..value.len() - 1], Duration::from_secs)
+    } else {
+        (value, Duration::from_secs)
+    };
+    num.parse()
+        .map(to_duration)
+        .map_err(|_| format!("invalid timeout: {}", value))
+}
+
+/// Whether `c` is a token character (RFC 7230, Section 3.2.6), as used in header names and
+/// methods.
+fn is_tchar(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+/// Check that a header name only contains token characters and that the value cannot
+/// inject another header line.
+fn check_header(name: &str, value: &str) -> Result<(), String> {
+    if name.is_empty() || !name.chars().all(is_tchar) {
+        return Err(format!("invalid header name: {:?}", name));
+    }
+    if value.contains(|c: char| c == '\r' || c == '\n' || c == '\0') {
+        return Err(format!("invalid value for header {}: {:?}", name, value));
+    }
+    Ok(())
+}
+
+/// Make a header from a name and a value. Surrounding whitespace is removed and the name is
+/// lowercased, as HTTP/3 requires.
+fn make_header(name: &str, value: &str) -> Result<Header, String> {
+    let (name, value) = (name.trim().to_ascii_lowercase(), value.trim());
+    check_header(&name, value)?;
+    Ok((name, value.to_string()))
+}
+
+fn parse_header(header: &str) -> Result<Header, String> {
+    let colon = header
+        .find(':')
+        .ok_or_else(|| format!("header must be \"name: value\": {}", header))?;
+    make_header(&header[..colon], &header[colon + 1..])
+}
+
+/// A request as it is passed to `Http3Client::fetch`.
+#[derive(Debug, PartialEq)]
+struct Request {
+    method: String,
+    authority: String,
+    path: String,
+    headers: Vec<Header>,
+}
+
+/// Headers that only apply to an HTTP/1.1 connection. HTTP/3 does not allow them, so they
+/// are dropped from a request file.
+const CONNECTION_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Parse an HTTP/1.1 request line and header lines, ending at the end of `text` or at an
+/// empty line. `authority` is used if there is no Host header.
+fn parse_request(text: &str, authority: &str) -> Result<Request, String> {
+    let mut lines = text.lines().map(|l| l.trim_end_matches('\r'));
+    let request_line = lines.next().unwrap_or("");
+    let mut parts = request_line.split(' ');
+    let (method, path) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), version, None)
+            if version.map_or(true, |v| v.starts_with("HTTP/")) =>
+        {
+            (method, path)
+        }
+        _ => return Err(format!("invalid request line: {:?}", request_line)),
+    };
+    if method.is_empty() || !method.chars().all(is_tchar) {
+        return Err(format!("invalid method: {:?}", method));
+    }
+    if !path.starts_with('/') && path != "*" {
+        return Err(format!("the request target must be a path: {:?}", path));
+    }
+
+    let mut request = Request {
+        method: method.to_string(),
+        authority: authority.to_string(),
+        path: path.to_string(),
+        headers: Vec::new(),
+    };
+    let mut host = false;
+    for line in lines.by_ref().take_while(|l| !l.is_empty()) {
+        if line.starts_with(|c: char| c == ' ' || c == '\t') {
+            return Err(format!("folded header lines are not supported: {:?}", line));
+        }
+        let (name, value) = parse_header(line)?;
+        if name == "host" {
+            if host {
+                return Err(String::from("more than one Host header"));
+            }
+            host = true;
+            request.authority = value;
+        } else if !CONNECTION_HEADERS.contains(&name.as_str()) {
+            request.headers.push((name, value));
+        }
+    }
+    if lines.any(|l| !l.is_empty()) {
+        return Err(String::from("a request file can't have a body"));
+    }
+    Ok(request)
+}
+
+fn parse_connect_to(value: &str) -> Result<String, String> {
+    let colon = value
+        .rfind(':')
+        .ok_or_else(|| format!("--connect-to must be \"host:port\": {}", value))?;
+    if colon == 0 {
+        return Err(format!("missing host in --connect-to: {}", value));
+    }
+    value[colon + 1..]
+        .parse::<u16>()
+        .map_err(|_| format!("invalid port in --connect-to: {}", value))?;
+    Ok(value.to_string())
+}
+
+fn parse_cc(name: &str) -> Result<CongestionControlAlgorithm, String> {
+    match name {
+        "newreno" => Ok(CongestionControlAlgorithm::NewReno),
+        _ => Err(format!("unknown congestion control algorithm: {}", name)),
+    }
 }
 
 impl Args {
-    fn remote_addr(&self) -> Result<SocketAddr, io::Error> {
-        Ok(self.to_socket_addrs()?.next().expect("No remote addresses"))
+    /// The request headers, from both --header and --request-header.
+    fn headers(&self) -> Result<Vec<Header>, String> {
+        let mut headers = self
+            .header
+            .chunks(2)
+            .map(|h| make_header(&h[0], &h[1]))
+            .collect::<Result<Vec<_>, _>>()?;
+        headers.extend_from_slice(&self.request_header);
+        Ok(headers)
+    }
+
+    /// The requests to send, from --request-file or from the URLs, -m and the request headers.
+    fn requests(&self) -> Result<Vec<Request>, String> {
+        if let Some(path) = &self.request_file {
+            if !self.more_urls.is_empty() {
+                return Err(String::from("--request-file can only be used with one URL"));
+            }
+            let text = fs::read_to_string(path)
+                .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+            return parse_request(&text, &authority(&self.url))
+                .map(|request| vec![request])
+                .map_err(|e| format!("{}: {}", path.display(), e));
+        }
+        if self.output.is_some() && !self.more_urls.is_empty() {
+            return Err(String::from("--output can only be used with one URL"));
+        }
+        let headers = self.headers()?;
+        let mut requests = Vec::new();
+        for url in Some(&self.url).into_iter().chain(&self.more_urls) {
+            if url.origin() != self.url.origin() {
+                return Err(format!("{} does not have the origin of {}", url, self.url));
+            }
+            requests.push(Request {
+                method: self.method.clone(),
+                authority: authority(url),
+                path: url.path().to_string(),
+                headers: headers.clone(),
+            });
+        }
+        Ok(requests)
     }
 
-    fn local_addr(&self) -> Result<SocketAddr, io::Error> {
-        match self.remote_addr()? {
-            SocketAddr::V4(..) => Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::from([0; 4])), 0)),
-            SocketAddr::V6(..) => Ok(SocketAddr::new(IpAddr::V6(Ipv6Addr::from([0; 16])), 0)),
+    fn server_name(&self) -> &str {
+        match &self.sni {
+            Some(sni) if !sni.is_empty() => sni,
+            _ => self.url.host_str().unwrap(),
         }
     }
+
+    /// Whether a resolved address of the server has the address family chosen with -4 or -6.
+    fn family_allowed(&self, addr: &SocketAddr) -> bool {
+        match addr {
+            SocketAddr::V4(..) => !self.ipv6,
+            SocketAddr::V6(..) => !self.ipv4,
+        }
+    }
+}
+
+/// The unspecified local address of the same family as `remote_addr`.
+fn local_addr_for(remote_addr: &SocketAddr) -> SocketAddr {
+    match remote_addr {
+        SocketAddr::V4(..) => SocketAddr::new(IpAddr::V4(Ipv4Addr::from([0; 4])), 0),
+        SocketAddr::V6(..) => SocketAddr::new(IpAddr::V6(Ipv6Addr::from([0; 16])), 0),
+    }
+}
+
+/// Try the addresses of the server in turn until a socket can be bound and connected to one.
+fn connect_socket(args: &Args) -> Result<(UdpSocket, SocketAddr), io::Error> {
+    let mut last_err = None;
+    for remote_addr in args.to_socket_addrs()? {
+        match UdpSocket::bind(local_addr_for(&remote_addr))
+            .and_then(|socket| socket.connect(remote_addr).map(|_| socket))
+        {
+            Ok(socket) => return Ok((socket, remote_addr)),
+            Err(e) => {
+                eprintln!("Unable to connect to {}: {}", remote_addr, e);
+                last_err = Some(e);
+            }
+        }
+    }
+    Err(last_err
+        .unwrap_or_else(|| io::Error::new(ErrorKind::AddrNotAvailable, "No remote addresses")))
 }
 
 impl ToSocketAddrs for Args {
     type Iter = ::std::vec::IntoIter<SocketAddr>;
     fn to_socket_addrs(&self) -> ::std::io::Result<Self::Iter> {
         // This is idiotic.  There is no path from hostname: String to IpAddr.
-        // And no means of controlling name resolution either.
-        if self.url.port_or_known_default().is_none() {
-            return Err(io::Error::new(ErrorKind::InvalidInput, "invalid port"));
-        }
-        std::fmt::format(format_args!(
-            "{}:{}",
-            self.url.host_str().unwrap_or("localhost"),
-            self.url.port_or_known_default().unwrap()
-        ))
-        .to_socket_addrs()
+        // And no means of controlling name resolution either, other than --connect-to.
+        let target = match &self.connect_to {
+            Some(connect_to) => connect_to.clone(),
+            None => {
+                if self.url.port_or_known_default().is_none() {
+                    return Err(io::Error::new(ErrorKind::InvalidInput, "invalid port"));
+                }
+                format!(
+                    "{}:{}",
+                    self.url.host_str().unwrap_or("localhost"),
+                    self.url.port_or_known_default().unwrap()
+                )
+            }
+        };
+        let addrs = target.to_socket_addrs()?;
+        Ok(addrs
+            .filter(|addr| self.family_allowed(addr))
+            .collect::<Vec<_>>()
+            .into_iter())
     }
 }
 
 trait Handler {
     fn handle(&mut self, args: &Args, client: &mut Http3Client) -> bool;
+
+    /// The time at which `handle` needs to be called, even if nothing happens
+    /// on the connection.
+    fn deadline(&self) -> Option<Instant> {
+        None
+    }
+}
+
+// Whether a send failed because the datagram is too large for the path.
+#[cfg(unix)]
+fn is_msg_size_error(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EMSGSIZE)
+}
+
+#[cfg(windows)]
+fn is_msg_size_error(err: &io::Error) -> bool {
+    // WSAEMSGSIZE, which the libc crate does not define.
+    err.raw_os_error() == Some(10040)
+}
+
+// Write one record of a packet dump: a direction byte ('>' for sent, '<' for received), the
+// time since the start of the dump in microseconds as a big-endian u64, the length of the
+// datagram as a big-endian u32 and the datagram.
+fn write_dump_record(
+    out: &mut impl Write,
+    sent: bool,
+    elapsed: Duration,
+    data: &[u8],
+) -> io::Result<()> {
+    let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::max_value());
+    let len = u32::try_from(data.len()).expect("datagrams are smaller than 4GB");
+    out.write_all(&[if sent { b'>' } else { b'<' }])?;
+    out.write_all(&micros.to_be_bytes())?;
+    out.write_all(&len.to_be_bytes())?;
+    out.write_all(data)
+}
+
+/// Records the datagrams that the client sends and receives, for `--dump-packets`.
+struct PacketDump {
+    file: File,
+    start: Instant,
 }
 
-fn emit_datagram(socket: &UdpSocket, d: Option<Datagram>) {
-    if let Some(d) = d {
-        let sent = socket.send(&d[..]).expect("Error sending datagram");
-        if sent != d.len() {
-            eprintln!("Unable to send all {} bytes of datagram", d.len());
+impl PacketDump {
+    fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: File::create(path)?,
+            start: Instant::now(),
+        })
+    }
+
+    fn record(&mut self, sent: bool, data: &[u8]) {
+        let elapsed = self.start.elapsed();
+        if let Err(err) = write_dump_record(&mut self.file, sent, elapsed, data) {
+            eprintln!("Unable to write the packet dump: {}", err);
         }
     }
 }
 
+// Open the packet dump if one was asked for; failing to create it is fatal.
+fn open_packet_dump(args: &Args) -> Option<PacketDump> {
+    args.dump_packets
+        .as_ref()
+        .map(|path| match PacketDump::create(path) {
+            Ok(dump) => dump,
+            Err(err) => {
+                eprintln!("Unable to create {}: {}", path.display(), err);
+                exit(1)
+            }
+        })
+}
+
+/// Where response bodies go: the --output file, or stdout. A printed body is collected
+/// until the stream ends, so that it is not broken up by the reads, which could also split
+/// a UTF-8 character.
+#[derive(Default)]
+struct ResponseOutput {
+    file: Option<File>,
+    bodies: HashMap<u64, Vec<u8>>,
+}
+
+impl ResponseOutput {
+    // Create the file for --output; failing to create it is fatal.
+    fn new(args: &Args) -> Self {
+        let file = args.output.as_ref().map(|path| match File::create(path) {
+            Ok(file) => file,
+            Err(err) => {
+                eprintln!("Unable to create {}: {}", path.display(), err);
+                exit(1)
+            }
+        });
+        Self {
+            file,
+            bodies: HashMap::new(),
+        }
+    }
+
+    fn data(&mut self, args: &Args, stream_id: u64, data: &[u8]) -> io::Result<()> {
+        if let Some(file) = &mut self.file {
+            println!("READ[{}]: {} bytes", stream_id, data.len());
+            file.write_all(data)
+        } else if args.omit_read_data {
+            println!("READ[{}]: {} bytes", stream_id, data.len());
+            Ok(())
+        } else {
+            self.bodies
+                .entry(stream_id)
+                .or_default()
+                .extend_from_slice(data);
+            Ok(())
+        }
+    }
+
+    fn take_body(&mut self, stream_id: u64) -> Option<String> {
+        self.bodies
+            .remove(&stream_id)
+            .map(|body| String::from_utf8_lossy(&body).into_owned())
+    }
+
+    /// The response on `stream_id` is complete.
+    fn fin(&mut self, stream_id: u64) {
+        if let Some(body) = self.take_body(stream_id) {
+            println!("READ[{}]: {}", stream_id, body);
+        }
+    }
+}
+
+// Send a datagram. A send that would block is tried once more. Sending only a part of the
+// datagram is an error too, as a truncated packet went on the wire. `socket_ecn` is the
+// codepoint that the socket marks datagrams with, which only changes with `--ecn`.
+fn emit_datagram(
+    socket: &UdpSocket,
+    socket_ecn: &mut Ecn,
+    d: &Datagram,
+    dump: &mut Option<PacketDump>,
+) -> io::Result<()> {
+    if let Some(dump) = dump {
+        dump.record(true, &d[..]);
+    }
+    if d.ecn() != *socket_ecn {
+        ecn::set(socket, d.ecn())?;
+        *socket_ecn = d.ecn();
+    }
+    let res = match socket.send(&d[..]) {
+        Err(ref err) if err.kind() == ErrorKind::WouldBlock => socket.send(&d[..]),
+        res => res,
+    };
+    match res {
+        Ok(sent) if sent == d.len() => Ok(()),
+        Ok(sent) => Err(io::Error::new(
+            ErrorKind::WriteZero,
+            format!("only {} of {} bytes of a datagram were sent", sent, d.len()),
+        )),
+        Err(err) => {
+            if is_msg_size_error(&err) {
+                eprintln!(
+                    "A datagram of {} bytes is larger than the path MTU allows",
+                    d.len()
+                );
+            }
+            Err(err)
+        }
+    }
+}
+
+// For `--migrate`: bind a new socket to the server and move the connection to its address.
+// The old socket is closed, so whatever the server sends to it before the new path is
+// validated is lost, and is sent again on the new path.
+fn migrate_socket(
+    args: &Args,
+    conn: &mut Connection,
+    socket: &mut UdpSocket,
+    socket_ecn: &mut Ecn,
+    local_addr: &mut SocketAddr,
+    remote_addr: &SocketAddr,
+) -> io::Result<()> {
+    let new_socket = UdpSocket::bind(local_addr_for(remote_addr))?;
+    new_socket.connect(remote_addr)?;
+    let new_addr = new_socket.local_addr()?;
+    if args.ecn {
+        ecn::enable_recv(&new_socket)?;
+    }
+    conn.migrate(new_addr)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+    println!("Client migrating: {:?} -> {:?}", new_addr, remote_addr);
+    *socket = new_socket;
+    *socket_ecn = Ecn::default();
+    *local_addr = new_addr;
+    Ok(())
+}
+
+// Wait for the next datagram at most until the connection's next timer.
+// `set_read_timeout` does not accept a zero duration, so wait at least 1ms.
+fn set_read_timeout(socket: &UdpSocket, timeout: Option<Duration>) {
+    let timeout = timeout.map(|t| max(t, Duration::from_millis(1)));
+    socket
+        .set_read_timeout(timeout)
+        .expect("Unable to set the read timeout");
+}
+
 fn process_loop(
-    local_addr: &SocketAddr,
+    local_addr: &mut SocketAddr,
     remote_addr: &SocketAddr,
-    socket: &UdpSocket,
+    socket: &mut UdpSocket,
     client: &mut Http3Client,
     handler: &mut dyn Handler,
     args: &Args,
+    dump: &mut Option<PacketDump>,
 ) -> neqo_http3::Http3State {
     let buf = &mut [0u8; 2048];
+    let mut received = 0;
+    let mut migrate_after = args.migrate;
+    let mut socket_ecn = Ecn::default();
     loop {
         if let Http3State::Closed(..) = client.state() {
             return client.state();
         }
 
         let mut exiting = !handler.handle(args, client);
+        // Let the HTTP/3 layer write whatever the handler queued, so that it is
+        // part of the datagrams sent below rather than waiting for the next read.
+        // If that produces new events, handle them before waiting for the socket.
+        while client.process_http3(Instant::now()) && !exiting {
+            exiting = !handler.handle(args, client);
+        }
 
+        // Send every datagram that is ready before blocking in `recv`.
         loop {
             let output = client.process_output(Instant::now());
             match output {
-                Output::Datagram(dgram) => emit_datagram(&socket, Some(dgram)),
+                Output::Datagram(dgram) => {
+                    let dgram = if args.ecn {
+                        dgram.with_ecn(Ecn::Ect0)
+                    } else {
+                        dgram
+                    };
+                    if let Err(err) = emit_datagram(socket, &mut socket_ecn, &dgram, dump) {
+                        eprintln!("Unable to send a datagram: {}", err);
+                        // Try to tell the server, then stop.
+                        if !matches!(
+                            client.state(),
+                            Http3State::Closing(_) | Http3State::Closed(_)
+                        ) {
+                            client.close(
+                                Instant::now(),
+                                Error::HttpInternalError.code(),
+                                "send error",
+                            );
+                        }
+                        exiting = true;
+                    }
+                }
                 Output::Callback(duration) => {
-                    socket.set_read_timeout(Some(duration)).unwrap();
+                    let duration = match handler.deadline() {
+                        Some(deadline) => {
+                            min(duration, deadline.saturating_duration_since(Instant::now()))
+                        }
+                        None => duration,
+                    };
+                    set_read_timeout(socket, Some(duration));
                     break;
                 }
                 Output::None => {
                     // Not strictly necessary, since we're about to exit
-                    socket.set_read_timeout(None).unwrap();
+                    set_read_timeout(socket, None);
                     exiting = true;
                     break;
                 }
             }
         }
-        client.process_http3(Instant::now());
 
         if exiting {
             return client.state();
         }
 
-        match socket.recv(&mut buf[..]) {
+        match ecn::recv(socket, &mut buf[..]) {
             Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
                 // timer expired
                 client.process_timer(Instant::now());
@@ -148,42 +723,165 @@ fn process_loop(
                 eprintln!("UDP error: {}", err);
                 exit(1)
             }
-            Ok(sz) => {
+            Ok((sz, ecn)) => {
                 if sz == buf.len() {
                     eprintln!("Received more than {} bytes", buf.len());
                     continue;
                 }
                 if sz > 0 {
-                    let d = Datagram::new(*remote_addr, *local_addr, &buf[..sz]);
+                    if let Some(dump) = dump {
+                        dump.record(false, &buf[..sz]);
+                    }
+                    let d = Datagram::new(*remote_addr, *local_addr, &buf[..sz]).with_ecn(ecn);
                     client.process_input(d, Instant::now());
                     client.process_http3(Instant::now());
+                    received += 1;
+                    if migrate_after.map_or(false, |n| received >= n)
+                        && *client.conn().state() == State::Connected
+                    {
+                        migrate_after = None;
+                        let res = migrate_socket(
+                            args,
+                            client.conn(),
+                            socket,
+                            &mut socket_ecn,
+                            local_addr,
+                            remote_addr,
+                        );
+                        if let Err(err) = res {
+                            eprintln!("Unable to migrate: {}", err);
+                        }
+                    }
                 }
             }
         };
     }
 }
 
-struct PreConnectHandler {}
-impl Handler for PreConnectHandler {
-    fn handle(&mut self, _args: &Args, client: &mut Http3Client) -> bool {
-        let authentication_needed = |e| matches!(e, Http3ClientEvent::AuthenticationNeeded);
-        if client.events().any(authentication_needed) {
-            client.authenticated(AuthenticationStatus::Ok, Instant::now());
+// Print why the connection was closed and exit with an error unless it was closed normally.
+fn check_close_reason(state: &Http3State, vn_versions: &[u32]) {
+    let reason = match state {
+        Http3State::Closing(reason) | Http3State::Closed(reason) => reason,
+        _ => return,
+    };
+    match reason {
+        ConnectionError::Application(0) => {}
+        ConnectionError::Application(e) if *e == Error::HttpNoError.code() => {}
+        ConnectionError::Application(e) => {
+            eprintln!(
+                "Connection closed with application error {}",
+                Error::describe_code(*e)
+            );
+            exit(1);
+        }
+        ConnectionError::Transport(TransportError::IdleTimeout) => {
+            eprintln!("Connection closed: idle timeout");
+            exit(1);
+        }
+        ConnectionError::Transport(TransportError::VersionNegotiation) => {
+            eprintln!(
+                "Connection closed: the server does not support QUIC version {:#x}, only {:x?}",
+                QUIC_VERSION, vn_versions
+            );
+            exit(1);
+        }
+        ConnectionError::Transport(e) => {
+            eprintln!("Connection closed with transport error {:?}", e);
+            exit(1);
         }
-        Http3State::Connected != client.state()
     }
 }
 
 #[derive(Default)]
-struct PostConnectHandler {
+struct RequestHandler {
+    // The requests that have not been sent yet.
+    queue: VecDeque<Request>,
+    // The requests that were sent and have no complete response yet, by stream.
+    sent: HashMap<u64, Request>,
+    // The request streams in `sent` and the push streams whose responses are not complete.
     streams: HashSet<u64>,
+    deadline: Option<Instant>,
+    timed_out: bool,
+    output: ResponseOutput,
+    settings_printed: bool,
+    connected_printed: bool,
+}
+
+impl RequestHandler {
+    // Send queued requests as soon as the connection allows it, i.e. in 0-RTT or once it is
+    // connected, keeping at most --parallel of them in flight. Returns false if a request
+    // can't be sent at all.
+    fn send_requests(&mut self, args: &Args, client: &mut Http3Client) -> bool {
+        while self.sent.len() < args.parallel.unwrap_or(usize::max_value()) {
+            let request = match self.queue.pop_front() {
+                Some(request) => request,
+                None => break,
+            };
+            match fetch(args, client, &request) {
+                Ok(stream_id) => {
+                    self.print_connected(client);
+                    if let Some(headers) = client.get_sent_headers(StreamId(stream_id)) {
+                        println!("SENT HEADERS[{}]: {:?}", stream_id, headers);
+                    }
+                    self.sent.insert(stream_id, request);
+                    self.streams.insert(stream_id);
+                    if self.deadline.is_none() {
+                        self.deadline = args.timeout.map(|t| Instant::now() + t);
+                    }
+                }
+                // Wait until the connection is ready or the server allows more streams.
+                Err(Error::Unavailable) | Err(Error::StreamLimit) => {
+                    self.queue.push_front(request);
+                    break;
+                }
+                Err(err) => {
+                    eprintln!("Could not send the request: {:?}", err);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn print_connected(&mut self, client: &Http3Client) {
+        if self.connected_printed {
+            return;
+        }
+        self.connected_printed = true;
+        if let Some((local, remote)) = client.current_path() {
+            println!("Connected: {} -> {}", local, remote);
+        }
+        if let Some(dcid) = client.stats().retry_dcid {
+            println!(
+                "Retry received, new destination connection ID {}",
+                hex(&dcid)
+            );
+        }
+    }
 }
 
 // This is a bit fancier than actually needed.
-impl Handler for PostConnectHandler {
+impl Handler for RequestHandler {
     fn handle(&mut self, args: &Args, client: &mut Http3Client) -> bool {
+        if self.deadline.map_or(false, |d| Instant::now() >= d) {
+            for stream_id in self.streams.drain() {
+                eprintln!("Request on stream {} timed out", stream_id);
+                let _ =
+                    client.stream_reset(StreamId(stream_id), Error::HttpRequestCancelled.code());
+            }
+            self.timed_out = true;
+            client.close_graceful(Instant::now(), 0, "timeout");
+            return false;
+        }
+
         let mut data = vec![0; 4000];
         client.process_http3(Instant::now());
+        if !self.settings_printed {
+            if let Some(settings) = client.peer_settings() {
+                println!("Server settings: {:?}", settings);
+                self.settings_printed = true;
+            }
+        }
         while let Some(event) = client.next_event() {
             match event {
                 Http3ClientEvent::HeaderReady { stream_id } => {
@@ -195,147 +893,355 @@ impl Handler for PostConnectHandler {
                     let headers = client.read_response_headers(StreamId(stream_id));
                     println!("READ HEADERS[{}]: {:?}", stream_id, headers);
                 }
+                Http3ClientEvent::NewPushStream { stream_id } => {
+                    println!("New push stream: {}", stream_id);
+                    self.streams.insert(stream_id);
+                }
+                Http3ClientEvent::InformationalHeaders { stream_id, headers } => {
+                    println!("READ INFORMATIONAL HEADERS[{}]: {:?}", stream_id, headers);
+                }
+                Http3ClientEvent::TrailersReady { stream_id } => {
+                    let trailers = client.read_response_trailers(StreamId(stream_id));
+                    println!("READ TRAILERS[{}]: {:?}", stream_id, trailers);
+                }
                 Http3ClientEvent::DataReadable { stream_id } => {
                     if !self.streams.contains(&stream_id) {
                         println!("Data on unexpected stream: {}", stream_id);
                         return false;
                     }
 
-                    let (sz, fin) = client
-                        .read_response_data(Instant::now(), StreamId(stream_id), &mut data)
-                        .expect("Read should succeed");
-                    if args.omit_read_data {
-                        println!("READ[{}]: {} bytes", stream_id, sz);
-                    } else {
-                        println!(
-                            "READ[{}]: {}",
-                            stream_id,
-                            String::from_utf8(data.clone()).unwrap()
-                        )
+                    // Read everything that is available; it may not fit into the buffer at once.
+                    loop {
+                        let (sz, fin) = match client.read_response_data(
+                            Instant::now(),
+                            StreamId(stream_id),
+                            &mut data,
+                        ) {
+                            Ok(r) => r,
+                            Err(err) => {
+                                eprintln!("Unable to read the response[{}]: {}", stream_id, err);
+                                return false;
+                            }
+                        };
+                        if sz > 0 {
+                            if let Err(err) = self.output.data(args, stream_id, &data[..sz]) {
+                                eprintln!("Unable to write the response: {}", err);
+                                client.close_graceful(Instant::now(), 0, "output failed");
+                                return false;
+                            }
+                        }
+                        if fin {
+                            self.output.fin(stream_id);
+                            println!("<FIN[{}]>", stream_id);
+                            // Pushed responses can still be arriving after the request is done.
+                            self.streams.remove(&stream_id);
+                            self.sent.remove(&stream_id);
+                            if !self.streams.is_empty() || !self.queue.is_empty() {
+                                break;
+                            }
+                            save_resumption_token(args, client);
+                            client.close_graceful(Instant::now(), 0, "kthxbye!");
+                            return false;
+                        }
+                        if sz == 0 {
+                            break;
+                        }
                     }
-                    if fin {
-                        println!("<FIN[{}]>", stream_id);
-                        client.close(Instant::now(), 0, "kthxbye!");
-                        return false;
+                }
+                Http3ClientEvent::Reset { stream_id, error } => {
+                    eprintln!(
+                        "Request on stream {} was reset: {}",
+                        stream_id,
+                        Error::describe_code(error)
+                    );
+                    client.close_graceful(Instant::now(), 0, "request reset");
+                    return false;
+                }
+                Http3ClientEvent::StopSending { stream_id, error } => {
+                    eprintln!(
+                        "Server stopped reading the request on stream {}: {}",
+                        stream_id,
+                        Error::describe_code(error)
+                    );
+                }
+                Http3ClientEvent::AuthenticationNeeded => {
+                    client.authenticated(AuthenticationStatus::Ok, Instant::now());
+                }
+                Http3ClientEvent::ZeroRttRejected => {
+                    // All requests sent in 0-RTT are dropped, so send them again once
+                    // the connection is established.
+                    println!("0-RTT rejected, resending the requests");
+                    self.streams.clear();
+                    let mut sent = self.sent.drain().collect::<Vec<_>>();
+                    sent.sort_by_key(|(stream_id, _)| *stream_id);
+                    for (_, request) in sent.into_iter().rev() {
+                        self.queue.push_front(request);
                     }
                 }
                 _ => {}
             }
         }
 
-        true
+        self.send_requests(args, client)
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.deadline
     }
 }
 
-fn to_headers(values: &[impl AsRef<str>]) -> Vec<Header> {
-    values
-        .iter()
-        .scan(None, |state, value| {
-            if let Some(name) = state.take() {
-                *state = None;
-                Some((name, value.as_ref().to_string())) // TODO use a real type
-            } else {
-                *state = Some(value.as_ref().to_string());
-                None
+fn load_resumption_token(args: &Args, client: &mut Http3Client) {
+    if let Some(path) = &args.resumption_token_file {
+        // A missing file only means that there is no token yet.
+        if let Ok(token) = fs::read(path) {
+            if let Err(err) = client.set_resumption_token(Instant::now(), &token) {
+                eprintln!("Unable to use the resumption token: {:?}", err);
             }
-        })
-        .collect()
+        }
+    }
+}
+
+fn save_resumption_token(args: &Args, client: &Http3Client) {
+    if let Some(path) = &args.resumption_token_file {
+        // The token is only available after a NewSessionTicket has been received.
+        match client.resumption_token() {
+            Some(token) => {
+                if let Err(err) = fs::write(path, token) {
+                    eprintln!("Unable to write the resumption token: {}", err);
+                }
+            }
+            None => eprintln!("No resumption token available"),
+        }
+    }
 }
 
-fn client(args: Args, socket: UdpSocket, local_addr: SocketAddr, remote_addr: SocketAddr) {
-    let mut client = Http3Client::new(
-        args.url.host_str().unwrap(),
-        &args.alpn,
+/// The :authority of a request for `url`. `Url` already converts IDNs to punycode and
+/// brackets IPv6 addresses; the port is added if it is not the default port of the scheme.
+fn authority(url: &Url) -> String {
+    let host = url.host_str().unwrap();
+    match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    }
+}
+
+fn fetch(args: &Args, client: &mut Http3Client, request: &Request) -> Result<u64, Error> {
+    let stream_id = client.fetch(
+        &request.method,
+        &args.url.scheme(),
+        &request.authority,
+        &request.path,
+        &request.headers,
+    )?;
+    let _ = client.stream_close_send(StreamId(stream_id));
+    Ok(stream_id)
+}
+
+/// Prints the frames of `--frame-trace`, one line per frame.
+#[cfg(feature = "frame-trace")]
+struct FramePrinter;
+
+#[cfg(feature = "frame-trace")]
+impl FrameTracer for FramePrinter {
+    fn frame(&mut self, event: &FrameEvent) {
+        println!("{}", event);
+    }
+
+    fn qpack_insert(&mut self, direction: FrameDirection, name: &[u8], value: &[u8]) {
+        let arrow = match direction {
+            FrameDirection::Sent => ">",
+            FrameDirection::Received => "<",
+        };
+        println!(
+            "{} qpack insert {}: {}",
+            arrow,
+            String::from_utf8_lossy(name),
+            String::from_utf8_lossy(value)
+        );
+    }
+}
+
+/// The ALPN label of HTTP/0.9, which neqo-server speaks.
+const HTTP_09_ALPN: &str = "http/0.9";
+
+// Make the transport connection with the transport settings of the arguments.
+fn transport_connection(
+    args: &Args,
+    protocols: &[impl AsRef<str>],
+    local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+) -> Connection {
+    let mut conn = Connection::new_client(
+        args.server_name(),
+        protocols,
         Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
         local_addr,
         remote_addr,
-        args.max_table_size,
-        args.max_blocked_streams,
     )
     .expect("must succeed");
-    // Temporary here to help out the type inference engine
-    let mut h = PreConnectHandler {};
-    process_loop(
-        &local_addr,
-        &remote_addr,
-        &socket,
-        &mut client,
-        &mut h,
-        &args,
-    );
-
-    let client_stream_id = client.fetch(
-        &args.method,
-        &args.url.scheme(),
-        &args.url.host_str().unwrap(),
-        &args.url.path(),
-        &to_headers(&args.header),
-    );
+    conn.set_congestion_control(args.cc).expect("must succeed");
+    conn.set_keep_alive(args.keep_alive);
+    if let Some(max_data) = args.max_data {
+        conn.set_max_data(max_data).expect("must succeed");
+    }
+    if let Some(max_stream_data) = args.max_stream_data {
+        conn.set_max_stream_data(max_stream_data)
+            .expect("must succeed");
+    }
+    conn
+}
 
-    if let Err(err) = client_stream_id {
-        eprintln!("Could not connect: {:?}", err);
-        return;
+// Run HTTP/3 on `conn`. Its handshake has already started if the protocol was negotiated;
+// the server's certificate then still needs to be accepted.
+fn client(
+    args: Args,
+    mut socket: UdpSocket,
+    mut local_addr: SocketAddr,
+    remote_addr: SocketAddr,
+    conn: Connection,
+    mut dump: Option<PacketDump>,
+) {
+    let negotiated = *conn.state() != State::Init;
+    let mut client = Http3Client::new_with_conn_and_params(
+        conn,
+        Http3Parameters {
+            max_table_size: args.max_table_size,
+            max_blocked_streams: args.max_blocked_streams,
+            max_header_list_size: None,
+            qpack_dynamic_table: !args.no_qpack_dynamic,
+        },
+    )
+    .expect("must succeed");
+    if negotiated {
+        client.authenticated(AuthenticationStatus::Ok, Instant::now());
+    } else {
+        load_resumption_token(&args, &mut client);
+    }
+    #[cfg(feature = "frame-trace")]
+    {
+        if args.frame_trace {
+            client.set_frame_tracer(Box::new(FramePrinter));
+        }
     }
-    let client_stream_id = client_stream_id.unwrap();
-    let _ = client.stream_close_send(StreamId(client_stream_id));
 
-    let mut h2 = PostConnectHandler::default();
-    h2.streams.insert(client_stream_id);
-    process_loop(
-        &local_addr,
+    let mut h = RequestHandler {
+        queue: args
+            .requests()
+            .expect("the requests were checked at startup")
+            .into_iter()
+            .collect(),
+        output: ResponseOutput::new(&args),
+        ..RequestHandler::default()
+    };
+    let state = process_loop(
+        &mut local_addr,
         &remote_addr,
-        &socket,
+        &mut socket,
         &mut client,
-        &mut h2,
+        &mut h,
         &args,
+        &mut dump,
     );
+    println!("{:?}", client.stats());
+    check_close_reason(&state, &client.stats().vn_versions);
+    if h.timed_out {
+        exit(1);
+    }
 }
 
 fn main() {
-    init();
     let args = Args::from_args();
+    // NSS writes the key log itself; it reads the path from the environment when the
+    // first TLS socket is created.
+    if let Some(keylog) = &args.keylog {
+        env::set_var("SSLKEYLOGFILE", keylog);
+    }
+    init();
+    if args.frame_trace && cfg!(not(feature = "frame-trace")) {
+        eprintln!("--frame-trace needs neqo-client to be built with the frame-trace feature");
+        exit(1)
+    }
+    if let Err(e) = args.requests() {
+        eprintln!("{}", e);
+        exit(1)
+    }
+    let offers_http_09 = args.alpn.iter().any(|alpn| alpn == HTTP_09_ALPN);
+    if (args.use_old_http || offers_http_09) && !args.more_urls.is_empty() {
+        eprintln!("Only one URL can be fetched with HTTP/0.9");
+        exit(1)
+    }
+    if args.parallel == Some(0) {
+        eprintln!("--parallel must be at least 1");
+        exit(1)
+    }
 
-    let remote_addr = match args.remote_addr() {
-        Err(e) => {
-            eprintln!("Unable to resolve remote addr: {}", e);
-            exit(1)
-        }
-        Ok(addr) => addr,
-    };
-    let socket = match args.local_addr().and_then(UdpSocket::bind) {
+    let (socket, remote_addr) = match connect_socket(&args) {
         Err(e) => {
-            eprintln!("Unable to bind UDP socket: {}", e);
+            eprintln!("Unable to connect UDP socket: {}", e);
             exit(1)
         }
-        Ok(s) => s,
+        Ok(r) => r,
     };
-    socket.connect(&args).expect("Unable to connect UDP socket");
 
     let local_addr = socket.local_addr().expect("Socket local address not bound");
+    if args.ecn {
+        if let Err(e) = ecn::enable_recv(&socket) {
+            eprintln!("Unable to read the ECN codepoint of datagrams: {}", e);
+        }
+    }
 
     println!("Client connecting: {:?} -> {:?}", local_addr, remote_addr);
 
+    let mut dump = open_packet_dump(&args);
     if args.use_old_http {
-        old::old_client(args, socket, local_addr, remote_addr)
+        let conn = transport_connection(&args, &[HTTP_09_ALPN], local_addr, remote_addr);
+        old::old_client(args, socket, local_addr, remote_addr, conn, dump)
+    } else if offers_http_09 {
+        let mut socket = socket;
+        let mut local_addr = local_addr;
+        let mut conn = transport_connection(&args, &args.alpn, local_addr, remote_addr);
+        let alpn = old::negotiate_alpn(
+            &mut local_addr,
+            &remote_addr,
+            &mut socket,
+            &mut conn,
+            &args,
+            &mut dump,
+        );
+        match alpn.as_ref().map(String::as_str) {
+            Some(HTTP_09_ALPN) => {
+                println!("Negotiated {}", HTTP_09_ALPN);
+                conn.authenticated(AuthenticationStatus::Ok, Instant::now());
+                old::old_client(args, socket, local_addr, remote_addr, conn, dump)
+            }
+            Some(alpn) => {
+                println!("Negotiated {}", alpn);
+                client(args, socket, local_addr, remote_addr, conn, dump)
+            }
+            None => {
+                eprintln!("The handshake failed: {:?}", conn.state());
+                exit(1)
+            }
+        }
     } else {
-        client(args, socket, local_addr, remote_addr)
+        let conn = transport_connection(&args, &args.alpn, local_addr, remote_addr);
+        client(args, socket, local_addr, remote_addr, conn, dump)
     }
 }
 
 mod old {
-    use std::cell::RefCell;
-    use std::collections::HashSet;
+    use std::collections::{HashMap, HashSet};
+    use std::io::ErrorKind;
     use std::net::{SocketAddr, UdpSocket};
     use std::process::exit;
-    use std::rc::Rc;
     use std::time::Instant;
 
-    use neqo_common::Datagram;
-    use neqo_transport::{
-        Connection, ConnectionEvent, FixedConnectionIdManager, State, StreamType,
-    };
+    use neqo_common::{matches, Datagram, Ecn};
+    use neqo_transport::{Connection, ConnectionEvent, Output, State, StreamType};
 
-    use super::{emit_datagram, Args};
+    use super::{
+        ecn, emit_datagram, migrate_socket, set_read_timeout, Args, PacketDump, ResponseOutput,
+    };
 
     trait HandlerOld {
         fn handle(&mut self, args: &Args, client: &mut Connection) -> bool;
@@ -351,6 +1257,36 @@ mod old {
     #[derive(Default)]
     struct PostConnectHandlerOld {
         streams: HashSet<u64>,
+        // The part of each request that its stream has not accepted yet.
+        unsent: HashMap<u64, Vec<u8>>,
+        output: ResponseOutput,
+    }
+
+    impl PostConnectHandlerOld {
+        fn request(&mut self, client: &mut Connection, path: &str) {
+            let stream_id = client.stream_create(StreamType::BiDi).unwrap();
+            self.streams.insert(stream_id);
+            self.unsent
+                .insert(stream_id, format!("GET {}\r\n", path).into_bytes());
+            self.send_request(client, stream_id);
+        }
+
+        // Write as much of the request as the stream takes. The sending side is closed once
+        // the whole request has been written, which ends the request.
+        fn send_request(&mut self, client: &mut Connection, stream_id: u64) {
+            if let Some(buf) = self.unsent.get_mut(&stream_id) {
+                let sent = client
+                    .stream_send(stream_id, buf)
+                    .expect("Write should succeed");
+                buf.drain(..sent);
+                if buf.is_empty() {
+                    self.unsent.remove(&stream_id);
+                    client
+                        .stream_close_send(stream_id)
+                        .expect("Close should succeed");
+                }
+            }
+        }
     }
 
     // This is a bit fancier than actually needed.
@@ -365,26 +1301,32 @@ mod old {
                             return false;
                         }
 
-                        let (sz, fin) = client
-                            .stream_recv(stream_id, &mut data)
-                            .expect("Read should succeed");
-                        if args.omit_read_data {
-                            println!("READ[{}]: {} bytes", stream_id, sz);
-                        } else {
-                            println!(
-                                "READ[{}]: {}",
-                                stream_id,
-                                String::from_utf8(data.clone()).unwrap()
-                            )
-                        }
-                        if fin {
-                            println!("<FIN[{}]>", stream_id);
-                            client.close(Instant::now(), 0, "kthxbye!");
-                            return false;
+                        // Read everything that is available; the response may not fit into
+                        // the buffer at once.
+                        loop {
+                            let (sz, fin) = client
+                                .stream_recv(stream_id, &mut data)
+                                .expect("Read should succeed");
+                            if sz > 0 {
+                                if let Err(err) = self.output.data(args, stream_id, &data[..sz]) {
+                                    eprintln!("Unable to write the response: {}", err);
+                                    client.close(Instant::now(), 0, "output failed");
+                                    return false;
+                                }
+                            }
+                            if fin {
+                                self.output.fin(stream_id);
+                                println!("<FIN[{}]>", stream_id);
+                                client.close(Instant::now(), 0, "kthxbye!");
+                                return false;
+                            }
+                            if sz == 0 {
+                                break;
+                            }
                         }
                     }
                     ConnectionEvent::SendStreamWritable { stream_id } => {
-                        println!("stream {} writable", stream_id)
+                        self.send_request(client, stream_id);
                     }
                     _ => {
                         println!("Unexpected event {:?}", event);
@@ -397,90 +1339,406 @@ mod old {
     }
 
     fn process_loop_old(
-        local_addr: &SocketAddr,
+        local_addr: &mut SocketAddr,
         remote_addr: &SocketAddr,
-        socket: &UdpSocket,
+        socket: &mut UdpSocket,
         client: &mut Connection,
         handler: &mut dyn HandlerOld,
         args: &Args,
+        dump: &mut Option<PacketDump>,
     ) -> State {
         let buf = &mut [0u8; 2048];
+        let mut received = 0;
+        let mut migrate_after = args.migrate;
+        let mut socket_ecn = Ecn::default();
         loop {
             if let State::Closed(..) = client.state() {
                 return client.state().clone();
             }
 
-            let exiting = !handler.handle(args, client);
+            let mut exiting = !handler.handle(args, client);
 
-            let out_dgram = client.process_output(Instant::now());
-            emit_datagram(&socket, out_dgram.dgram());
+            loop {
+                match client.process_output(Instant::now()) {
+                    Output::Datagram(dgram) => {
+                        let dgram = if args.ecn {
+                            dgram.with_ecn(Ecn::Ect0)
+                        } else {
+                            dgram
+                        };
+                        if let Err(err) = emit_datagram(socket, &mut socket_ecn, &dgram, dump) {
+                            eprintln!("Unable to send a datagram: {}", err);
+                            // Try to tell the server, then stop.
+                            if !matches!(client.state(), State::Closing { .. } | State::Closed(_)) {
+                                client.close(Instant::now(), 0, "send error");
+                            }
+                            exiting = true;
+                        }
+                    }
+                    Output::Callback(duration) => {
+                        set_read_timeout(socket, Some(duration));
+                        break;
+                    }
+                    Output::None => {
+                        set_read_timeout(socket, None);
+                        exiting = true;
+                        break;
+                    }
+                }
+            }
 
             if exiting {
                 return client.state().clone();
             }
 
-            let sz = match socket.recv(&mut buf[..]) {
+            let (sz, ecn) = match ecn::recv(socket, &mut buf[..]) {
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                    // timer expired
+                    client.process_timer(Instant::now());
+                    continue;
+                }
                 Err(err) => {
                     eprintln!("UDP error: {}", err);
                     exit(1)
                 }
-                Ok(sz) => sz,
+                Ok(r) => r,
             };
             if sz == buf.len() {
                 eprintln!("Received more than {} bytes", buf.len());
                 continue;
             }
             if sz > 0 {
-                let d = Datagram::new(*remote_addr, *local_addr, &buf[..sz]);
+                if let Some(dump) = dump {
+                    dump.record(false, &buf[..sz]);
+                }
+                let d = Datagram::new(*remote_addr, *local_addr, &buf[..sz]).with_ecn(ecn);
                 client.process_input(d, Instant::now());
+                received += 1;
+                if migrate_after.map_or(false, |n| received >= n)
+                    && *client.state() == State::Connected
+                {
+                    migrate_after = None;
+                    let res = migrate_socket(
+                        args,
+                        client,
+                        socket,
+                        &mut socket_ecn,
+                        local_addr,
+                        remote_addr,
+                    );
+                    if let Err(err) = res {
+                        eprintln!("Unable to migrate: {}", err);
+                    }
+                }
             }
         }
     }
 
+    // Waits until the server has chosen the ALPN protocol, which is when its certificate
+    // needs to be checked. The certificate is left for the client of that protocol.
+    struct AlpnHandlerOld {}
+    impl HandlerOld for AlpnHandlerOld {
+        fn handle(&mut self, _args: &Args, client: &mut Connection) -> bool {
+            !client
+                .events()
+                .any(|e| matches!(e, ConnectionEvent::AuthenticationNeeded))
+        }
+    }
+
+    /// Start the handshake of `client` and return the ALPN protocol that the server chose,
+    /// or `None` if the handshake failed.
+    pub fn negotiate_alpn(
+        local_addr: &mut SocketAddr,
+        remote_addr: &SocketAddr,
+        socket: &mut UdpSocket,
+        client: &mut Connection,
+        args: &Args,
+        dump: &mut Option<PacketDump>,
+    ) -> Option<String> {
+        process_loop_old(
+            local_addr,
+            remote_addr,
+            socket,
+            client,
+            &mut AlpnHandlerOld {},
+            args,
+            dump,
+        );
+        client.alpn()
+    }
+
     pub fn old_client(
         args: Args,
-        socket: UdpSocket,
-        local_addr: SocketAddr,
+        mut socket: UdpSocket,
+        mut local_addr: SocketAddr,
         remote_addr: SocketAddr,
+        mut client: Connection,
+        mut dump: Option<PacketDump>,
     ) {
-        dbg!(args.url.host_str().unwrap());
+        dbg!(args.server_name());
         dbg!(&args.alpn);
         dbg!(local_addr);
         dbg!(remote_addr);
 
-        let mut client = Connection::new_client(
-            args.url.host_str().unwrap(),
-            &["http/0.9"],
-            Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
-            local_addr,
-            remote_addr,
-        )
-        .expect("must succeed");
         // Temporary here to help out the type inference engine
         let mut h = PreConnectHandlerOld {};
         process_loop_old(
-            &local_addr,
+            &mut local_addr,
             &remote_addr,
-            &socket,
+            &mut socket,
             &mut client,
             &mut h,
             &args,
+            &mut dump,
         );
 
-        let client_stream_id = client.stream_create(StreamType::BiDi).unwrap();
-        let req: String = "GET /10\r\n".to_string();
-        client
-            .stream_send(client_stream_id, req.as_bytes())
-            .unwrap();
-        let mut h2 = PostConnectHandlerOld::default();
-        h2.streams.insert(client_stream_id);
+        let mut h2 = PostConnectHandlerOld {
+            output: ResponseOutput::new(&args),
+            ..PostConnectHandlerOld::default()
+        };
+        h2.request(&mut client, args.url.path());
         process_loop_old(
-            &local_addr,
+            &mut local_addr,
             &remote_addr,
-            &socket,
+            &mut socket,
             &mut client,
             &mut h2,
             &args,
+            &mut dump,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, value: &str) -> Header {
+        (String::from(name), String::from(value))
+    }
+
+    fn authority_of(url: &str) -> String {
+        authority(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn dump_record() {
+        let mut out = Vec::new();
+        write_dump_record(&mut out, true, Duration::from_micros(0x0102), &[0xaa, 0xbb]).unwrap();
+        write_dump_record(&mut out, false, Duration::from_millis(1), &[]).unwrap();
+        assert_eq!(
+            out,
+            vec![
+                b'>', 0, 0, 0, 0, 0, 0, 0x01, 0x02, 0, 0, 0, 2, 0xaa, 0xbb, //
+                b'<', 0, 0, 0, 0, 0, 0, 0x03, 0xe8, 0, 0, 0, 0,
+            ]
         );
     }
+
+    #[test]
+    fn response_body_split_across_reads() {
+        let args = Args::from_iter(&["neqo-client", "https://example.com/"]);
+        let mut output = ResponseOutput::default();
+        // "caf\u{e9}" with the two bytes of the last character in different reads.
+        output.data(&args, 0, &[0x63, 0x61, 0x66, 0xc3]).unwrap();
+        output.data(&args, 0, &[0xa9]).unwrap();
+        output.data(&args, 4, &[0x78]).unwrap();
+        assert_eq!(output.take_body(0), Some(String::from("caf\u{e9}")));
+        assert_eq!(output.take_body(0), None);
+        assert_eq!(output.take_body(4), Some(String::from("x")));
+    }
+
+    #[test]
+    fn response_body_omitted() {
+        let args = Args::from_iter(&["neqo-client", "--omit-read-data", "https://example.com/"]);
+        let mut output = ResponseOutput::default();
+        output.data(&args, 0, &[0xff, 0x00]).unwrap();
+        assert_eq!(output.take_body(0), None);
+    }
+
+    #[test]
+    fn requests_for_more_urls() {
+        let args = Args::from_iter(&[
+            "neqo-client",
+            "https://example.com:8443/a",
+            "https://example.com:8443/b",
+            "-H",
+            "accept: */*",
+        ]);
+        let requests = args.requests().unwrap();
+        assert_eq!(
+            requests.iter().map(|r| r.path.as_str()).collect::<Vec<_>>(),
+            vec!["/a", "/b"]
+        );
+        for request in &requests {
+            assert_eq!(request.authority, "example.com:8443");
+            assert_eq!(request.headers, vec![header("accept", "*/*")]);
+        }
+    }
+
+    #[test]
+    fn requests_need_the_same_origin() {
+        for other in &[
+            "http://example.com/b",
+            "https://example.net/b",
+            "https://example.com:4433/b",
+        ] {
+            let args = Args::from_iter(&["neqo-client", "https://example.com/a", other]);
+            assert!(args.requests().is_err());
+        }
+    }
+
+    #[test]
+    fn output_needs_one_url() {
+        let args = Args::from_iter(&[
+            "neqo-client",
+            "--output",
+            "body",
+            "https://example.com/a",
+            "https://example.com/b",
+        ]);
+        assert!(args.requests().is_err());
+    }
+
+    #[test]
+    fn connect_to_keeps_logical_host() {
+        let args = Args::from_iter(&[
+            "neqo-client",
+            "--connect-to",
+            "127.0.0.1:4433",
+            "https://example.com/",
+        ]);
+        assert_eq!(
+            args.to_socket_addrs().unwrap().collect::<Vec<_>>(),
+            vec!["127.0.0.1:4433".parse::<SocketAddr>().unwrap()]
+        );
+        assert_eq!(args.server_name(), "example.com");
+        assert_eq!(authority(&args.url), "example.com");
+    }
+
+    #[test]
+    fn connect_to_values() {
+        assert_eq!(
+            parse_connect_to("[::1]:4433"),
+            Ok(String::from("[::1]:4433"))
+        );
+        assert!(parse_connect_to("example.com").is_err());
+        assert!(parse_connect_to(":4433").is_err());
+        assert!(parse_connect_to("example.com:http").is_err());
+    }
+
+    #[test]
+    fn authority_with_port() {
+        assert_eq!(
+            authority_of("https://example.com:8443/"),
+            "example.com:8443"
+        );
+        assert_eq!(authority_of("https://[::1]:8443/"), "[::1]:8443");
+    }
+
+    #[test]
+    fn authority_default_port() {
+        assert_eq!(authority_of("https://example.com:443/"), "example.com");
+        assert_eq!(authority_of("https://[::1]/"), "[::1]");
+        assert_eq!(
+            authority_of("https://bücher.example/"),
+            "xn--bcher-kva.example"
+        );
+    }
+
+    #[test]
+    fn parse_header_trims_and_lowercases() {
+        assert_eq!(
+            parse_header("  Accept :  text/html  "),
+            Ok(header("accept", "text/html"))
+        );
+    }
+
+    #[test]
+    fn parse_header_value_with_comma_and_parenthesis() {
+        assert_eq!(
+            parse_header("accept: text/html, application/xml (q=0.9)"),
+            Ok(header("accept", "text/html, application/xml (q=0.9)"))
+        );
+    }
+
+    #[test]
+    fn parse_header_value_with_colon() {
+        assert_eq!(
+            parse_header("referer: https://example.com:443/"),
+            Ok(header("referer", "https://example.com:443/"))
+        );
+    }
+
+    #[test]
+    fn parse_header_empty_value() {
+        assert_eq!(parse_header("x-empty:"), Ok(header("x-empty", "")));
+    }
+
+    #[test]
+    fn parse_header_invalid() {
+        assert!(parse_header("no-colon").is_err());
+        assert!(parse_header(": value").is_err());
+        assert!(parse_header("bad name: value").is_err());
+        assert!(parse_header("x-injected: a\r\nhost: evil").is_err());
+    }
+
+    #[test]
+    fn parse_request_file() {
+        let text = "POST /upload?id=1 HTTP/1.1\r\n\
+                    Host: example.com:8443\r\n\
+                    Content-Type: text/plain\r\n\
+                    Connection: keep-alive\r\n\
+                    \r\n";
+        assert_eq!(
+            parse_request(text, "127.0.0.1"),
+            Ok(Request {
+                method: String::from("POST"),
+                authority: String::from("example.com:8443"),
+                path: String::from("/upload?id=1"),
+                headers: vec![header("content-type", "text/plain")],
+            })
+        );
+    }
+
+    #[test]
+    fn parse_request_file_without_host() {
+        let request = parse_request("GET /\naccept: */*\n", "example.com").unwrap();
+        assert_eq!(request.authority, "example.com");
+        assert_eq!(request.headers, vec![header("accept", "*/*")]);
+    }
+
+    #[test]
+    fn parse_request_file_invalid() {
+        assert!(parse_request("", "example.com").is_err());
+        assert!(parse_request("GET\n", "example.com").is_err());
+        assert!(parse_request("GET / HTTP/1.1 extra\n", "example.com").is_err());
+        assert!(parse_request("G(T / HTTP/1.1\n", "example.com").is_err());
+        assert!(parse_request("GET example.com HTTP/1.1\n", "example.com").is_err());
+        assert!(parse_request("GET / HTTP/1.1\nno-colon\n", "example.com").is_err());
+        assert!(parse_request("GET / HTTP/1.1\nhost: a\nhost: b\n", "example.com").is_err());
+        assert!(parse_request("GET / HTTP/1.1\nx-a: 1\n  folded\n", "example.com").is_err());
+        assert!(parse_request("GET / HTTP/1.1\n\nbody\n", "example.com").is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn ecn_codepoint_is_received() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        ecn::enable_recv(&receiver).unwrap();
+        sender.connect(receiver.local_addr().unwrap()).unwrap();
+        let mut buf = [0; 10];
+        let mut socket_ecn = Ecn::default();
+        for codepoint in &[Ecn::Ect0, Ecn::Ce, Ecn::NotEct] {
+            let d = Datagram::new(
+                sender.local_addr().unwrap(),
+                receiver.local_addr().unwrap(),
+                &[1, 2, 3][..],
+            )
+            .with_ecn(*codepoint);
+            emit_datagram(&sender, &mut socket_ecn, &d, &mut None).unwrap();
+            assert_eq!(ecn::recv(&receiver, &mut buf).unwrap(), (3, *codepoint));
+        }
+    }
 }
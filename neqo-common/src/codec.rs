@@ -677,6 +677,24 @@ mod tests {
         assert_eq!(v, vec![0x40, 0x41, 0xa5]);
     }
 
+    #[test]
+    fn vvec_roundtrip() {
+        let mut enc = Encoder::default();
+        enc.encode_vvec_with(|enc_inner| {
+            enc_inner.encode_varint(0x1234_u64);
+            enc_inner.encode(&[0xa5; 70]);
+        });
+        enc.encode_vvec(&[]);
+        let mut dec = enc.as_decoder();
+        let inner = dec.decode_vvec().expect("read the first vector");
+        assert_eq!(inner.len(), 72);
+        let mut dec_inner = Decoder::from(inner);
+        assert_eq!(dec_inner.decode_varint(), Some(0x1234));
+        assert_eq!(dec_inner.decode_remainder(), &[0xa5; 70][..]);
+        assert!(dec.decode_vvec().expect("read the empty vector").is_empty());
+        assert_eq!(dec.remaining(), 0);
+    }
+
     // Test that Deref to &[u8] works for Encoder.
     #[test]
     fn encode_builder() {
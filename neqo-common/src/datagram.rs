@@ -9,10 +9,49 @@ use std::ops::Deref;
 
 use crate::hex;
 
+/// The ECN codepoint of a datagram, the two low bits of the IP TOS or traffic class field.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Ecn {
+    NotEct,
+    Ect1,
+    Ect0,
+    Ce,
+}
+
+impl Default for Ecn {
+    fn default() -> Self {
+        Self::NotEct
+    }
+}
+
+impl From<u8> for Ecn {
+    /// Take the ECN codepoint from a TOS or traffic class value.
+    fn from(tos: u8) -> Self {
+        match tos & 0x3 {
+            0b00 => Self::NotEct,
+            0b01 => Self::Ect1,
+            0b10 => Self::Ect0,
+            _ => Self::Ce,
+        }
+    }
+}
+
+impl From<Ecn> for u8 {
+    fn from(ecn: Ecn) -> Self {
+        match ecn {
+            Ecn::NotEct => 0b00,
+            Ecn::Ect1 => 0b01,
+            Ecn::Ect0 => 0b10,
+            Ecn::Ce => 0b11,
+        }
+    }
+}
+
 #[derive(PartialEq, Clone)]
 pub struct Datagram {
     src: SocketAddr,
     dst: SocketAddr,
+    ecn: Ecn,
     d: Vec<u8>,
 }
 
@@ -21,10 +60,23 @@ impl Datagram {
         Self {
             src,
             dst,
+            ecn: Ecn::NotEct,
             d: d.into(),
         }
     }
 
+    /// Set the ECN codepoint that the datagram is sent with, or was received with.
+    #[must_use]
+    pub fn with_ecn(mut self, ecn: Ecn) -> Self {
+        self.ecn = ecn;
+        self
+    }
+
+    #[must_use]
+    pub fn ecn(&self) -> Ecn {
+        self.ecn
+    }
+
     #[must_use]
     pub fn source(&self) -> SocketAddr {
         self.src
@@ -48,10 +100,26 @@ impl std::fmt::Debug for Datagram {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "Datagram {:?}->{:?}: {}",
+            "Datagram {:?}->{:?} {:?}: {}",
             self.src,
             self.dst,
+            self.ecn,
             hex(&self.d)
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Ecn;
+
+    #[test]
+    fn ecn_codepoints() {
+        for ecn in &[Ecn::NotEct, Ecn::Ect1, Ecn::Ect0, Ecn::Ce] {
+            assert_eq!(Ecn::from(u8::from(*ecn)), *ecn);
+        }
+        // DSCP bits are ignored.
+        assert_eq!(Ecn::from(0xb9), Ecn::Ect1);
+        assert_eq!(Ecn::from(0xba), Ecn::Ect0);
+    }
+}
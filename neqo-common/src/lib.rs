@@ -15,7 +15,7 @@ pub mod once;
 pub mod timer;
 
 pub use self::codec::{Decoder, Encoder};
-pub use self::datagram::Datagram;
+pub use self::datagram::{Datagram, Ecn};
 pub use self::incrdecoder::{IncrementalDecoder, IncrementalDecoderResult};
 
 #[macro_use]
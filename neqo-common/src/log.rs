@@ -44,6 +44,18 @@ macro_rules! qlog {
         ::log::log!($lvl, "[{}] {}", $ctx, format!($($arg)*));
     } )
 }
+/// Format `$ctx` as the context of log messages, or return an empty string unless debug
+/// logging is enabled. Use this where `self` is still borrowed when the message is logged.
+#[macro_export]
+macro_rules! qlabel {
+    ($ctx:expr) => {
+        if ::log::log_enabled!(::log::Level::Debug) {
+            format!("{}", $ctx)
+        } else {
+            String::new()
+        }
+    };
+}
 #[macro_export]
 macro_rules! qerror {
     ([$ctx:expr], $($arg:tt)*) => (::neqo_common::qlog!(::log::Level::Error, $ctx, $($arg)*););
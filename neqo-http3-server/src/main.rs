@@ -7,16 +7,17 @@
 #![cfg_attr(feature = "deny-warnings", deny(warnings))]
 #![warn(clippy::use_self)]
 
-use neqo_common::{qdebug, qinfo, Datagram};
+use neqo_common::{matches, qdebug, qinfo, Datagram};
 use neqo_crypto::{init_db, AntiReplay};
-use neqo_http3::{Http3Server, Http3ServerEvent};
+use neqo_http3::{ClientRequestStream, Http3Server, Http3ServerEvent};
 use neqo_transport::{FixedConnectionIdManager, Output};
 
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fs;
 use std::io;
 use std::net::{SocketAddr, ToSocketAddrs};
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::process::exit;
 use std::rc::Rc;
 use std::time::{Duration, Instant};
@@ -55,6 +56,21 @@ struct Args {
     ///
     /// This server still only does HTTP3 no matter what the ALPN says.
     alpn: String,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Serve the files in this directory. A request for a file that does
+    /// not exist is answered with 404. Without this, the response to a
+    /// request for "/N" is N bytes of data.
+    root: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Send a Retry to every new client, so that its address is validated
+    /// before a connection is created.
+    retry: bool,
+
+    #[structopt(long)]
+    /// Allow clients to migrate to a new address.
+    allow_migration: bool,
 }
 
 impl Args {
@@ -67,7 +83,24 @@ impl Args {
     }
 }
 
-fn process_events(server: &mut Http3Server) {
+/// Read the file that `path` names below `root`. Paths that would leave `root`, e.g. with
+/// "..", are not served.
+fn read_file(root: &Path, path: &str) -> Option<Vec<u8>> {
+    let relative = Path::new(path.trim_start_matches('/'));
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+    fs::read(root.join(relative)).ok()
+}
+
+fn process_events(
+    server: &mut Http3Server,
+    root: Option<&Path>,
+    bodies: &mut HashMap<ClientRequestStream, Vec<u8>>,
+) {
     while let Some(event) = server.next_event() {
         eprintln!("Event: {:?}", event);
         match event {
@@ -78,30 +111,61 @@ fn process_events(server: &mut Http3Server) {
             } => {
                 println!("Headers (request={} fin={}): {:?}", request, fin, headers);
 
+                // The response to a POST is its body, so wait until all of it is here.
+                let is_post = headers.iter().any(|(k, v)| k == ":method" && v == "POST");
+                if is_post && !fin {
+                    bodies.insert(request, Vec::new());
+                    continue;
+                }
+
                 let default_ret = b"Hello World".to_vec();
 
-                let response = match headers.iter().find(|&(k, _)| k == ":path") {
-                    Some((_, path)) if !path.is_empty() => {
+                let path = headers.iter().find(|&(k, _)| k == ":path").map(|(_, v)| v);
+                let (status, response) = match (root, path) {
+                    (Some(root), Some(path)) => match read_file(root, path) {
+                        Some(data) => (200, data),
+                        None => (404, b"Not found".to_vec()),
+                    },
+                    (Some(_), None) => (404, b"Not found".to_vec()),
+                    (None, Some(path)) if !path.is_empty() => {
                         match path.trim_matches(|p| p == '/').parse::<usize>() {
-                            Ok(v) => vec![b'a'; v],
-                            Err(_) => default_ret,
+                            Ok(v) => (200, vec![b'a'; v]),
+                            Err(_) => (200, default_ret),
                         }
                     }
-                    _ => default_ret,
+                    (None, _) => (200, default_ret),
                 };
 
                 request
-                    .set_response(
-                        &[
-                            (String::from(":status"), String::from("200")),
-                            (String::from("content-length"), response.len().to_string()),
-                        ],
+                    .set_response_with_status(
+                        status,
+                        &[(String::from("content-length"), response.len().to_string())],
                         response,
                     )
                     .unwrap();
             }
-            Http3ServerEvent::Data { request, data, fin } => {
+            Http3ServerEvent::Data {
+                mut request,
+                data,
+                fin,
+            } => {
                 println!("Data (request={} fin={}): {:?}", request, fin, data);
+                if let Some(body) = bodies.get_mut(&request) {
+                    body.extend_from_slice(&data);
+                    if fin {
+                        let body = bodies.remove(&request).unwrap();
+                        request
+                            .set_response_with_status(
+                                200,
+                                &[(String::from("content-length"), body.len().to_string())],
+                                body,
+                            )
+                            .unwrap();
+                    }
+                }
+            }
+            Http3ServerEvent::Reset { request, .. } => {
+                bodies.remove(&request);
             }
             _ => {}
         }
@@ -206,26 +270,24 @@ fn main() -> Result<(), io::Error> {
             PollOpt::edge(),
         )?;
         sockets.push(socket);
-        servers.insert(
-            local_addr,
-            (
-                Http3Server::new(
-                    Instant::now(),
-                    &[args.key.clone()],
-                    &[args.alpn.clone()],
-                    AntiReplay::new(Instant::now(), Duration::from_secs(10), 7, 14)
-                        .expect("unable to setup anti-replay"),
-                    Rc::new(RefCell::new(FixedConnectionIdManager::new(10))),
-                    args.max_table_size,
-                    args.max_blocked_streams,
-                )
-                .expect("We cannot make a server!"),
-                None,
-            ),
-        );
+        let mut server = Http3Server::new(
+            Instant::now(),
+            &[args.key.clone()],
+            &[args.alpn.clone()],
+            AntiReplay::new(Instant::now(), Duration::from_secs(10), 7, 14)
+                .expect("unable to setup anti-replay"),
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(10))),
+            args.max_table_size,
+            args.max_blocked_streams,
+        )
+        .expect("We cannot make a server!");
+        server.set_retry_required(args.retry);
+        server.set_allow_migration(args.allow_migration);
+        servers.insert(local_addr, (server, None));
     }
 
     let buf = &mut [0u8; 2048];
+    let mut bodies = HashMap::new();
 
     let mut events = Events::with_capacity(1024);
 
@@ -290,7 +352,11 @@ fn main() -> Result<(), io::Error> {
                             out,
                             &mut timer,
                         );
-                        process_events(server);
+                        process_events(
+                            server,
+                            args.root.as_ref().map(PathBuf::as_path),
+                            &mut bodies,
+                        );
                         process(server, svr_timeout, event.token().0, None, out, &mut timer);
                     }
                 }
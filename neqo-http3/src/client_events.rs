@@ -5,10 +5,11 @@
 // except according to those terms.
 
 use crate::connection::Http3State;
+use crate::Header;
 use neqo_common::matches;
 use neqo_transport::{AppError, StreamType};
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::VecDeque;
 use std::rc::Rc;
 
@@ -20,7 +21,15 @@ pub enum Http3ClientEvent {
     DataWritable { stream_id: u64 },
     /// New bytes available for reading.
     DataReadable { stream_id: u64 },
-    /// Peer reset the stream.
+    /// An informational (1xx) response has been received. The final response follows.
+    InformationalHeaders {
+        stream_id: u64,
+        headers: Vec<Header>,
+    },
+    /// Trailers have been received after the response body.
+    TrailersReady { stream_id: u64 },
+    /// The peer reset the stream, or reading the response failed. In the latter case the
+    /// error is an HTTP/3 error and the connection is closed as well.
     Reset { stream_id: u64, error: AppError },
     /// Peer has send STOP_SENDING with error code EarlyResponse, other error will post a reset event.
     StopSending { stream_id: u64, error: AppError },
@@ -36,11 +45,38 @@ pub enum Http3ClientEvent {
     GoawayReceived,
     /// Connection state change.
     StateChange(Http3State),
+    /// A datagram has been received.
+    Datagram { data: Vec<u8> },
 }
 
-#[derive(Debug, Default, Clone)]
+impl Http3ClientEvent {
+    /// Events that only say that something can be tried again. If the queue is full, they
+    /// are dropped; the application can still create a request or write to a stream.
+    /// Datagrams are unreliable, so they are dropped too.
+    fn is_low_priority(&self) -> bool {
+        matches!(
+            self,
+            Self::DataWritable { .. } | Self::RequestsCreatable | Self::Datagram { .. }
+        )
+    }
+}
+
+/// The queue of events for the application. An event that is already queued is not queued
+/// again, except for datagrams. If the application does not take the events, at most
+/// `max_events` are kept: a low-priority event that would exceed that is dropped.
+#[derive(Debug, Clone)]
 pub struct Http3ClientEvents {
     events: Rc<RefCell<VecDeque<Http3ClientEvent>>>,
+    max_events: Rc<Cell<usize>>,
+}
+
+impl Default for Http3ClientEvents {
+    fn default() -> Self {
+        Self {
+            events: Rc::default(),
+            max_events: Rc::new(Cell::new(usize::max_value())),
+        }
+    }
 }
 
 impl Http3ClientEvents {
@@ -56,6 +92,14 @@ impl Http3ClientEvents {
         self.insert(Http3ClientEvent::DataReadable { stream_id });
     }
 
+    pub fn informational_headers(&self, stream_id: u64, headers: Vec<Header>) {
+        self.insert(Http3ClientEvent::InformationalHeaders { stream_id, headers });
+    }
+
+    pub fn trailers_ready(&self, stream_id: u64) {
+        self.insert(Http3ClientEvent::TrailersReady { stream_id });
+    }
+
     pub fn stop_sending(&self, stream_id: u64, error: AppError) {
         // Remove DataWritable event if any.
         self.remove(|evt| {
@@ -84,6 +128,10 @@ impl Http3ClientEvents {
         self.insert(Http3ClientEvent::ZeroRttRejected);
     }
 
+    pub fn datagram(&self, data: Vec<u8>) {
+        self.insert(Http3ClientEvent::Datagram { data });
+    }
+
     pub fn goaway_received(&self) {
         self.remove(|evt| matches!(evt, Http3ClientEvent::RequestsCreatable));
         self.insert(Http3ClientEvent::GoawayReceived);
@@ -101,8 +149,24 @@ impl Http3ClientEvents {
         self.events.borrow_mut().pop_front()
     }
 
+    #[cfg(test)]
+    pub fn clear(&self) {
+        self.events.borrow_mut().clear();
+    }
+
+    pub fn set_max_events(&self, max_events: usize) {
+        self.max_events.set(max_events);
+    }
+
     fn insert(&self, event: Http3ClientEvent) {
-        self.events.borrow_mut().push_back(event);
+        let mut events = self.events.borrow_mut();
+        if !matches!(event, Http3ClientEvent::Datagram { .. }) && events.contains(&event) {
+            return;
+        }
+        if event.is_low_priority() && events.len() >= self.max_events.get() {
+            return;
+        }
+        events.push_back(event);
     }
 
     fn remove<F>(&self, f: F)
@@ -118,9 +182,12 @@ impl Http3ClientEvents {
     }
 
     pub fn connection_state_change(&self, state: Http3State) {
-        // If closing, existing events no longer relevant.
+        // If closing, existing events are no longer relevant, except for the streams that
+        // were reset: the application needs to know that those requests failed.
         match state {
-            Http3State::Closing { .. } | Http3State::Closed(_) => self.events.borrow_mut().clear(),
+            Http3State::Closing { .. } | Http3State::Closed(_) => {
+                self.remove(|evt| !matches!(evt, Http3ClientEvent::Reset { .. }))
+            }
             _ => (),
         }
         self.insert(Http3ClientEvent::StateChange(state));
@@ -132,9 +199,51 @@ impl Http3ClientEvents {
                 Http3ClientEvent::HeaderReady { stream_id: x }
                 | Http3ClientEvent::DataWritable { stream_id: x }
                 | Http3ClientEvent::DataReadable { stream_id: x }
+                | Http3ClientEvent::InformationalHeaders { stream_id: x, .. }
+                | Http3ClientEvent::TrailersReady { stream_id: x }
                 | Http3ClientEvent::NewPushStream { stream_id: x }
                 | Http3ClientEvent::Reset { stream_id: x, .. }
                 | Http3ClientEvent::StopSending { stream_id: x, .. } if *x == stream_id)
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Http3ClientEvent, Http3ClientEvents};
+
+    #[test]
+    fn duplicate_events_are_coalesced() {
+        let events = Http3ClientEvents::default();
+        events.data_readable(0);
+        events.data_readable(4);
+        events.data_readable(0);
+        assert_eq!(
+            events.events().collect::<Vec<_>>(),
+            vec![
+                Http3ClientEvent::DataReadable { stream_id: 0 },
+                Http3ClientEvent::DataReadable { stream_id: 4 },
+            ]
+        );
+    }
+
+    #[test]
+    fn max_events() {
+        let events = Http3ClientEvents::default();
+        events.set_max_events(10);
+        for stream_id in 0..100 {
+            events.data_writable(stream_id * 4);
+        }
+        assert_eq!(events.events().count(), 10);
+
+        // Other events are still queued.
+        for stream_id in 0..10 {
+            events.data_writable(stream_id * 4);
+        }
+        events.data_readable(0);
+        events.header_ready(4);
+        let queued = events.events().collect::<Vec<_>>();
+        assert_eq!(queued.len(), 12);
+        assert_eq!(queued[10], Http3ClientEvent::DataReadable { stream_id: 0 });
+    }
+}
@@ -6,20 +6,35 @@
 
 use crate::control_stream_local::{ControlStreamLocal, HTTP3_UNI_STREAM_TYPE_CONTROL};
 use crate::control_stream_remote::ControlStreamRemote;
+#[cfg(feature = "frame-trace")]
+use crate::frame_trace::{FrameDirection, FrameTracer};
 use crate::hframe::HFrame;
-use crate::hsettings_frame::{HSetting, HSettingType, HSettings};
+use crate::hsettings_frame::{HSetting, HSettingType, HSettings, PeerSettings};
+use crate::priority::Priority;
+use crate::stats::{Http3Stats, Http3StatsRecorder};
 use crate::stream_type_reader::NewStreamTypeReader;
-use neqo_common::{matches, qdebug, qerror, qinfo, qtrace, qwarn};
+use neqo_common::{hex, matches, qdebug, qerror, qinfo, qlabel, qtrace, qwarn};
 use neqo_qpack::decoder::{QPackDecoder, QPACK_UNI_STREAM_TYPE_DECODER};
 use neqo_qpack::encoder::{QPackEncoder, QPACK_UNI_STREAM_TYPE_ENCODER};
-use neqo_transport::{AppError, CloseError, Connection, State, StreamType};
+use neqo_transport::{AppError, Connection, ConnectionError, Role, State, StreamType};
 use std::collections::{BTreeSet, HashMap};
 use std::fmt::Debug;
-use std::mem;
+use std::time::{Duration, Instant};
 
-use crate::{Error, Res};
+use crate::{is_reserved, Error, Header, Res};
 
 const HTTP3_UNI_STREAM_TYPE_PUSH: u64 = 0x1;
+// The weight of a request stream that has not been given a priority.
+pub const DEFAULT_STREAM_WEIGHT: u8 = 16;
+
+/// Returns the size of a header list as it is compared with SETTINGS_MAX_HEADER_LIST_SIZE:
+/// the length of each name and value plus an overhead of 32 bytes per field.
+pub(crate) fn header_list_size(headers: &[Header]) -> u64 {
+    headers
+        .iter()
+        .map(|(name, value)| (name.len() + value.len() + 32) as u64)
+        .sum()
+}
 
 pub(crate) enum HandleReadableOutput {
     NoOutput,
@@ -35,6 +50,7 @@ pub trait Http3Transaction: Debug {
     fn stop_sending(&mut self);
     fn done(&self) -> bool;
     fn close_send(&mut self, conn: &mut Connection) -> Res<()>;
+    fn qpack_unblocked(&mut self, blocked: Duration);
 }
 
 #[derive(Debug)]
@@ -44,65 +60,242 @@ enum Http3RemoteSettingsState {
     ZeroRtt(HSettings),
 }
 
+/// The largest QPACK dynamic table capacity that can be advertised.
+const MAX_TABLE_SIZE: u32 = (1 << 30) - 1;
+
+/// Parameters of an HTTP/3 connection, named so that they can't be swapped by accident.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Http3Parameters {
+    /// The capacity of the QPACK decoder's dynamic table, at most 2^30 - 1.
+    pub max_table_size: u32,
+    /// The number of streams that may be blocked waiting for QPACK encoder instructions.
+    pub max_blocked_streams: u16,
+    /// The limit on the size of header lists that the peer may send, if any.
+    pub max_header_list_size: Option<u64>,
+    /// Whether the QPACK encoder inserts into the dynamic table that the peer allows.
+    /// If not, headers are only encoded with static table references and literals.
+    pub qpack_dynamic_table: bool,
+}
+
+impl Default for Http3Parameters {
+    fn default() -> Self {
+        Self {
+            max_table_size: 0,
+            max_blocked_streams: 0,
+            max_header_list_size: None,
+            qpack_dynamic_table: true,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, PartialOrd, Ord, Eq, Clone)]
 struct LocalSettings {
     max_table_size: u32,
     max_blocked_streams: u16,
+    max_header_list_size: Option<u64>,
+    qpack_dynamic_table: bool,
+}
+
+impl LocalSettings {
+    fn qpack_encoder(&self) -> QPackEncoder {
+        let mut encoder = QPackEncoder::new(true);
+        encoder.set_use_dynamic_table(self.qpack_dynamic_table);
+        encoder
+    }
 }
 
+/// The state of an HTTP/3 connection.
+///
+/// `Closing` and `Closed` carry the reason that the transport reports, so that e.g. an idle
+/// timeout (`ConnectionError::Transport(Error::IdleTimeout)`) can be told apart from a close by
+/// either side.
 #[derive(Debug, PartialEq, PartialOrd, Ord, Eq, Clone)]
 pub enum Http3State {
     Initializing,
     ZeroRtt,
     Connected,
     GoingAway,
-    Closing(CloseError),
-    Closed(CloseError),
+    Closing(ConnectionError),
+    Closed(ConnectionError),
 }
 
 #[derive(Debug)]
 pub struct Http3Connection<T: Http3Transaction> {
     pub state: Http3State,
+    // The role and the local connection ID of the transport connection, for log messages.
+    id: Option<(Role, Vec<u8>)>,
     local_settings: LocalSettings,
     control_stream_local: ControlStreamLocal,
     control_stream_remote: ControlStreamRemote,
     new_streams: HashMap<u64, NewStreamTypeReader>,
+    // Unidirectional streams of a reserved type, whose data is read and discarded.
+    discarded_streams: BTreeSet<u64>,
     pub qpack_encoder: QPackEncoder,
     pub qpack_decoder: QPackDecoder,
     settings_state: Http3RemoteSettingsState,
     streams_have_data_to_send: BTreeSet<u64>,
+    stream_weights: HashMap<u64, u8>,
+    // The latest priority of request streams, sent or received in PRIORITY_UPDATE frames.
+    stream_priorities: HashMap<u64, Priority>,
+    // Request streams whose header block waits for QPACK encoder instructions, and since when.
+    qpack_blocked_since: HashMap<u64, Instant>,
     pub transactions: HashMap<u64, T>,
+    wait_for_settings: bool,
+    settings_pending: bool,
+    stats: Http3StatsRecorder,
 }
 
 impl<T: Http3Transaction> ::std::fmt::Display for Http3Connection<T> {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
-        write!(f, "Http3 connection")
+        match &self.id {
+            Some((role, cid)) => write!(f, "Http3 connection {:?} {}", role, hex(cid)),
+            None => write!(f, "Http3 connection"),
+        }
     }
 }
 
 impl<T: Http3Transaction> Http3Connection<T> {
     pub fn new(max_table_size: u32, max_blocked_streams: u16) -> Self {
-        if max_table_size > (1 << 30) - 1 {
-            panic!("Wrong max_table_size");
+        Self::new_with_params(Http3Parameters {
+            max_table_size,
+            max_blocked_streams,
+            max_header_list_size: None,
+            qpack_dynamic_table: true,
+        })
+        .expect("Wrong max_table_size")
+    }
+
+    /// Create a connection from `params`. This fails with `Error::InvalidInput` if
+    /// the parameters can't be used, e.g. if `max_table_size` is too large.
+    pub fn new_with_params(params: Http3Parameters) -> Res<Self> {
+        if params.max_table_size > MAX_TABLE_SIZE {
+            qerror!("Wrong max_table_size {}", params.max_table_size);
+            return Err(Error::InvalidInput);
         }
-        Self {
+        let local_settings = LocalSettings {
+            max_table_size: params.max_table_size,
+            max_blocked_streams: params.max_blocked_streams,
+            max_header_list_size: params.max_header_list_size,
+            qpack_dynamic_table: params.qpack_dynamic_table,
+        };
+        let mut qpack_decoder =
+            QPackDecoder::new(params.max_table_size, params.max_blocked_streams);
+        // Stop decoding a header block as soon as it exceeds the limit.
+        if let Some(limit) = params.max_header_list_size {
+            qpack_decoder.set_max_header_list_size(limit);
+        }
+        let stats = Http3StatsRecorder::default();
+        Ok(Self {
             state: Http3State::Initializing,
-            local_settings: LocalSettings {
-                max_table_size,
-                max_blocked_streams,
-            },
-            control_stream_local: ControlStreamLocal::default(),
-            control_stream_remote: ControlStreamRemote::new(),
+            id: None,
+            qpack_encoder: local_settings.qpack_encoder(),
+            local_settings,
+            control_stream_local: ControlStreamLocal::new(stats.clone()),
+            control_stream_remote: ControlStreamRemote::new(stats.clone()),
             new_streams: HashMap::new(),
-            qpack_encoder: QPackEncoder::new(true),
-            qpack_decoder: QPackDecoder::new(max_table_size, max_blocked_streams),
+            discarded_streams: BTreeSet::new(),
+            qpack_decoder,
             settings_state: Http3RemoteSettingsState::NotReceived,
             streams_have_data_to_send: BTreeSet::new(),
+            stream_weights: HashMap::new(),
+            stream_priorities: HashMap::new(),
+            qpack_blocked_since: HashMap::new(),
             transactions: HashMap::new(),
+            wait_for_settings: false,
+            settings_pending: false,
+            stats,
+        })
+    }
+
+    /// If set, the state only changes to `Connected` after the transport handshake
+    /// is done and the peer's SETTINGS frame has been received.
+    pub fn set_wait_for_settings(&mut self, wait: bool) {
+        self.wait_for_settings = wait;
+    }
+
+    /// Limit the size of the header lists that the peer may send. This is advertised in
+    /// SETTINGS_MAX_HEADER_LIST_SIZE and must be set before the SETTINGS frame is sent. A
+    /// larger header block is not decoded past the limit.
+    pub fn set_max_header_list_size(&mut self, limit: u64) {
+        self.local_settings.max_header_list_size = Some(limit);
+        self.qpack_decoder.set_max_header_list_size(limit);
+    }
+
+    /// A handle for recording statistics, shared with the request streams.
+    pub fn stats_recorder(&self) -> Http3StatsRecorder {
+        self.stats.clone()
+    }
+
+    /// Pass the frames that are sent and received, and the QPACK insertions, to `tracer`.
+    #[cfg(feature = "frame-trace")]
+    pub fn set_frame_tracer(&mut self, tracer: Box<dyn FrameTracer>) {
+        self.stats.set_frame_tracer(tracer);
+        self.record_qpack_inserts();
+    }
+
+    // With a frame tracer, the QPACK tables keep their insertions for `trace_qpack_inserts`.
+    // This is needed again whenever the QPACK state is replaced.
+    #[cfg(feature = "frame-trace")]
+    fn record_qpack_inserts(&mut self) {
+        if self.stats.has_frame_tracer() {
+            self.qpack_encoder.record_inserts();
+            self.qpack_decoder.record_inserts();
+        }
+    }
+
+    #[cfg(not(feature = "frame-trace"))]
+    fn record_qpack_inserts(&mut self) {}
+
+    #[cfg(feature = "frame-trace")]
+    fn trace_qpack_inserts(&mut self) {
+        let inserts = self.qpack_encoder.take_inserts();
+        self.stats.qpack_inserted(FrameDirection::Sent, &inserts);
+        let inserts = self.qpack_decoder.take_inserts();
+        self.stats
+            .qpack_inserted(FrameDirection::Received, &inserts);
+    }
+
+    #[cfg(not(feature = "frame-trace"))]
+    fn trace_qpack_inserts(&mut self) {}
+
+    /// Get the HTTP/3 statistics of the connection, including the QPACK table sizes.
+    pub fn stats(&self) -> Http3Stats {
+        let mut stats = self.stats.stats();
+        stats.qpack_encoder_table_capacity = self.qpack_encoder.capacity();
+        stats.qpack_encoder_table_used = self.qpack_encoder.used_capacity();
+        stats.qpack_decoder_table_capacity = self.qpack_decoder.capacity();
+        stats.qpack_decoder_table_used = self.qpack_decoder.used_capacity();
+        stats
+    }
+
+    /// The peer's SETTINGS_MAX_HEADER_LIST_SIZE, or `None` if its SETTINGS are not known yet.
+    pub fn peer_max_header_list_size(&self) -> Option<u64> {
+        match &self.settings_state {
+            Http3RemoteSettingsState::Received(settings)
+            | Http3RemoteSettingsState::ZeroRtt(settings) => {
+                Some(settings.get(HSettingType::MaxHeaderListSize))
+            }
+            Http3RemoteSettingsState::NotReceived => None,
+        }
+    }
+
+    /// The SETTINGS that the peer sent on this connection, or `None` if they have not been
+    /// received yet. Settings remembered for 0-RTT are not reported.
+    pub fn peer_settings(&self) -> Option<PeerSettings> {
+        match &self.settings_state {
+            Http3RemoteSettingsState::Received(settings) => Some(PeerSettings::from(settings)),
+            _ => None,
         }
     }
 
+    /// Returns true if the transport is connected, but we are still waiting for the peer's
+    /// SETTINGS frame before changing the state to `Connected`.
+    pub fn settings_pending(&self) -> bool {
+        self.settings_pending
+    }
+
     fn initialize_http3_connection(&mut self, conn: &mut Connection) -> Res<()> {
+        self.id = Some((conn.role(), conn.local_cid().unwrap_or_default().to_vec()));
         qinfo!([self], "Initialize the http3 connection.");
         self.control_stream_local.create(conn)?;
 
@@ -113,17 +306,24 @@ impl<T: Http3Transaction> Http3Connection<T> {
 
     fn send_settings(&mut self) {
         qdebug!([self], "Send settings.");
+        let mut settings = vec![
+            HSetting {
+                setting_type: HSettingType::MaxTableCapacity,
+                value: self.qpack_decoder.get_max_table_size().into(),
+            },
+            HSetting {
+                setting_type: HSettingType::BlockedStreams,
+                value: self.qpack_decoder.get_blocked_streams().into(),
+            },
+        ];
+        if let Some(limit) = self.local_settings.max_header_list_size {
+            settings.push(HSetting {
+                setting_type: HSettingType::MaxHeaderListSize,
+                value: limit,
+            });
+        }
         self.control_stream_local.queue_frame(HFrame::Settings {
-            settings: HSettings::new(&[
-                HSetting {
-                    setting_type: HSettingType::MaxTableCapacity,
-                    value: self.qpack_decoder.get_max_table_size().into(),
-                },
-                HSetting {
-                    setting_type: HSettingType::BlockedStreams,
-                    value: self.qpack_decoder.get_blocked_streams().into(),
-                },
-            ]),
+            settings: HSettings::new(&settings),
         });
     }
 
@@ -144,11 +344,91 @@ impl<T: Http3Transaction> Http3Connection<T> {
         !self.streams_have_data_to_send.is_empty()
     }
 
+    /// Returns the ids of the request streams that are still open, in ascending order. These
+    /// are the client's requests or the server's responses, depending on the role.
+    pub fn active_streams(&self) -> Vec<u64> {
+        let mut streams: Vec<u64> = self.transactions.keys().cloned().collect();
+        streams.sort_unstable();
+        streams
+    }
+
+    pub fn is_active(&self, stream_id: u64) -> bool {
+        self.transactions.contains_key(&stream_id)
+    }
+
+    /// Set the weight of a request stream. Streams with a higher weight are given the
+    /// chance to write their data first.
+    pub fn set_stream_weight(&mut self, stream_id: u64, weight: u8) -> Res<()> {
+        if !self.transactions.contains_key(&stream_id) {
+            return Err(Error::InvalidStreamId);
+        }
+        self.stream_weights.insert(stream_id, weight);
+        Ok(())
+    }
+
+    /// Change the priority of a request stream and send it to the peer in a PRIORITY_UPDATE
+    /// frame.
+    pub fn send_priority_update(&mut self, stream_id: u64, priority: Priority) -> Res<()> {
+        if !self.transactions.contains_key(&stream_id) {
+            return Err(Error::InvalidStreamId);
+        }
+        self.stream_priorities.insert(stream_id, priority);
+        self.control_stream_local
+            .queue_frame(HFrame::PriorityUpdateRequest {
+                element_id: stream_id,
+                priority,
+            });
+        Ok(())
+    }
+
+    /// Store the priority that the peer sent in a PRIORITY_UPDATE frame. A frame for a stream
+    /// that is not open (anymore) is ignored.
+    pub fn handle_priority_update(&mut self, element_id: u64, priority: Priority) -> Res<()> {
+        // Only client-initiated bidirectional streams are request streams.
+        if element_id % 4 != 0 {
+            return Err(Error::HttpIdError);
+        }
+        if self.transactions.contains_key(&element_id) {
+            qdebug!([self], "Stream {} has priority {}.", element_id, priority);
+            self.stream_priorities.insert(element_id, priority);
+        }
+        Ok(())
+    }
+
+    // Returns the streams that have data to send, ordered by urgency and then by weight.
+    // Streams with the same urgency and weight are ordered by stream id.
+    pub fn streams_in_send_order(&self) -> Vec<u64> {
+        let mut streams: Vec<u64> = self.streams_have_data_to_send.iter().cloned().collect();
+        // sort_by_key is stable, the set is already ordered by stream id.
+        streams.sort_by_key(|id| {
+            (
+                self.stream_priorities
+                    .get(id)
+                    .cloned()
+                    .unwrap_or_default()
+                    .urgency(),
+                std::cmp::Reverse(
+                    self.stream_weights
+                        .get(id)
+                        .cloned()
+                        .unwrap_or(DEFAULT_STREAM_WEIGHT),
+                ),
+            )
+        });
+        streams
+    }
+
     pub fn process_sending(&mut self, conn: &mut Connection) -> Res<()> {
         // check if control stream has data to send.
         self.control_stream_local.send(conn)?;
 
-        let to_send = mem::replace(&mut self.streams_have_data_to_send, BTreeSet::new());
+        let transactions = &self.transactions;
+        self.stream_weights
+            .retain(|stream_id, _| transactions.contains_key(stream_id));
+        self.stream_priorities
+            .retain(|stream_id, _| transactions.contains_key(stream_id));
+        let to_send = self.streams_in_send_order();
+        self.streams_have_data_to_send.clear();
         for stream_id in to_send {
             if let Some(t) = &mut self.transactions.get_mut(&stream_id) {
                 t.send(conn, &mut self.qpack_encoder)?;
@@ -157,6 +437,7 @@ impl<T: Http3Transaction> Http3Connection<T> {
                 }
             }
         }
+        self.trace_qpack_inserts();
         self.qpack_decoder.send(conn)?;
         self.qpack_encoder.send(conn)?;
         Ok(())
@@ -218,24 +499,21 @@ impl<T: Http3Transaction> Http3Connection<T> {
     // stream and unidi stream that are still do not have a type.
     // The function cannot handle:
     // 1) a Push stream (if a unkown unidi stream is decoded to be a push stream)
-    // 2) frames MaxPushId or Goaway must be handled by Http3Client/Server.
+    // 2) frames MaxPushId, Goaway or PriorityUpdateRequest must be handled by Http3Client/Server.
     // The function returns HandleReadableOutput.
     pub(crate) fn handle_stream_readable(
         &mut self,
         conn: &mut Connection,
         stream_id: u64,
+        now: Instant,
     ) -> Res<HandleReadableOutput> {
         qtrace!([self], "Readable stream {}.", stream_id);
 
         debug_assert!(self.state_active());
 
-        let label = if ::log::log_enabled!(::log::Level::Debug) {
-            format!("{}", self)
-        } else {
-            String::new()
-        };
+        let label = qlabel!(self);
 
-        if self.handle_read_stream(conn, stream_id)? {
+        if self.handle_read_stream(conn, stream_id, now)? {
             qdebug!([label], "Request/response stream {} read.", stream_id);
             Ok(HandleReadableOutput::NoOutput)
         } else if self
@@ -278,11 +556,22 @@ impl<T: Http3Transaction> Http3Connection<T> {
                 stream_id
             );
             let unblocked_streams = self.qpack_decoder.receive(conn, stream_id)?;
+            self.trace_qpack_inserts();
             for stream_id in unblocked_streams {
                 qinfo!([self], "Stream {} is unblocked", stream_id);
-                self.handle_read_stream(conn, stream_id)?;
+                if let Some(since) = self.qpack_blocked_since.remove(&stream_id) {
+                    let blocked = now.saturating_duration_since(since);
+                    self.stats.qpack_blocked(blocked);
+                    if let Some(t) = self.transactions.get_mut(&stream_id) {
+                        t.qpack_unblocked(blocked);
+                    }
+                }
+                self.handle_read_stream(conn, stream_id, now)?;
             }
             Ok(HandleReadableOutput::NoOutput)
+        } else if self.discarded_streams.contains(&stream_id) {
+            self.discard_stream_data(conn, stream_id);
+            Ok(HandleReadableOutput::NoOutput)
         } else if let Some(ns) = self.new_streams.get_mut(&stream_id) {
             let stream_type = ns.get_type(conn, stream_id);
             let fin = ns.fin();
@@ -334,8 +623,10 @@ impl<T: Http3Transaction> Http3Connection<T> {
             let _ = conn.stream_reset_send(stream_id, app_err);
             // remove the stream
             self.transactions.remove(&stream_id);
+            self.qpack_blocked_since.remove(&stream_id);
             Ok(true)
         } else {
+            self.discarded_streams.remove(&stream_id);
             Ok(false)
         }
     }
@@ -349,21 +640,30 @@ impl<T: Http3Transaction> Http3Connection<T> {
                 ));
                 if self.state == Http3State::Initializing {
                     self.initialize_http3_connection(conn)?;
+                    if self.wait_for_settings
+                        && matches!(self.settings_state, Http3RemoteSettingsState::NotReceived)
+                    {
+                        qdebug!([self], "Wait for the peer's SETTINGS frame.");
+                        self.settings_pending = true;
+                        return Ok(false);
+                    }
                 }
                 self.state = Http3State::Connected;
                 Ok(true)
             }
             State::Closing { error, .. } => {
+                self.settings_pending = false;
                 if !matches!(self.state, Http3State::Closing(_)| Http3State::Closed(_)) {
-                    self.state = Http3State::Closing(error.clone().into());
+                    self.state = Http3State::Closing(error.clone());
                     Ok(true)
                 } else {
                     Ok(false)
                 }
             }
             State::Closed(error) => {
+                self.settings_pending = false;
                 if !matches!(self.state, Http3State::Closed(_)) {
-                    self.state = Http3State::Closed(error.clone().into());
+                    self.state = Http3State::Closed(error.clone());
                     Ok(true)
                 } else {
                     Ok(false)
@@ -376,16 +676,22 @@ impl<T: Http3Transaction> Http3Connection<T> {
     pub fn handle_zero_rtt_rejected(&mut self) -> Res<()> {
         if self.state == Http3State::ZeroRtt {
             self.state = Http3State::Initializing;
-            self.control_stream_local = ControlStreamLocal::default();
-            self.control_stream_remote = ControlStreamRemote::new();
+            self.control_stream_local = ControlStreamLocal::new(self.stats.clone());
+            self.control_stream_remote = ControlStreamRemote::new(self.stats.clone());
             self.new_streams.clear();
-            self.qpack_encoder = QPackEncoder::new(true);
+            self.discarded_streams.clear();
+            self.qpack_encoder = self.local_settings.qpack_encoder();
             self.qpack_decoder = QPackDecoder::new(
                 self.local_settings.max_table_size,
                 self.local_settings.max_blocked_streams,
             );
+            self.record_qpack_inserts();
             self.settings_state = Http3RemoteSettingsState::NotReceived;
+            self.settings_pending = false;
             self.streams_have_data_to_send.clear();
+            self.stream_weights.clear();
+            self.stream_priorities.clear();
+            self.qpack_blocked_since.clear();
             // TODO: investigate whether this code can automatically retry failed transactions.
             self.transactions.clear();
             Ok(())
@@ -395,12 +701,48 @@ impl<T: Http3Transaction> Http3Connection<T> {
         }
     }
 
-    fn handle_read_stream(&mut self, conn: &mut Connection, stream_id: u64) -> Res<bool> {
-        let label = if ::log::log_enabled!(::log::Level::Debug) {
-            format!("{}", self)
-        } else {
-            String::new()
-        };
+    #[cfg(test)]
+    pub fn queue_control_frame(&mut self, frame: HFrame) {
+        self.control_stream_local.queue_frame(frame);
+    }
+
+    /// Reset the local HTTP/3 state, i.e. the request streams, the control and the QPACK
+    /// streams, and initialize it again on the same transport connection. The received
+    /// SETTINGS are kept. This is only used to reuse a connected pair in tests.
+    #[cfg(test)]
+    pub fn reset_http3_state(&mut self, conn: &mut Connection) -> Res<()> {
+        qinfo!([self], "Reset the http3 state.");
+        self.control_stream_local = ControlStreamLocal::new(self.stats.clone());
+        self.control_stream_remote = ControlStreamRemote::new(self.stats.clone());
+        self.new_streams.clear();
+        self.discarded_streams.clear();
+        self.qpack_encoder = self.local_settings.qpack_encoder();
+        self.qpack_decoder = QPackDecoder::new(
+            self.local_settings.max_table_size,
+            self.local_settings.max_blocked_streams,
+        );
+        self.record_qpack_inserts();
+        self.streams_have_data_to_send.clear();
+        self.stream_weights.clear();
+        self.stream_priorities.clear();
+        self.qpack_blocked_since.clear();
+        self.settings_pending = false;
+        self.transactions.clear();
+        self.initialize_http3_connection(conn)?;
+        if let Http3RemoteSettingsState::Received(settings) = &self.settings_state {
+            let settings = settings.clone();
+            self.set_qpack_settings(&settings)?;
+        }
+        Ok(())
+    }
+
+    fn handle_read_stream(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        now: Instant,
+    ) -> Res<bool> {
+        let label = qlabel!(self);
 
         debug_assert!(self.state_active());
 
@@ -416,11 +758,16 @@ impl<T: Http3Transaction> Http3Connection<T> {
                     return Err(e);
                 }
                 Ok(()) => {
+                    // A request may be answered while it is read, e.g. if it is rejected.
+                    if transaction.has_data_to_send() {
+                        self.streams_have_data_to_send.insert(stream_id);
+                    }
                     if transaction.done() {
                         self.transactions.remove(&stream_id);
                     }
                 }
             }
+            self.note_qpack_blocked(stream_id, now);
             Ok(true)
         } else {
             Ok(false)
@@ -458,7 +805,17 @@ impl<T: Http3Transaction> Http3Connection<T> {
                     .map_err(|_| Error::HttpStreamCreationError)?;
                 Ok(false)
             }
-            // TODO reserved stream types
+            t if is_reserved(t) => {
+                qinfo!(
+                    [self],
+                    "A new stream {} of reserved type {}, discard it.",
+                    stream_id,
+                    t
+                );
+                self.discarded_streams.insert(stream_id);
+                self.discard_stream_data(conn, stream_id);
+                Ok(false)
+            }
             _ => {
                 conn.stream_stop_sending(stream_id, Error::HttpStreamCreationError.code())?;
                 Ok(false)
@@ -466,13 +823,31 @@ impl<T: Http3Transaction> Http3Connection<T> {
         }
     }
 
+    // Read and drop everything that has arrived on a discarded stream. The stream is
+    // forgotten once it is finished or can't be read any more.
+    fn discard_stream_data(&mut self, conn: &mut Connection, stream_id: u64) {
+        let mut buf = [0u8; 1024];
+        loop {
+            match conn.stream_recv(stream_id, &mut buf) {
+                Ok((0, false)) => break,
+                Ok((_, false)) => {}
+                Ok((_, true)) | Err(_) => {
+                    self.discarded_streams.remove(&stream_id);
+                    break;
+                }
+            }
+        }
+    }
+
     pub fn close(&mut self, error: AppError) {
         qinfo!([self], "Close connection error {:?}.", error);
-        self.state = Http3State::Closing(CloseError::Application(error));
+        self.state = Http3State::Closing(ConnectionError::Application(error));
+        self.settings_pending = false;
         if !self.transactions.is_empty() && (error == 0) {
             qwarn!("close() called when streams still active");
         }
         self.transactions.clear();
+        self.discarded_streams.clear();
     }
 
     pub fn stream_reset(
@@ -486,6 +861,7 @@ impl<T: Http3Transaction> Http3Connection<T> {
             .transactions
             .remove(&stream_id)
             .ok_or(Error::InvalidStreamId)?;
+        self.qpack_blocked_since.remove(&stream_id);
         transaction.stop_sending();
         // Stream maybe already be closed and we may get an error here, but we do not care.
         let _ = conn.stream_reset_send(stream_id, error);
@@ -495,6 +871,35 @@ impl<T: Http3Transaction> Http3Connection<T> {
         Ok(())
     }
 
+    /// Abort a response, e.g. because the data for its body could not be produced. The
+    /// sending side of the stream is reset with `error` and the request is forgotten; if the
+    /// request has not been received completely, the peer is asked to stop sending it.
+    pub fn reset_response(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        error: AppError,
+    ) -> Res<()> {
+        qinfo!(
+            [self],
+            "Reset response on stream {} error={}.",
+            stream_id,
+            error
+        );
+        let mut transaction = self
+            .transactions
+            .remove(&stream_id)
+            .ok_or(Error::InvalidStreamId)?;
+        self.qpack_blocked_since.remove(&stream_id);
+        self.streams_have_data_to_send.remove(&stream_id);
+        transaction.stop_sending();
+        conn.stream_reset_send(stream_id, error)?;
+        transaction.reset_receiving_side();
+        // The request may have been read completely, then there is nothing to stop.
+        let _ = conn.stream_stop_sending(stream_id, error);
+        Ok(())
+    }
+
     pub fn stream_close_send(&mut self, conn: &mut Connection, stream_id: u64) -> Res<()> {
         qinfo!([self], "Close sending side for stream {}.", stream_id);
         debug_assert!(self.state_active() || self.state_zero_rtt());
@@ -509,8 +914,9 @@ impl<T: Http3Transaction> Http3Connection<T> {
         Ok(())
     }
 
-    // If the control stream has received frames MaxPushId or Goaway which handling is specific to
-    // the client and server, we must give them to the specific client/server handler..
+    // If the control stream has received frames MaxPushId, Goaway or PriorityUpdateRequest which
+    // handling is specific to the client and server, we must give them to the specific
+    // client/server handler..
     fn handle_control_frame(&mut self) -> Res<Option<HFrame>> {
         if self.control_stream_remote.recvd_fin() {
             return Err(Error::HttpClosedCriticalStream);
@@ -529,7 +935,9 @@ impl<T: Http3Transaction> Http3Connection<T> {
                     Ok(None)
                 }
                 HFrame::CancelPush { .. } => Err(Error::HttpFrameUnexpected),
-                HFrame::Goaway { .. } | HFrame::MaxPushId { .. } => Ok(Some(f)),
+                HFrame::Goaway { .. }
+                | HFrame::MaxPushId { .. }
+                | HFrame::PriorityUpdateRequest { .. } => Ok(Some(f)),
                 _ => Err(Error::HttpFrameUnexpected),
             };
         }
@@ -539,11 +947,17 @@ impl<T: Http3Transaction> Http3Connection<T> {
     fn set_qpack_settings(&mut self, settings: &[HSetting]) -> Res<()> {
         for s in settings {
             qinfo!([self], " {:?} = {:?}", s.setting_type, s.value);
+            // The encoder refuses values it cannot support; that is a problem
+            // with the peer's SETTINGS, not with the encoder stream.
             match s.setting_type {
-                HSettingType::MaxTableCapacity => self.qpack_encoder.set_max_capacity(s.value)?,
-                HSettingType::BlockedStreams => {
-                    self.qpack_encoder.set_max_blocked_streams(s.value)?
-                }
+                HSettingType::MaxTableCapacity => self
+                    .qpack_encoder
+                    .set_max_capacity(s.value)
+                    .map_err(|_| Error::HttpSettingsError)?,
+                HSettingType::BlockedStreams => self
+                    .qpack_encoder
+                    .set_max_blocked_streams(s.value)
+                    .map_err(|_| Error::HttpSettingsError)?,
                 _ => {}
             }
         }
@@ -556,6 +970,10 @@ impl<T: Http3Transaction> Http3Connection<T> {
             Http3RemoteSettingsState::NotReceived => {
                 self.set_qpack_settings(&new_settings)?;
                 self.settings_state = Http3RemoteSettingsState::Received(new_settings);
+                if self.settings_pending {
+                    self.settings_pending = false;
+                    self.state = Http3State::Connected;
+                }
                 Ok(())
             }
             Http3RemoteSettingsState::ZeroRtt(settings) => {
@@ -604,7 +1022,7 @@ impl<T: Http3Transaction> Http3Connection<T> {
     }
 
     fn state_active(&self) -> bool {
-        matches!(self.state, Http3State::Connected | Http3State::GoingAway)
+        matches!(self.state, Http3State::Connected | Http3State::GoingAway) || self.settings_pending
     }
 
     fn state_zero_rtt(&self) -> bool {
@@ -615,7 +1033,17 @@ impl<T: Http3Transaction> Http3Connection<T> {
         self.state.clone()
     }
 
+    /// Remember when a request stream started to wait for QPACK encoder instructions. The
+    /// time spent blocked is added to the transaction and the statistics once the stream is
+    /// unblocked.
+    pub(crate) fn note_qpack_blocked(&mut self, stream_id: u64, now: Instant) {
+        if self.qpack_decoder.is_stream_blocked(stream_id) {
+            self.qpack_blocked_since.entry(stream_id).or_insert(now);
+        }
+    }
+
     pub fn add_transaction(&mut self, stream_id: u64, transaction: T) {
+        self.stats.request_created();
         if transaction.has_data_to_send() {
             self.streams_have_data_to_send.insert(stream_id);
         }
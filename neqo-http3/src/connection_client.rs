@@ -5,21 +5,28 @@
 // except according to those terms.
 
 use crate::client_events::{Http3ClientEvent, Http3ClientEvents};
-use crate::connection::{HandleReadableOutput, Http3Connection, Http3State, Http3Transaction};
+use crate::connection::{
+    HandleReadableOutput, Http3Connection, Http3Parameters, Http3State, Http3Transaction,
+};
+#[cfg(feature = "frame-trace")]
+use crate::frame_trace::FrameTracer;
 use crate::hframe::HFrame;
-use crate::hsettings_frame::HSettings;
-use crate::transaction_client::TransactionClient;
+use crate::hsettings_frame::{HSettings, PeerSettings};
+use crate::priority::Priority;
+use crate::stats::Http3Stats;
+use crate::transaction_client::{encode_request, request_header_list_size, TransactionClient};
 use crate::Header;
-use neqo_common::{hex, matches, qdebug, qinfo, qtrace, Datagram, Decoder, Encoder};
+use neqo_common::{hex, matches, qdebug, qinfo, qtrace, qwarn, Datagram, Decoder, Encoder};
 use neqo_crypto::{agent::CertificateInfo, AuthenticationStatus, SecretAgentInfo};
 use neqo_transport::stream_id::StreamId;
 use neqo_transport::{
-    AppError, Connection, ConnectionEvent, ConnectionIdManager, Output, Role, StreamType,
+    AppError, CongestionControlAlgorithm, Connection, ConnectionEvent, ConnectionIdManager,
+    Error as TransportError, Output, Role, State, StreamType,
 };
 use std::cell::RefCell;
 use std::net::SocketAddr;
 use std::rc::Rc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use crate::{Error, Res};
 
@@ -27,6 +34,10 @@ pub struct Http3Client {
     conn: Connection,
     base_handler: Http3Connection<TransactionClient>,
     events: Http3ClientEvents,
+    // A close requested with `close_graceful` that waits until the queued data is sent.
+    pending_close: Option<(AppError, String)>,
+    // The stream ID of the last GOAWAY frame. A later GOAWAY can't have a larger one.
+    goaway_stream_id: Option<u64>,
 }
 
 impl ::std::fmt::Display for Http3Client {
@@ -52,14 +63,67 @@ impl Http3Client {
         ))
     }
 
+    /// Create a client with the HTTP/3 parameters in `params`. Unlike `new`, this reports
+    /// parameters that can't be used as an error.
+    pub fn new_with_params(
+        server_name: &str,
+        protocols: &[impl AsRef<str>],
+        cid_manager: Rc<RefCell<dyn ConnectionIdManager>>,
+        local_addr: SocketAddr,
+        remote_addr: SocketAddr,
+        params: Http3Parameters,
+    ) -> Res<Self> {
+        Self::new_with_conn_and_params(
+            Connection::new_client(server_name, protocols, cid_manager, local_addr, remote_addr)?,
+            params,
+        )
+    }
+
+    /// Create a client with the HTTP/3 parameters in `params` on a transport connection
+    /// that the application made. The handshake may have started, e.g. if the application
+    /// offered several ALPN protocols and waited for `AuthenticationNeeded` to see which one
+    /// was chosen; `authenticated` is then called on the new client. The connection must not
+    /// be connected yet.
+    pub fn new_with_conn_and_params(conn: Connection, params: Http3Parameters) -> Res<Self> {
+        if matches!(
+            conn.state(),
+            State::Connected | State::Closing { .. } | State::Closed(_)
+        ) {
+            return Err(Error::Unexpected);
+        }
+        let base_handler = Http3Connection::new_with_params(params)?;
+        Ok(Self {
+            conn,
+            base_handler,
+            events: Http3ClientEvents::default(),
+            pending_close: None,
+            goaway_stream_id: None,
+        })
+    }
+
     pub fn new_with_conn(c: Connection, max_table_size: u32, max_blocked_streams: u16) -> Self {
         Self {
             conn: c,
             base_handler: Http3Connection::new(max_table_size, max_blocked_streams),
             events: Http3ClientEvents::default(),
+            pending_close: None,
+            goaway_stream_id: None,
         }
     }
 
+    /// Only report `Http3State::Connected` after the server's SETTINGS frame has been
+    /// received. This must be called before the transport handshake completes.
+    pub fn set_wait_for_settings(&mut self, wait: bool) {
+        self.base_handler.set_wait_for_settings(wait);
+    }
+
+    /// Pass every HTTP/3 frame that is sent or received, on the control stream and on request
+    /// streams, and every QPACK dynamic table insertion to `tracer`.
+    #[cfg(feature = "frame-trace")]
+    pub fn set_frame_tracer(&mut self, tracer: Box<dyn FrameTracer>) {
+        self.base_handler.set_frame_tracer(tracer);
+    }
+
     pub fn role(&self) -> Role {
         self.conn.role()
     }
@@ -77,10 +141,127 @@ impl Http3Client {
         self.conn.peer_certificate()
     }
 
+    /// Get the statistics of the connection. These combine HTTP/3 counters, e.g. the number
+    /// of ignored frames, with those of the transport, e.g. whether a Retry has been received.
+    pub fn stats(&self) -> Http3Stats {
+        let mut stats = self.base_handler.stats();
+        let transport = self.conn.stats();
+        stats.retry_dcid = transport.retry_dcid.clone();
+        stats.retry_received = stats.retry_dcid.is_some();
+        stats.vn_versions = transport.vn_versions.clone();
+        stats.packets_tx = transport.packets_tx;
+        stats.packets_rx = transport.packets_rx;
+        stats.bytes_tx = transport.bytes_tx;
+        stats.bytes_rx = transport.bytes_rx;
+        stats.lost_packets = transport.lost;
+        stats.ect0_rx = transport.ect0_rx;
+        stats.ect1_rx = transport.ect1_rx;
+        stats.ce_rx = transport.ce_rx;
+        stats.congestion_window = self.conn.congestion_window();
+        stats.slow_start_threshold = self.conn.slow_start_threshold();
+        stats
+    }
+
+    /// Select the congestion control algorithm used by the transport. This must be
+    /// called before the connection starts.
+    pub fn set_congestion_control(&mut self, algorithm: CongestionControlAlgorithm) -> Res<()> {
+        self.conn.set_congestion_control(algorithm)?;
+        Ok(())
+    }
+
+    /// The name of the congestion control algorithm in use.
+    pub fn congestion_control(&self) -> &str {
+        self.conn.congestion_control().name()
+    }
+
+    /// Whether the response headers of a request stream have been received but can't be
+    /// decoded yet, because they refer to QPACK dynamic table entries that the server's
+    /// encoder stream has not delivered. A `HeaderReady` event follows once they arrive.
+    pub fn is_qpack_blocked(&self, stream_id: StreamId) -> bool {
+        self.base_handler
+            .qpack_decoder
+            .is_stream_blocked(stream_id.0)
+    }
+
+    /// The time that the response headers of a request stream waited for QPACK encoder
+    /// instructions. This is `None` once the request is done and the stream has been removed.
+    pub fn qpack_blocked_time(&self, stream_id: StreamId) -> Option<Duration> {
+        self.base_handler
+            .transactions
+            .get(&stream_id.0)
+            .map(TransactionClient::qpack_blocked_time)
+    }
+
+    /// The headers of the request on `stream_id`, including the pseudo-headers, as they are
+    /// sent. This is `None` once the request is done and the stream has been removed.
+    pub fn get_sent_headers(&self, stream_id: StreamId) -> Option<&[Header]> {
+        self.base_handler
+            .transactions
+            .get(&stream_id.0)
+            .map(TransactionClient::request_headers)
+    }
+
+    /// The SETTINGS that the server sent, or `None` if they have not been received yet.
+    pub fn peer_settings(&self) -> Option<PeerSettings> {
+        self.base_handler.peer_settings()
+    }
+
+    /// Whether a response header block (the headers, informational headers or trailers) of
+    /// a request stream referred to the QPACK dynamic table. This is `false` once the request
+    /// is done and the stream has been removed.
+    pub fn headers_used_dynamic_table(&self, stream_id: StreamId) -> bool {
+        self.base_handler
+            .transactions
+            .get(&stream_id.0)
+            .map_or(false, TransactionClient::used_dynamic_table)
+    }
+
+    /// Enable or disable transport keep-alive PINGs, which stop an otherwise quiet
+    /// connection from reaching the idle timeout.
+    pub fn set_keep_alive(&mut self, keep_alive: bool) {
+        self.conn.set_keep_alive(keep_alive);
+    }
+
+    /// Set how much data, in bytes, the server may send on all streams together before the
+    /// application reads it. This can only be done before the connection starts.
+    pub fn set_max_data(&mut self, max_data: u64) -> Res<()> {
+        self.conn.set_max_data(max_data)?;
+        Ok(())
+    }
+
+    /// Allow the server to send datagrams. `size` is the largest DATAGRAM frame that the server
+    /// may send, including the frame type and length. This can only be done before the
+    /// connection starts.
+    pub fn set_max_datagram_frame_size(&mut self, size: u64) -> Res<()> {
+        self.conn.set_max_datagram_frame_size(size)?;
+        Ok(())
+    }
+
+    /// Send `data` in a QUIC DATAGRAM frame. It is not retransmitted if it is lost. This fails
+    /// if the server does not allow a datagram of this size, or does not allow datagrams.
+    pub fn send_datagram(&mut self, data: &[u8]) -> Res<()> {
+        self.conn.send_datagram(data)?;
+        Ok(())
+    }
+
+    /// Set how much data, in bytes, the server may send on a stream before it gets more
+    /// credit. This can only be done before the connection starts.
+    pub fn set_max_stream_data(&mut self, max_stream_data: u64) -> Res<()> {
+        self.conn.set_max_stream_data(max_stream_data)?;
+        Ok(())
+    }
+
+    /// The local and remote addresses of the connection's current path.
+    pub fn current_path(&self) -> Option<(SocketAddr, SocketAddr)> {
+        self.conn.current_path()
+    }
+
     pub fn authenticated(&mut self, status: AuthenticationStatus, now: Instant) {
         self.conn.authenticated(status, now);
     }
 
+    /// Get a resumption token that can be passed to `set_resumption_token` on a new client.
+    /// This returns `None` until the server's NewSessionTicket and SETTINGS have been received.
     pub fn resumption_token(&self) -> Option<Vec<u8>> {
         if let Some(token) = self.conn.resumption_token() {
             if let Some(settings) = self.base_handler.get_settings() {
@@ -96,6 +277,8 @@ impl Http3Client {
         }
     }
 
+    /// Enable resumption and attempt 0-RTT using a token from `resumption_token`.
+    /// This must be called before the connection starts.
     pub fn set_resumption_token(&mut self, now: Instant, token: &[u8]) -> Res<()> {
         let mut dec = Decoder::from(token);
         let settings_slice = match dec.decode_vvec() {
@@ -113,6 +296,8 @@ impl Http3Client {
             .set_resumption_settings(&mut self.conn, settings)
     }
 
+    /// Close the connection with an application CONNECTION_CLOSE frame that carries `error`,
+    /// e.g. `Error::HttpRequestRejected.code()`, and `msg` as the reason.
     pub fn close(&mut self, now: Instant, error: AppError, msg: &str) {
         qinfo!([self], "Close the connection error={} msg={}.", error, msg);
         if !matches!(self.base_handler.state, Http3State::Closing(_)| Http3State::Closed(_)) {
@@ -123,6 +308,48 @@ impl Http3Client {
         }
     }
 
+    /// Close the connection after the data that is queued, e.g. frames on the control
+    /// stream, has been delivered. The data is passed to the transport now, and the
+    /// connection is closed once the server has acknowledged all stream data, including data
+    /// that the congestion window holds back. If the server stops acknowledging, the idle
+    /// timeout ends the connection. Use this instead of `close` when the peer should see what
+    /// was queued before the CONNECTION_CLOSE.
+    pub fn close_graceful(&mut self, now: Instant, error: AppError, msg: &str) {
+        qinfo!(
+            [self],
+            "Close the connection gracefully error={} msg={}.",
+            error,
+            msg
+        );
+        if matches!(
+            self.base_handler.state,
+            Http3State::Closing(_) | Http3State::Closed(_)
+        ) {
+            return;
+        }
+        let res = self.base_handler.process_sending(&mut self.conn);
+        if !self.check_result(now, res) {
+            self.pending_close = Some((error, msg.to_owned()));
+        }
+    }
+
+    /// Returns the bytes that `fetch` writes to the request stream for a request, i.e. its
+    /// HEADERS frame, without sending anything. The QPACK dynamic table is not used, so the
+    /// encoding is only the same as that of `fetch` as long as the peer does not allow it.
+    pub fn encode_request(
+        method: &str,
+        scheme: &str,
+        host: &str,
+        path: &str,
+        headers: &[Header],
+    ) -> Vec<u8> {
+        encode_request(method, scheme, host, path, headers)
+    }
+
+    /// Send a request. If the peer's SETTINGS are known, this fails with
+    /// `Error::HeaderListTooLarge` when the header list exceeds its SETTINGS_MAX_HEADER_LIST_SIZE.
+    /// This fails with `Error::StreamLimit` if the peer does not allow more concurrent requests;
+    /// a `RequestsCreatable` event is posted when a request can be sent again.
     pub fn fetch(
         &mut self,
         method: &str,
@@ -139,14 +366,70 @@ impl Http3Client {
             host,
             path
         );
-        let id = self.conn.stream_create(StreamType::BiDi)?;
+        // Requests can be sent in 0-RTT, but not before the connection (or 0-RTT) is
+        // established; a stream created earlier could not be used until then.
+        if !matches!(
+            self.base_handler.state(),
+            Http3State::ZeroRtt | Http3State::Connected | Http3State::GoingAway
+        ) {
+            return Err(Error::Unavailable);
+        }
+        if let Some(limit) = self.base_handler.peer_max_header_list_size() {
+            let size = request_header_list_size(method, scheme, host, path, headers);
+            if size > limit {
+                qinfo!(
+                    [self],
+                    "The header list size {} exceeds the peer's limit {}",
+                    size,
+                    limit
+                );
+                return Err(Error::HeaderListTooLarge);
+            }
+        }
+        let id = self
+            .conn
+            .stream_create(StreamType::BiDi)
+            .map_err(|e| match e {
+                TransportError::StreamLimitError => Error::StreamLimit,
+                _ => Error::from(e),
+            })?;
         self.base_handler.add_transaction(
             id,
-            TransactionClient::new(id, method, scheme, host, path, headers, self.events.clone()),
+            TransactionClient::new(
+                id,
+                method,
+                scheme,
+                host,
+                path,
+                headers,
+                self.events.clone(),
+                self.base_handler.stats_recorder(),
+            ),
         );
         Ok(id)
     }
 
+    /// Send a CONNECT request for a tunnel to `authority`, e.g. "example.com:443". The
+    /// request has no `:scheme` and `:path`. Once a 2xx response has been received, a
+    /// `DataWritable` event is posted and the stream carries the tunnel: bytes passed to
+    /// `send_request_body` and returned by `read_response_data` are the payload of DATA
+    /// frames. Any other final response resets the sending side of the stream.
+    ///
+    /// `stream_close_send` closes the client's direction of the tunnel, and a FIN from the
+    /// server is reported like the end of a response body; the tunnel is torn down once
+    /// both directions are closed. `stream_reset` aborts the tunnel in both directions.
+    pub fn connect(&mut self, authority: &str) -> Res<u64> {
+        self.fetch("CONNECT", "", authority, "", &[])
+    }
+
+    /// Clear all events and reset the HTTP/3 state without recreating the transport
+    /// connection. This is only used to reuse a connected pair in tests.
+    #[cfg(test)]
+    pub fn reset_http3_state(&mut self) -> Res<()> {
+        self.events.clear();
+        self.base_handler.reset_http3_state(&mut self.conn)
+    }
+
     pub fn stream_reset(&mut self, stream_id: StreamId, error: AppError) -> Res<()> {
         qinfo!([self], "reset_stream {} error={}.", stream_id, error);
         self.base_handler
@@ -161,6 +444,59 @@ impl Http3Client {
             .stream_close_send(&mut self.conn, stream_id.0)
     }
 
+    /// Set the weight of a request stream. If several requests have data to send, the ones
+    /// with a higher weight are written first; the default weight is 16. The PRIORITY frame
+    /// is not part of the HTTP/3 version we support, so this is not signaled to the server.
+    pub fn priority(&mut self, stream_id: StreamId, weight: u8) -> Res<()> {
+        qinfo!([self], "Set weight {} for stream {}.", weight, stream_id);
+        self.base_handler.set_stream_weight(stream_id.0, weight)
+    }
+
+    /// Change the priority of a request and signal it to the server in a PRIORITY_UPDATE
+    /// frame (RFC 9218). `urgency` goes from 0 (most urgent) to 7; the default is 3. Requests
+    /// with a lower urgency are also written first if several have data to send.
+    pub fn priority_update(
+        &mut self,
+        stream_id: StreamId,
+        urgency: u8,
+        incremental: bool,
+    ) -> Res<()> {
+        qinfo!(
+            [self],
+            "Set urgency {} incremental {} for stream {}.",
+            urgency,
+            incremental,
+            stream_id
+        );
+        let priority = Priority::new(urgency, incremental).ok_or(Error::InvalidInput)?;
+        self.base_handler
+            .send_priority_update(stream_id.0, priority)
+    }
+
+    /// Returns the ids of the requests that are still outstanding, in ascending order. A
+    /// request is gone once it is done, reset or rejected, e.g. after a GOAWAY.
+    pub fn active_streams(&self) -> Vec<u64> {
+        self.base_handler.active_streams()
+    }
+
+    pub fn is_active(&self, stream_id: StreamId) -> bool {
+        self.base_handler.is_active(stream_id.0)
+    }
+
+    /// Returns the error code the server sent in STOP_SENDING if it stopped the request body
+    /// of a stream, otherwise `None`. This is `None` once the request is done and the stream
+    /// has been removed; if the server also stopped the response, the `Reset` event carries
+    /// the same error code.
+    pub fn send_side_reset(&self, stream_id: StreamId) -> Option<AppError> {
+        self.base_handler
+            .transactions
+            .get(&stream_id.0)
+            .and_then(TransactionClient::send_side_reset)
+    }
+
+    /// Send request body data on a stream. This returns the number of bytes that were accepted,
+    /// which is limited by the stream's flow control credit and may be 0. Nothing is buffered,
+    /// the rest must be resent after a `DataWritable` event is posted for the stream.
     pub fn send_request_body(&mut self, stream_id: StreamId, buf: &[u8]) -> Res<usize> {
         qinfo!(
             [self],
@@ -209,24 +545,46 @@ impl Http3Client {
         match transaction.read_response_data(&mut self.conn, buf) {
             Ok((amount, fin)) => {
                 if fin {
-                    self.base_handler.transactions.remove(&stream_id.0);
+                    // Keep the transaction until the application has read the trailers.
+                    if !transaction.has_unread_trailers() {
+                        self.base_handler.transactions.remove(&stream_id.0);
+                    }
                 } else if amount > 0 {
                     // Directly call receive instead of adding to
                     // streams_are_readable here. This allows the app to
                     // pick up subsequent already-received data frames in
                     // the stream even if no new packets arrive to cause
-                    // process_http3() to run.
-                    transaction.receive(&mut self.conn, &mut self.base_handler.qpack_decoder)?;
+                    // process_http3() to run. A new DataReadable event is
+                    // only posted if there is still data or a fin to read.
+                    if let Err(e) =
+                        transaction.receive(&mut self.conn, &mut self.base_handler.qpack_decoder)
+                    {
+                        return Err(self.read_error(now, e));
+                    }
+                    if transaction.done() {
+                        self.base_handler.transactions.remove(&stream_id.0);
+                    }
+                    self.base_handler.note_qpack_blocked(stream_id.0, now);
                 }
                 Ok((amount, fin))
             }
-            Err(e) => {
-                if e == Error::HttpFrameError {
-                    self.close(now, e.code(), "");
-                }
-                Err(e)
-            }
+            Err(e) => Err(self.read_error(now, e)),
+        }
+    }
+
+    /// Read the trailers of a response. They are available after a `TrailersReady` event.
+    pub fn read_response_trailers(&mut self, stream_id: StreamId) -> Res<Vec<Header>> {
+        qinfo!([self], "read_response_trailers from stream {}.", stream_id);
+        let transaction = self
+            .base_handler
+            .transactions
+            .get_mut(&stream_id.0)
+            .ok_or(Error::InvalidStreamId)?;
+        let trailers = transaction.read_response_trailers()?;
+        if transaction.is_recv_closed() {
+            self.base_handler.transactions.remove(&stream_id.0);
         }
+        Ok(trailers)
     }
 
     /// Get all current events. Best used just in debug/testing code, use
@@ -235,6 +593,14 @@ impl Http3Client {
         self.events.events()
     }
 
+    /// Limit the number of events that are kept until the application takes them. Once the
+    /// limit is reached, `DataWritable`, `RequestsCreatable` and `Datagram` events are dropped;
+    /// the other events are always kept. Events that are already queued, other than datagrams,
+    /// are never queued twice.
+    pub fn set_max_events(&mut self, max_events: usize) {
+        self.events.set_max_events(max_events);
+    }
+
     /// Return true if there are outstanding events.
     pub fn has_events(&self) -> bool {
         self.events.has_events()
@@ -270,28 +636,61 @@ impl Http3Client {
         &mut self.conn
     }
 
-    pub fn process_http3(&mut self, now: Instant) {
+    /// Handle the events of the transport and pass queued data to it. This returns true if
+    /// there were transport events to handle, i.e. if calling it may have produced new events
+    /// for the application. If it returns false, nothing happens until a datagram arrives, a
+    /// timer fires or the application acts.
+    pub fn process_http3(&mut self, now: Instant) -> bool {
         qtrace!([self], "Process http3 internal.");
+        let progress = self.conn.has_events();
         match self.base_handler.state() {
             Http3State::ZeroRtt | Http3State::Connected | Http3State::GoingAway => {
-                let res = self.check_connection_events();
+                let res = self.check_connection_events(now);
                 if self.check_result(now, res) {
-                    return;
+                    return true;
                 }
                 let res = self.base_handler.process_sending(&mut self.conn);
                 self.check_result(now, res);
             }
             Http3State::Closed { .. } => {}
             _ => {
-                let res = self.check_connection_events();
-                let _ = self.check_result(now, res);
+                let res = self.check_connection_events(now);
+                if self.check_result(now, res) {
+                    return true;
+                }
+                if self.base_handler.settings_pending() {
+                    // The transport is connected and we are waiting for the server's SETTINGS,
+                    // our own SETTINGS can already be sent.
+                    let res = self.base_handler.process_sending(&mut self.conn);
+                    self.check_result(now, res);
+                }
             }
         }
+        progress
     }
 
     pub fn process_output(&mut self, now: Instant) -> Output {
         qtrace!([self], "Process output.");
-        self.conn.process_output(now)
+        let out = self.conn.process_output(now);
+        if out.as_dgram_ref().is_none() && !self.conn.has_unacked_stream_data() {
+            if let Some((error, msg)) = self.pending_close.take() {
+                qinfo!([self], "Queued data has been sent, close the connection.");
+                self.close(now, error, &msg);
+                return self.conn.process_output(now);
+            }
+        }
+        out
+    }
+
+    // An error while reading a response closes the connection, unless it comes from the
+    // transport, e.g. because the stream has been reset. The error is passed on so that the
+    // application learns the cause.
+    fn read_error(&mut self, now: Instant, e: Error) -> Error {
+        if !matches!(e, Error::TransportError(_)) {
+            qinfo!([self], "Error reading the response: {}.", e);
+            self.close(now, e.code(), &format!("{}", e));
+        }
+        e
     }
 
     // This function takes the provided result and check for an error.
@@ -308,7 +707,7 @@ impl Http3Client {
     }
 
     // If this return an error the connection must be closed.
-    fn check_connection_events(&mut self) -> Res<()> {
+    fn check_connection_events(&mut self, now: Instant) -> Res<()> {
         qtrace!([self], "Check connection events.");
         while let Some(e) = self.conn.next_event() {
             qdebug!([self], "check_connection_events - event {:?}.", e);
@@ -336,7 +735,7 @@ impl Http3Client {
                 }
                 ConnectionEvent::RecvStreamReadable { stream_id } => {
                     let stream_id = StreamId(stream_id);
-                    self.handle_stream_readable(stream_id)?
+                    self.handle_stream_readable(stream_id, now)?
                 }
                 ConnectionEvent::RecvStreamReset {
                     stream_id,
@@ -373,26 +772,47 @@ impl Http3Client {
                     self.base_handler.handle_zero_rtt_rejected()?;
                     self.events.zero_rtt_rejected();
                 }
+                ConnectionEvent::Datagram { data } => self.events.datagram(data),
             }
         }
         Ok(())
     }
 
-    fn handle_stream_readable(&mut self, stream_id: StreamId) -> Res<()> {
-        match self
-            .base_handler
-            .handle_stream_readable(&mut self.conn, stream_id.0)?
-        {
+    fn handle_stream_readable(&mut self, stream_id: StreamId, now: Instant) -> Res<()> {
+        let settings_pending = self.base_handler.settings_pending();
+        let is_request = self.base_handler.transactions.contains_key(&stream_id.0);
+        let output =
+            match self
+                .base_handler
+                .handle_stream_readable(&mut self.conn, stream_id.0, now)
+            {
+                Ok(output) => output,
+                Err(e) => {
+                    // The error closes the connection, but the application should also learn
+                    // that this request failed rather than seeing it disappear.
+                    if is_request {
+                        self.events.reset(stream_id.0, e.code());
+                    }
+                    return Err(e);
+                }
+            };
+        if settings_pending && !self.base_handler.settings_pending() {
+            // The SETTINGS frame has been received and the connection is now connected.
+            self.events
+                .connection_state_change(self.base_handler.state());
+        }
+        match output {
+            // Push is not supported, so the client never sends MAX_PUSH_ID and the server may
+            // not open a push stream.
             HandleReadableOutput::PushStream => Err(Error::HttpIdError),
             HandleReadableOutput::ControlFrames(control_frames) => {
                 for f in control_frames.into_iter() {
                     match f {
                         HFrame::MaxPushId { .. } => Err(Error::HttpFrameUnexpected),
                         HFrame::Goaway { stream_id } => self.handle_goaway(stream_id),
+                        HFrame::PriorityUpdateRequest { .. } => Err(Error::HttpFrameUnexpected),
                         _ => {
-                            unreachable!(
-                                "we should only put MaxPushId and Goaway into control_frames."
-                            );
+                            unreachable!("not a control frame");
                         }
                     }?;
                 }
@@ -418,6 +838,7 @@ impl Http3Client {
             }
             // close sending side.
             t.stop_sending();
+            t.set_send_side_reset(app_err);
             // if error is not Error::EarlyResponse we will close receiving part as well.
             if app_err != Error::HttpEarlyResponse.code() {
                 self.events.reset(stop_stream_id, app_err);
@@ -435,6 +856,19 @@ impl Http3Client {
 
     fn handle_goaway(&mut self, goaway_stream_id: u64) -> Res<()> {
         qinfo!([self], "handle_goaway");
+        if self
+            .goaway_stream_id
+            .map_or(false, |last| goaway_stream_id > last)
+        {
+            qinfo!(
+                [self],
+                "GOAWAY stream ID {} is larger than the previous one",
+                goaway_stream_id
+            );
+            return Err(Error::HttpIdError);
+        }
+        self.goaway_stream_id = Some(goaway_stream_id);
+
         // Issue reset events for streams >= goaway stream id
         for id in self
             .base_handler
@@ -454,9 +888,33 @@ impl Http3Client {
 
         if self.base_handler.state == Http3State::Connected {
             self.base_handler.state = Http3State::GoingAway;
+            self.events
+                .connection_state_change(self.base_handler.state());
         }
         Ok(())
     }
+
+    // Whether the server would only notice that the client is gone after its idle timeout,
+    // because `close` was not called or its CONNECTION_CLOSE has not been sent.
+    fn needs_close(&self) -> bool {
+        matches!(
+            self.base_handler.state,
+            Http3State::ZeroRtt | Http3State::Connected | Http3State::GoingAway
+        )
+    }
+}
+
+// Dropping a client can't send a CONNECTION_CLOSE, as the application sends the datagrams,
+// so only warn that the server is left waiting.
+impl Drop for Http3Client {
+    fn drop(&mut self) {
+        if self.needs_close() {
+            qwarn!(
+                [self],
+                "Dropped without closing; the server will wait for its idle timeout."
+            );
+        }
+    }
 }
 
 #[cfg(test)]
@@ -467,13 +925,19 @@ mod tests {
     use neqo_common::{matches, Encoder};
     use neqo_crypto::AntiReplay;
     use neqo_qpack::encoder::QPackEncoder;
-    use neqo_transport::{CloseError, ConnectionEvent, FixedConnectionIdManager, State};
+    use neqo_transport::{
+        ConnectionError, ConnectionEvent, Error as TransportError, FixedConnectionIdManager, State,
+    };
+    use std::time::Duration;
     use test_fixture::*;
 
+    // The longest time that a peer waits before it acknowledges a packet.
+    const ACK_DELAY: Duration = Duration::from_millis(20);
+
     fn assert_closed(client: &Http3Client, expected: Error) {
         match client.state() {
             Http3State::Closing(err) | Http3State::Closed(err) => {
-                assert_eq!(err, CloseError::Application(expected.code()))
+                assert_eq!(err, ConnectionError::Application(expected.code()))
             }
             _ => panic!("Wrong state {:?}", client.state()),
         };
@@ -583,7 +1047,11 @@ mod tests {
     // Perform Quic transport handshake and exchange Http3 settings.
     fn connect_with(client: &mut Http3Client, server: &mut TestServer) {
         connect_only_transport_with(client, server);
+        exchange_settings(client, server);
+    }
 
+    // Exchange Http3 settings after the Quic transport handshake is done.
+    fn exchange_settings(client: &mut Http3Client, server: &mut TestServer) {
         // send and receive client settings
         let out = client.process(None, now());
         server.conn.process(out.dgram(), now());
@@ -725,6 +1193,32 @@ mod tests {
         0x43, 0xd3, 0xc1,
     ];
 
+    #[test]
+    fn test_encode_request() {
+        assert_eq!(
+            Http3Client::encode_request("GET", "https", "something.com", "/", &[]),
+            EXPECTED_REQUEST_HEADER_FRAME
+        );
+    }
+
+    #[test]
+    fn test_new_with_params_table_size_too_large() {
+        fixture_init();
+        let res = Http3Client::new_with_params(
+            DEFAULT_SERVER_NAME,
+            DEFAULT_ALPN,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(3))),
+            loopback(),
+            loopback(),
+            Http3Parameters {
+                max_table_size: 1 << 30,
+                max_blocked_streams: 100,
+                ..Http3Parameters::default()
+            },
+        );
+        assert!(matches!(res, Err(Error::InvalidInput)));
+    }
+
     const HTTP_RESPONSE_1: &[u8] = &[
         // headers
         0x01, 0x06, 0x00, 0x00, 0xd9, 0x54, 0x01, 0x37, // the first data frame
@@ -812,90 +1306,514 @@ mod tests {
         let _ = connect();
     }
 
-    // Client: Test that the connection will be closed if control stream
-    // has been closed.
-    #[test]
-    fn test_client_close_control_stream() {
-        let (mut client, mut server) = connect();
-        server
-            .conn
-            .stream_close_send(server.control_stream_id.unwrap())
-            .unwrap();
-        let out = server.conn.process(None, now());
-        client.process(out.dgram(), now());
-        assert_closed(&client, Error::HttpClosedCriticalStream);
+    fn default_http3_parameters() -> Http3Parameters {
+        Http3Parameters {
+            max_table_size: 100,
+            max_blocked_streams: 100,
+            max_header_list_size: None,
+            qpack_dynamic_table: true,
+        }
     }
 
-    // Client: test missing SETTINGS frame
-    // (the first frame sent is a garbage frame).
+    // Client: the application starts the handshake on a transport connection, e.g. to see
+    // which ALPN protocol was chosen, and then makes an HTTP/3 client on it.
     #[test]
-    fn test_client_missing_settings() {
-        let (mut client, mut server) = connect_only_transport();
-        // Create server control stream.
-        let control_stream = server.conn.stream_create(StreamType::UniDi).unwrap();
-        // Send a HEADERS frame instead (which contains garbage).
-        let sent = server
-            .conn
-            .stream_send(control_stream, &[0x0, 0x1, 0x3, 0x0, 0x1, 0x2]);
-        assert_eq!(sent, Ok(6));
-        let out = server.conn.process(None, now());
-        client.process(out.dgram(), now());
-        assert_closed(&client, Error::HttpMissingSettings);
-    }
+    fn test_client_on_transport_connection() {
+        let mut conn = default_client();
+        let mut server = make_default_server();
+        let out = conn.process(None, now());
+        let out = server.conn.process(out.dgram(), now());
+        let out = conn.process(out.dgram(), now());
+        let out = server.conn.process(out.dgram(), now());
+        let authentication_needed = |e| matches!(e, ConnectionEvent::AuthenticationNeeded);
+        assert!(conn.events().any(authentication_needed));
 
-    // Client: receiving SETTINGS frame twice causes connection close
-    // with error HTTP_UNEXPECTED_FRAME.
-    #[test]
-    fn test_client_receive_settings_twice() {
-        let (mut client, mut server) = connect();
-        // send the second SETTINGS frame.
-        let sent = server.conn.stream_send(
-            server.control_stream_id.unwrap(),
-            &[0x4, 0x6, 0x1, 0x40, 0x64, 0x7, 0x40, 0x64],
+        let mut client =
+            Http3Client::new_with_conn_and_params(conn, default_http3_parameters()).unwrap();
+        client.authenticated(AuthenticationStatus::Ok, now());
+        let out = client.process(out.dgram(), now());
+        assert_eq!(client.state(), Http3State::Connected);
+        let _ = server.conn.process(out.dgram(), now());
+        exchange_settings(&mut client, &mut server);
+        assert!(client.peer_settings().is_some());
+
+        // A connection that is already connected can't be used.
+        let (conn, _server) = test_fixture::connect();
+        assert_eq!(
+            Http3Client::new_with_conn_and_params(conn, default_http3_parameters()).err(),
+            Some(Error::Unexpected)
         );
-        assert_eq!(sent, Ok(8));
-        let out = server.conn.process(None, now());
-        client.process(out.dgram(), now());
-        assert_closed(&client, Error::HttpFrameUnexpected);
     }
 
-    fn test_wrong_frame_on_control_stream(v: &[u8]) {
+    // Client: the headers of a request can be read back while the request is active.
+    #[test]
+    fn test_client_sent_headers() {
         let (mut client, mut server) = connect();
+        let request_stream_id = client
+            .fetch(
+                "GET",
+                "https",
+                "something.com",
+                "/",
+                &[(String::from("x-trace"), String::from("1"))],
+            )
+            .unwrap();
+        let _ = client.stream_close_send(StreamId(request_stream_id));
+        assert_eq!(
+            client.get_sent_headers(StreamId(request_stream_id)),
+            Some(
+                &[
+                    (String::from(":method"), String::from("GET")),
+                    (String::from(":scheme"), String::from("https")),
+                    (String::from(":authority"), String::from("something.com")),
+                    (String::from(":path"), String::from("/")),
+                    (String::from("x-trace"), String::from("1")),
+                ][..]
+            )
+        );
+        assert_eq!(client.get_sent_headers(StreamId(4)), None);
 
-        // send a frame that is not allowed on the control stream.
-        let _ = server
-            .conn
-            .stream_send(server.control_stream_id.unwrap(), v);
-
-        let out = server.conn.process(None, now());
-        client.process(out.dgram(), now());
-
-        assert_closed(&client, Error::HttpFrameUnexpected);
+        let out = client.process(None, now());
+        server.conn.process(out.dgram(), now());
+        let _ = server.conn.stream_send(request_stream_id, HTTP_RESPONSE_2);
+        server.conn.stream_close_send(request_stream_id).unwrap();
+        read_response(&mut client, &mut server.conn, request_stream_id);
+        assert_eq!(client.get_sent_headers(StreamId(request_stream_id)), None);
     }
 
-    // send DATA frame on a cortrol stream
+    // Client: a client that is dropped while connected warns that it was not closed.
     #[test]
-    fn test_data_frame_on_control_stream() {
-        test_wrong_frame_on_control_stream(&[0x0, 0x2, 0x1, 0x2]);
+    fn test_client_needs_close() {
+        let mut client = default_http3_client();
+        assert!(!client.needs_close());
+        let (mut client2, _server) = connect();
+        assert!(client2.needs_close());
+        client2.close(now(), 0, "");
+        assert!(!client2.needs_close());
+        client.close(now(), 0, "");
+        assert!(!client.needs_close());
     }
 
-    // send HEADERS frame on a cortrol stream
+    // Client: Test that the server's SETTINGS are reported once they are received.
     #[test]
-    fn test_headers_frame_on_control_stream() {
-        test_wrong_frame_on_control_stream(&[0x1, 0x2, 0x1, 0x2]);
-    }
+    fn test_client_peer_settings() {
+        let (mut client, mut server) = connect_only_transport();
+        assert_eq!(client.peer_settings(), None);
 
-    // send PUSH_PROMISE frame on a cortrol stream
-    #[test]
-    fn test_push_promise_frame_on_control_stream() {
-        test_wrong_frame_on_control_stream(&[0x5, 0x2, 0x1, 0x2]);
+        exchange_settings(&mut client, &mut server);
+        assert_eq!(
+            client.peer_settings(),
+            Some(PeerSettings {
+                max_table_capacity: 100,
+                blocked_streams: 100,
+                max_header_list_size: 10000,
+            })
+        );
     }
 
-    // send DUPLICATE_PUSH frame on a cortrol stream
-    #[test]
-    fn test_duplicate_push_frame_on_control_stream() {
-        test_wrong_frame_on_control_stream(&[0xe, 0x2, 0x1, 0x2]);
-    }
+    // Connect the transport of a client that waits for SETTINGS, without sending the
+    // server's SETTINGS.
+    fn connect_waiting_for_settings() -> (Http3Client, TestServer) {
+        let mut client = default_http3_client();
+        client.set_wait_for_settings(true);
+        let mut server = make_default_server();
+
+        let out = client.process(None, now());
+        let out = server.conn.process(out.dgram(), now());
+        let out = client.process(out.dgram(), now());
+        let out = server.conn.process(out.dgram(), now());
+        assert!(out.as_dgram_ref().is_none());
+        client.authenticated(AuthenticationStatus::Ok, now());
+        let out = client.process(out.dgram(), now());
+        let _ = server.conn.process(out.dgram(), now());
+        assert_eq!(*server.conn.state(), State::Connected);
+        assert!(client.base_handler.settings_pending());
+        (client, server)
+    }
+
+    // Client: Test that with wait_for_settings the Connected state is only reached
+    // after the server's SETTINGS frame has been received.
+    #[test]
+    fn test_client_wait_for_settings() {
+        let (mut client, mut server) = connect_waiting_for_settings();
+
+        // The transport is connected, but the SETTINGS frame has not been received yet.
+        let connected = |e| matches!(e, Http3ClientEvent::StateChange(Http3State::Connected));
+        assert!(!client.events().any(connected));
+        assert_eq!(client.state(), Http3State::Initializing);
+        assert_eq!(
+            client.fetch("GET", "https", "something.com", "/", &[]),
+            Err(Error::Unavailable)
+        );
+
+        exchange_settings(&mut client, &mut server);
+        assert!(client.events().any(connected));
+    }
+
+    // Client: closing the connection ends the wait for SETTINGS.
+    #[test]
+    fn test_client_close_while_waiting_for_settings() {
+        let (mut client, _server) = connect_waiting_for_settings();
+        client.close(now(), 0, "");
+        assert!(matches!(client.state(), Http3State::Closing(_)));
+        assert!(!client.base_handler.settings_pending());
+    }
+
+    // Client: the server closing the connection ends the wait for SETTINGS.
+    #[test]
+    fn test_client_peer_close_while_waiting_for_settings() {
+        let (mut client, mut server) = connect_waiting_for_settings();
+        server.conn.close(now(), 0, "");
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        assert!(matches!(
+            client.state(),
+            Http3State::Closing(_) | Http3State::Closed(_)
+        ));
+        assert!(!client.base_handler.settings_pending());
+    }
+
+    // Client: log messages of the connection name its role and local connection ID.
+    #[test]
+    fn test_client_display() {
+        let mut client = default_http3_client();
+        assert_eq!(client.base_handler.to_string(), "Http3 connection");
+        let mut server = make_default_server();
+        connect_with(&mut client, &mut server);
+        assert_eq!(
+            client.base_handler.to_string(),
+            format!(
+                "Http3 connection Client {}",
+                hex(client.conn.local_cid().unwrap())
+            )
+        );
+    }
+
+    // Client: a request can't be sent before the connection is established.
+    #[test]
+    fn test_client_fetch_before_connect() {
+        let mut client = default_http3_client();
+        assert_eq!(
+            client.fetch("GET", "https", "something.com", "/", &[]),
+            Err(Error::Unavailable)
+        );
+        let mut server = make_default_server();
+        connect_with(&mut client, &mut server);
+        assert!(client
+            .fetch("GET", "https", "something.com", "/", &[])
+            .is_ok());
+    }
+
+    // Client: a request whose header list is larger than the server's
+    // SETTINGS_MAX_HEADER_LIST_SIZE is refused and nothing is sent.
+    #[test]
+    fn test_client_fetch_exceeds_max_header_list_size() {
+        let mut client = default_http3_client();
+        let mut server = make_server(&[HSetting::new(HSettingType::MaxHeaderListSize, 200)]);
+        connect_with(&mut client, &mut server);
+
+        // The pseudo-headers alone add up to 179 bytes.
+        let headers = vec![(String::from("myheaders"), String::from("myvalue"))];
+        assert_eq!(
+            client.fetch("GET", "https", "something.com", "/", &headers),
+            Err(Error::HeaderListTooLarge)
+        );
+        let out = client.process(None, now());
+        server.conn.process(out.dgram(), now());
+        assert!(!server
+            .conn
+            .events()
+            .any(|e| matches!(e, ConnectionEvent::NewStream { .. })));
+
+        // A request within the limit can still be sent.
+        assert!(client
+            .fetch("GET", "https", "something.com", "/", &[])
+            .is_ok());
+        assert_eq!(client.state(), Http3State::Connected);
+    }
+
+    // Client: process_http3 reports whether it had work to do, e.g. a received response.
+    #[test]
+    fn test_client_process_http3_progress() {
+        let (mut client, mut server) = connect();
+        let _ = client.events();
+        assert!(!client.process_http3(now()));
+
+        let request_stream_id = make_request(&mut client, true);
+        let out = client.process(None, now());
+        server.conn.process(out.dgram(), now());
+        let _ = server.conn.stream_send(request_stream_id, HTTP_RESPONSE_1);
+        let out = server.conn.process(None, now());
+        client.process_input(out.dgram().unwrap(), now());
+        assert!(client.process_http3(now()));
+        assert!(client.has_events());
+        assert!(!client.process_http3(now()));
+    }
+
+    // A request that exceeds the peer's stream limit fails until the peer allows another one.
+    #[test]
+    fn test_client_fetch_stream_limit() {
+        let (mut client, mut server) = connect();
+        let mut request_stream_ids = Vec::new();
+        loop {
+            match client.fetch("GET", "https", "something.com", "/", &[]) {
+                Ok(stream_id) => {
+                    let _ = client.stream_close_send(StreamId(stream_id));
+                    request_stream_ids.push(stream_id);
+                }
+                Err(e) => {
+                    assert_eq!(e, Error::StreamLimit);
+                    break;
+                }
+            }
+        }
+        assert!(!request_stream_ids.is_empty());
+        let _ = client.events();
+
+        let out = client.process(None, now());
+        server.conn.process(out.dgram(), now());
+
+        // The server reads the first request, which lets the client open another stream.
+        let mut buf = [0u8; 100];
+        let (_, fin) = server
+            .conn
+            .stream_recv(request_stream_ids[0], &mut buf)
+            .unwrap();
+        assert!(fin);
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        assert!(client
+            .events()
+            .any(|e| e == Http3ClientEvent::RequestsCreatable));
+        assert!(client
+            .fetch("GET", "https", "something.com", "/", &[])
+            .is_ok());
+    }
+
+    // Client: the connection is closed when the peer is silent for longer than the idle timeout.
+    #[test]
+    fn test_client_idle_timeout() {
+        let (mut client, _server) = connect();
+        let _ = client.events();
+
+        let later = now() + Duration::from_secs(60);
+        client.process_timer(later);
+        client.process_http3(later);
+
+        let idle_timeout = ConnectionError::Transport(TransportError::IdleTimeout);
+        let closed = |e| {
+            matches!(e, Http3ClientEvent::StateChange(Http3State::Closed(ref err))
+                if *err == idle_timeout)
+        };
+        assert!(client.events().any(closed));
+        assert_eq!(client.state(), Http3State::Closed(idle_timeout));
+    }
+
+    #[test]
+    fn test_client_congestion_control() {
+        let mut client = default_http3_client();
+        client
+            .set_congestion_control(CongestionControlAlgorithm::NewReno)
+            .unwrap();
+        let mut server = make_default_server();
+        connect_with(&mut client, &mut server);
+        assert_eq!(client.congestion_control(), "newreno");
+        assert_eq!(
+            client.set_congestion_control(CongestionControlAlgorithm::NewReno),
+            Err(Error::TransportError(TransportError::ConnectionState))
+        );
+    }
+
+    // Client: Test that the connection will be closed if control stream
+    // has been closed.
+    #[test]
+    fn test_client_close_control_stream() {
+        let (mut client, mut server) = connect();
+        server
+            .conn
+            .stream_close_send(server.control_stream_id.unwrap())
+            .unwrap();
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        assert_closed(&client, Error::HttpClosedCriticalStream);
+    }
+
+    // Client: test that the connection will be closed if the server's QPACK encoder stream
+    // (stream 7) or decoder stream (stream 11) has been closed.
+    fn test_client_close_qpack_stream(stream_id: u64) {
+        let (mut client, mut server) = connect();
+        server.conn.stream_close_send(stream_id).unwrap();
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        assert_closed(&client, Error::HttpClosedCriticalStream);
+    }
+
+    #[test]
+    fn test_client_close_qpack_encoder_stream() {
+        test_client_close_qpack_stream(7);
+    }
+
+    #[test]
+    fn test_client_close_qpack_decoder_stream() {
+        test_client_close_qpack_stream(11);
+    }
+
+    // Client: test missing SETTINGS frame
+    // (the first frame sent is a garbage frame).
+    #[test]
+    fn test_client_missing_settings() {
+        let (mut client, mut server) = connect_only_transport();
+        // Create server control stream.
+        let control_stream = server.conn.stream_create(StreamType::UniDi).unwrap();
+        // Send a HEADERS frame instead (which contains garbage).
+        let sent = server
+            .conn
+            .stream_send(control_stream, &[0x0, 0x1, 0x3, 0x0, 0x1, 0x2]);
+        assert_eq!(sent, Ok(6));
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        assert_closed(&client, Error::HttpMissingSettings);
+    }
+
+    // Client: receiving SETTINGS frame twice causes connection close
+    // with error HTTP_UNEXPECTED_FRAME.
+    #[test]
+    fn test_client_receive_settings_twice() {
+        let (mut client, mut server) = connect();
+        // send the second SETTINGS frame.
+        let sent = server.conn.stream_send(
+            server.control_stream_id.unwrap(),
+            &[0x4, 0x6, 0x1, 0x40, 0x64, 0x7, 0x40, 0x64],
+        );
+        assert_eq!(sent, Ok(8));
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        assert_closed(&client, Error::HttpFrameUnexpected);
+    }
+
+    // Client: a control frame with a length that is too large to be buffered closes the
+    // connection with H3_EXCESSIVE_LOAD.
+    #[test]
+    fn test_client_receive_huge_control_frame() {
+        let (mut client, mut server) = connect();
+        // A GOAWAY frame with a length of 2^62-1.
+        let _ = server.conn.stream_send(
+            server.control_stream_id.unwrap(),
+            &[0x7, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x0],
+        );
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        assert_closed(&client, Error::HttpExcessiveLoad);
+    }
+
+    fn test_wrong_frame_on_control_stream(v: &[u8]) {
+        let (mut client, mut server) = connect();
+
+        // send a frame that is not allowed on the control stream.
+        let _ = server
+            .conn
+            .stream_send(server.control_stream_id.unwrap(), v);
+
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        assert_closed(&client, Error::HttpFrameUnexpected);
+    }
+
+    // send DATA frame on a cortrol stream
+    #[test]
+    fn test_data_frame_on_control_stream() {
+        test_wrong_frame_on_control_stream(&[0x0, 0x2, 0x1, 0x2]);
+    }
+
+    // send HEADERS frame on a cortrol stream
+    #[test]
+    fn test_headers_frame_on_control_stream() {
+        test_wrong_frame_on_control_stream(&[0x1, 0x2, 0x1, 0x2]);
+    }
+
+    // send PUSH_PROMISE frame on a cortrol stream
+    #[test]
+    fn test_push_promise_frame_on_control_stream() {
+        test_wrong_frame_on_control_stream(&[0x5, 0x2, 0x1, 0x2]);
+    }
+
+    // send DUPLICATE_PUSH frame on a cortrol stream
+    #[test]
+    fn test_duplicate_push_frame_on_control_stream() {
+        test_wrong_frame_on_control_stream(&[0xe, 0x2, 0x1, 0x2]);
+    }
+
+    // Client: receiving PRIORITY_UPDATE, which only a client may send, causes connection close
+    // with error HTTP_UNEXPECTED_FRAME.
+    #[test]
+    fn test_priority_update_frame_on_control_stream() {
+        test_wrong_frame_on_control_stream(&[0x80, 0x0f, 0x07, 0x00, 0x4, 0x0, 0x75, 0x3d, 0x31]);
+    }
+
+    // send a PRIORITY frame (type 0x2) on a control stream. We do not implement it, it is
+    // ignored and counted in the statistics.
+    #[test]
+    fn test_ignored_frame_on_control_stream() {
+        let (mut client, mut server) = connect();
+        assert!(client.stats().ignored_frames.is_empty());
+
+        let _ = server
+            .conn
+            .stream_send(server.control_stream_id.unwrap(), &[0x2, 0x2, 0x1, 0x2]);
+
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        assert_eq!(client.state(), Http3State::Connected);
+        assert_eq!(client.stats().ignored_frames.get(&0x2), Some(&1));
+    }
+
+    // Client: a setting value larger than the QPACK encoder supports closes the
+    // connection with HttpSettingsError.
+    fn test_client_settings_out_of_range(setting: HSetting) {
+        let mut client = default_http3_client();
+        let mut server = make_server(&[setting]);
+        connect_only_transport_with(&mut client, &mut server);
+
+        let control_stream = server.conn.stream_create(StreamType::UniDi).unwrap();
+        let mut enc = Encoder::default();
+        server.settings.encode(&mut enc);
+        let _ = server.conn.stream_send(control_stream, CONTROL_STREAM_TYPE);
+        let _ = server.conn.stream_send(control_stream, &enc[..]);
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        assert_closed(&client, Error::HttpSettingsError);
+    }
+
+    #[test]
+    fn test_client_settings_max_table_capacity_too_large() {
+        test_client_settings_out_of_range(HSetting::new(HSettingType::MaxTableCapacity, 1 << 31));
+    }
+
+    #[test]
+    fn test_client_settings_blocked_streams_too_large() {
+        test_client_settings_out_of_range(HSetting::new(HSettingType::BlockedStreams, 1 << 16));
+    }
+
+    // Client: a SETTINGS frame with the same setting twice closes the connection
+    // with HttpSettingsError.
+    #[test]
+    fn test_client_duplicate_setting() {
+        let (mut client, mut server) = connect_only_transport();
+        let control_stream = server.conn.stream_create(StreamType::UniDi).unwrap();
+        let _ = server.conn.stream_send(control_stream, CONTROL_STREAM_TYPE);
+        // SETTINGS frame with MaxTableCapacity twice.
+        let sent = server
+            .conn
+            .stream_send(control_stream, &[0x4, 0x5, 0x1, 0x40, 0x64, 0x1, 0x0]);
+        assert_eq!(sent, Ok(7));
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        assert_closed(&client, Error::HttpSettingsError);
+    }
 
     // Client: receive unknown stream type
     // This function also tests getting stream id that does not fit into a single byte.
@@ -944,6 +1862,50 @@ mod tests {
         assert_closed(&client, Error::HttpIdError);
     }
 
+    // Client: a stream of a reserved type is read and discarded instead of being stopped,
+    // even if its type arrives in two packets.
+    #[test]
+    fn test_client_received_reserved_stream_type() {
+        let (mut client, mut server) = connect();
+
+        // 0x5f = 0x1f * 2 + 0x21 is encoded as a 2-byte varint.
+        let stream_id = server.conn.stream_create(StreamType::UniDi).unwrap();
+        let _ = server.conn.stream_send(stream_id, &[0x40]);
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        let _ = server
+            .conn
+            .stream_send(stream_id, &[0x5f, 0x61, 0x62, 0x63]);
+        let out = server.conn.process(None, now());
+        let out = client.process(out.dgram(), now());
+        server.conn.process(out.dgram(), now());
+
+        assert_eq!(client.state(), Http3State::Connected);
+        let stop_sending = |e| matches!(e, ConnectionEvent::SendStreamStopSending { .. });
+        assert!(!server.conn.events().any(stop_sending));
+
+        server.conn.stream_close_send(stream_id).unwrap();
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        assert_eq!(client.state(), Http3State::Connected);
+    }
+
+    // Client: a second control stream is a connection error.
+    #[test]
+    fn test_client_received_second_control_stream() {
+        let (mut client, mut server) = connect();
+
+        let control_stream_id = server.conn.stream_create(StreamType::UniDi).unwrap();
+        let _ = server
+            .conn
+            .stream_send(control_stream_id, CONTROL_STREAM_TYPE);
+        let out = server.conn.process(None, now());
+        let out = client.process(out.dgram(), now());
+        server.conn.process(out.dgram(), now());
+
+        assert_closed(&client, Error::HttpStreamCreationError);
+    }
+
     // Test wrong frame on req/rec stream
     fn test_wrong_frame_on_request_stream(v: &[u8]) {
         let (mut client, mut server, request_stream_id) = connect_and_send_request(false);
@@ -958,6 +1920,28 @@ mod tests {
         assert_closed(&client, Error::HttpFrameUnexpected);
     }
 
+    // A response must start with a HEADERS frame, a leading DATA frame resets the stream.
+    #[test]
+    fn test_data_frame_first_on_request_stream() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
+
+        let _ = server
+            .conn
+            .stream_send(request_stream_id, &[0x0, 0x3, 0x61, 0x62, 0x63]);
+        let out = server.conn.process(None, now());
+        let out = client.process(out.dgram(), now());
+
+        assert_stream_reset(&mut client, request_stream_id, Error::HttpFrameUnexpected);
+
+        // The server sees the stream reset.
+        server.conn.process(out.dgram(), now());
+        let reset = |e| {
+            matches!(e, ConnectionEvent::RecvStreamReset { stream_id, app_error }
+                if stream_id == request_stream_id && app_error == Error::HttpFrameUnexpected.code())
+        };
+        assert!(server.conn.events().any(reset));
+    }
+
     #[test]
     fn test_cancel_push_frame_on_request_stream() {
         test_wrong_frame_on_request_stream(&[0x3, 0x1, 0x5]);
@@ -978,6 +1962,58 @@ mod tests {
         test_wrong_frame_on_request_stream(&[0xd, 0x1, 0x5]);
     }
 
+    // The client does not send MAX_PUSH_ID, so a DUPLICATE_PUSH on a request stream refers to a
+    // push ID that the server may not use.
+    #[test]
+    fn test_duplicate_push_frame_on_request_stream() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(false);
+
+        let _ = server.conn.stream_send(request_stream_id, &[0xe, 0x1, 0x0]);
+        let out = server.conn.process(None, now());
+        let _ = client.process(out.dgram(), now());
+
+        assert_closed(&client, Error::HttpIdError);
+    }
+
+    // A DUPLICATE_PUSH may also follow the response headers.
+    #[test]
+    fn test_duplicate_push_frame_after_headers() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(false);
+
+        let _ = server.conn.stream_send(request_stream_id, HTTP_RESPONSE_1);
+        let _ = server.conn.stream_send(request_stream_id, &[0xe, 0x1, 0x0]);
+        let out = server.conn.process(None, now());
+        let _ = client.process(out.dgram(), now());
+
+        assert_closed(&client, Error::HttpIdError);
+    }
+
+    // A HEADERS frame on a response stream is received one byte at a time. The headers are
+    // decoded once the whole frame has arrived and HeaderReady is posted only once.
+    #[test]
+    fn test_response_headers_frame_reading() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
+
+        let mut header_ready = 0;
+        for b in HTTP_RESPONSE_HEADER_ONLY_2 {
+            let _ = server.conn.stream_send(request_stream_id, &[*b]);
+            let out = server.conn.process(None, now());
+            let out = client.process(out.dgram(), now());
+            server.conn.process(out.dgram(), now());
+            header_ready += client
+                .events()
+                .filter(|e| matches!(e, Http3ClientEvent::HeaderReady { .. }))
+                .count();
+        }
+        assert_eq!(header_ready, 1);
+
+        let (h, fin) = client
+            .read_response_headers(StreamId(request_stream_id))
+            .unwrap();
+        check_response_header_2(h);
+        assert_eq!(fin, false);
+    }
+
     // Test reading of a slowly streamed frame. bytes are received one by one
     #[test]
     fn test_frame_reading() {
@@ -1088,6 +2124,8 @@ mod tests {
                     let (h, fin) = client.read_response_headers(StreamId(stream_id)).unwrap();
                     check_response_header_1(h);
                     assert_eq!(fin, false);
+                    // HTTP_RESPONSE_1 only refers to the static table.
+                    assert!(!client.headers_used_dynamic_table(StreamId(stream_id)));
                 }
                 Http3ClientEvent::DataReadable { stream_id } => {
                     assert_eq!(stream_id, request_stream_id);
@@ -1128,9 +2166,67 @@ mod tests {
         let res = client.read_response_data(now(), StreamId(request_stream_id), &mut buf);
         assert_eq!(res.unwrap_err(), Error::InvalidStreamId);
 
+        let stats = client.stats();
+        assert_eq!(stats.requests, 1);
+        assert!(stats.header_bytes_encoded > 0);
+        assert_eq!(stats.data_bytes_sent, 0);
+        assert_eq!(
+            stats.data_bytes_received,
+            (EXPECTED_RESPONSE_DATA_1_FRAME_1.len() + EXPECTED_RESPONSE_DATA_1_FRAME_2.len())
+                as u64
+        );
+        assert!(stats.packets_tx > 0);
+        assert!(stats.packets_rx > 0);
+        assert!(stats.bytes_tx > 0);
+        assert!(stats.bytes_rx > 0);
+        assert!(stats.congestion_window > 0);
+        // Nothing was lost.
+        assert_eq!(stats.slow_start_threshold, usize::max_value());
+
         client.close(now(), 0, "");
     }
 
+    #[cfg(feature = "frame-trace")]
+    struct TestTracer(Rc<RefCell<Vec<String>>>);
+
+    #[cfg(feature = "frame-trace")]
+    impl FrameTracer for TestTracer {
+        fn frame(&mut self, event: &crate::frame_trace::FrameEvent) {
+            self.0.borrow_mut().push(event.to_string());
+        }
+    }
+
+    // Client: the frames of the control and request streams go to the frame tracer.
+    #[cfg(feature = "frame-trace")]
+    #[test]
+    fn frame_trace() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut client = default_http3_client();
+        client.set_frame_tracer(Box::new(TestTracer(log.clone())));
+        let mut server = make_default_server();
+        connect_with(&mut client, &mut server);
+
+        let request_stream_id = make_request(&mut client, true);
+        let out = client.process(None, now());
+        server.conn.process(out.dgram(), now());
+
+        let _ = server.conn.stream_send(request_stream_id, HTTP_RESPONSE_1);
+        server.conn.stream_close_send(request_stream_id).unwrap();
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        let mut buf = [0u8; 100];
+        let _ = client.read_response_headers(StreamId(request_stream_id));
+        let _ = client.read_response_data(now(), StreamId(request_stream_id), &mut buf);
+
+        let log = log.borrow();
+        let has = |prefix: &str| log.iter().any(|l| l.starts_with(prefix));
+        assert!(has("> stream 2 SETTINGS len=6 "));
+        assert!(has("< stream 3 SETTINGS len=6 "));
+        assert!(has("> stream 0 HEADERS len=16"));
+        assert!(has("< stream 0 HEADERS len=6"));
+        assert!(has("< stream 0 DATA len=3"));
+    }
+
     // Helper function: read response when a server sends HTTP_RESPONSE_2.
     fn read_response(client: &mut Http3Client, server: &mut Connection, request_stream_id: u64) {
         let out = server.process(None, now());
@@ -1168,6 +2264,29 @@ mod tests {
         client.close(now(), 0, "");
     }
 
+    // Reset the http3 state of a connected client and check that a new request still works.
+    #[test]
+    fn test_reset_http3_state_then_fetch() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
+
+        client.reset_http3_state().unwrap();
+        assert!(!client.has_events());
+        // The old request is gone.
+        let mut buf = [0u8; 100];
+        assert_eq!(
+            client.read_response_data(now(), StreamId(request_stream_id), &mut buf),
+            Err(Error::InvalidStreamId)
+        );
+
+        let request_stream_id = make_request(&mut client, true);
+        let out = client.process(None, now());
+        server.conn.process(out.dgram(), now());
+
+        let _ = server.conn.stream_send(request_stream_id, HTTP_RESPONSE_2);
+        server.conn.stream_close_send(request_stream_id).unwrap();
+        read_response(&mut client, &mut server.conn, request_stream_id);
+    }
+
     // Data sent with a request:
     const REQUEST_BODY: &[u8] = &[0x64, 0x65, 0x66];
     // Corresponding data frame that server will receive.
@@ -1296,10 +2415,128 @@ mod tests {
         fetch_with_data_length_xbytes(&[0u8; 16383], &[0x0, 0x7f, 0xff]);
     }
 
-    // send a request with 16384 bytes. The DATA frame length field will need 4 byte.
+    // send a request with 16384 bytes. The DATA frame length field will need 4 byte.
+    #[test]
+    fn fetch_with_data_length_16384bytes() {
+        fetch_with_data_length_xbytes(&[0u8; 16384], &[0x0, 0x80, 0x0, 0x40, 0x0]);
+    }
+
+    // Requests with a higher weight are sent first.
+    #[test]
+    fn fetch_priority_orders_sending() {
+        let (mut client, _server) = connect();
+        let first = client
+            .fetch("GET", "https", "something.com", "/", &[])
+            .unwrap();
+        let second = client
+            .fetch("GET", "https", "something.com", "/", &[])
+            .unwrap();
+        let third = client
+            .fetch("GET", "https", "something.com", "/", &[])
+            .unwrap();
+        assert_eq!(
+            client.base_handler.streams_in_send_order(),
+            vec![first, second, third]
+        );
+
+        assert_eq!(client.priority(StreamId(third), 200), Ok(()));
+        assert_eq!(client.priority(StreamId(first), 1), Ok(()));
+        assert_eq!(
+            client.base_handler.streams_in_send_order(),
+            vec![third, second, first]
+        );
+
+        assert_eq!(
+            client.priority(StreamId(third + 4), 200),
+            Err(Error::InvalidStreamId)
+        );
+
+        let _ = client.process(None, now());
+        assert!(client.base_handler.streams_in_send_order().is_empty());
+    }
+
+    // Requests with a lower urgency are sent first and the new priority is sent to the server.
+    #[test]
+    fn fetch_priority_update() {
+        let (mut client, mut server) = connect();
+        let first = client
+            .fetch("GET", "https", "something.com", "/", &[])
+            .unwrap();
+        let second = client
+            .fetch("GET", "https", "something.com", "/", &[])
+            .unwrap();
+        let third = client
+            .fetch("GET", "https", "something.com", "/", &[])
+            .unwrap();
+
+        assert_eq!(client.priority_update(StreamId(third), 0, true), Ok(()));
+        assert_eq!(client.priority_update(StreamId(first), 5, false), Ok(()));
+        // The urgency takes precedence over the weight.
+        assert_eq!(client.priority(StreamId(first), 200), Ok(()));
+        assert_eq!(
+            client.base_handler.streams_in_send_order(),
+            vec![third, second, first]
+        );
+
+        assert_eq!(
+            client.priority_update(StreamId(second), 8, false),
+            Err(Error::InvalidInput)
+        );
+        assert_eq!(
+            client.priority_update(StreamId(third + 4), 1, false),
+            Err(Error::InvalidStreamId)
+        );
+
+        let out = client.process(None, now());
+        server.conn.process(out.dgram(), now());
+        // The PRIORITY_UPDATE frames arrive on the client's control stream.
+        read_and_check_stream_data(
+            &mut server.conn,
+            StreamId(2),
+            &[
+                0x80, 0x0f, 0x07, 0x00, 0x07, 0x08, 0x75, 0x3d, 0x30, 0x2c, 0x20, 0x69, 0x80, 0x0f,
+                0x07, 0x00, 0x04, 0x00, 0x75, 0x3d, 0x35,
+            ],
+            false,
+        );
+    }
+
+    // Use up the flow control credit of the request stream. DataWritable must be posted again
+    // once the server has read the data and sent a MAX_STREAM_DATA update.
     #[test]
-    fn fetch_with_data_length_16384bytes() {
-        fetch_with_data_length_xbytes(&[0u8; 16384], &[0x0, 0x80, 0x0, 0x40, 0x0]);
+    fn fetch_data_writable_after_flow_control_update() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(false);
+
+        let data_writable = |e| matches!(e, Http3ClientEvent::DataWritable { .. });
+        assert!(client.events().any(data_writable));
+
+        // Send as much as the stream accepts. After this the stream is blocked.
+        let sent = client
+            .send_request_body(StreamId(request_stream_id), &[0u8; 0xffff])
+            .unwrap();
+        assert!(sent > 0);
+        assert!(sent < 0xffff);
+        let sent = client.send_request_body(StreamId(request_stream_id), &[0u8; 10]);
+        assert_eq!(sent, Ok(0));
+
+        let mut out = client.process(None, now());
+        let mut writable = false;
+        for _i in 0..55 {
+            out = server.conn.process(out.dgram(), now());
+            // Consume the data so that the server gives more credit to the client.
+            let mut buf = [0u8; 0xffff];
+            let _ = server.conn.stream_recv(request_stream_id, &mut buf);
+            out = client.process(out.dgram(), now());
+            if client.events().any(data_writable) {
+                writable = true;
+                break;
+            }
+        }
+        assert!(writable);
+
+        // More body can be sent now.
+        let sent = client.send_request_body(StreamId(request_stream_id), &[0u8; 10]);
+        assert_eq!(sent, Ok(10));
     }
 
     // Send 2 data frames so that the second one cannot fit into the send_buf and it is only
@@ -1539,6 +2776,59 @@ mod tests {
         client.close(now(), 0, "");
     }
 
+    // The server stops the request body; send_side_reset reports its error code while the
+    // request is active.
+    #[test]
+    fn test_send_side_reset() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(false);
+        assert_eq!(client.send_side_reset(StreamId(request_stream_id)), None);
+
+        assert_eq!(
+            Ok(()),
+            server
+                .conn
+                .stream_stop_sending(request_stream_id, Error::HttpEarlyResponse.code())
+        );
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        assert_eq!(
+            client.send_side_reset(StreamId(request_stream_id)),
+            Some(Error::HttpEarlyResponse.code())
+        );
+        assert_eq!(
+            Err(Error::AlreadyClosed),
+            client.send_request_body(StreamId(request_stream_id), &[0u8; 10])
+        );
+
+        client.close(now(), 0, "");
+    }
+
+    // A STOP_SENDING with another error also resets the response, so the stream is removed
+    // and its error code is only reported in the Reset event.
+    #[test]
+    fn test_send_side_reset_removed_with_stream() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(false);
+
+        assert_eq!(
+            Ok(()),
+            server
+                .conn
+                .stream_stop_sending(request_stream_id, Error::HttpRequestRejected.code())
+        );
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        let reset = |e| {
+            matches!(e, Http3ClientEvent::Reset { stream_id, error }
+                if stream_id == request_stream_id && error == Error::HttpRequestRejected.code())
+        };
+        assert!(client.events().any(reset));
+        assert_eq!(client.send_side_reset(StreamId(request_stream_id)), None);
+
+        client.close(now(), 0, "");
+    }
+
     // Server sends stop sending and reset.
     #[test]
     fn test_stop_sending_other_error_with_reset() {
@@ -1844,11 +3134,194 @@ mod tests {
         test_incomplet_frame(&HTTP_RESPONSE_2[..7], Error::HttpFrameError);
     }
 
+    // Only the type of a reserved frame (0x1f * N + 0x21).
     #[test]
     fn test_incomplet_unknown_frame() {
         test_incomplet_frame(&[0x21], Error::HttpFrameError);
     }
 
+    // A reserved frame that ends before its payload does.
+    #[test]
+    fn test_incomplet_reserved_frame_payload() {
+        test_incomplet_frame(&[0x21, 0x3, 0xaa], Error::HttpFrameError);
+    }
+
+    // A complete reserved frame on a request stream is skipped.
+    #[test]
+    fn test_reserved_frame_before_response() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
+        let _ = server
+            .conn
+            .stream_send(request_stream_id, &[0x21, 0x3, 0xaa, 0xbb, 0xcc]);
+        let _ = server.conn.stream_send(request_stream_id, HTTP_RESPONSE_2);
+        server.conn.stream_close_send(request_stream_id).unwrap();
+        read_response(&mut client, &mut server.conn, request_stream_id);
+        assert_eq!(client.stats().ignored_frames.get(&0x21), Some(&1));
+    }
+
+    // Client: a frame that is not allowed on a request stream follows a DATA frame. It is read
+    // when the application reads the data, which fails with the cause and closes the connection.
+    #[test]
+    fn test_read_data_then_unexpected_frame() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
+        let _ = server.conn.stream_send(request_stream_id, HTTP_RESPONSE_2);
+        // A GOAWAY frame.
+        let _ = server.conn.stream_send(request_stream_id, &[0x7, 0x1, 0x5]);
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        let data_readable = |e| matches!(e, Http3ClientEvent::DataReadable { .. });
+        assert!(client.events().any(data_readable));
+        let mut buf = [0u8; 100];
+        assert_eq!(
+            client.read_response_data(now(), StreamId(request_stream_id), &mut buf),
+            Err(Error::HttpFrameUnexpected)
+        );
+        assert_closed(&client, Error::HttpFrameUnexpected);
+    }
+
+    // A frame that is not allowed on a request stream closes the connection and resets the
+    // request with the same error.
+    #[test]
+    fn test_unexpected_frame_on_request_stream() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
+        // A GOAWAY frame instead of the response headers.
+        let _ = server.conn.stream_send(request_stream_id, &[0x7, 0x1, 0x5]);
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        let reset = |e| {
+            e == Http3ClientEvent::Reset {
+                stream_id: request_stream_id,
+                error: Error::HttpFrameUnexpected.code(),
+            }
+        };
+        assert!(client.events().any(reset));
+        assert_closed(&client, Error::HttpFrameUnexpected);
+    }
+
+    #[test]
+    fn datagram() {
+        let mut client = default_http3_client();
+        let mut server = make_default_server();
+        client.set_max_datagram_frame_size(100).unwrap();
+        server.conn.set_max_datagram_frame_size(100).unwrap();
+        connect_with(&mut client, &mut server);
+
+        client.send_datagram(&[1, 2, 3]).unwrap();
+        let out = client.process(None, now());
+        server.conn.process(out.dgram(), now());
+        let datagram = |e| {
+            e == ConnectionEvent::Datagram {
+                data: vec![1, 2, 3],
+            }
+        };
+        assert!(server.conn.events().any(datagram));
+
+        // Datagrams are not coalesced like other events.
+        server.conn.send_datagram(&[4, 5]).unwrap();
+        server.conn.send_datagram(&[4, 5]).unwrap();
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        let datagram = |e: &Http3ClientEvent| *e == Http3ClientEvent::Datagram { data: vec![4, 5] };
+        assert_eq!(client.events().filter(datagram).count(), 2);
+    }
+
+    #[test]
+    fn datagram_not_allowed() {
+        let (mut client, _server) = connect();
+        assert_eq!(
+            client.send_datagram(&[1]),
+            Err(Error::TransportError(TransportError::TooMuchData))
+        );
+    }
+
+    // Frames queued on the control stream are sent before the connection is closed.
+    #[test]
+    fn test_close_graceful() {
+        let (mut client, mut server) = connect();
+        client
+            .base_handler
+            .queue_control_frame(HFrame::Goaway { stream_id: 4 });
+        client.close_graceful(now(), 0, "");
+
+        let out = client.process(None, now());
+        server.conn.process(out.dgram(), now());
+        assert_eq!(client.state(), Http3State::Connected);
+        // The GOAWAY frame arrives on the client's control stream.
+        read_and_check_stream_data(&mut server.conn, StreamId(2), &[0x7, 0x1, 0x4], false);
+
+        // The connection is closed once the server has acknowledged the GOAWAY.
+        let out = client.process(None, now());
+        assert!(out.as_dgram_ref().is_none());
+        assert_eq!(client.state(), Http3State::Connected);
+        let ack = server.conn.process(None, now() + ACK_DELAY).dgram();
+        let out = client.process(ack, now() + ACK_DELAY);
+        assert!(matches!(client.state(), Http3State::Closing(_)));
+        server.conn.process(out.dgram(), now() + ACK_DELAY);
+        assert!(matches!(server.conn.state(), State::Closed(_)));
+    }
+
+    // close_graceful does not close the connection while the congestion window holds back
+    // request body data.
+    #[test]
+    fn test_close_graceful_congestion_limited() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(false);
+        let sent = client
+            .send_request_body(StreamId(request_stream_id), &[0x61; 30_000])
+            .unwrap();
+        client.close_graceful(now(), 0, "");
+
+        let mut now = now();
+        let mut out = client.process(None, now);
+        let mut rounds = 0;
+        while client.state() == Http3State::Connected {
+            // Deliver what the client may send, then let the server acknowledge it.
+            while let Some(d) = out.dgram() {
+                server.conn.process_input(d, now);
+                out = client.process(None, now);
+            }
+            now += ACK_DELAY;
+            let ack = server.conn.process(None, now).dgram();
+            out = client.process(ack, now);
+            rounds += 1;
+            assert!(rounds < 100);
+        }
+        // The congestion window did not allow everything to be sent at once.
+        assert!(rounds > 1);
+
+        // All of the body arrived before the CONNECTION_CLOSE.
+        let mut buf = vec![0; 40_000];
+        let (amount, fin) = server
+            .conn
+            .stream_recv(request_stream_id, &mut buf)
+            .unwrap();
+        assert!(!fin);
+        let header_len = 1 + Encoder::varint_len(sent as u64);
+        assert_eq!(amount, header_len + sent);
+        assert!(buf[header_len..amount].iter().all(|&b| b == 0x61));
+
+        server.conn.process(out.dgram(), now);
+        assert!(matches!(server.conn.state(), State::Closed(_)));
+    }
+
+    // The peer sees the HTTP/3 error code as an application error.
+    #[test]
+    fn test_close_app_error() {
+        let (mut client, mut server) = connect();
+        client.close(now(), Error::HttpRequestRejected.code(), "rejected");
+        assert_closed(&client, Error::HttpRequestRejected);
+
+        let out = client.process(None, now());
+        server.conn.process(out.dgram(), now());
+        assert_eq!(
+            *server.conn.state(),
+            State::Closed(ConnectionError::Application(
+                Error::HttpRequestRejected.code()
+            ))
+        );
+    }
+
     // test goaway
     #[test]
     fn test_goaway() {
@@ -1859,6 +3332,7 @@ mod tests {
         assert_eq!(request_stream_id_2, 4);
         let request_stream_id_3 = make_request(&mut client, false);
         assert_eq!(request_stream_id_3, 8);
+        assert_eq!(client.active_streams(), vec![0, 4, 8]);
 
         let out = client.process(None, now());
         server.conn.process(out.dgram(), now());
@@ -1926,7 +3400,64 @@ mod tests {
 
         assert!(stream_reset);
         assert_eq!(client.state(), Http3State::GoingAway);
+        // The request that the server did not process is gone.
+        assert!(!client.is_active(StreamId(request_stream_id_3)));
+        client.close(now(), 0, "");
+    }
+
+    // A second GOAWAY may lower the stream ID, but not raise it.
+    #[test]
+    fn test_goaway_increasing_stream_id() {
+        let (mut client, mut server) = connect();
+        let control_stream_id = server.control_stream_id.unwrap();
+        let _ = server
+            .conn
+            .stream_send(control_stream_id, &[0x7, 0x1, 0x8, 0x7, 0x1, 0x4]);
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        assert_eq!(client.state(), Http3State::GoingAway);
+
+        let _ = server.conn.stream_send(control_stream_id, &[0x7, 0x1, 0x8]);
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        assert_closed(&client, Error::HttpIdError);
+    }
+
+    fn state_changes(client: &mut Http3Client) -> Vec<Http3State> {
+        client
+            .events()
+            .filter_map(|e| match e {
+                Http3ClientEvent::StateChange(state) => Some(state),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Client: the states that follow Connected, after a GOAWAY and while closing, are
+    // reported with StateChange events, so the application does not have to poll state().
+    #[test]
+    fn test_client_state_change_events() {
+        let (mut client, mut server) = connect();
+        let _ = client.events();
+
+        let _ = server
+            .conn
+            .stream_send(server.control_stream_id.unwrap(), &[0x7, 0x1, 0x0]);
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        assert_eq!(state_changes(&mut client), vec![Http3State::GoingAway]);
+
         client.close(now(), 0, "");
+        let error = ConnectionError::Application(0);
+        assert_eq!(
+            state_changes(&mut client),
+            vec![Http3State::Closing(error.clone())]
+        );
+
+        let later = now() + Duration::from_secs(60);
+        client.process_timer(later);
+        client.process_http3(later);
+        assert_eq!(state_changes(&mut client), vec![Http3State::Closed(error)]);
     }
 
     // Close stream before headers.
@@ -2093,6 +3624,54 @@ mod tests {
         );
     }
 
+    // An empty DATA frame before the DATA frame with the body is skipped; the app only gets
+    // a DataReadable event for the body.
+    #[test]
+    fn test_empty_data_frame_then_data_frame() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
+        let _ = server
+            .conn
+            .stream_send(request_stream_id, HTTP_RESPONSE_HEADER_ONLY_2);
+        let _ = server.conn.stream_send(request_stream_id, &[0x00, 0x00]);
+        let _ = server
+            .conn
+            .stream_send(request_stream_id, &[0x00, 0x03, 0x61, 0x62, 0x63]);
+        server.conn.stream_close_send(request_stream_id).unwrap();
+        read_response(&mut client, &mut server.conn, request_stream_id);
+    }
+
+    // An empty DATA frame that arrives on its own does not make the stream readable.
+    #[test]
+    fn test_empty_data_frame_arrives_alone() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
+        let _ = server
+            .conn
+            .stream_send(request_stream_id, HTTP_RESPONSE_HEADER_ONLY_2);
+        let _ = server.conn.stream_send(request_stream_id, &[0x00, 0x00]);
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        let header_ready = |e| matches!(e, Http3ClientEvent::HeaderReady { .. });
+        assert!(client.events().any(header_ready));
+        let data_readable = |e| matches!(e, Http3ClientEvent::DataReadable { .. });
+        assert!(!client.events().any(data_readable));
+
+        let _ = server
+            .conn
+            .stream_send(request_stream_id, &[0x00, 0x03, 0x61, 0x62, 0x63]);
+        server.conn.stream_close_send(request_stream_id).unwrap();
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        assert!(client.events().any(data_readable));
+        let mut buf = [0u8; 100];
+        let (amount, fin) = client
+            .read_response_data(now(), StreamId(request_stream_id), &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..amount], EXPECTED_RESPONSE_DATA_2_FRAME_1);
+        assert_eq!(fin, true);
+    }
+
     // Send headers and an empty data frame. Read headers and then close the stream.
     // We should get a HeaderReady without fin and a DataReadable wo data and with fin.
     #[test]
@@ -2185,31 +3764,75 @@ mod tests {
                     assert_eq!(fin, false);
                 }
                 _ => {}
-            };
-        }
-
-        // ok NOW send fin
-        server.conn.stream_close_send(request_stream_id).unwrap();
-        let out = server.conn.process(None, now());
-        client.process(out.dgram(), now());
-
-        // fin wo data should generate DataReadable
-        let e = client.events().next().unwrap();
-        if let Http3ClientEvent::DataReadable { stream_id } = e {
-            assert_eq!(stream_id, request_stream_id);
-            let mut buf = [0u8; 100];
-            let res = client.read_response_data(now(), StreamId(stream_id), &mut buf);
-            let (len, fin) = res.expect("should read");
-            assert_eq!(0, len);
-            assert_eq!(fin, true);
-        } else {
-            panic!("wrong event type");
+            };
+        }
+
+        // ok NOW send fin
+        server.conn.stream_close_send(request_stream_id).unwrap();
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        // fin wo data should generate DataReadable
+        let e = client.events().next().unwrap();
+        if let Http3ClientEvent::DataReadable { stream_id } = e {
+            assert_eq!(stream_id, request_stream_id);
+            let mut buf = [0u8; 100];
+            let res = client.read_response_data(now(), StreamId(stream_id), &mut buf);
+            let (len, fin) = res.expect("should read");
+            assert_eq!(0, len);
+            assert_eq!(fin, true);
+        } else {
+            panic!("wrong event type");
+        }
+
+        // Stream should now be closed and gone
+        let mut buf = [0u8; 100];
+        assert_eq!(
+            client.read_response_data(now(), StreamId(0), &mut buf),
+            Err(Error::InvalidStreamId)
+        );
+    }
+
+    // The response body exactly fills the application's buffer and the FIN follows in an
+    // empty DATA frame. The FIN must be reported by a subsequent read.
+    #[test]
+    fn test_stream_fin_in_empty_data_frame_after_full_buffer() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
+        let _ = server.conn.stream_send(request_stream_id, HTTP_RESPONSE_2);
+        let _ = server.conn.stream_send(request_stream_id, &[0x0, 0x0]);
+        server.conn.stream_close_send(request_stream_id).unwrap();
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        let mut data = Vec::new();
+        let mut fin_read = false;
+        while let Some(e) = client.next_event() {
+            match e {
+                Http3ClientEvent::HeaderReady { stream_id } => {
+                    let (h, fin) = client.read_response_headers(StreamId(stream_id)).unwrap();
+                    check_response_header_2(h);
+                    assert_eq!(fin, false);
+                }
+                Http3ClientEvent::DataReadable { stream_id } => {
+                    assert_eq!(stream_id, request_stream_id);
+                    assert!(!fin_read);
+                    let mut buf = [0u8; 3];
+                    let (len, fin) = client
+                        .read_response_data(now(), StreamId(stream_id), &mut buf)
+                        .unwrap();
+                    data.extend_from_slice(&buf[..len]);
+                    fin_read = fin;
+                }
+                _ => {}
+            }
         }
+        assert_eq!(&data[..], EXPECTED_RESPONSE_DATA_2_FRAME_1);
+        assert!(fin_read);
 
         // Stream should now be closed and gone
         let mut buf = [0u8; 100];
         assert_eq!(
-            client.read_response_data(now(), StreamId(0), &mut buf),
+            client.read_response_data(now(), StreamId(request_stream_id), &mut buf),
             Err(Error::InvalidStreamId)
         );
     }
@@ -2271,6 +3894,55 @@ mod tests {
         );
     }
 
+    // Test that DataReadable is posted again only while there is something to read.
+    #[test]
+    fn test_data_readable_after_partial_read() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
+
+        // Send the headers and a DATA frame of 5 bytes, but only 3 bytes of its payload.
+        let _ = server
+            .conn
+            .stream_send(request_stream_id, HTTP_RESPONSE_HEADER_ONLY_2);
+        let _ = server
+            .conn
+            .stream_send(request_stream_id, &[0x0, 0x5, 0x61, 0x62, 0x63]);
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        let data_readable = |e| matches!(e, Http3ClientEvent::DataReadable { .. });
+        assert!(client.events().any(data_readable));
+
+        // Read only 2 bytes, the third one is still buffered.
+        let mut buf = [0u8; 2];
+        let (amount, fin) = client
+            .read_response_data(now(), StreamId(request_stream_id), &mut buf)
+            .unwrap();
+        assert_eq!(amount, 2);
+        assert_eq!(fin, false);
+        assert!(client.events().any(data_readable));
+
+        // Read the last buffered byte, there is nothing more to read on the stream.
+        let (amount, fin) = client
+            .read_response_data(now(), StreamId(request_stream_id), &mut buf)
+            .unwrap();
+        assert_eq!(amount, 1);
+        assert_eq!(fin, false);
+        assert!(!client.events().any(data_readable));
+
+        // The rest of the frame arrives.
+        let _ = server.conn.stream_send(request_stream_id, &[0x64, 0x65]);
+        server.conn.stream_close_send(request_stream_id).unwrap();
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        assert!(client.events().any(data_readable));
+
+        let (amount, fin) = client
+            .read_response_data(now(), StreamId(request_stream_id), &mut buf)
+            .unwrap();
+        assert_eq!(amount, 2);
+        assert_eq!(fin, true);
+    }
+
     #[test]
     fn test_receive_grease_before_response() {
         let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
@@ -2317,6 +3989,379 @@ mod tests {
         );
     }
 
+    // Encode headers with the server's QPACK encoder and send them in a HEADERS frame.
+    fn send_headers_frame(server: &mut TestServer, stream_id: u64, headers: &[Header]) {
+        let encoded_headers = server.encoder.encode_header_block(headers, stream_id);
+        let hframe = HFrame::Headers {
+            len: encoded_headers.len() as u64,
+        };
+        let mut d = Encoder::default();
+        hframe.encode(&mut d);
+        d.encode(&encoded_headers);
+        let _ = server.conn.stream_send(stream_id, &d[..]);
+    }
+
+    const RESPONSE_HEADERS: &[(&str, &str)] = &[(":status", "200"), ("content-length", "3")];
+
+    fn to_headers(headers: &[(&str, &str)]) -> Vec<Header> {
+        headers
+            .iter()
+            .map(|(n, v)| (String::from(*n), String::from(*v)))
+            .collect()
+    }
+
+    #[test]
+    fn test_response_with_trailers() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
+
+        send_headers_frame(
+            &mut server,
+            request_stream_id,
+            &to_headers(RESPONSE_HEADERS),
+        );
+        let _ = server
+            .conn
+            .stream_send(request_stream_id, &[0x0, 0x3, 0x61, 0x62, 0x63]);
+        let trailers = to_headers(&[("my-trailer", "my-value")]);
+        send_headers_frame(&mut server, request_stream_id, &trailers);
+        server.conn.stream_close_send(request_stream_id).unwrap();
+
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        let header_ready = |e| matches!(e, Http3ClientEvent::HeaderReady { .. });
+        assert!(client.events().any(header_ready));
+        let (headers, fin) = client
+            .read_response_headers(StreamId(request_stream_id))
+            .unwrap();
+        assert_eq!(headers, to_headers(RESPONSE_HEADERS));
+        assert_eq!(fin, false);
+
+        let mut buf = [0u8; 100];
+        let (amount, fin) = client
+            .read_response_data(now(), StreamId(request_stream_id), &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..amount], &[0x61, 0x62, 0x63]);
+        assert_eq!(fin, false);
+
+        let trailers_ready = |e| {
+            matches!(e, Http3ClientEvent::TrailersReady { stream_id }
+                if stream_id == request_stream_id)
+        };
+        assert!(client.events().any(trailers_ready));
+        assert_eq!(
+            client.read_response_trailers(StreamId(request_stream_id)),
+            Ok(trailers)
+        );
+        assert_eq!(
+            client.read_response_trailers(StreamId(request_stream_id)),
+            Err(Error::Unavailable)
+        );
+
+        let (amount, fin) = client
+            .read_response_data(now(), StreamId(request_stream_id), &mut buf)
+            .unwrap();
+        assert_eq!(amount, 0);
+        assert_eq!(fin, true);
+
+        // Stream should now be closed and gone
+        assert_eq!(
+            client.read_response_data(now(), StreamId(request_stream_id), &mut buf),
+            Err(Error::InvalidStreamId)
+        );
+    }
+
+    // Check that the stream is reset with `error`, while the connection stays open.
+    fn assert_stream_reset(client: &mut Http3Client, request_stream_id: u64, expected: Error) {
+        let reset = |e| {
+            matches!(e, Http3ClientEvent::Reset { stream_id, error }
+                if stream_id == request_stream_id && error == expected.code())
+        };
+        assert!(client.events().any(reset));
+        assert_eq!(client.state(), Http3State::Connected);
+        let mut buf = [0u8; 100];
+        assert_eq!(
+            client.read_response_data(now(), StreamId(request_stream_id), &mut buf),
+            Err(Error::InvalidStreamId)
+        );
+    }
+
+    // Trailers must not be received before a DATA frame.
+    #[test]
+    fn test_trailers_before_data() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
+
+        send_headers_frame(
+            &mut server,
+            request_stream_id,
+            &to_headers(RESPONSE_HEADERS),
+        );
+        send_headers_frame(
+            &mut server,
+            request_stream_id,
+            &to_headers(&[("my-trailer", "my-value")]),
+        );
+        server.conn.stream_close_send(request_stream_id).unwrap();
+
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        assert_stream_reset(&mut client, request_stream_id, Error::HttpFrameUnexpected);
+    }
+
+    // A response header list that exceeds our SETTINGS_MAX_HEADER_LIST_SIZE resets the stream.
+    #[test]
+    fn test_response_header_list_too_large() {
+        fixture_init();
+        let mut client = Http3Client::new_with_params(
+            DEFAULT_SERVER_NAME,
+            DEFAULT_ALPN,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(3))),
+            loopback(),
+            loopback(),
+            Http3Parameters {
+                max_header_list_size: Some(200),
+                ..Http3Parameters::default()
+            },
+        )
+        .unwrap();
+        let mut server = make_default_server();
+        connect_with(&mut client, &mut server);
+        let request_stream_id = make_request(&mut client, true);
+        let out = client.process(None, now());
+        server.conn.process(out.dgram(), now());
+
+        let mut headers = to_headers(RESPONSE_HEADERS);
+        for i in 0..10 {
+            headers.push((format!("my-header{}", i), String::from("my-value")));
+        }
+        send_headers_frame(&mut server, request_stream_id, &headers);
+        server.conn.stream_close_send(request_stream_id).unwrap();
+
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        assert_stream_reset(&mut client, request_stream_id, Error::HttpExcessiveLoad);
+    }
+
+    // Trailers must not contain pseudo-headers.
+    #[test]
+    fn test_trailers_with_pseudo_header() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
+
+        send_headers_frame(
+            &mut server,
+            request_stream_id,
+            &to_headers(RESPONSE_HEADERS),
+        );
+        let _ = server
+            .conn
+            .stream_send(request_stream_id, &[0x0, 0x3, 0x61, 0x62, 0x63]);
+        send_headers_frame(
+            &mut server,
+            request_stream_id,
+            &to_headers(&[(":status", "200")]),
+        );
+        server.conn.stream_close_send(request_stream_id).unwrap();
+
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        let mut buf = [0u8; 100];
+        let (amount, fin) = client
+            .read_response_data(now(), StreamId(request_stream_id), &mut buf)
+            .unwrap();
+        assert_eq!(amount, 3);
+        assert_eq!(fin, false);
+
+        assert_stream_reset(
+            &mut client,
+            request_stream_id,
+            Error::HttpGeneralProtocolError,
+        );
+    }
+
+    // Send a request with "expect: 100-continue" and let the server read the request headers.
+    fn send_request_expecting_continue(client: &mut Http3Client, server: &mut TestServer) -> u64 {
+        let request_stream_id = client
+            .fetch(
+                "POST",
+                "https",
+                "something.com",
+                "/",
+                &to_headers(&[("expect", "100-continue")]),
+            )
+            .unwrap();
+        let out = client.process(None, now());
+        server.conn.process(out.dgram(), now());
+        let mut buf = [0u8; 100];
+        let (amount, fin) = server
+            .conn
+            .stream_recv(request_stream_id, &mut buf)
+            .unwrap();
+        assert!(amount > 0);
+        assert_eq!(fin, false);
+        request_stream_id
+    }
+
+    #[test]
+    fn test_expect_continue() {
+        let (mut client, mut server) = connect();
+        let request_stream_id = send_request_expecting_continue(&mut client, &mut server);
+
+        // The body is held back until the server sends 100 (Continue).
+        let data_writable = |e| matches!(e, Http3ClientEvent::DataWritable { .. });
+        assert!(!client.events().any(data_writable));
+        assert_eq!(
+            client.send_request_body(StreamId(request_stream_id), &[0x61]),
+            Ok(0)
+        );
+
+        let continue_headers = to_headers(&[(":status", "100")]);
+        send_headers_frame(&mut server, request_stream_id, &continue_headers);
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        let mut informational = false;
+        let mut writable = false;
+        for e in client.events() {
+            match e {
+                Http3ClientEvent::InformationalHeaders { stream_id, headers } => {
+                    assert_eq!(stream_id, request_stream_id);
+                    assert_eq!(headers, continue_headers);
+                    informational = true;
+                }
+                Http3ClientEvent::DataWritable { stream_id } => {
+                    assert_eq!(stream_id, request_stream_id);
+                    writable = true;
+                }
+                Http3ClientEvent::HeaderReady { .. } => panic!("100 is not the final response"),
+                _ => {}
+            }
+        }
+        assert!(informational);
+        assert!(writable);
+
+        let sent = client.send_request_body(StreamId(request_stream_id), &[0x61, 0x62, 0x63]);
+        assert_eq!(sent, Ok(3));
+        client
+            .stream_close_send(StreamId(request_stream_id))
+            .unwrap();
+        let out = client.process(None, now());
+        server.conn.process(out.dgram(), now());
+        read_and_check_stream_data(
+            &mut server.conn,
+            StreamId(request_stream_id),
+            &[0x0, 0x3, 0x61, 0x62, 0x63],
+            true,
+        );
+
+        // The final response is still delivered.
+        send_headers_frame(
+            &mut server,
+            request_stream_id,
+            &to_headers(RESPONSE_HEADERS),
+        );
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        let header_ready = |e| matches!(e, Http3ClientEvent::HeaderReady { .. });
+        assert!(client.events().any(header_ready));
+        let (headers, _) = client
+            .read_response_headers(StreamId(request_stream_id))
+            .unwrap();
+        assert_eq!(headers, to_headers(RESPONSE_HEADERS));
+    }
+
+    // A final response instead of 100 (Continue) means that the request body is not sent.
+    #[test]
+    fn test_expect_continue_final_response() {
+        let (mut client, mut server) = connect();
+        let request_stream_id = send_request_expecting_continue(&mut client, &mut server);
+
+        send_headers_frame(
+            &mut server,
+            request_stream_id,
+            &to_headers(&[(":status", "417")]),
+        );
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+
+        let data_writable = |e| matches!(e, Http3ClientEvent::DataWritable { .. });
+        let header_ready = |e| matches!(e, Http3ClientEvent::HeaderReady { .. });
+        let events: Vec<_> = client.events().collect();
+        assert!(!events.iter().cloned().any(data_writable));
+        assert!(events.into_iter().any(header_ready));
+        assert_eq!(
+            client.send_request_body(StreamId(request_stream_id), &[0x61]),
+            Err(Error::AlreadyClosed)
+        );
+
+        // The server sees the request stream reset instead of a body.
+        let out = client.process(None, now());
+        server.conn.process(out.dgram(), now());
+        let reset = |e| {
+            matches!(e, ConnectionEvent::RecvStreamReset { stream_id, app_error }
+                if stream_id == request_stream_id
+                    && app_error == Error::HttpRequestCancelled.code())
+        };
+        assert!(server.conn.events().any(reset));
+    }
+
+    // A CONNECT request has only :method and :authority, and the stream becomes a tunnel
+    // after a 2xx response.
+    #[test]
+    fn test_connect_tunnel() {
+        let (mut client, mut server) = connect();
+        let request_stream_id = client.connect("something.com:443").unwrap();
+        let out = client.process(None, now());
+        server.conn.process(out.dgram(), now());
+        read_and_check_stream_data(
+            &mut server.conn,
+            StreamId(request_stream_id),
+            &Http3Client::encode_request("CONNECT", "", "something.com:443", "", &[]),
+            false,
+        );
+        assert_eq!(
+            client.send_request_body(StreamId(request_stream_id), &[0x61]),
+            Ok(0)
+        );
+
+        send_headers_frame(
+            &mut server,
+            request_stream_id,
+            &to_headers(&[(":status", "200")]),
+        );
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        let data_writable = |e| matches!(e, Http3ClientEvent::DataWritable { .. });
+        assert!(client.events().any(data_writable));
+
+        // Tunnel data goes both ways in DATA frames.
+        let sent = client.send_request_body(StreamId(request_stream_id), &[0x61, 0x62, 0x63]);
+        assert_eq!(sent, Ok(3));
+        let out = client.process(None, now());
+        server.conn.process(out.dgram(), now());
+        read_and_check_stream_data(
+            &mut server.conn,
+            StreamId(request_stream_id),
+            &[0x0, 0x3, 0x61, 0x62, 0x63],
+            false,
+        );
+
+        let _ = server
+            .conn
+            .stream_send(request_stream_id, &[0x0, 0x2, 0x64, 0x65]);
+        server.conn.stream_close_send(request_stream_id).unwrap();
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        let mut buf = [0u8; 10];
+        let (amount, fin) = client
+            .read_response_data(now(), StreamId(request_stream_id), &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..amount], &[0x64, 0x65]);
+        assert_eq!(fin, true);
+    }
+
     #[test]
     fn test_read_frames_header_blocked() {
         let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
@@ -2380,6 +4425,45 @@ mod tests {
         assert!(recv_header && recv_data);
     }
 
+    // The time a stream waits for encoder instructions is recorded in the statistics.
+    #[test]
+    fn test_qpack_blocked_time() {
+        let (mut client, mut server, request_stream_id) = connect_and_send_request(true);
+
+        server.encoder.set_max_capacity(100).unwrap();
+        server.encoder.set_max_blocked_streams(100).unwrap();
+        let headers = to_headers(&[(":status", "200"), ("my-header", "my-header")]);
+        send_headers_frame(&mut server, request_stream_id, &headers);
+        server.conn.stream_close_send(request_stream_id).unwrap();
+        // Nothing has been received yet, so the stream is not blocked.
+        assert!(!client.is_qpack_blocked(StreamId(request_stream_id)));
+
+        // Send the response before the encoder instructions.
+        let out = server.conn.process(None, now());
+        client.process(out.dgram(), now());
+        let header_ready = |e| matches!(e, Http3ClientEvent::HeaderReady { .. });
+        assert!(!client.events().any(header_ready));
+        assert!(client.is_qpack_blocked(StreamId(request_stream_id)));
+        assert_eq!(
+            client.qpack_blocked_time(StreamId(request_stream_id)),
+            Some(Duration::from_millis(0))
+        );
+
+        // The encoder instructions arrive later and unblock the stream.
+        server.encoder.send(&mut server.conn).unwrap();
+        let out = server.conn.process(None, now());
+        let blocked = Duration::from_millis(100);
+        client.process(out.dgram(), now() + blocked);
+        assert!(client.events().any(header_ready));
+        assert!(!client.is_qpack_blocked(StreamId(request_stream_id)));
+        assert!(client.headers_used_dynamic_table(StreamId(request_stream_id)));
+        assert_eq!(
+            client.qpack_blocked_time(StreamId(request_stream_id)),
+            Some(blocked)
+        );
+        assert_eq!(client.stats().qpack_blocked_time, blocked);
+    }
+
     fn check_control_qpack_request_streams_resumption(
         server: &mut Connection,
         expect_encoder_stream_data: &[u8],
@@ -2719,7 +4803,7 @@ mod tests {
                 HSetting::new(HSettingType::BlockedStreams, 100),
                 HSetting::new(HSettingType::MaxHeaderListSize, 10000),
             ],
-            Http3State::Closing(CloseError::Application(265)),
+            Http3State::Closing(ConnectionError::Application(265)),
             State::Connected,
             ENCODER_STREAM_DATA_WITH_CAP_INSTRUCTION,
         );
@@ -2738,7 +4822,7 @@ mod tests {
                 HSetting::new(HSettingType::MaxTableCapacity, 100),
                 HSetting::new(HSettingType::MaxHeaderListSize, 10000),
             ],
-            Http3State::Closing(CloseError::Application(265)),
+            Http3State::Closing(ConnectionError::Application(265)),
             State::Connected,
             ENCODER_STREAM_DATA_WITH_CAP_INSTRUCTION,
         );
@@ -2777,7 +4861,7 @@ mod tests {
                 HSetting::new(HSettingType::BlockedStreams, 100),
                 HSetting::new(HSettingType::MaxHeaderListSize, 10000),
             ],
-            Http3State::Closing(CloseError::Application(265)),
+            Http3State::Closing(ConnectionError::Application(265)),
             State::Connected,
             ENCODER_STREAM_DATA_WITH_CAP_INSTRUCTION,
         );
@@ -2797,7 +4881,7 @@ mod tests {
                 HSetting::new(HSettingType::BlockedStreams, 100),
                 HSetting::new(HSettingType::MaxHeaderListSize, 10000),
             ],
-            Http3State::Closing(CloseError::Application(265)),
+            Http3State::Closing(ConnectionError::Application(265)),
             State::Connected,
             ENCODER_STREAM_DATA_WITH_CAP_INSTRUCTION,
         );
@@ -2837,7 +4921,7 @@ mod tests {
                 HSetting::new(HSettingType::BlockedStreams, 50),
                 HSetting::new(HSettingType::MaxHeaderListSize, 10000),
             ],
-            Http3State::Closing(CloseError::Application(265)),
+            Http3State::Closing(ConnectionError::Application(265)),
             State::Connected,
             ENCODER_STREAM_DATA_WITH_CAP_INSTRUCTION,
         );
@@ -2877,7 +4961,7 @@ mod tests {
                 HSetting::new(HSettingType::BlockedStreams, 100),
                 HSetting::new(HSettingType::MaxHeaderListSize, 5000),
             ],
-            Http3State::Closing(CloseError::Application(265)),
+            Http3State::Closing(ConnectionError::Application(265)),
             State::Connected,
             ENCODER_STREAM_DATA_WITH_CAP_INSTRUCTION,
         );
@@ -2937,7 +5021,7 @@ mod tests {
                 HSetting::new(HSettingType::BlockedStreams, 100),
                 HSetting::new(HSettingType::MaxHeaderListSize, 10000),
             ],
-            Http3State::Closing(CloseError::Application(265)),
+            Http3State::Closing(ConnectionError::Application(265)),
             State::Connected,
             ENCODER_STREAM_DATA_WITH_CAP_INSTRUCTION,
         );
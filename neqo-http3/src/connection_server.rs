@@ -7,7 +7,7 @@
 use crate::connection::{HandleReadableOutput, Http3Connection, Http3State, Http3Transaction};
 use crate::hframe::HFrame;
 use crate::server_connection_events::{Http3ServerConnEvent, Http3ServerConnEvents};
-use crate::transaction_server::TransactionServer;
+use crate::transaction_server::{check_response_headers, TransactionServer};
 use crate::{Error, Header, Res};
 use neqo_common::{qdebug, qinfo, qtrace};
 use neqo_transport::{AppError, Connection, ConnectionEvent, StreamType};
@@ -32,7 +32,17 @@ impl Http3ServerHandler {
             events: Http3ServerConnEvents::default(),
         }
     }
+
+    /// Limit the size of request header lists; a larger request is answered with 431
+    /// (Request Header Fields Too Large).
+    pub fn set_max_header_list_size(&mut self, limit: u64) {
+        self.base_handler.set_max_header_list_size(limit);
+    }
+
+    /// Send a response on a request stream. This fails with `Error::InvalidHeader` if
+    /// `headers` don't have a valid `:status`; the stream is left as it was.
     pub fn set_response(&mut self, stream_id: u64, headers: &[Header], data: Vec<u8>) -> Res<()> {
+        check_response_headers(headers)?;
         self.base_handler
             .transactions
             .get_mut(&stream_id)
@@ -54,11 +64,23 @@ impl Http3ServerHandler {
         Ok(())
     }
 
+    pub fn reset_response(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        app_error: AppError,
+    ) -> Res<()> {
+        self.base_handler
+            .reset_response(conn, stream_id, app_error)?;
+        self.events.remove_events_for_stream_id(stream_id);
+        Ok(())
+    }
+
     pub fn process_http3(&mut self, conn: &mut Connection, now: Instant) {
         qtrace!([self], "Process http3 internal.");
         match self.base_handler.state() {
             Http3State::Connected | Http3State::GoingAway => {
-                let res = self.check_connection_events(conn);
+                let res = self.check_connection_events(conn, now);
                 if self.check_result(conn, now, res) {
                     return;
                 }
@@ -67,7 +89,7 @@ impl Http3ServerHandler {
             }
             Http3State::Closed { .. } => {}
             _ => {
-                let res = self.check_connection_events(conn);
+                let res = self.check_connection_events(conn, now);
                 let _ = self.check_result(conn, now, res);
             }
         }
@@ -77,6 +99,11 @@ impl Http3ServerHandler {
         self.events.next_event()
     }
 
+    #[cfg(test)]
+    pub fn streams_in_send_order(&self) -> Vec<u64> {
+        self.base_handler.streams_in_send_order()
+    }
+
     pub fn should_be_processed(&self) -> bool {
         self.base_handler.has_data_to_send() | self.events.has_events()
     }
@@ -98,7 +125,7 @@ impl Http3ServerHandler {
     }
 
     // If this return an error the connection must be closed.
-    fn check_connection_events(&mut self, conn: &mut Connection) -> Res<()> {
+    fn check_connection_events(&mut self, conn: &mut Connection, now: Instant) -> Res<()> {
         qtrace!([self], "Check connection events.");
         while let Some(e) = conn.next_event() {
             qdebug!([self], "check_connection_events - event {:?}.", e);
@@ -109,7 +136,11 @@ impl Http3ServerHandler {
                 } => match stream_type {
                     StreamType::BiDi => self.base_handler.add_transaction(
                         stream_id,
-                        TransactionServer::new(stream_id, self.events.clone()),
+                        TransactionServer::new(
+                            stream_id,
+                            self.events.clone(),
+                            self.base_handler.stats_recorder(),
+                        ),
                     ),
                     StreamType::UniDi => {
                         if self.base_handler.handle_new_unidi_stream(conn, stream_id)? {
@@ -119,15 +150,18 @@ impl Http3ServerHandler {
                 },
                 ConnectionEvent::SendStreamWritable { .. } => {}
                 ConnectionEvent::RecvStreamReadable { stream_id } => {
-                    self.handle_stream_readable(conn, stream_id)?
+                    self.handle_stream_readable(conn, stream_id, now)?
                 }
                 ConnectionEvent::RecvStreamReset {
                     stream_id,
                     app_error,
                 } => {
-                    let _ = self
+                    if self
                         .base_handler
-                        .handle_stream_reset(conn, stream_id, app_error)?;
+                        .handle_stream_reset(conn, stream_id, app_error)?
+                    {
+                        self.events.reset(stream_id, app_error);
+                    }
                 }
                 ConnectionEvent::SendStreamStopSending {
                     stream_id,
@@ -143,13 +177,23 @@ impl Http3ServerHandler {
                     }
                 }
                 ConnectionEvent::ZeroRttRejected => return Err(Error::HttpInternalError),
+                // The server does not pass datagrams to the application, so they are dropped.
+                ConnectionEvent::Datagram { .. } => {}
             }
         }
         Ok(())
     }
 
-    fn handle_stream_readable(&mut self, conn: &mut Connection, stream_id: u64) -> Res<()> {
-        match self.base_handler.handle_stream_readable(conn, stream_id)? {
+    fn handle_stream_readable(
+        &mut self,
+        conn: &mut Connection,
+        stream_id: u64,
+        now: Instant,
+    ) -> Res<()> {
+        match self
+            .base_handler
+            .handle_stream_readable(conn, stream_id, now)?
+        {
             HandleReadableOutput::PushStream => Err(Error::HttpStreamCreationError),
             HandleReadableOutput::ControlFrames(control_frames) => {
                 for f in control_frames.into_iter() {
@@ -159,9 +203,13 @@ impl Http3ServerHandler {
                             Ok(())
                         }
                         HFrame::Goaway { .. } => Err(Error::HttpFrameUnexpected),
-                        _ => unreachable!(
-                            "we should only put MaxPushId and Goaway into control_frames."
-                        ),
+                        HFrame::PriorityUpdateRequest {
+                            element_id,
+                            priority,
+                        } => self
+                            .base_handler
+                            .handle_priority_update(element_id, priority),
+                        _ => unreachable!("not a control frame"),
                     }?;
                 }
                 Ok(())
@@ -183,6 +231,7 @@ impl Http3ServerHandler {
             let _ = conn.stream_stop_sending(stop_stream_id, app_err);
             t.reset_receiving_side();
             self.base_handler.transactions.remove(&stop_stream_id);
+            self.events.reset(stop_stream_id, app_err);
         }
     }
 }
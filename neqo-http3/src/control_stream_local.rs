@@ -5,6 +5,7 @@
 // except according to those terms.
 
 use crate::hframe::HFrame;
+use crate::stats::Http3StatsRecorder;
 use crate::Res;
 use neqo_common::{qtrace, Encoder};
 use neqo_transport::{Connection, StreamType};
@@ -16,6 +17,7 @@ pub const HTTP3_UNI_STREAM_TYPE_CONTROL: u64 = 0x0;
 pub struct ControlStreamLocal {
     stream_id: Option<u64>,
     buf: Vec<u8>,
+    stats: Http3StatsRecorder,
 }
 
 impl ::std::fmt::Display for ControlStreamLocal {
@@ -25,7 +27,19 @@ impl ::std::fmt::Display for ControlStreamLocal {
 }
 
 impl ControlStreamLocal {
+    pub fn new(stats: Http3StatsRecorder) -> Self {
+        Self {
+            stream_id: None,
+            buf: Vec::new(),
+            stats,
+        }
+    }
+
     pub fn queue_frame(&mut self, f: HFrame) {
+        // A frame queued before the stream is created has no stream ID to be traced with.
+        if let Some(stream_id) = self.stream_id {
+            self.stats.frame_sent(stream_id, &f);
+        }
         let mut enc = Encoder::default();
         f.encode(&mut enc);
         self.buf.append(&mut enc.into());
@@ -5,6 +5,7 @@
 // except according to those terms.
 
 use crate::hframe::{HFrame, HFrameReader};
+use crate::stats::Http3StatsRecorder;
 use crate::{Error, Res};
 use neqo_common::{qdebug, qinfo};
 use neqo_transport::Connection;
@@ -24,10 +25,10 @@ impl ::std::fmt::Display for ControlStreamRemote {
 }
 
 impl ControlStreamRemote {
-    pub fn new() -> Self {
+    pub fn new(stats: Http3StatsRecorder) -> Self {
         Self {
             stream_id: None,
-            frame_reader: HFrameReader::new(),
+            frame_reader: HFrameReader::with_stats(stats),
             fin: false,
         }
     }
@@ -0,0 +1,58 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Structured events for the frames that a connection sends and receives, for applications
+// that want a protocol transcript. This is only built with the `frame-trace` feature.
+
+use crate::hframe::frame_type_name;
+use neqo_common::hex;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameDirection {
+    Sent,
+    Received,
+}
+
+/// An HTTP/3 frame on a stream. The payload of DATA and HEADERS frames is not part of the
+/// event: it is passed to the application or to QPACK separately, so only `len` is known.
+#[derive(Debug, PartialEq)]
+pub struct FrameEvent<'a> {
+    pub direction: FrameDirection,
+    pub stream_id: u64,
+    pub frame_type: u64,
+    pub len: u64,
+    pub payload: &'a [u8],
+}
+
+impl fmt::Display for FrameEvent<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let arrow = match self.direction {
+            FrameDirection::Sent => ">",
+            FrameDirection::Received => "<",
+        };
+        write!(f, "{} stream {} ", arrow, self.stream_id)?;
+        match frame_type_name(self.frame_type) {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "0x{:x}", self.frame_type)?,
+        }
+        write!(f, " len={}", self.len)?;
+        if !self.payload.is_empty() {
+            write!(f, " {}", hex(self.payload))?;
+        }
+        Ok(())
+    }
+}
+
+/// Receives the frames of a connection, see `Http3Client::set_frame_tracer`.
+pub trait FrameTracer {
+    /// An HTTP/3 frame was sent or received, on a control or a request stream.
+    fn frame(&mut self, event: &FrameEvent);
+
+    /// An entry was inserted into a QPACK dynamic table: `Sent` for the table of our encoder,
+    /// `Received` for entries that the peer inserted into the table of our decoder.
+    fn qpack_insert(&mut self, _direction: FrameDirection, _name: &[u8], _value: &[u8]) {}
+}
@@ -5,6 +5,8 @@
 // except according to those terms.
 
 use crate::hsettings_frame::HSettings;
+use crate::priority::Priority;
+use crate::stats::Http3StatsRecorder;
 use neqo_common::{
     hex, qdebug, qtrace, Decoder, Encoder, IncrementalDecoder, IncrementalDecoderResult,
 };
@@ -24,6 +26,28 @@ const H3_FRAME_TYPE_PUSH_PROMISE: HFrameType = 0x5;
 const H3_FRAME_TYPE_GOAWAY: HFrameType = 0x7;
 const H3_FRAME_TYPE_MAX_PUSH_ID: HFrameType = 0xd;
 const H3_FRAME_TYPE_DUPLICATE_PUSH: HFrameType = 0xe;
+const H3_FRAME_TYPE_PRIORITY_UPDATE_REQUEST: HFrameType = 0xf0700;
+
+/// The largest payload of a frame that is read completely before it is decoded, i.e. any
+/// frame other than DATA and HEADERS. A larger frame is rejected with
+/// `Error::HttpExcessiveLoad` instead of being buffered.
+const MAX_BUFFERED_FRAME_LEN: u64 = 1 << 16;
+
+/// The name of a frame type, or `None` for an unknown or reserved type.
+pub fn frame_type_name(frame_type: HFrameType) -> Option<&'static str> {
+    match frame_type {
+        H3_FRAME_TYPE_DATA => Some("DATA"),
+        H3_FRAME_TYPE_HEADERS => Some("HEADERS"),
+        H3_FRAME_TYPE_CANCEL_PUSH => Some("CANCEL_PUSH"),
+        H3_FRAME_TYPE_SETTINGS => Some("SETTINGS"),
+        H3_FRAME_TYPE_PUSH_PROMISE => Some("PUSH_PROMISE"),
+        H3_FRAME_TYPE_GOAWAY => Some("GOAWAY"),
+        H3_FRAME_TYPE_MAX_PUSH_ID => Some("MAX_PUSH_ID"),
+        H3_FRAME_TYPE_DUPLICATE_PUSH => Some("DUPLICATE_PUSH"),
+        H3_FRAME_TYPE_PRIORITY_UPDATE_REQUEST => Some("PRIORITY_UPDATE"),
+        _ => None,
+    }
+}
 
 #[derive(Copy, Clone, PartialEq)]
 pub enum HStreamType {
@@ -60,6 +84,10 @@ pub enum HFrame {
     DuplicatePush {
         push_id: u64,
     },
+    PriorityUpdateRequest {
+        element_id: u64,
+        priority: Priority,
+    },
 }
 
 impl HFrame {
@@ -73,6 +101,7 @@ impl HFrame {
             Self::Goaway { .. } => H3_FRAME_TYPE_GOAWAY,
             Self::MaxPushId { .. } => H3_FRAME_TYPE_MAX_PUSH_ID,
             Self::DuplicatePush { .. } => H3_FRAME_TYPE_DUPLICATE_PUSH,
+            Self::PriorityUpdateRequest { .. } => H3_FRAME_TYPE_PRIORITY_UPDATE_REQUEST,
         }
     }
 
@@ -96,9 +125,10 @@ impl HFrame {
                 push_id,
                 header_block,
             } => {
-                enc.encode_varint((header_block.len() + (Encoder::varint_len(*push_id))) as u64);
-                enc.encode_varint(*push_id);
-                enc.encode(header_block);
+                enc.encode_vvec_with(|enc_inner| {
+                    enc_inner.encode_varint(*push_id);
+                    enc_inner.encode(header_block);
+                });
             }
             Self::Goaway { stream_id } => {
                 enc.encode_vvec_with(|enc_inner| {
@@ -115,6 +145,15 @@ impl HFrame {
                     enc_inner.encode_varint(*push_id);
                 });
             }
+            Self::PriorityUpdateRequest {
+                element_id,
+                priority,
+            } => {
+                enc.encode_vvec_with(|enc_inner| {
+                    enc_inner.encode_varint(*element_id);
+                    enc_inner.encode(priority.to_string().as_bytes());
+                });
+            }
         }
     }
 
@@ -128,6 +167,7 @@ impl HFrame {
             Self::Goaway { .. } => (s == HStreamType::Control),
             Self::MaxPushId { .. } => (s == HStreamType::Control),
             Self::DuplicatePush { .. } => (s == HStreamType::Request),
+            Self::PriorityUpdateRequest { .. } => (s == HStreamType::Control),
         }
     }
 }
@@ -149,6 +189,7 @@ pub struct HFrameReader {
     hframe_type: u64,
     hframe_len: u64,
     payload: Vec<u8>,
+    stats: Http3StatsRecorder,
 }
 
 impl Default for HFrameReader {
@@ -159,12 +200,18 @@ impl Default for HFrameReader {
 
 impl HFrameReader {
     pub fn new() -> Self {
+        Self::with_stats(Http3StatsRecorder::default())
+    }
+
+    /// Create a reader that records statistics, e.g. ignored frames, in `stats`.
+    pub fn with_stats(stats: Http3StatsRecorder) -> Self {
         Self {
             state: HFrameReaderState::BeforeFrame,
             hframe_type: 0,
             hframe_len: 0,
             decoder: IncrementalDecoder::decode_varint(),
             payload: Vec::new(),
+            stats,
         }
     }
 
@@ -246,7 +293,16 @@ impl HFrameReader {
                                 | H3_FRAME_TYPE_GOAWAY
                                 | H3_FRAME_TYPE_MAX_PUSH_ID
                                 | H3_FRAME_TYPE_DUPLICATE_PUSH
-                                | H3_FRAME_TYPE_PUSH_PROMISE => {
+                                | H3_FRAME_TYPE_PUSH_PROMISE
+                                | H3_FRAME_TYPE_PRIORITY_UPDATE_REQUEST => {
+                                    if len > MAX_BUFFERED_FRAME_LEN {
+                                        qdebug!(
+                                            [conn],
+                                            "HFrameReader::receive: frame type {} is too long",
+                                            self.hframe_type
+                                        );
+                                        break Err(Error::HttpExcessiveLoad);
+                                    }
                                     if len == 0 {
                                         HFrameReaderState::Done
                                     } else {
@@ -255,6 +311,12 @@ impl HFrameReader {
                                     }
                                 }
                                 _ => {
+                                    qdebug!(
+                                        [conn],
+                                        "HFrameReader::receive: ignoring frame type {}",
+                                        self.hframe_type
+                                    );
+                                    self.stats.frame_ignored(self.hframe_type);
                                     if len == 0 {
                                         self.decoder = IncrementalDecoder::decode_varint();
                                         HFrameReaderState::BeforeFrame
@@ -300,6 +362,12 @@ impl HFrameReader {
             };
 
             if self.state == HFrameReaderState::Done {
+                self.stats.frame_received(
+                    stream_id,
+                    self.hframe_type,
+                    self.hframe_len,
+                    &self.payload,
+                );
                 break Ok(fin);
             }
 
@@ -370,6 +438,19 @@ impl HFrameReader {
                     _ => return Err(Error::NotEnoughData),
                 },
             },
+            H3_FRAME_TYPE_PRIORITY_UPDATE_REQUEST => {
+                let element_id = match dec.decode_varint() {
+                    Some(v) => v,
+                    _ => return Err(Error::NotEnoughData),
+                };
+                // The Priority Field Value is ASCII; anything else is parsed leniently.
+                let priority =
+                    Priority::from_field_value(&String::from_utf8_lossy(dec.decode_remainder()));
+                HFrame::PriorityUpdateRequest {
+                    element_id,
+                    priority,
+                }
+            }
             _ => panic!("We should not be in state Done with unknown frame type!"),
         };
         self.reset();
@@ -493,6 +574,15 @@ mod tests {
         enc_dec(&f, "0e0105", 0);
     }
 
+    #[test]
+    fn test_priority_update_request_frame4() {
+        let f = HFrame::PriorityUpdateRequest {
+            element_id: 4,
+            priority: Priority::new(5, true).unwrap(),
+        };
+        enc_dec(&f, "800f07000704753d352c2069", 0);
+    }
+
     // We have 3 code paths in frame_reader:
     // 1) All frames except DATA, HEADERES and PUSH_PROMISE (here we test SETTING and SETTINGS with larger varints)
     // 2) PUSH_PROMISE and
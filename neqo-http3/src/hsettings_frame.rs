@@ -4,8 +4,9 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use crate::{Error, Res};
+use crate::{is_reserved, Error, Res};
 use neqo_common::{Decoder, Encoder};
+use std::collections::HashSet;
 use std::ops::Deref;
 
 type SettingsType = u64;
@@ -14,6 +15,9 @@ const SETTINGS_MAX_HEADER_LIST_SIZE: SettingsType = 0x6;
 const SETTINGS_QPACK_MAX_TABLE_CAPACITY: SettingsType = 0x1;
 const SETTINGS_QPACK_BLOCKED_STREAMS: SettingsType = 0x7;
 
+// Identifiers of HTTP/2 settings that have no HTTP/3 equivalent. Receiving them is an error.
+const H2_RESERVED_SETTINGS: &[SettingsType] = &[0x2, 0x3, 0x4, 0x5];
+
 #[derive(Clone, PartialEq, Debug, Copy)]
 pub enum HSettingType {
     MaxHeaderListSize,
@@ -84,11 +88,20 @@ impl HSettings {
         });
     }
 
+    /// Decode the settings. A setting identifier that appears twice or an identifier
+    /// reserved for HTTP/2 is an `HttpSettingsError`.
     pub fn decode_frame_contents(&mut self, dec: &mut Decoder) -> Res<()> {
+        let mut seen = HashSet::new();
         while dec.remaining() > 0 {
             let t = dec.decode_varint();
             let v = dec.decode_varint();
 
+            if let Some(t) = t {
+                if !seen.insert(t) || H2_RESERVED_SETTINGS.contains(&t) {
+                    return Err(Error::HttpSettingsError);
+                }
+            }
+
             match (t, v) {
                 (Some(SETTINGS_MAX_HEADER_LIST_SIZE), Some(value)) => self
                     .settings
@@ -100,6 +113,7 @@ impl HSettings {
                     .settings
                     .push(HSetting::new(HSettingType::BlockedStreams, value)),
                 // other supported settings here
+                (Some(t), Some(_)) if is_reserved(t) => {}
                 (Some(_), Some(_)) => {} // ignore unknown setting, it is fine.
                 _ => return Err(Error::NotEnoughData),
             };
@@ -108,9 +122,66 @@ impl HSettings {
     }
 }
 
+/// The SETTINGS that the peer sent. Settings that it did not send have their default value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeerSettings {
+    /// SETTINGS_QPACK_MAX_TABLE_CAPACITY, the limit for the capacity of our encoder's table.
+    pub max_table_capacity: u64,
+    /// SETTINGS_QPACK_BLOCKED_STREAMS, how many streams our encoder may block.
+    pub blocked_streams: u64,
+    /// SETTINGS_MAX_HEADER_LIST_SIZE.
+    pub max_header_list_size: u64,
+}
+
+impl From<&HSettings> for PeerSettings {
+    fn from(settings: &HSettings) -> Self {
+        Self {
+            max_table_capacity: settings.get(HSettingType::MaxTableCapacity),
+            blocked_streams: settings.get(HSettingType::BlockedStreams),
+            max_header_list_size: settings.get(HSettingType::MaxHeaderListSize),
+        }
+    }
+}
+
 impl Deref for HSettings {
     type Target = [HSetting];
     fn deref(&self) -> &Self::Target {
         &self.settings
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(hex: &str) -> Res<HSettings> {
+        let enc = Encoder::from_hex(hex);
+        let mut settings = HSettings::default();
+        settings.decode_frame_contents(&mut enc.as_decoder())?;
+        Ok(settings)
+    }
+
+    #[test]
+    fn unknown_and_grease_settings_are_ignored() {
+        // MaxHeaderListSize = 4, a grease setting (0x21) and an unknown setting (0x30).
+        let settings = decode("06042101300a").unwrap();
+        assert_eq!(
+            &settings[..],
+            &[HSetting::new(HSettingType::MaxHeaderListSize, 4)]
+        );
+    }
+
+    #[test]
+    fn duplicate_setting() {
+        // MaxTableCapacity twice.
+        assert_eq!(decode("0140640100"), Err(Error::HttpSettingsError));
+        // An unknown setting twice.
+        assert_eq!(decode("30013002"), Err(Error::HttpSettingsError));
+    }
+
+    #[test]
+    fn h2_setting() {
+        // SETTINGS_ENABLE_PUSH from HTTP/2.
+        assert_eq!(decode("0201"), Err(Error::HttpSettingsError));
+    }
+}
@@ -13,11 +13,15 @@ pub mod connection_client;
 mod connection_server;
 mod control_stream_local;
 mod control_stream_remote;
+#[cfg(feature = "frame-trace")]
+pub mod frame_trace;
 pub mod hframe;
 mod hsettings_frame;
+mod priority;
 pub mod server;
 mod server_connection_events;
 mod server_events;
+mod stats;
 mod stream_type_reader;
 mod transaction_client;
 pub mod transaction_server;
@@ -28,15 +32,26 @@ pub use neqo_transport::Output;
 use neqo_transport::{AppError, Error as TransportError};
 
 pub use client_events::Http3ClientEvent;
-pub use connection::Http3State;
+pub use connection::{Http3Parameters, Http3State};
 pub use connection_client::Http3Client;
+#[cfg(feature = "frame-trace")]
+pub use frame_trace::{FrameDirection, FrameEvent, FrameTracer};
+pub use hsettings_frame::PeerSettings;
 pub use neqo_qpack::Header;
+pub use priority::Priority;
 pub use server::Http3Server;
-pub use server_events::Http3ServerEvent;
+pub use server_events::{ClientRequestStream, Http3ServerEvent};
+pub use stats::Http3Stats;
 pub use transaction_server::TransactionServer;
 
 type Res<T> = Result<T, Error>;
 
+/// Reserved stream types and setting identifiers (0x1f * N + 0x21) exercise the requirement
+/// that unknown ones are ignored (RFC 9114, Sections 6.2.3 and 7.2.4.1).
+pub(crate) fn is_reserved(value: u64) -> bool {
+    value >= 0x21 && (value - 0x21) % 0x1f == 0
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Error {
     HttpNoError,
@@ -68,6 +83,10 @@ pub enum Error {
     Unavailable,
     Unexpected,
     InvalidResumptionToken,
+    HeaderListTooLarge,
+    InvalidInput,
+    InvalidHeader,
+    StreamLimit,
 }
 
 impl Error {
@@ -95,6 +114,43 @@ impl Error {
             _ => 3,
         }
     }
+
+    /// The name that the HTTP/3 and QPACK specifications give to an error code, or `None`
+    /// if they do not define it.
+    pub fn code_name(code: AppError) -> Option<&'static str> {
+        match code {
+            0x100 => Some("H3_NO_ERROR"),
+            0x101 => Some("H3_GENERAL_PROTOCOL_ERROR"),
+            0x102 => Some("H3_INTERNAL_ERROR"),
+            0x103 => Some("H3_STREAM_CREATION_ERROR"),
+            0x104 => Some("H3_CLOSED_CRITICAL_STREAM"),
+            0x105 => Some("H3_FRAME_UNEXPECTED"),
+            0x106 => Some("H3_FRAME_ERROR"),
+            0x107 => Some("H3_EXCESSIVE_LOAD"),
+            0x108 => Some("H3_ID_ERROR"),
+            0x109 => Some("H3_SETTINGS_ERROR"),
+            0x10a => Some("H3_MISSING_SETTINGS"),
+            0x10b => Some("H3_REQUEST_REJECTED"),
+            0x10c => Some("H3_REQUEST_CANCELLED"),
+            0x10d => Some("H3_REQUEST_INCOMPLETE"),
+            0x10e => Some("H3_EARLY_RESPONSE"),
+            0x10f => Some("H3_CONNECT_ERROR"),
+            0x110 => Some("H3_VERSION_FALLBACK"),
+            0x200 => Some("QPACK_DECOMPRESSION_FAILED"),
+            0x201 => Some("QPACK_ENCODER_STREAM_ERROR"),
+            0x202 => Some("QPACK_DECODER_STREAM_ERROR"),
+            _ => None,
+        }
+    }
+
+    /// An error code for messages, e.g. "H3_FRAME_UNEXPECTED (0x105)", or just the number if
+    /// the code has no name.
+    pub fn describe_code(code: AppError) -> String {
+        match Self::code_name(code) {
+            Some(name) => format!("{} ({:#x})", name, code),
+            None => format!("{:#x}", code),
+        }
+    }
 }
 
 impl From<TransportError> for Error {
@@ -105,7 +161,11 @@ impl From<TransportError> for Error {
 
 impl From<QpackError> for Error {
     fn from(err: QpackError) -> Self {
-        Self::QpackError(err)
+        match err {
+            // The QPACK encoder and decoder streams are critical streams.
+            QpackError::ClosedCriticalStream => Self::HttpClosedCriticalStream,
+            _ => Self::QpackError(err),
+        }
     }
 }
 
@@ -152,3 +212,26 @@ impl ::std::fmt::Display for Error {
         write!(f, "HTTP/3 error: {:?}", self)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+
+    #[test]
+    fn code_names() {
+        assert_eq!(
+            Error::describe_code(Error::HttpFrameUnexpected.code()),
+            "H3_FRAME_UNEXPECTED (0x105)"
+        );
+        assert_eq!(
+            Error::describe_code(0x202),
+            "QPACK_DECODER_STREAM_ERROR (0x202)"
+        );
+        assert_eq!(Error::describe_code(0x21), "0x21");
+        // Every HTTP/3 error that has a code has a name.
+        for code in 0x100..=0x110 {
+            assert!(Error::code_name(code).is_some());
+            assert_eq!(Error::from(code).code(), code);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// The priority parameters of Extensible Priorities (RFC 9218).
+
+use std::fmt;
+
+const DEFAULT_URGENCY: u8 = 3;
+const MAX_URGENCY: u8 = 7;
+
+/// The priority of a request: an urgency from 0 (most urgent) to 7, and whether the
+/// response can be processed incrementally.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Priority {
+    urgency: u8,
+    incremental: bool,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self {
+            urgency: DEFAULT_URGENCY,
+            incremental: false,
+        }
+    }
+}
+
+impl Priority {
+    /// Returns `None` if `urgency` is larger than 7.
+    pub fn new(urgency: u8, incremental: bool) -> Option<Self> {
+        if urgency > MAX_URGENCY {
+            None
+        } else {
+            Some(Self {
+                urgency,
+                incremental,
+            })
+        }
+    }
+
+    pub fn urgency(self) -> u8 {
+        self.urgency
+    }
+
+    pub fn incremental(self) -> bool {
+        self.incremental
+    }
+
+    /// Parse a Priority Field Value, e.g. "u=5, i". Parameters that are unknown or
+    /// have invalid values are ignored and the defaults are used instead (RFC 9218,
+    /// Section 4).
+    pub fn from_field_value(value: &str) -> Self {
+        let mut priority = Self::default();
+        for member in value.split(',') {
+            let mut parts = member.trim().splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("u"), Some(u)) => {
+                    if let Ok(u) = u.parse::<u8>() {
+                        if u <= MAX_URGENCY {
+                            priority.urgency = u;
+                        }
+                    }
+                }
+                (Some("i"), None) | (Some("i"), Some("?1")) => priority.incremental = true,
+                (Some("i"), Some("?0")) => priority.incremental = false,
+                _ => {}
+            }
+        }
+        priority
+    }
+}
+
+/// Formats the priority as a Priority Field Value.
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "u={}", self.urgency)?;
+        if self.incremental {
+            write!(f, ", i")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn field_value() {
+        assert_eq!(Priority::default().to_string(), "u=3");
+        assert_eq!(Priority::new(5, true).unwrap().to_string(), "u=5, i");
+        assert_eq!(Priority::new(8, false), None);
+
+        assert_eq!(
+            Priority::from_field_value("u=5, i"),
+            Priority::new(5, true).unwrap()
+        );
+        assert_eq!(
+            Priority::from_field_value("i=?0,u=0"),
+            Priority::new(0, false).unwrap()
+        );
+        // Unknown parameters and invalid values are ignored.
+        assert_eq!(
+            Priority::from_field_value("u=9, x=1, i=?1"),
+            Priority::new(3, true).unwrap()
+        );
+        assert_eq!(Priority::from_field_value(""), Priority::default());
+    }
+}
@@ -24,6 +24,7 @@ pub struct Http3Server {
     server: Server,
     max_table_size: u32,
     max_blocked_streams: u16,
+    max_header_list_size: Option<u64>,
     http3_handlers: HashMap<ActiveConnectionRef, HandlerRef>,
     events: Http3ServerEvents,
 }
@@ -48,11 +49,32 @@ impl Http3Server {
             server: Server::new(now, certs, protocols, anti_replay, cid_manager)?,
             max_table_size,
             max_blocked_streams,
+            max_header_list_size: None,
             http3_handlers: HashMap::new(),
             events: Http3ServerEvents::default(),
         })
     }
 
+    /// Limit the size of the header lists that clients may send. This is advertised in
+    /// SETTINGS_MAX_HEADER_LIST_SIZE and applies to new connections. Requests with a larger
+    /// header list are answered with 431 (Request Header Fields Too Large).
+    pub fn set_max_header_list_size(&mut self, limit: u64) {
+        self.max_header_list_size = Some(limit);
+    }
+
+    /// Require that new clients validate their address with a Retry before a connection is
+    /// created. Whether this happened can be checked with `Connection::address_validated` on
+    /// the connection of `Http3ServerEvent::StateChange`.
+    pub fn set_retry_required(&mut self, retry: bool) {
+        self.server.set_retry_required(retry);
+    }
+
+    /// Allow clients of new connections to migrate to a new address. Migration is disabled by
+    /// default.
+    pub fn set_allow_migration(&mut self, allow: bool) {
+        self.server.set_allow_migration(allow);
+    }
+
     pub fn process(&mut self, dgram: Option<Datagram>, now: Instant) -> Output {
         qtrace!([self], "Process.");
         let out = self.server.process(dgram, now);
@@ -90,12 +112,14 @@ impl Http3Server {
         active_conns.dedup();
         let max_table_size = self.max_table_size;
         let max_blocked_streams = self.max_blocked_streams;
+        let max_header_list_size = self.max_header_list_size;
         for mut conn in active_conns {
             let handler = self.http3_handlers.entry(conn.clone()).or_insert_with(|| {
-                Rc::new(RefCell::new(Http3ServerHandler::new(
-                    max_table_size,
-                    max_blocked_streams,
-                )))
+                let mut handler = Http3ServerHandler::new(max_table_size, max_blocked_streams);
+                if let Some(limit) = max_header_list_size {
+                    handler.set_max_header_list_size(limit);
+                }
+                Rc::new(RefCell::new(handler))
             });
 
             handler
@@ -122,6 +146,10 @@ impl Http3Server {
                         data,
                         fin,
                     ),
+                    Http3ServerConnEvent::Reset { stream_id, error } => self.events.reset(
+                        ClientRequestStream::new(conn.clone(), handler.clone(), stream_id),
+                        error,
+                    ),
                     Http3ServerConnEvent::StateChange(state) => {
                         self.events
                             .connection_state_change(conn.clone(), state.clone());
@@ -129,7 +157,6 @@ impl Http3Server {
                             remove = true;
                         }
                     }
-                    _ => {}
                 }
             }
             if remove {
@@ -165,7 +192,8 @@ mod tests {
     use neqo_crypto::AuthenticationStatus;
     use neqo_qpack::encoder::QPackEncoder;
     use neqo_transport::{
-        CloseError, Connection, ConnectionEvent, FixedConnectionIdManager, State, StreamType,
+        AppError, Connection, ConnectionError, ConnectionEvent, FixedConnectionIdManager, State,
+        StreamType,
     };
     use test_fixture::*;
 
@@ -185,7 +213,7 @@ mod tests {
     }
 
     fn assert_closed(hconn: &mut Http3Server, expected: Error) {
-        let err = CloseError::Application(expected.code());
+        let err = ConnectionError::Application(expected.code());
         let closed = |e| {
             matches!(e,
             Http3ServerEvent::StateChange{ state: Http3State::Closing(e), .. }
@@ -209,17 +237,26 @@ mod tests {
         assert!(!hconn.events().any(closed));
     }
 
+    // The control stream type and the SETTINGS frame of a default server.
+    const CONTROL_STREAM_DATA: &[u8] = &[0x0, 0x4, 0x6, 0x1, 0x40, 0x64, 0x7, 0x40, 0x64];
+
     // Start a client/server and check setting frame.
-    #[allow(clippy::cognitive_complexity)]
     fn connect_and_receive_settings() -> (Http3Server, Connection) {
-        // Create a server and connect it to a client.
+        connect_server_and_receive_settings(default_http3_server(), CONTROL_STREAM_DATA)
+    }
+
+    #[allow(clippy::cognitive_complexity)]
+    fn connect_server_and_receive_settings(
+        mut hconn: Http3Server,
+        control_stream_data: &[u8],
+    ) -> (Http3Server, Connection) {
+        // Connect the server to a client.
         // We will have a http3 server on one side and a neqo_transport
         // connection on the other side so that we can check what the http3
         // side sends and also to simulate an incorrectly behaving http3
         // client.
 
         fixture_init();
-        let mut hconn = default_http3_server();
         let mut neqo_trans_conn = default_client();
 
         let out = neqo_trans_conn.process(None, now());
@@ -251,10 +288,7 @@ mod tests {
                         let (amount, fin) =
                             neqo_trans_conn.stream_recv(stream_id, &mut buf).unwrap();
                         assert_eq!(fin, false);
-                        const CONTROL_STREAM_DATA: &[u8] =
-                            &[0x0, 0x4, 0x6, 0x1, 0x40, 0x64, 0x7, 0x40, 0x64];
-                        assert_eq!(amount, CONTROL_STREAM_DATA.len());
-                        assert_eq!(&buf[..9], CONTROL_STREAM_DATA);
+                        assert_eq!(&buf[..amount], control_stream_data);
                     } else if stream_id == 6 || stream_id == 7 {
                         let mut buf = [0u8; 100];
                         let (amount, fin) =
@@ -292,6 +326,31 @@ mod tests {
         let _ = connect_and_receive_settings();
     }
 
+    // With a Retry, the server knows that the address of the client is validated.
+    #[test]
+    fn test_server_retry() {
+        let mut hconn = default_http3_server();
+        hconn.set_retry_required(true);
+        let mut neqo_trans_conn = default_client();
+
+        let out = neqo_trans_conn.process(None, now());
+        let out = hconn.process(out.dgram(), now());
+        // The Retry is answered with a new Initial that carries the token.
+        let out = neqo_trans_conn.process(out.dgram(), now());
+        let out = hconn.process(out.dgram(), now());
+        let out = neqo_trans_conn.process(out.dgram(), now());
+        let _ = hconn.process(out.dgram(), now());
+        neqo_trans_conn.authenticated(AuthenticationStatus::Ok, now());
+        let out = neqo_trans_conn.process(None, now());
+        hconn.process(out.dgram(), now());
+
+        let validated = |e| {
+            matches!(e, Http3ServerEvent::StateChange { conn, state: Http3State::Connected }
+                if conn.borrow().address_validated())
+        };
+        assert!(hconn.events().any(validated));
+    }
+
     struct PeerConnection {
         conn: Connection,
         control_stream_id: u64,
@@ -299,7 +358,15 @@ mod tests {
 
     // Connect transport, send and receive settings.
     fn connect() -> (Http3Server, PeerConnection) {
-        let (mut hconn, mut neqo_trans_conn) = connect_and_receive_settings();
+        connect_server(default_http3_server(), CONTROL_STREAM_DATA)
+    }
+
+    fn connect_server(
+        hconn: Http3Server,
+        control_stream_data: &[u8],
+    ) -> (Http3Server, PeerConnection) {
+        let (mut hconn, mut neqo_trans_conn) =
+            connect_server_and_receive_settings(hconn, control_stream_data);
         let control_stream = neqo_trans_conn.stream_create(StreamType::UniDi).unwrap();
         let mut sent = neqo_trans_conn.stream_send(
             control_stream,
@@ -346,6 +413,26 @@ mod tests {
         assert_closed(&mut hconn, Error::HttpClosedCriticalStream);
     }
 
+    // Server: test that the connection will be closed if the client's QPACK encoder stream
+    // (stream 6) or decoder stream (stream 10) has been closed.
+    fn test_server_close_qpack_stream(stream_id: u64) {
+        let (mut hconn, mut peer_conn) = connect();
+        peer_conn.conn.stream_close_send(stream_id).unwrap();
+        let out = peer_conn.conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        assert_closed(&mut hconn, Error::HttpClosedCriticalStream);
+    }
+
+    #[test]
+    fn test_server_close_qpack_encoder_stream() {
+        test_server_close_qpack_stream(6);
+    }
+
+    #[test]
+    fn test_server_close_qpack_decoder_stream() {
+        test_server_close_qpack_stream(10);
+    }
+
     // Server: test missing SETTINGS frame
     // (the first frame sent is a MAX_PUSH_ID frame).
     #[test]
@@ -412,6 +499,22 @@ mod tests {
         test_wrong_frame_on_control_stream(&[0xe, 0x2, 0x1, 0x2]);
     }
 
+    // A PRIORITY_UPDATE frame for a stream that is not a request stream closes the
+    // connection with HTTP_ID_ERROR.
+    #[test]
+    fn test_server_priority_update_for_control_stream() {
+        let (mut hconn, mut peer_conn) = connect();
+
+        let _ = peer_conn.conn.stream_send(
+            peer_conn.control_stream_id,
+            &[0x80, 0x0f, 0x07, 0x00, 0x4, 0x2, 0x75, 0x3d, 0x31],
+        );
+
+        let out = peer_conn.conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        assert_closed(&mut hconn, Error::HttpIdError);
+    }
+
     // Server: receive unkonwn stream type
     // also test getting stream id that does not fit into a single byte.
     #[test]
@@ -460,6 +563,19 @@ mod tests {
         assert_closed(&mut hconn, Error::HttpStreamCreationError);
     }
 
+    // Server: a second control stream is a connection error.
+    #[test]
+    fn test_server_received_second_control_stream() {
+        let (mut hconn, mut peer_conn) = connect();
+
+        let control_stream_id = peer_conn.conn.stream_create(StreamType::UniDi).unwrap();
+        let _ = peer_conn.conn.stream_send(control_stream_id, &[0x0]);
+        let out = peer_conn.conn.process(None, now());
+        let out = hconn.process(out.dgram(), now());
+        peer_conn.conn.process(out.dgram(), now());
+        assert_closed(&mut hconn, Error::HttpStreamCreationError);
+    }
+
     //// Test reading of a slowly streamed frame. bytes are received one by one
     #[test]
     fn test_server_frame_reading() {
@@ -547,6 +663,57 @@ mod tests {
         assert_closed(&mut hconn, Error::HttpFrameUnexpected);
     }
 
+    // A request must start with a HEADERS frame. A leading frame that is never allowed on a
+    // request stream, e.g. SETTINGS, closes the connection; a leading DATA frame only resets the
+    // stream, see below.
+    fn test_wrong_first_frame_on_request_stream(v: &[u8]) {
+        let (mut hconn, mut peer_conn) = connect();
+
+        let stream_id = peer_conn.conn.stream_create(StreamType::BiDi).unwrap();
+        peer_conn.conn.stream_send(stream_id, v).unwrap();
+
+        let out = peer_conn.conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        assert_closed(&mut hconn, Error::HttpFrameUnexpected);
+    }
+
+    // A request that starts with a DATA frame is reset, the connection stays open.
+    #[test]
+    fn test_server_data_frame_first_on_request_stream() {
+        let (mut hconn, mut peer_conn) = connect();
+
+        let stream_id = peer_conn.conn.stream_create(StreamType::BiDi).unwrap();
+        peer_conn
+            .conn
+            .stream_send(stream_id, &[0x0, 0x3, 0x61, 0x62, 0x63])
+            .unwrap();
+        let out = peer_conn.conn.process(None, now());
+        let out = hconn.process(out.dgram(), now());
+        peer_conn.conn.process(out.dgram(), now());
+
+        // The app does not see the request and the connection is not closed.
+        while let Some(e) = hconn.next_event() {
+            assert!(!matches!(
+                e,
+                Http3ServerEvent::Headers { .. }
+                    | Http3ServerEvent::StateChange {
+                        state: Http3State::Closing(..),
+                        ..
+                    }
+            ));
+        }
+        let reset = |e| {
+            matches!(e, ConnectionEvent::RecvStreamReset { stream_id: id, app_error }
+                if id == stream_id && app_error == Error::HttpFrameUnexpected.code())
+        };
+        assert!(peer_conn.conn.events().any(reset));
+    }
+
+    #[test]
+    fn test_server_settings_frame_first_on_request_stream() {
+        test_wrong_first_frame_on_request_stream(&[0x4, 0x4, 0x6, 0x4, 0x8, 0x4]);
+    }
+
     // Test reading of a slowly streamed frame. bytes are received one by one
     fn test_incomplet_frame(res: &[u8]) {
         let (mut hconn, mut peer_conn) = connect_and_receive_settings();
@@ -587,6 +754,11 @@ mod tests {
         test_incomplet_frame(&[0x21]);
     }
 
+    #[test]
+    fn test_server_incomplet_reserved_frame_payload() {
+        test_incomplet_frame(&[0x21, 0x3, 0xaa]);
+    }
+
     #[test]
     fn test_server_request_with_body() {
         let (mut hconn, mut peer_conn) = connect();
@@ -797,4 +969,400 @@ mod tests {
         assert_eq!(reset, 1);
         assert_eq!(stop_sending, 1);
     }
+
+    // Send request headers and return the stream id.
+    fn send_request_headers(hconn: &mut Http3Server, peer_conn: &mut PeerConnection) -> u64 {
+        let request_stream_id = peer_conn.conn.stream_create(StreamType::BiDi).unwrap();
+        peer_conn
+            .conn
+            .stream_send(request_stream_id, &REQUEST_WITH_BODY[..20])
+            .unwrap();
+        let out = peer_conn.conn.process(None, now());
+        hconn.process(out.dgram(), now());
+        let headers = |e| matches!(e, Http3ServerEvent::Headers { .. });
+        assert!(hconn.events().any(headers));
+        request_stream_id
+    }
+
+    // One server handles several client connections, each with its own HTTP/3 state.
+    #[test]
+    fn test_server_two_connections() {
+        let (mut hconn, mut peer_conn1) = connect();
+        // Deliver what the server still has for the first client, so that the second
+        // handshake only exchanges packets of the second connection.
+        while let Output::Datagram(d) = hconn.process(None, now()) {
+            peer_conn1.conn.process(Some(d), now());
+        }
+        let (mut hconn, mut peer_conn2) = connect_server(hconn, CONTROL_STREAM_DATA);
+        assert_eq!(hconn.http3_handlers.len(), 2);
+
+        // Both clients use the first client-initiated bidirectional stream, and each gets the
+        // response to its own request.
+        for (peer_conn, body) in &mut [(&mut peer_conn1, b"one"), (&mut peer_conn2, b"two")] {
+            let request_stream_id = peer_conn.conn.stream_create(StreamType::BiDi).unwrap();
+            assert_eq!(request_stream_id, 0);
+            peer_conn
+                .conn
+                .stream_send(request_stream_id, REQUEST_WITH_BODY)
+                .unwrap();
+            peer_conn.conn.stream_close_send(request_stream_id).unwrap();
+            let out = peer_conn.conn.process(None, now());
+            hconn.process(out.dgram(), now());
+
+            while let Some(event) = hconn.next_event() {
+                if let Http3ServerEvent::Headers { mut request, .. } = event {
+                    request
+                        .set_response_with_status(200, &[], body.to_vec())
+                        .unwrap();
+                }
+            }
+            while let Output::Datagram(d) = hconn.process(None, now()) {
+                peer_conn.conn.process(Some(d), now());
+            }
+        }
+
+        let mut buf = [0u8; 100];
+        for (peer_conn, body) in &mut [(&mut peer_conn1, b"one"), (&mut peer_conn2, b"two")] {
+            let (amount, fin) = peer_conn.conn.stream_recv(0, &mut buf).unwrap();
+            assert!(fin);
+            // The response ends with a DATA frame that carries the body.
+            let mut data_frame = vec![0x00, 0x03];
+            data_frame.extend_from_slice(&body[..]);
+            assert!(buf[..amount].ends_with(&data_frame));
+        }
+    }
+
+    fn assert_reset(hconn: &mut Http3Server, request_stream_id: u64, expected: AppError) {
+        let mut reset = 0;
+        while let Some(event) = hconn.next_event() {
+            match event {
+                Http3ServerEvent::Reset { request, error } => {
+                    assert_eq!(request.stream_id(), request_stream_id);
+                    assert_eq!(error, expected);
+                    reset += 1;
+                }
+                Http3ServerEvent::Headers { .. } | Http3ServerEvent::Data { .. } => {
+                    panic!("We should not have a Headers or Data event");
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(reset, 1);
+    }
+
+    // The client resets a request stream.
+    #[test]
+    fn test_server_request_reset_by_client() {
+        let (mut hconn, mut peer_conn) = connect();
+        let request_stream_id = send_request_headers(&mut hconn, &mut peer_conn);
+
+        peer_conn
+            .conn
+            .stream_reset_send(request_stream_id, Error::HttpRequestCancelled.code())
+            .unwrap();
+        let out = peer_conn.conn.process(None, now());
+        hconn.process(out.dgram(), now());
+
+        assert_reset(
+            &mut hconn,
+            request_stream_id,
+            Error::HttpRequestCancelled.code(),
+        );
+    }
+
+    // The client stops the response with STOP_SENDING.
+    #[test]
+    fn test_server_request_stop_sending_by_client() {
+        let (mut hconn, mut peer_conn) = connect();
+        let request_stream_id = send_request_headers(&mut hconn, &mut peer_conn);
+
+        peer_conn
+            .conn
+            .stream_stop_sending(request_stream_id, Error::HttpRequestCancelled.code())
+            .unwrap();
+        let out = peer_conn.conn.process(None, now());
+        let out = hconn.process(out.dgram(), now());
+
+        assert_reset(
+            &mut hconn,
+            request_stream_id,
+            Error::HttpRequestCancelled.code(),
+        );
+
+        // The server stops reading the request as well.
+        peer_conn.conn.process(out.dgram(), now());
+        let out = hconn.process(None, now());
+        peer_conn.conn.process(out.dgram(), now());
+        let stop_sending = |e| {
+            matches!(e, ConnectionEvent::SendStreamStopSending { stream_id, .. }
+                if stream_id == request_stream_id)
+        };
+        assert!(peer_conn.conn.events().any(stop_sending));
+    }
+
+    // A request whose header list exceeds our SETTINGS_MAX_HEADER_LIST_SIZE is answered
+    // with 431 and the server stops reading it.
+    #[test]
+    fn test_server_request_header_list_too_large() {
+        let mut hconn = default_http3_server();
+        // The header list of REQUEST_WITH_BODY is 179 bytes long.
+        hconn.set_max_header_list_size(100);
+        let (mut hconn, mut peer_conn) = connect_server(
+            hconn,
+            &[
+                0x0, 0x4, 0x9, 0x1, 0x40, 0x64, 0x7, 0x40, 0x64, 0x6, 0x40, 0x64,
+            ],
+        );
+
+        let request_stream_id = peer_conn.conn.stream_create(StreamType::BiDi).unwrap();
+        peer_conn
+            .conn
+            .stream_send(request_stream_id, &REQUEST_WITH_BODY[..20])
+            .unwrap();
+        let out = peer_conn.conn.process(None, now());
+        let out = hconn.process(out.dgram(), now());
+
+        // The request is not passed to the application.
+        let headers = |e| matches!(e, Http3ServerEvent::Headers { .. });
+        assert!(!hconn.events().any(headers));
+
+        peer_conn.conn.process(out.dgram(), now());
+        let out = hconn.process(None, now());
+        peer_conn.conn.process(out.dgram(), now());
+
+        let mut stop_sending = false;
+        let mut response = false;
+        while let Some(e) = peer_conn.conn.next_event() {
+            match e {
+                ConnectionEvent::SendStreamStopSending {
+                    stream_id,
+                    app_error,
+                } => {
+                    assert_eq!(stream_id, request_stream_id);
+                    assert_eq!(app_error, Error::HttpEarlyResponse.code());
+                    stop_sending = true;
+                }
+                ConnectionEvent::RecvStreamReadable { stream_id }
+                    if stream_id == request_stream_id =>
+                {
+                    let mut buf = [0u8; 100];
+                    let (amount, fin) = peer_conn.conn.stream_recv(stream_id, &mut buf).unwrap();
+                    assert_eq!(fin, true);
+                    // Only a HEADERS frame, the response has no body.
+                    assert_eq!(buf[0], 0x1);
+                    assert_eq!(amount, 2 + buf[1] as usize);
+                    response = true;
+                }
+                _ => {}
+            }
+        }
+        assert!(stop_sending);
+        assert!(response);
+    }
+
+    #[test]
+    fn test_server_response_status() {
+        let (mut hconn, mut peer_conn) = connect();
+
+        let request_stream_id = peer_conn.conn.stream_create(StreamType::BiDi).unwrap();
+        peer_conn
+            .conn
+            .stream_send(request_stream_id, REQUEST_WITH_BODY)
+            .unwrap();
+        peer_conn.conn.stream_close_send(request_stream_id).unwrap();
+        let out = peer_conn.conn.process(None, now());
+        hconn.process(out.dgram(), now());
+
+        let mut responded = false;
+        while let Some(event) = hconn.next_event() {
+            if let Http3ServerEvent::Headers { mut request, .. } = event {
+                let content_length = [(String::from("content-length"), String::from("3"))];
+                // A response without :status is rejected.
+                assert_eq!(
+                    request.set_response(&content_length, vec![0x67, 0x68, 0x69]),
+                    Err(Error::InvalidHeader)
+                );
+                assert_eq!(
+                    request.set_response(
+                        &[(String::from(":status"), String::from("2000"))],
+                        Vec::new()
+                    ),
+                    Err(Error::InvalidHeader)
+                );
+                request
+                    .set_response_with_status(404, &content_length, vec![0x67, 0x68, 0x69])
+                    .unwrap();
+                responded = true;
+            }
+        }
+        assert!(responded);
+
+        let out = hconn.process(None, now());
+        peer_conn.conn.process(out.dgram(), now());
+
+        let mut buf = [0u8; 100];
+        let (amount, fin) = peer_conn
+            .conn
+            .stream_recv(request_stream_id, &mut buf)
+            .unwrap();
+        assert_eq!(fin, true);
+        // A HEADERS frame with :status 404 (static index 27) and content-length 3,
+        // followed by a DATA frame.
+        assert_eq!(
+            &buf[..amount],
+            &[0x01, 0x06, 0x00, 0x00, 0xdb, 0x54, 0x81, 0x67, 0x00, 0x03, 0x67, 0x68, 0x69]
+        );
+    }
+
+    // The application can collect the request body from the Data events and use it in the
+    // response.
+    #[test]
+    fn test_server_echo_request_body() {
+        let (mut hconn, mut peer_conn) = connect();
+
+        let request_stream_id = peer_conn.conn.stream_create(StreamType::BiDi).unwrap();
+        peer_conn
+            .conn
+            .stream_send(request_stream_id, REQUEST_WITH_BODY)
+            .unwrap();
+        peer_conn.conn.stream_close_send(request_stream_id).unwrap();
+        let out = peer_conn.conn.process(None, now());
+        hconn.process(out.dgram(), now());
+
+        let mut bodies = HashMap::new();
+        while let Some(event) = hconn.next_event() {
+            match event {
+                Http3ServerEvent::Headers { request, fin, .. } => {
+                    assert!(!fin);
+                    bodies.insert(request, Vec::new());
+                }
+                Http3ServerEvent::Data {
+                    mut request,
+                    data,
+                    fin,
+                } => {
+                    bodies.get_mut(&request).unwrap().extend_from_slice(&data);
+                    if fin {
+                        let body = bodies.remove(&request).unwrap();
+                        request.set_response_with_status(200, &[], body).unwrap();
+                    }
+                }
+                _ => {}
+            }
+        }
+        assert!(bodies.is_empty());
+
+        let out = hconn.process(None, now());
+        peer_conn.conn.process(out.dgram(), now());
+
+        let mut buf = [0u8; 100];
+        let (amount, fin) = peer_conn
+            .conn
+            .stream_recv(request_stream_id, &mut buf)
+            .unwrap();
+        assert!(fin);
+        // The response ends with a DATA frame that carries both DATA frames of the request.
+        assert!(buf[..amount].ends_with(&[0x00, 0x06, 0x61, 0x62, 0x63, 0x64, 0x65, 0x66]));
+    }
+
+    // A body that can't be sent at once is sent from where the transport stopped, without
+    // losing or repeating data.
+    #[test]
+    fn test_server_large_response() {
+        let (mut hconn, mut peer_conn) = connect();
+
+        let request_stream_id = peer_conn.conn.stream_create(StreamType::BiDi).unwrap();
+        peer_conn
+            .conn
+            .stream_send(request_stream_id, REQUEST_WITH_BODY)
+            .unwrap();
+        peer_conn.conn.stream_close_send(request_stream_id).unwrap();
+        let out = peer_conn.conn.process(None, now());
+        hconn.process(out.dgram(), now());
+
+        let body: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        while let Some(event) = hconn.next_event() {
+            if let Http3ServerEvent::Headers { mut request, .. } = event {
+                request
+                    .set_response_with_status(200, &[], body.clone())
+                    .unwrap();
+            }
+        }
+
+        let mut response = Vec::new();
+        let mut fin = false;
+        let mut out = hconn.process(None, now());
+        for _ in 0..1000 {
+            let ack = peer_conn.conn.process(out.dgram(), now());
+            let mut buf = [0u8; 4096];
+            loop {
+                let (amount, f) = peer_conn
+                    .conn
+                    .stream_recv(request_stream_id, &mut buf)
+                    .unwrap();
+                response.extend_from_slice(&buf[..amount]);
+                fin = f;
+                if amount == 0 || fin {
+                    break;
+                }
+            }
+            if fin {
+                break;
+            }
+            out = hconn.process(ack.dgram(), now());
+        }
+        assert!(fin);
+        // A HEADERS frame with :status 200 (static index 25) and the header of a DATA frame
+        // with a length of 100000.
+        assert_eq!(
+            &response[..10],
+            &[0x01, 0x03, 0x00, 0x00, 0xd9, 0x00, 0x80, 0x01, 0x86, 0xa0]
+        );
+        assert_eq!(&response[10..], &body[..]);
+    }
+
+    // Responses to requests with a lower urgency, set by the client in PRIORITY_UPDATE frames,
+    // are sent first.
+    #[test]
+    fn test_server_priority_update() {
+        let (mut hconn, mut peer_conn) = connect();
+
+        let mut requests = Vec::new();
+        for _ in 0..2 {
+            let request_stream_id = peer_conn.conn.stream_create(StreamType::BiDi).unwrap();
+            peer_conn
+                .conn
+                .stream_send(request_stream_id, REQUEST_WITH_BODY)
+                .unwrap();
+            peer_conn.conn.stream_close_send(request_stream_id).unwrap();
+            requests.push(request_stream_id);
+        }
+        let out = peer_conn.conn.process(None, now());
+        hconn.process(out.dgram(), now());
+
+        // PRIORITY_UPDATE for the second request with "u=1".
+        let _ = peer_conn.conn.stream_send(
+            peer_conn.control_stream_id,
+            &[0x80, 0x0f, 0x07, 0x00, 0x4, 0x4, 0x75, 0x3d, 0x31],
+        );
+        let out = peer_conn.conn.process(None, now());
+        hconn.process(out.dgram(), now());
+
+        let mut responses = 0;
+        while let Some(event) = hconn.next_event() {
+            if let Http3ServerEvent::Headers { mut request, .. } = event {
+                request
+                    .set_response_with_status(200, &[], Vec::new())
+                    .unwrap();
+                responses += 1;
+            }
+        }
+        assert_eq!(responses, 2);
+
+        let handler = hconn.http3_handlers.values().next().unwrap().clone();
+        assert_eq!(
+            handler.borrow().streams_in_send_order(),
+            vec![requests[1], requests[0]]
+        );
+    }
 }
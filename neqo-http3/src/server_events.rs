@@ -13,6 +13,7 @@ use neqo_transport::{AppError, Connection};
 
 use std::cell::RefCell;
 use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
 use std::rc::Rc;
 
 #[derive(Debug, Clone)]
@@ -33,6 +34,23 @@ impl ::std::fmt::Display for ClientRequestStream {
     }
 }
 
+// Two handles are equal if they refer to the same stream, so that the application can keep
+// state for a request, e.g. the body that has been received so far, in a map.
+impl PartialEq for ClientRequestStream {
+    fn eq(&self, other: &Self) -> bool {
+        self.conn == other.conn && self.stream_id == other.stream_id
+    }
+}
+
+impl Eq for ClientRequestStream {}
+
+impl Hash for ClientRequestStream {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.conn.hash(state);
+        self.stream_id.hash(state);
+    }
+}
+
 impl ClientRequestStream {
     pub fn new(
         conn: ActiveConnectionRef,
@@ -45,6 +63,11 @@ impl ClientRequestStream {
             stream_id,
         }
     }
+
+    pub fn stream_id(&self) -> u64 {
+        self.stream_id
+    }
+
     pub fn set_response(&mut self, headers: &[Header], data: Vec<u8>) -> Res<()> {
         qinfo!([self], "Set new response.");
         self.handler
@@ -52,6 +75,20 @@ impl ClientRequestStream {
             .set_response(self.stream_id, headers, data)
     }
 
+    /// Like `set_response`, but `status` is sent as the `:status` pseudo-header
+    /// ahead of `headers`.
+    pub fn set_response_with_status(
+        &mut self,
+        status: u16,
+        headers: &[Header],
+        data: Vec<u8>,
+    ) -> Res<()> {
+        let mut response = Vec::with_capacity(headers.len() + 1);
+        response.push((String::from(":status"), status.to_string()));
+        response.extend_from_slice(headers);
+        self.set_response(&response, data)
+    }
+
     pub fn stream_stop_sending(&mut self, app_error: AppError) -> Res<()> {
         qdebug!(
             [self],
@@ -73,6 +110,17 @@ impl ClientRequestStream {
             app_error,
         )
     }
+
+    /// Abort a response that is being sent, see `Http3Connection::reset_response`. The
+    /// client sees the request as reset with `app_error`.
+    pub fn reset_response(&mut self, app_error: AppError) -> Res<()> {
+        qdebug!([self], "reset response error:{}.", app_error);
+        self.handler.borrow_mut().reset_response(
+            &mut self.conn.borrow_mut(),
+            self.stream_id,
+            app_error,
+        )
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -89,6 +137,12 @@ pub enum Http3ServerEvent {
         data: Vec<u8>,
         fin: bool,
     },
+    /// The client reset the request stream, or stopped the response with STOP_SENDING.
+    /// The stream is closed.
+    Reset {
+        request: ClientRequestStream,
+        error: AppError,
+    },
     /// When individual connection change state. It is only used for tests.
     StateChange {
         conn: ActiveConnectionRef,
@@ -133,4 +187,8 @@ impl Http3ServerEvents {
     pub fn data(&self, request: ClientRequestStream, data: Vec<u8>, fin: bool) {
         self.insert(Http3ServerEvent::Data { request, data, fin });
     }
+
+    pub fn reset(&self, request: ClientRequestStream, error: AppError) {
+        self.insert(Http3ServerEvent::Reset { request, error });
+    }
 }
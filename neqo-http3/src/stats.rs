@@ -0,0 +1,200 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Tracking of some useful HTTP/3 statistics.
+
+#[cfg(feature = "frame-trace")]
+use crate::frame_trace::{FrameDirection, FrameEvent, FrameTracer};
+use crate::hframe::{HFrame, HFrameType};
+#[cfg(feature = "frame-trace")]
+use neqo_common::Encoder;
+use std::cell::RefCell;
+use std::collections::HashMap;
+#[cfg(feature = "frame-trace")]
+use std::fmt;
+use std::rc::Rc;
+use std::time::Duration;
+
+#[derive(Default, Debug, Clone, PartialEq)]
+/// HTTP/3 connection statistics
+pub struct Http3Stats {
+    /// Received frames that were ignored, e.g. frames of unknown or reserved types,
+    /// counted by frame type
+    pub ignored_frames: HashMap<u64, u64>,
+    /// Whether the server sent a Retry, after which the client restarted the handshake
+    pub retry_received: bool,
+    /// The destination connection ID that the Retry provided
+    pub retry_dcid: Option<Vec<u8>>,
+    /// The QUIC versions that the server offered in a Version Negotiation packet
+    pub vn_versions: Vec<u32>,
+    /// Requests sent by a client or received by a server
+    pub requests: u64,
+    /// Bytes of header blocks produced by the QPACK encoder
+    pub header_bytes_encoded: u64,
+    /// Payload bytes of DATA frames sent
+    pub data_bytes_sent: u64,
+    /// Payload bytes of DATA frames received
+    pub data_bytes_received: u64,
+    /// Capacity of the QPACK encoder's dynamic table
+    pub qpack_encoder_table_capacity: u64,
+    /// Bytes in use in the QPACK encoder's dynamic table
+    pub qpack_encoder_table_used: u64,
+    /// Capacity of the QPACK decoder's dynamic table
+    pub qpack_decoder_table_capacity: u64,
+    /// Bytes in use in the QPACK decoder's dynamic table
+    pub qpack_decoder_table_used: u64,
+    /// Packets sent by the transport
+    pub packets_tx: usize,
+    /// Packets received by the transport
+    pub packets_rx: usize,
+    /// Bytes sent by the transport
+    pub bytes_tx: usize,
+    /// Bytes received by the transport
+    pub bytes_rx: usize,
+    /// Packets declared lost and retransmitted by the transport
+    pub lost_packets: usize,
+    /// The current congestion window, in bytes
+    pub congestion_window: usize,
+    /// The current slow start threshold, in bytes; `usize::MAX` until the first congestion
+    /// event
+    pub slow_start_threshold: usize,
+    /// Datagrams received with the ECT(0) codepoint
+    pub ect0_rx: usize,
+    /// Datagrams received with the ECT(1) codepoint
+    pub ect1_rx: usize,
+    /// Datagrams received with the CE codepoint
+    pub ce_rx: usize,
+    /// Time that request streams spent waiting for QPACK encoder instructions before their
+    /// headers could be decoded, added up over all streams
+    pub qpack_blocked_time: Duration,
+}
+
+/// The frame tracer of a connection, if one is set.
+#[cfg(feature = "frame-trace")]
+#[derive(Default, Clone)]
+struct SharedTracer(Rc<RefCell<Option<Box<dyn FrameTracer>>>>);
+
+#[cfg(feature = "frame-trace")]
+impl fmt::Debug for SharedTracer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FrameTracer set={}", self.0.borrow().is_some())
+    }
+}
+
+/// The statistics of a connection are shared by all of its frame readers. With the
+/// `frame-trace` feature, the frames they see also go to the frame tracer.
+#[derive(Default, Debug, Clone)]
+pub struct Http3StatsRecorder {
+    stats: Rc<RefCell<Http3Stats>>,
+    #[cfg(feature = "frame-trace")]
+    tracer: SharedTracer,
+}
+
+impl Http3StatsRecorder {
+    pub fn frame_ignored(&self, frame_type: u64) {
+        *self
+            .stats
+            .borrow_mut()
+            .ignored_frames
+            .entry(frame_type)
+            .or_insert(0) += 1;
+    }
+
+    pub fn request_created(&self) {
+        self.stats.borrow_mut().requests += 1;
+    }
+
+    pub fn headers_encoded(&self, len: usize) {
+        self.stats.borrow_mut().header_bytes_encoded += len as u64;
+    }
+
+    pub fn data_sent(&self, len: usize) {
+        self.stats.borrow_mut().data_bytes_sent += len as u64;
+    }
+
+    pub fn data_received(&self, len: usize) {
+        self.stats.borrow_mut().data_bytes_received += len as u64;
+    }
+
+    pub fn qpack_blocked(&self, duration: Duration) {
+        self.stats.borrow_mut().qpack_blocked_time += duration;
+    }
+
+    pub fn stats(&self) -> Http3Stats {
+        self.stats.borrow().clone()
+    }
+
+    #[cfg(feature = "frame-trace")]
+    pub fn set_frame_tracer(&self, tracer: Box<dyn FrameTracer>) {
+        *self.tracer.0.borrow_mut() = Some(tracer);
+    }
+
+    #[cfg(feature = "frame-trace")]
+    pub fn has_frame_tracer(&self) -> bool {
+        self.tracer.0.borrow().is_some()
+    }
+
+    #[cfg(feature = "frame-trace")]
+    fn trace_frame(&self, event: &FrameEvent) {
+        if let Some(tracer) = self.tracer.0.borrow_mut().as_mut() {
+            tracer.frame(event);
+        }
+    }
+
+    #[cfg(feature = "frame-trace")]
+    pub fn frame_sent(&self, stream_id: u64, frame: &HFrame) {
+        if !self.has_frame_tracer() {
+            return;
+        }
+        // Only DATA and HEADERS frames are encoded without their payload.
+        let mut enc = Encoder::default();
+        frame.encode(&mut enc);
+        let mut dec = enc.as_decoder();
+        let frame_type = dec.decode_varint().unwrap();
+        let len = dec.decode_varint().unwrap();
+        self.trace_frame(&FrameEvent {
+            direction: FrameDirection::Sent,
+            stream_id,
+            frame_type,
+            len,
+            payload: dec.decode_remainder(),
+        });
+    }
+
+    #[cfg(not(feature = "frame-trace"))]
+    pub fn frame_sent(&self, _stream_id: u64, _frame: &HFrame) {}
+
+    #[cfg(feature = "frame-trace")]
+    pub fn frame_received(&self, stream_id: u64, frame_type: HFrameType, len: u64, payload: &[u8]) {
+        self.trace_frame(&FrameEvent {
+            direction: FrameDirection::Received,
+            stream_id,
+            frame_type,
+            len,
+            payload,
+        });
+    }
+
+    #[cfg(not(feature = "frame-trace"))]
+    pub fn frame_received(
+        &self,
+        _stream_id: u64,
+        _frame_type: HFrameType,
+        _len: u64,
+        _payload: &[u8],
+    ) {
+    }
+
+    /// Pass the entries inserted into the QPACK tables to the frame tracer.
+    #[cfg(feature = "frame-trace")]
+    pub fn qpack_inserted(&self, direction: FrameDirection, inserts: &[(Vec<u8>, Vec<u8>)]) {
+        if let Some(tracer) = self.tracer.0.borrow_mut().as_mut() {
+            for (name, value) in inserts {
+                tracer.qpack_insert(direction, name, value);
+            }
+        }
+    }
+}
@@ -7,16 +7,19 @@
 use crate::hframe::{HFrame, HFrameReader};
 
 use crate::client_events::Http3ClientEvents;
-use crate::connection::Http3Transaction;
+use crate::connection::{header_list_size, Http3Transaction};
+use crate::stats::Http3StatsRecorder;
 use crate::Header;
-use neqo_common::{qdebug, qinfo, qtrace, Encoder};
+use neqo_common::{matches, qdebug, qinfo, qlabel, qtrace, Encoder};
 use neqo_qpack::decoder::QPackDecoder;
 use neqo_qpack::encoder::QPackEncoder;
-use neqo_transport::Connection;
+use neqo_qpack::Error as QpackError;
+use neqo_transport::{AppError, Connection};
 
 use crate::{Error, Res};
 use std::cmp::min;
 use std::mem;
+use std::time::Duration;
 
 const MAX_DATA_HEADER_SIZE_2: usize = (1 << 6) - 1; // Maximal amount of data with DATA frame header size 2
 const MAX_DATA_HEADER_SIZE_2_LIMIT: usize = MAX_DATA_HEADER_SIZE_2 + 3; // 63 + 3 (size of the next buffer data frame header)
@@ -46,23 +49,53 @@ impl Request {
             buf: None,
         };
         r.headers.push((":method".into(), method.to_owned()));
-        r.headers.push((":scheme".into(), r.scheme.clone()));
-        r.headers.push((":authority".into(), r.host.clone()));
-        r.headers.push((":path".into(), r.path.clone()));
+        // A CONNECT request has no :scheme and :path (RFC 9114, Section 4.4).
+        if r.is_connect() {
+            r.headers.push((":authority".into(), r.host.clone()));
+        } else {
+            r.headers.push((":scheme".into(), r.scheme.clone()));
+            r.headers.push((":authority".into(), r.host.clone()));
+            r.headers.push((":path".into(), r.path.clone()));
+        }
         r.headers.extend_from_slice(headers);
         r
     }
 
-    pub fn ensure_encoded(&mut self, encoder: &mut QPackEncoder, stream_id: u64) {
+    pub fn is_connect(&self) -> bool {
+        self.method == "CONNECT"
+    }
+
+    // The size of a header field is the length of its name and value plus an overhead of
+    // 32 bytes (RFC 7541, Section 4.1).
+    pub fn header_list_size(&self) -> u64 {
+        header_list_size(&self.headers)
+    }
+
+    // The request body is only sent after the server has agreed with a 100 (Continue)
+    // response (RFC 7231, Section 5.1.1).
+    pub fn expects_continue(&self) -> bool {
+        self.headers.iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case("expect") && value.eq_ignore_ascii_case("100-continue")
+        })
+    }
+
+    pub fn ensure_encoded(
+        &mut self,
+        encoder: &mut QPackEncoder,
+        stream_id: u64,
+        stats: &Http3StatsRecorder,
+    ) {
         if self.buf.is_some() {
             return;
         }
 
         qinfo!([self], "Encoding headers for {}/{}", self.host, self.path);
         let encoded_headers = encoder.encode_header_block(&self.headers, stream_id);
+        stats.headers_encoded(encoded_headers.len());
         let f = HFrame::Headers {
             len: encoded_headers.len() as u64,
         };
+        stats.frame_sent(stream_id, &f);
         let mut d = Encoder::default();
         f.encode(&mut d);
         d.encode(&encoded_headers[..]);
@@ -74,13 +107,10 @@ impl Request {
         conn: &mut Connection,
         encoder: &mut QPackEncoder,
         stream_id: u64,
+        stats: &Http3StatsRecorder,
     ) -> Res<bool> {
-        let label = if ::log::log_enabled!(::log::Level::Debug) {
-            format!("{}", self)
-        } else {
-            String::new()
-        };
-        self.ensure_encoded(encoder, stream_id);
+        let label = qlabel!(self);
+        self.ensure_encoded(encoder, stream_id, stats);
         if let Some(buf) = &mut self.buf {
             let sent = conn.stream_send(stream_id, &buf)?;
             qinfo!([label], "{} bytes sent", sent);
@@ -107,9 +137,15 @@ impl ::std::fmt::Display for Request {
 
 /*
  *  Transaction send states:
- *    SendingHeaders : sending headers. From here we may switch to SendingData
- *                     or Closed (if the app does not want to send data and
- *                     has alreadyclosed the send stream).
+ *    SendingHeaders : sending headers. From here we may switch to SendingData,
+ *                     WaitingForContinue or Closed (if the app does not want
+ *                     to send data and has alreadyclosed the send stream).
+ *    WaitingForContinue : the request has "expect: 100-continue"; the body is
+ *                         sent only after a 100 response. If a final response
+ *                         arrives instead, the sending side is reset.
+ *    WaitingForTunnel : the request is a CONNECT; tunnel data is sent only
+ *                       after a 2xx response. Any other final response resets
+ *                       the sending side.
  *    SendingData : We are sending request data until the app closes the stream.
  *    Closed
  */
@@ -117,6 +153,8 @@ impl ::std::fmt::Display for Request {
 #[derive(PartialEq, Debug)]
 enum TransactionSendState {
     SendingHeaders { request: Request, fin: bool },
+    WaitingForContinue,
+    WaitingForTunnel,
     SendingData,
     Closed,
 }
@@ -148,7 +186,6 @@ enum TransactionRecvState {
     BlockedDecodingHeaders { buf: Vec<u8>, fin: bool },
     WaitingForData,
     ReadingData { remaining_data_len: usize },
-    //    ReadingTrailers,
     ClosePending, // Close must first be read by application
     Closed,
 }
@@ -160,6 +197,48 @@ enum ResponseHeadersState {
     Read,
 }
 
+#[derive(Debug, PartialEq)]
+enum TrailersState {
+    NoTrailers,
+    Ready(Vec<Header>),
+    Read,
+}
+
+fn response_status(headers: &[Header]) -> Option<u16> {
+    headers
+        .iter()
+        .find(|(name, _)| name == ":status")
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+/// Returns the size of a request's header list, including the pseudo-headers, as it is
+/// compared with the peer's SETTINGS_MAX_HEADER_LIST_SIZE.
+pub fn request_header_list_size(
+    method: &str,
+    scheme: &str,
+    host: &str,
+    path: &str,
+    headers: &[Header],
+) -> u64 {
+    Request::new(method, scheme, host, path, headers).header_list_size()
+}
+
+/// Returns the HEADERS frame of a request as it is written to a request stream when the
+/// QPACK dynamic table is not used.
+pub fn encode_request(
+    method: &str,
+    scheme: &str,
+    host: &str,
+    path: &str,
+    headers: &[Header],
+) -> Vec<u8> {
+    let mut request = Request::new(method, scheme, host, path, headers);
+    // A new encoder has no dynamic table capacity, so only the static table is used.
+    let mut encoder = QPackEncoder::new(true);
+    request.ensure_encoded(&mut encoder, 0, &Http3StatsRecorder::default());
+    request.buf.take().unwrap()
+}
+
 //  This is used for normal request/responses.
 #[derive(Debug)]
 pub struct TransactionClient {
@@ -168,10 +247,22 @@ pub struct TransactionClient {
     stream_id: u64,
     frame_reader: HFrameReader,
     response_headers_state: ResponseHeadersState,
+    data_frame_received: bool,
+    trailers_state: TrailersState,
     conn_events: Http3ClientEvents,
+    stats: Http3StatsRecorder,
+    // The request headers, including the pseudo-headers, as they were passed to the encoder.
+    request_headers: Vec<Header>,
+    // The time that the response headers waited for QPACK encoder instructions.
+    qpack_blocked_time: Duration,
+    // Whether a response header block referred to the QPACK dynamic table.
+    used_dynamic_table: bool,
+    // The error code of the server's STOP_SENDING, if it stopped the request body.
+    send_side_reset: Option<AppError>,
 }
 
 impl TransactionClient {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         stream_id: u64,
         method: &str,
@@ -180,21 +271,51 @@ impl TransactionClient {
         path: &str,
         headers: &[Header],
         conn_events: Http3ClientEvents,
+        stats: Http3StatsRecorder,
     ) -> Self {
         qinfo!("Create a request stream_id={}", stream_id);
+        let request = Request::new(method, scheme, host, path, headers);
+        let request_headers = request.headers.clone();
         Self {
             send_state: TransactionSendState::SendingHeaders {
-                request: Request::new(method, scheme, host, path, headers),
+                request,
                 fin: false,
             },
             recv_state: TransactionRecvState::WaitingForResponseHeaders,
             stream_id,
             response_headers_state: ResponseHeadersState::NoHeaders,
-            frame_reader: HFrameReader::new(),
+            data_frame_received: false,
+            trailers_state: TrailersState::NoTrailers,
+            frame_reader: HFrameReader::with_stats(stats.clone()),
             conn_events,
+            stats,
+            request_headers,
+            qpack_blocked_time: Duration::default(),
+            used_dynamic_table: false,
+            send_side_reset: None,
         }
     }
 
+    pub fn request_headers(&self) -> &[Header] {
+        &self.request_headers
+    }
+
+    pub fn qpack_blocked_time(&self) -> Duration {
+        self.qpack_blocked_time
+    }
+
+    pub fn used_dynamic_table(&self) -> bool {
+        self.used_dynamic_table
+    }
+
+    pub fn set_send_side_reset(&mut self, app_err: AppError) {
+        self.send_side_reset = Some(app_err);
+    }
+
+    pub fn send_side_reset(&self) -> Option<AppError> {
+        self.send_side_reset
+    }
+
     pub fn send_request_body(&mut self, conn: &mut Connection, buf: &[u8]) -> Res<usize> {
         qinfo!(
             [self],
@@ -203,7 +324,9 @@ impl TransactionClient {
             buf.len()
         );
         match self.send_state {
-            TransactionSendState::SendingHeaders { .. } => Ok(0),
+            TransactionSendState::SendingHeaders { .. }
+            | TransactionSendState::WaitingForContinue
+            | TransactionSendState::WaitingForTunnel => Ok(0),
             TransactionSendState::SendingData => {
                 let available = conn.stream_avail_send_space(self.stream_id)? as usize;
                 if available <= 2 {
@@ -233,6 +356,7 @@ impl TransactionClient {
                 let data_frame = HFrame::Data {
                     len: to_send as u64,
                 };
+                self.stats.frame_sent(self.stream_id, &data_frame);
                 let mut enc = Encoder::default();
                 data_frame.encode(&mut enc);
                 match conn.stream_send(self.stream_id, &enc) {
@@ -242,7 +366,10 @@ impl TransactionClient {
                     Err(e) => return Err(Error::TransportError(e)),
                 }
                 match conn.stream_send(self.stream_id, &buf[..to_send]) {
-                    Ok(sent) => Ok(sent),
+                    Ok(sent) => {
+                        self.stats.data_sent(sent);
+                        Ok(sent)
+                    }
                     Err(e) => Err(Error::TransportError(e)),
                 }
             }
@@ -259,7 +386,9 @@ impl TransactionClient {
         );
         match frame {
             HFrame::Headers { len } => self.handle_headers_frame(len, fin),
-            HFrame::PushPromise { .. } => Err(Error::HttpIdError),
+            // Push is not supported, so no push ID is allowed and the push that a
+            // DUPLICATE_PUSH refers to cannot exist.
+            HFrame::PushPromise { .. } | HFrame::DuplicatePush { .. } => Err(Error::HttpIdError),
             _ => Err(Error::HttpFrameUnexpected),
         }
     }
@@ -286,18 +415,31 @@ impl TransactionClient {
             frame,
             self.recv_state
         );
+        if self.trailers_state != TrailersState::NoTrailers {
+            // No frame may follow the trailers.
+            return Err(Error::HttpFrameUnexpected);
+        }
         match frame {
             HFrame::Data { len } => self.handle_data_frame(len, fin),
-            HFrame::PushPromise { .. } => Err(Error::HttpIdError),
-            HFrame::Headers { .. } => {
-                // TODO implement trailers!
-                Err(Error::HttpFrameUnexpected)
-            }
+            HFrame::PushPromise { .. } | HFrame::DuplicatePush { .. } => Err(Error::HttpIdError),
+            HFrame::Headers { len } => self.handle_trailers_frame(len, fin),
             _ => Err(Error::HttpFrameUnexpected),
         }
     }
 
+    fn handle_trailers_frame(&mut self, len: u64, fin: bool) -> Res<()> {
+        if len == 0 || fin {
+            return Err(Error::HttpFrameError);
+        }
+        self.recv_state = TransactionRecvState::ReadingHeaders {
+            buf: vec![0; len as usize],
+            offset: 0,
+        };
+        Ok(())
+    }
+
     fn handle_data_frame(&mut self, len: u64, fin: bool) -> Res<()> {
+        self.data_frame_received = true;
         if len > 0 {
             if fin {
                 return Err(Error::HttpFrameError);
@@ -319,6 +461,88 @@ impl TransactionClient {
         Ok(())
     }
 
+    // A decoded header block is an informational (1xx) response or the response headers if
+    // they have not been received yet, otherwise it is the trailers.
+    fn headers_decoded(&mut self, conn: &mut Connection, headers: Vec<Header>) -> Res<()> {
+        if self.response_headers_state != ResponseHeadersState::NoHeaders {
+            return self.add_trailers(conn, headers);
+        }
+        match response_status(&headers) {
+            Some(status) if (100..200).contains(&status) => {
+                self.add_informational_headers(status, headers);
+                Ok(())
+            }
+            Some(status)
+                if (200..300).contains(&status)
+                    && self.send_state == TransactionSendState::WaitingForTunnel =>
+            {
+                qinfo!([self], "The tunnel is established.");
+                self.send_state = TransactionSendState::SendingData;
+                self.conn_events.data_writable(self.stream_id);
+                self.add_headers(Some(headers))
+            }
+            _ => {
+                if matches!(
+                    self.send_state,
+                    TransactionSendState::WaitingForContinue
+                        | TransactionSendState::WaitingForTunnel
+                ) {
+                    qinfo!(
+                        [self],
+                        "Final response received, the request body is not sent."
+                    );
+                    let _ =
+                        conn.stream_reset_send(self.stream_id, Error::HttpRequestCancelled.code());
+                    self.send_state = TransactionSendState::Closed;
+                }
+                self.add_headers(Some(headers))
+            }
+        }
+    }
+
+    fn add_informational_headers(&mut self, status: u16, headers: Vec<Header>) {
+        if status == 100 && self.send_state == TransactionSendState::WaitingForContinue {
+            qinfo!([self], "100 (Continue) received, send the request body.");
+            self.send_state = TransactionSendState::SendingData;
+            self.conn_events.data_writable(self.stream_id);
+        }
+        self.conn_events
+            .informational_headers(self.stream_id, headers);
+        // The final response headers are still to come.
+        self.recv_state = TransactionRecvState::WaitingForResponseHeaders;
+    }
+
+    fn add_trailers(&mut self, conn: &mut Connection, trailers: Vec<Header>) -> Res<()> {
+        if trailers.iter().any(|(name, _)| name.starts_with(':')) {
+            qinfo!([self], "Trailers must not contain pseudo-headers.");
+            return self.stream_error(conn, Error::HttpGeneralProtocolError);
+        }
+        self.trailers_state = TrailersState::Ready(trailers);
+        self.conn_events.trailers_ready(self.stream_id);
+        self.recv_state = TransactionRecvState::WaitingForData;
+        Ok(())
+    }
+
+    // Close both sides of the stream with `error` and inform the application. The connection
+    // stays open.
+    fn stream_error(&mut self, conn: &mut Connection, error: Error) -> Res<()> {
+        qinfo!([self], "Reset the stream with error {:?}.", error);
+        // The stream may already be closed, we do not care about errors here.
+        let _ = conn.stream_stop_sending(self.stream_id, error.code());
+        let _ = conn.stream_reset_send(self.stream_id, error.code());
+        self.conn_events.reset(self.stream_id, error.code());
+        self.send_state = TransactionSendState::Closed;
+        self.recv_state = TransactionRecvState::Closed;
+        Ok(())
+    }
+
+    // The QPACK decoder stopped decoding a header block that exceeds our
+    // SETTINGS_MAX_HEADER_LIST_SIZE.
+    fn header_list_too_large(&mut self, conn: &mut Connection) -> Res<()> {
+        qinfo!([self], "The header list exceeds our limit.");
+        self.stream_error(conn, Error::HttpExcessiveLoad)
+    }
+
     fn set_state_to_close_pending(&mut self) {
         // Stream has received fin. Depending on headers state set header_ready
         // or data_readable event so that app can pick up the fin.
@@ -363,11 +587,7 @@ impl TransactionClient {
         conn: &mut Connection,
         decoder: &mut QPackDecoder,
     ) -> Res<bool> {
-        let label = if ::log::log_enabled!(::log::Level::Debug) {
-            format!("{}", self)
-        } else {
-            String::new()
-        };
+        let label = qlabel!(self);
         if let TransactionRecvState::ReadingHeaders {
             ref mut buf,
             ref mut offset,
@@ -389,21 +609,32 @@ impl TransactionClient {
                 [label],
                 "read_headers: read all headers, try decoding them."
             );
-            match decoder.decode_header_block(buf, self.stream_id)? {
-                Some(headers) => {
-                    self.add_headers(Some(headers))?;
+            if QPackDecoder::refers_to_dynamic_table(buf) {
+                self.used_dynamic_table = true;
+            }
+            match decoder.decode_header_block(buf, self.stream_id) {
+                Ok(Some(headers)) => {
+                    self.headers_decoded(conn, headers)?;
+                    if self.recv_state == TransactionRecvState::Closed {
+                        return Ok(true);
+                    }
                     if fin {
                         self.set_state_to_close_pending();
                     }
                     Ok(fin)
                 }
-                None => {
+                Ok(None) => {
                     let mut tmp: Vec<u8> = Vec::new();
                     mem::swap(&mut tmp, buf);
                     self.recv_state =
                         TransactionRecvState::BlockedDecodingHeaders { buf: tmp, fin };
                     Ok(true)
                 }
+                Err(QpackError::HeaderListTooLarge) => {
+                    self.header_list_too_large(conn)?;
+                    Ok(true)
+                }
+                Err(e) => Err(e.into()),
             }
         } else {
             panic!("This is only called when recv_state is ReadingHeaders.");
@@ -413,7 +644,9 @@ impl TransactionClient {
     pub fn is_sending_closed(&self) -> bool {
         match self.send_state {
             TransactionSendState::SendingHeaders { fin, .. } => fin,
-            TransactionSendState::SendingData => false,
+            TransactionSendState::WaitingForContinue
+            | TransactionSendState::WaitingForTunnel
+            | TransactionSendState::SendingData => false,
             _ => true,
         }
     }
@@ -453,6 +686,7 @@ impl TransactionClient {
                 let (amount, fin) = conn.stream_recv(self.stream_id, &mut buf[..to_read])?;
                 debug_assert!(amount <= to_read);
                 *remaining_data_len -= amount;
+                self.stats.data_received(amount);
 
                 if fin {
                     if *remaining_data_len > 0 {
@@ -473,6 +707,25 @@ impl TransactionClient {
         }
     }
 
+    pub fn read_response_trailers(&mut self) -> Res<Vec<Header>> {
+        match mem::replace(&mut self.trailers_state, TrailersState::Read) {
+            TrailersState::Ready(trailers) => Ok(trailers),
+            state => {
+                self.trailers_state = state;
+                Err(Error::Unavailable)
+            }
+        }
+    }
+
+    /// Trailers have been received, but the application has not read them yet.
+    pub fn has_unread_trailers(&self) -> bool {
+        matches!(self.trailers_state, TrailersState::Ready(..))
+    }
+
+    pub fn is_recv_closed(&self) -> bool {
+        self.recv_state == TransactionRecvState::Closed
+    }
+
     pub fn is_state_sending_data(&self) -> bool {
         self.send_state == TransactionSendState::SendingData
     }
@@ -486,21 +739,23 @@ impl ::std::fmt::Display for TransactionClient {
 
 impl Http3Transaction for TransactionClient {
     fn send(&mut self, conn: &mut Connection, encoder: &mut QPackEncoder) -> Res<()> {
-        let label = if ::log::log_enabled!(::log::Level::Debug) {
-            format!("{}", self)
-        } else {
-            String::new()
-        };
+        let label = qlabel!(self);
         if let TransactionSendState::SendingHeaders {
             ref mut request,
             fin,
         } = self.send_state
         {
-            if request.send(conn, encoder, self.stream_id)? {
+            if request.send(conn, encoder, self.stream_id, &self.stats)? {
                 if fin {
                     conn.stream_close_send(self.stream_id)?;
                     self.send_state = TransactionSendState::Closed;
                     qinfo!([label], "done sending request");
+                } else if request.expects_continue() {
+                    self.send_state = TransactionSendState::WaitingForContinue;
+                    qinfo!([label], "change to state WaitingForContinue");
+                } else if request.is_connect() {
+                    self.send_state = TransactionSendState::WaitingForTunnel;
+                    qinfo!([label], "change to state WaitingForTunnel");
                 } else {
                     self.send_state = TransactionSendState::SendingData;
                     self.conn_events.data_writable(self.stream_id);
@@ -512,11 +767,7 @@ impl Http3Transaction for TransactionClient {
     }
 
     fn receive(&mut self, conn: &mut Connection, decoder: &mut QPackDecoder) -> Res<()> {
-        let label = if ::log::log_enabled!(::log::Level::Debug) {
-            format!("{}", self)
-        } else {
-            String::new()
-        };
+        let label = qlabel!(self);
         loop {
             qdebug!(
                 [label],
@@ -528,6 +779,10 @@ impl Http3Transaction for TransactionClient {
                 TransactionRecvState::WaitingForResponseHeaders => {
                     match self.recv_frame_header(conn)? {
                         None => break Ok(()),
+                        Some((HFrame::Data { .. }, _)) => {
+                            qinfo!([label], "A response must start with a HEADERS frame.");
+                            break self.stream_error(conn, Error::HttpFrameUnexpected);
+                        }
                         Some((f, fin)) => {
                             self.handle_frame_in_state_waiting_for_headers(f, fin)?;
                             if fin {
@@ -543,23 +798,34 @@ impl Http3Transaction for TransactionClient {
                     }
                 }
                 TransactionRecvState::BlockedDecodingHeaders { ref buf, fin } => {
-                    match decoder.decode_header_block(buf, self.stream_id)? {
-                        Some(headers) => {
-                            self.add_headers(Some(headers))?;
+                    match decoder.decode_header_block(buf, self.stream_id) {
+                        Ok(Some(headers)) => {
+                            self.headers_decoded(conn, headers)?;
+                            if self.recv_state == TransactionRecvState::Closed {
+                                break Ok(());
+                            }
                             if fin {
                                 self.set_state_to_close_pending();
                                 break Ok(());
                             }
                         }
-                        None => {
+                        Ok(None) => {
                             qinfo!([self], "decoding header is blocked.");
                             break Ok(());
                         }
+                        Err(QpackError::HeaderListTooLarge) => {
+                            break self.header_list_too_large(conn);
+                        }
+                        Err(e) => break Err(e.into()),
                     }
                 }
                 TransactionRecvState::WaitingForData => {
                     match self.recv_frame_header(conn)? {
                         None => break Ok(()),
+                        Some((HFrame::Headers { .. }, _)) if !self.data_frame_received => {
+                            qinfo!([label], "Trailers must follow a DATA frame.");
+                            break self.stream_error(conn, Error::HttpFrameUnexpected);
+                        }
                         Some((f, fin)) => {
                             self.handle_frame_in_state_waiting_for_data(f, fin)?;
                             if fin {
@@ -570,10 +836,13 @@ impl Http3Transaction for TransactionClient {
                     };
                 }
                 TransactionRecvState::ReadingData { .. } => {
-                    self.conn_events.data_readable(self.stream_id);
+                    // Only tell the app to read if there is something on the stream, e.g.
+                    // the app may have drained the stream while the frame is not complete.
+                    if conn.stream_readable(self.stream_id)? {
+                        self.conn_events.data_readable(self.stream_id);
+                    }
                     break Ok(());
                 }
-                // TransactionRecvState::ReadingTrailers => break Ok(()),
                 TransactionRecvState::ClosePending => {
                     panic!("Stream readable after being closed!");
                 }
@@ -617,4 +886,8 @@ impl Http3Transaction for TransactionClient {
         }
         Ok(())
     }
+
+    fn qpack_unblocked(&mut self, blocked: Duration) {
+        self.qpack_blocked_time += blocked;
+    }
 }
@@ -7,13 +7,30 @@
 use crate::connection::Http3Transaction;
 use crate::hframe::{HFrame, HFrameReader};
 use crate::server_connection_events::Http3ServerConnEvents;
+use crate::stats::Http3StatsRecorder;
 use crate::Header;
 use crate::{Error, Res};
-use neqo_common::{matches, qdebug, qinfo, qtrace, Encoder};
+use neqo_common::{matches, qdebug, qinfo, qlabel, qtrace, Encoder};
 use neqo_qpack::decoder::QPackDecoder;
 use neqo_qpack::encoder::QPackEncoder;
+use neqo_qpack::Error as QpackError;
 use neqo_transport::Connection;
 use std::mem;
+use std::time::Duration;
+
+/// Check that a response has a `:status` pseudo-header with a 3-digit status code.
+pub(crate) fn check_response_headers(headers: &[Header]) -> Res<()> {
+    match headers.iter().find(|(name, _)| name == ":status") {
+        Some((_, value))
+            if value.len() == 3
+                && value.bytes().all(|b| b.is_ascii_digit())
+                && !value.starts_with('0') =>
+        {
+            Ok(())
+        }
+        _ => Err(Error::InvalidHeader),
+    }
+}
 
 #[derive(PartialEq, Debug)]
 enum TransactionRecvState {
@@ -28,7 +45,15 @@ enum TransactionRecvState {
 #[derive(PartialEq, Debug)]
 enum TransactionSendState {
     Initial,
-    SendingResponse { buf: Vec<u8> },
+    // The request's header list exceeds our limit, a 431 response is sent instead.
+    RejectingRequest,
+    // The HEADERS frame and the header of the DATA frame are in `head`. The body is sent
+    // from the application's buffer, and `offset` counts the bytes sent from both.
+    SendingResponse {
+        head: Vec<u8>,
+        body: Vec<u8>,
+        offset: usize,
+    },
     Closed,
 }
 
@@ -39,26 +64,34 @@ pub struct TransactionServer {
     stream_id: u64,
     frame_reader: HFrameReader,
     conn_events: Http3ServerConnEvents,
+    stats: Http3StatsRecorder,
 }
 
 impl TransactionServer {
-    pub fn new(stream_id: u64, conn_events: Http3ServerConnEvents) -> Self {
+    pub fn new(
+        stream_id: u64,
+        conn_events: Http3ServerConnEvents,
+        stats: Http3StatsRecorder,
+    ) -> Self {
         qinfo!("Create a request stream_id={}", stream_id);
         Self {
             recv_state: TransactionRecvState::WaitingForHeaders,
             send_state: TransactionSendState::Initial,
             stream_id,
-            frame_reader: HFrameReader::new(),
+            frame_reader: HFrameReader::with_stats(stats.clone()),
             conn_events,
+            stats,
         }
     }
 
     pub fn set_response(&mut self, headers: &[Header], data: Vec<u8>, encoder: &mut QPackEncoder) {
         qdebug!([self], "Encoding headers");
         let encoded_headers = encoder.encode_header_block(&headers, self.stream_id);
+        self.stats.headers_encoded(encoded_headers.len());
         let hframe = HFrame::Headers {
             len: encoded_headers.len() as u64,
         };
+        self.stats.frame_sent(self.stream_id, &hframe);
         let mut d = Encoder::default();
         hframe.encode(&mut d);
         d.encode(&encoded_headers);
@@ -67,11 +100,16 @@ impl TransactionServer {
             let d_frame = HFrame::Data {
                 len: data.len() as u64,
             };
+            self.stats.frame_sent(self.stream_id, &d_frame);
             d_frame.encode(&mut d);
-            d.encode(&data);
+            self.stats.data_sent(data.len());
         }
 
-        self.send_state = TransactionSendState::SendingResponse { buf: d.into() };
+        self.send_state = TransactionSendState::SendingResponse {
+            head: d.into(),
+            body: data,
+            offset: 0,
+        };
     }
 
     fn recv_frame_header(&mut self, conn: &mut Connection) -> Res<(Option<HFrame>, bool)> {
@@ -90,11 +128,7 @@ impl TransactionServer {
         conn: &mut Connection,
         decoder: &mut QPackDecoder,
     ) -> Res<bool> {
-        let label = if ::log::log_enabled!(::log::Level::Debug) {
-            format!("{}", self)
-        } else {
-            String::new()
-        };
+        let label = qlabel!(self);
         if let TransactionRecvState::ReadingHeaders {
             ref mut buf,
             ref mut offset,
@@ -116,8 +150,8 @@ impl TransactionServer {
                 [label],
                 "read_headers: read all headers, try decoding them."
             );
-            match decoder.decode_header_block(buf, self.stream_id)? {
-                Some(headers) => {
+            match decoder.decode_header_block(buf, self.stream_id) {
+                Ok(Some(headers)) => {
                     self.conn_events.headers(self.stream_id, headers, fin);
                     if fin {
                         self.recv_state = TransactionRecvState::Closed;
@@ -126,19 +160,48 @@ impl TransactionServer {
                     }
                     Ok(fin)
                 }
-                None => {
+                Ok(None) => {
                     let mut tmp: Vec<u8> = Vec::new();
                     mem::swap(&mut tmp, buf);
                     self.recv_state =
                         TransactionRecvState::BlockedDecodingHeaders { buf: tmp, fin };
                     Ok(true)
                 }
+                Err(QpackError::HeaderListTooLarge) => {
+                    self.reject_request(conn, fin);
+                    Ok(true)
+                }
+                Err(e) => Err(e.into()),
             }
         } else {
             panic!("This is only called when recv_state is ReadingHeaders.");
         }
     }
 
+    // The QPACK decoder stopped decoding a header block that exceeds our
+    // SETTINGS_MAX_HEADER_LIST_SIZE. Stop reading the request and answer it with 431 (Request
+    // Header Fields Too Large).
+    fn reject_request(&mut self, conn: &mut Connection, fin: bool) {
+        qinfo!([self], "The header list is too large, reject the request.");
+        if !fin {
+            let _ = conn.stream_stop_sending(self.stream_id, Error::HttpEarlyResponse.code());
+        }
+        self.recv_state = TransactionRecvState::Closed;
+        self.send_state = TransactionSendState::RejectingRequest;
+    }
+
+    // Reset both sides of the stream. The app has not seen the request yet, so it is not
+    // informed.
+    fn stream_error(&mut self, conn: &mut Connection, error: Error) -> Res<()> {
+        qinfo!([self], "Reset the stream with error {:?}.", error);
+        // The stream may already be closed, we do not care about errors here.
+        let _ = conn.stream_stop_sending(self.stream_id, error.code());
+        let _ = conn.stream_reset_send(self.stream_id, error.code());
+        self.send_state = TransactionSendState::Closed;
+        self.recv_state = TransactionRecvState::Closed;
+        Ok(())
+    }
+
     fn handle_frame_in_state_waiting_for_headers(&mut self, frame: HFrame, fin: bool) -> Res<()> {
         qdebug!([self], "A new frame has been received: {:?}", frame);
         match frame {
@@ -195,23 +258,36 @@ impl ::std::fmt::Display for TransactionServer {
 }
 
 impl Http3Transaction for TransactionServer {
-    fn send(&mut self, conn: &mut Connection, _encoder: &mut QPackEncoder) -> Res<()> {
+    fn send(&mut self, conn: &mut Connection, encoder: &mut QPackEncoder) -> Res<()> {
         qtrace!([self], "Sending response.");
-        let label = if ::log::log_enabled!(::log::Level::Debug) {
-            format!("{}", self)
-        } else {
-            String::new()
-        };
-        if let TransactionSendState::SendingResponse { ref mut buf } = self.send_state {
-            let sent = conn.stream_send(self.stream_id, &buf[..])?;
-            qinfo!([label], "{} bytes sent", sent);
-            if sent == buf.len() {
+        if self.send_state == TransactionSendState::RejectingRequest {
+            self.set_response(
+                &[(String::from(":status"), String::from("431"))],
+                Vec::new(),
+                encoder,
+            );
+        }
+        let label = qlabel!(self);
+        if let TransactionSendState::SendingResponse {
+            ref head,
+            ref body,
+            ref mut offset,
+        } = self.send_state
+        {
+            if *offset < head.len() {
+                let sent = conn.stream_send(self.stream_id, &head[*offset..])?;
+                qinfo!([label], "{} bytes of frame headers sent", sent);
+                *offset += sent;
+            }
+            if *offset >= head.len() && *offset < head.len() + body.len() {
+                let sent = conn.stream_send(self.stream_id, &body[*offset - head.len()..])?;
+                qinfo!([label], "{} bytes of body sent", sent);
+                *offset += sent;
+            }
+            if *offset == head.len() + body.len() {
                 conn.stream_close_send(self.stream_id)?;
                 self.send_state = TransactionSendState::Closed;
                 qinfo!([label], "done sending request");
-            } else {
-                let mut b = buf.split_off(sent);
-                mem::swap(buf, &mut b);
             }
         }
 
@@ -219,11 +295,7 @@ impl Http3Transaction for TransactionServer {
     }
 
     fn receive(&mut self, conn: &mut Connection, decoder: &mut QPackDecoder) -> Res<()> {
-        let label = if ::log::log_enabled!(::log::Level::Debug) {
-            format!("{}", self)
-        } else {
-            String::new()
-        };
+        let label = qlabel!(self);
 
         loop {
             qtrace!(
@@ -242,6 +314,10 @@ impl Http3Transaction for TransactionServer {
                             }
                             return Ok(());
                         }
+                        Some(HFrame::Data { .. }) => {
+                            qinfo!([label], "A request must start with a HEADERS frame.");
+                            return self.stream_error(conn, Error::HttpFrameUnexpected);
+                        }
                         Some(new_f) => {
                             self.handle_frame_in_state_waiting_for_headers(new_f, fin)?;
                             if fin {
@@ -257,17 +333,22 @@ impl Http3Transaction for TransactionServer {
                     }
                 }
                 TransactionRecvState::BlockedDecodingHeaders { ref mut buf, fin } => {
-                    match decoder.decode_header_block(buf, self.stream_id)? {
-                        Some(headers) => {
+                    match decoder.decode_header_block(buf, self.stream_id) {
+                        Ok(Some(headers)) => {
                             self.conn_events.headers(self.stream_id, headers, fin);
                             if fin {
                                 return Ok(());
                             }
                         }
-                        None => {
+                        Ok(None) => {
                             qinfo!([self], "decoding header is blocked.");
                             return Ok(());
                         }
+                        Err(QpackError::HeaderListTooLarge) => {
+                            self.reject_request(conn, fin);
+                            return Ok(());
+                        }
+                        Err(e) => return Err(e.into()),
                     }
                 }
                 TransactionRecvState::WaitingForData => {
@@ -303,6 +384,7 @@ impl Http3Transaction for TransactionServer {
                         let (amount, fin) = conn.stream_recv(self.stream_id, &mut data[..])?;
                         assert!(amount <= to_read);
                         if amount > 0 {
+                            self.stats.data_received(amount);
                             data.truncate(amount);
                             self.conn_events.data(self.stream_id, data, fin);
                             *remaining_data_len -= amount;
@@ -330,7 +412,10 @@ impl Http3Transaction for TransactionServer {
     }
 
     fn has_data_to_send(&self) -> bool {
-        matches!(self.send_state, TransactionSendState::SendingResponse { .. })
+        matches!(
+            self.send_state,
+            TransactionSendState::SendingResponse { .. } | TransactionSendState::RejectingRequest
+        )
     }
 
     fn reset_receiving_side(&mut self) {
@@ -347,4 +432,6 @@ impl Http3Transaction for TransactionServer {
     fn close_send(&mut self, _conn: &mut Connection) -> Res<()> {
         Ok(())
     }
+
+    fn qpack_unblocked(&mut self, _blocked: Duration) {}
 }
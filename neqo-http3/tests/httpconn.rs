@@ -8,7 +8,7 @@
 
 use neqo_common::{matches, Datagram};
 use neqo_crypto::AuthenticationStatus;
-use neqo_http3::{Http3Client, Http3ClientEvent, Http3Server, Http3ServerEvent, Http3State};
+use neqo_http3::{Error, Http3Client, Http3ClientEvent, Http3Server, Http3ServerEvent, Http3State};
 use neqo_transport::stream_id::StreamId;
 use test_fixture::*;
 
@@ -108,6 +108,33 @@ fn connect() -> (Http3Client, Http3Server, Option<Datagram>) {
     (hconn_c, hconn_s, out.dgram())
 }
 
+// Move datagrams between the client and the server until neither has anything to send.
+fn exchange_packets(client: &mut Http3Client, server: &mut Http3Server) {
+    let mut dgram = None;
+    loop {
+        let out = client.process(dgram, now()).dgram();
+        let client_done = out.is_none();
+        dgram = server.process(out, now()).dgram();
+        if client_done && dgram.is_none() {
+            break;
+        }
+    }
+}
+
+// A client and a server that have completed the handshake and exchanged their SETTINGS,
+// without any datagrams left in flight.
+fn connect_h3_pair() -> (Http3Client, Http3Server) {
+    let mut client = default_http3_client();
+    let mut server = default_http3_server();
+    exchange_packets(&mut client, &mut server);
+    let authentication_needed = |e| matches!(e, Http3ClientEvent::AuthenticationNeeded);
+    assert!(client.events().any(authentication_needed));
+    client.authenticated(AuthenticationStatus::Ok, now());
+    exchange_packets(&mut client, &mut server);
+    assert_eq!(client.state(), Http3State::Connected);
+    (client, server)
+}
+
 #[test]
 fn test_connect() {
     let (_hconn_c, _hconn_s, _d) = connect();
@@ -137,3 +164,64 @@ fn test_fetch() {
     let _ = hconn_c.process(out.dgram(), now());
     process_client_events(&mut hconn_c);
 }
+
+#[test]
+fn test_fetch_h3_pair() {
+    let (mut client, mut server) = connect_h3_pair();
+    let req = client
+        .fetch("GET", "https", "something.com", "/", &[])
+        .unwrap();
+    client.stream_close_send(StreamId(req)).unwrap();
+    exchange_packets(&mut client, &mut server);
+    process_server_events(&mut server);
+    exchange_packets(&mut client, &mut server);
+    process_client_events(&mut client);
+}
+
+#[test]
+fn test_reset_response() {
+    let (mut hconn_c, mut hconn_s, dgram) = connect();
+
+    let req = hconn_c
+        .fetch("GET", "https", "something.com", "/", &[])
+        .unwrap();
+    let req = StreamId(req);
+    hconn_c.stream_close_send(req).unwrap();
+    let out = hconn_c.process(dgram, now());
+    let _ = hconn_s.process(out.dgram(), now());
+
+    // The body is too large to be sent at once, so the server is still sending it when the
+    // response is reset.
+    let mut request = None;
+    while let Some(event) = hconn_s.next_event() {
+        if let Http3ServerEvent::Headers { request: r, .. } = event {
+            request = Some(r);
+        }
+    }
+    let mut request = request.unwrap();
+    request
+        .set_response(
+            &[(String::from(":status"), String::from("200"))],
+            vec![0; 100_000],
+        )
+        .unwrap();
+    let out = hconn_s.process(None, now());
+    let _ = hconn_c.process(out.dgram(), now());
+    let header_ready = |e| matches!(e, Http3ClientEvent::HeaderReady { .. });
+    assert!(hconn_c.events().any(header_ready));
+
+    request
+        .reset_response(Error::HttpInternalError.code())
+        .unwrap();
+    // The RESET_STREAM frame may have to wait for acknowledgments of the body.
+    let mut out = hconn_s.process(None, now());
+    while out.as_dgram_ref().is_some() {
+        let ack = hconn_c.process(out.dgram(), now());
+        out = hconn_s.process(ack.dgram(), now());
+    }
+    let reset = |e| {
+        matches!(e, Http3ClientEvent::Reset { stream_id, error }
+            if stream_id == req.as_u64() && error == Error::HttpInternalError.code())
+    };
+    assert!(hconn_c.events().any(reset));
+}
@@ -288,6 +288,20 @@ impl H3Handler {
     fn handle(&mut self) -> bool {
         let mut data = vec![0; 4000];
         self.h3.process_http3(Instant::now());
+        // The request can only be sent once the HTTP/3 connection is up.
+        if self.streams.is_empty() {
+            match self.h3.fetch("GET", "https", &self.host, &self.path, &[]) {
+                Ok(stream_id) => {
+                    let _ = self.h3.stream_close_send(StreamId(stream_id));
+                    self.streams.insert(stream_id);
+                }
+                Err(neqo_http3::Error::Unavailable) => {}
+                Err(e) => {
+                    eprintln!("Unable to send the request: {:?}", e);
+                    return false;
+                }
+            }
+        }
         while let Some(event) = self.h3.next_event() {
             match event {
                 Http3ClientEvent::HeaderReady { stream_id } => {
@@ -460,13 +474,6 @@ fn test_h3(nctx: &NetworkCtx, peer: &Peer, client: Connection) -> Result<(), Str
         path: String::from("/"),
     };
 
-    let client_stream_id = hc
-        .h3
-        .fetch("GET", "https", &hc.host, &hc.path, &[])
-        .unwrap();
-    let _ = hc.h3.stream_close_send(StreamId(client_stream_id));
-
-    hc.streams.insert(client_stream_id);
     if let Err(e) = process_loop_h3(nctx, &mut hc, Duration::new(5, 0)) {
         return Err(format!("ERROR: {}", e));
     }
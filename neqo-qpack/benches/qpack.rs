@@ -0,0 +1,225 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+// Throughput of encoding and decoding header blocks, reported both in headers and in bytes
+// of header block per second. Run with `cargo bench -p neqo-qpack`. The transport only
+// carries the encoder instructions; that is done outside of the timed code.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use neqo_qpack::decoder::QPackDecoder;
+use neqo_qpack::encoder::QPackEncoder;
+use neqo_qpack::Header;
+use neqo_transport::{Connection, StreamType};
+use test_fixture::now;
+
+const TABLE_SIZE: u64 = 4096;
+const MAX_BLOCKED_STREAMS: u16 = 100;
+const REQUEST_STREAM_ID: u64 = 0;
+
+fn header_list(list: &[(&str, &str)]) -> Vec<Header> {
+    list.iter()
+        .map(|(name, value)| (String::from(*name), String::from(*value)))
+        .collect()
+}
+
+// The encoder only inserts headers with names that are not in the static table, so both lists
+// end with a few of those.
+fn request_headers() -> Vec<Header> {
+    header_list(&[
+        (":method", "GET"),
+        (":scheme", "https"),
+        (":authority", "www.example.com"),
+        (":path", "/images/logo.png?size=large&format=webp"),
+        (
+            "user-agent",
+            "Mozilla/5.0 (X11; Linux x86_64; rv:72.0) Gecko/20100101 Firefox/72.0",
+        ),
+        ("accept", "image/webp,*/*"),
+        ("accept-language", "en-US,en;q=0.5"),
+        ("accept-encoding", "gzip, deflate, br"),
+        ("referer", "https://www.example.com/index.html"),
+        (
+            "cookie",
+            "session=4f2a9c1e7b3d5a80; theme=dark; consent=yes",
+        ),
+        ("sec-fetch-dest", "image"),
+        ("sec-fetch-mode", "no-cors"),
+        ("sec-fetch-site", "same-origin"),
+        ("x-request-id", "b7e2c4a0-1f3d-4e5a-9c8b-2d6f0a1e3c5b"),
+    ])
+}
+
+fn response_headers() -> Vec<Header> {
+    header_list(&[
+        (":status", "200"),
+        ("content-type", "image/webp"),
+        ("content-length", "18274"),
+        ("date", "Tue, 14 Jan 2020 10:21:33 GMT"),
+        ("server", "neqo"),
+        ("cache-control", "public, max-age=31536000"),
+        ("etag", "\"5e1d8a3c-4762\""),
+        ("last-modified", "Mon, 13 Jan 2020 08:12:44 GMT"),
+        ("x-cache", "HIT"),
+        ("x-served-by", "cache-ams21020"),
+        ("x-request-id", "b7e2c4a0-1f3d-4e5a-9c8b-2d6f0a1e3c5b"),
+    ])
+}
+
+/// An encoder and a decoder with a dynamic table, connected by an encoder stream as they
+/// are in an HTTP/3 connection.
+struct Pair {
+    encoder: QPackEncoder,
+    decoder: QPackDecoder,
+    conn_c: Connection,
+    conn_s: Connection,
+    encoder_stream: u64,
+}
+
+impl Pair {
+    fn new() -> Self {
+        let (mut conn_c, mut conn_s) = test_fixture::connect();
+        let encoder_stream = conn_c.stream_create(StreamType::UniDi).unwrap();
+        let mut encoder = QPackEncoder::new(true);
+        encoder.add_send_stream(encoder_stream);
+        encoder.set_max_capacity(TABLE_SIZE).unwrap();
+        encoder
+            .set_max_blocked_streams(u64::from(MAX_BLOCKED_STREAMS))
+            .unwrap();
+        let mut pair = Self {
+            encoder,
+            decoder: QPackDecoder::new(TABLE_SIZE as u32, MAX_BLOCKED_STREAMS),
+            conn_c,
+            conn_s,
+            encoder_stream,
+        };
+        pair.transfer();
+        // neqo-http3 reads the stream type before it passes the stream to the decoder.
+        let mut stream_type = [0; 1];
+        pair.conn_s
+            .stream_recv(encoder_stream, &mut stream_type)
+            .unwrap();
+        pair.receive();
+        pair
+    }
+
+    /// Send the encoder instructions to the other connection.
+    fn transfer(&mut self) {
+        self.encoder.send(&mut self.conn_c).unwrap();
+        let out = self.conn_c.process(None, now());
+        self.conn_s.process(out.dgram(), now());
+    }
+
+    /// Read the encoder instructions, as `Http3Connection::handle_stream_readable` does.
+    fn receive(&mut self) -> Vec<u64> {
+        self.decoder
+            .receive(&mut self.conn_s, self.encoder_stream)
+            .unwrap()
+    }
+}
+
+fn bench_static(c: &mut Criterion, name: &str, headers: &[Header]) {
+    let block = QPackEncoder::new(true).encode_header_block(headers, REQUEST_STREAM_ID);
+    for (unit, throughput) in &[
+        ("headers", Throughput::Elements(headers.len() as u64)),
+        ("bytes", Throughput::Bytes(block.len() as u64)),
+    ] {
+        let mut group = c.benchmark_group(format!("{} static {}", name, unit));
+        group.throughput(throughput.clone());
+        group.bench_function("encode", |b| {
+            let mut encoder = QPackEncoder::new(true);
+            b.iter(|| encoder.encode_header_block(headers, REQUEST_STREAM_ID))
+        });
+        group.bench_function("decode", |b| {
+            let mut decoder = QPackDecoder::new(0, 0);
+            b.iter(|| {
+                decoder
+                    .decode_header_block(&block, REQUEST_STREAM_ID)
+                    .unwrap()
+                    .unwrap()
+            })
+        });
+        group.finish();
+    }
+}
+
+fn bench_dynamic(c: &mut Criterion, name: &str, headers: &[Header]) {
+    let block_len = Pair::new()
+        .encoder
+        .encode_header_block(headers, REQUEST_STREAM_ID)
+        .len();
+    for (unit, throughput) in &[
+        ("headers", Throughput::Elements(headers.len() as u64)),
+        ("bytes", Throughput::Bytes(block_len as u64)),
+    ] {
+        let mut group = c.benchmark_group(format!("{} dynamic {}", name, unit));
+        group.throughput(throughput.clone());
+        // Only the encoding is timed; the encoder instructions are queued, but not sent.
+        group.bench_function("encode", |b| {
+            b.iter_batched_ref(
+                Pair::new,
+                |pair| pair.encoder.encode_header_block(headers, REQUEST_STREAM_ID),
+                BatchSize::LargeInput,
+            )
+        });
+        // The instructions arrive before the header block, so the headers are decoded at once.
+        // Sending them over the connections is part of the setup.
+        group.bench_function("decode", |b| {
+            b.iter_batched_ref(
+                || {
+                    let mut pair = Pair::new();
+                    let block = pair.encoder.encode_header_block(headers, REQUEST_STREAM_ID);
+                    pair.transfer();
+                    (pair, block)
+                },
+                |(pair, block)| {
+                    pair.receive();
+                    pair.decoder
+                        .decode_header_block(block, REQUEST_STREAM_ID)
+                        .unwrap()
+                        .unwrap()
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        // The header block arrives first and the stream is unblocked by the instructions.
+        group.bench_function("unblock", |b| {
+            b.iter_batched_ref(
+                || {
+                    let mut pair = Pair::new();
+                    let block = pair.encoder.encode_header_block(headers, REQUEST_STREAM_ID);
+                    assert!(pair
+                        .decoder
+                        .decode_header_block(&block, REQUEST_STREAM_ID)
+                        .unwrap()
+                        .is_none());
+                    pair.transfer();
+                    (pair, block)
+                },
+                |(pair, block)| {
+                    assert_eq!(pair.receive(), vec![REQUEST_STREAM_ID]);
+                    pair.decoder
+                        .decode_header_block(block, REQUEST_STREAM_ID)
+                        .unwrap()
+                        .unwrap()
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        group.finish();
+    }
+}
+
+fn qpack(c: &mut Criterion) {
+    let request = request_headers();
+    let response = response_headers();
+    bench_static(c, "request", &request);
+    bench_static(c, "response", &response);
+    bench_dynamic(c, "request", &request);
+    bench_dynamic(c, "response", &response);
+}
+
+criterion_group!(benches, qpack);
+criterion_main!(benches);
@@ -13,7 +13,7 @@ use crate::qpack_send_buf::QPData;
 use crate::table::HeaderTable;
 use crate::Header;
 use crate::{Error, Res};
-use neqo_common::qdebug;
+use neqo_common::{qdebug, qlabel};
 use neqo_transport::Connection;
 use std::{mem, str};
 
@@ -83,6 +83,7 @@ pub struct QPackDecoder {
     max_table_size: u32,
     max_blocked_streams: u16,
     blocked_streams: Vec<(u64, u64)>, //stream_id and requested inserts count.
+    max_header_list_size: Option<u64>,
 }
 
 impl QPackDecoder {
@@ -100,13 +101,37 @@ impl QPackDecoder {
             max_table_size,
             max_blocked_streams,
             blocked_streams: Vec::new(),
+            max_header_list_size: None,
         }
     }
 
+    /// Limit the size of a decoded header list, counted as for SETTINGS_MAX_HEADER_LIST_SIZE.
+    /// Decoding a larger header block stops with `Error::HeaderListTooLarge` as soon as the
+    /// limit is exceeded.
+    pub fn set_max_header_list_size(&mut self, limit: u64) {
+        self.max_header_list_size = Some(limit);
+    }
+
     pub fn capacity(&self) -> u64 {
         self.table.capacity()
     }
 
+    /// The number of bytes of the dynamic table that are in use.
+    pub fn used_capacity(&self) -> u64 {
+        self.table.used()
+    }
+
+    /// Keep the name and value of every entry that the peer inserts into the dynamic table,
+    /// until `take_inserts` is called.
+    pub fn record_inserts(&mut self) {
+        self.table.record_inserts();
+    }
+
+    /// The entries inserted since the last call, if `record_inserts` was called.
+    pub fn take_inserts(&mut self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.table.take_inserts()
+    }
+
     pub fn get_max_table_size(&self) -> u32 {
         self.max_table_size
     }
@@ -115,6 +140,17 @@ impl QPackDecoder {
         self.max_blocked_streams
     }
 
+    /// Whether decoding the header block of a stream waits for more encoder instructions.
+    pub fn is_stream_blocked(&self, stream_id: u64) -> bool {
+        self.blocked_streams.iter().any(|(id, _)| *id == stream_id)
+    }
+
+    /// Whether a header block refers to the dynamic table. Its Required Insert Count is zero
+    /// otherwise, and that is encoded as a single zero byte.
+    pub fn refers_to_dynamic_table(header_block: &[u8]) -> bool {
+        header_block.first().map_or(false, |b| *b != 0)
+    }
+
     // returns a list of unblocked streams
     pub fn receive(&mut self, conn: &mut Connection, stream_id: u64) -> Res<Vec<u64>> {
         self.read_instructions(conn, stream_id)?;
@@ -132,7 +168,7 @@ impl QPackDecoder {
     #[allow(clippy::cognitive_complexity)]
     #[allow(clippy::useless_let_if_seq)]
     fn read_instructions(&mut self, conn: &mut Connection, stream_id: u64) -> Res<()> {
-        let label = self.to_string();
+        let label = qlabel!(self);
         qdebug!([self], "reading instructions");
         loop {
             match self.state {
@@ -548,6 +584,7 @@ impl QPackDecoder {
             return Ok(None);
         }
         let mut h: Vec<Header> = Vec::new();
+        let mut size = 0;
 
         loop {
             if reader.done() {
@@ -560,18 +597,29 @@ impl QPackDecoder {
             }
 
             let b = reader.peek()?;
-            if b & 0x80 != 0 {
-                h.push(self.read_indexed(&mut reader, base)?);
+            let header = if b & 0x80 != 0 {
+                self.read_indexed(&mut reader, base)?
             } else if b & 0x40 != 0 {
-                h.push(self.read_literal_with_name_ref(&mut reader, base)?);
+                self.read_literal_with_name_ref(&mut reader, base)?
             } else if b & 0x20 != 0 {
-                h.push(self.read_literal_with_name_literal(&mut reader)?);
-                continue;
+                self.read_literal_with_name_literal(&mut reader)?
             } else if b & 0x10 != 0 {
-                h.push(self.read_post_base_index(&mut reader, base)?);
+                self.read_post_base_index(&mut reader, base)?
             } else {
-                h.push(self.read_literal_with_post_base_name_ref(&mut reader, base)?);
+                self.read_literal_with_post_base_name_ref(&mut reader, base)?
+            };
+            size += (header.0.len() + header.1.len() + 32) as u64;
+            if let Some(limit) = self.max_header_list_size {
+                if size > limit {
+                    qdebug!([self], "header list size exceeds the limit {}.", limit);
+                    // The header block will not be acknowledged.
+                    if req_inserts != 0 {
+                        self.cancel_stream(stream_id);
+                    }
+                    break Err(Error::HeaderListTooLarge);
+                }
             }
+            h.push(header);
         }
     }
 
@@ -1056,6 +1104,30 @@ mod tests {
         assert!(found_instruction);
     }
 
+    #[test]
+    fn test_header_block_decoder_max_header_list_size() {
+        let (mut decoder, _conn_c, _conn_s, _recv_stream_id, _send_stream_id) = connect();
+        // ":path: /somewhere" is 47 bytes and ":method: GET" is 42.
+        let header_block = &[
+            0x00, 0x00, 0x51, 0x0a, 0x2f, 0x73, 0x6f, 0x6d, 0x65, 0x77, 0x68, 0x65, 0x72, 0x65,
+            0xd1,
+        ];
+        decoder.set_max_header_list_size(89);
+        assert_eq!(
+            decoder
+                .decode_header_block(header_block, 0)
+                .unwrap()
+                .unwrap()
+                .len(),
+            2
+        );
+        decoder.set_max_header_list_size(88);
+        assert_eq!(
+            decoder.decode_header_block(header_block, 4),
+            Err(Error::HeaderListTooLarge)
+        );
+    }
+
     #[test]
     fn test_header_block_decoder_huffman() {
         let test_cases: [TestElement; 6] = [
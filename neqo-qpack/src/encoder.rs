@@ -12,7 +12,7 @@ use crate::qpack_send_buf::QPData;
 use crate::table::HeaderTable;
 use crate::Header;
 use crate::{Error, Res};
-use neqo_common::{qdebug, qtrace};
+use neqo_common::{qdebug, qlabel, qtrace};
 use neqo_transport::Connection;
 
 pub const QPACK_UNI_STREAM_TYPE_ENCODER: u64 = 0x2;
@@ -48,6 +48,7 @@ pub struct QPackEncoder {
     blocked_streams: Vec<u64>, // remember request insert counds for blocked streams.
     // TODO we may also remember stream_id and use stream acks as indication that a stream has beed unblocked.
     use_huffman: bool,
+    use_dynamic_table: bool,
 }
 
 impl QPackEncoder {
@@ -64,14 +65,47 @@ impl QPackEncoder {
             max_blocked_streams: 0,
             blocked_streams: Vec::new(),
             use_huffman,
+            use_dynamic_table: true,
         }
     }
 
+    /// Whether the dynamic table is used. Without it, every header is encoded with
+    /// static table references or literals and no insertions are sent on the encoder
+    /// stream, whatever capacity the peer allows. This must be set before the peer's
+    /// settings are applied with `set_max_capacity`.
+    pub fn set_use_dynamic_table(&mut self, use_dynamic_table: bool) {
+        self.use_dynamic_table = use_dynamic_table;
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.table.capacity()
+    }
+
+    /// The number of bytes of the dynamic table that are in use.
+    pub fn used_capacity(&self) -> u64 {
+        self.table.used()
+    }
+
+    /// Keep the name and value of every entry that is inserted into the dynamic table, until
+    /// `take_inserts` is called.
+    pub fn record_inserts(&mut self) {
+        self.table.record_inserts();
+    }
+
+    /// The entries inserted since the last call, if `record_inserts` was called.
+    pub fn take_inserts(&mut self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.table.take_inserts()
+    }
+
     pub fn set_max_capacity(&mut self, cap: u64) -> Res<()> {
         if cap > (1 << 30) - 1 {
             // TODO dragana check wat is the correct error.
             return Err(Error::EncoderStreamError);
         }
+        if !self.use_dynamic_table {
+            qdebug!([self], "Dynamic table not used, ignore capacity {}.", cap);
+            return Ok(());
+        }
         qdebug!([self], "Set max capacity to {}.", cap);
         self.max_entries = (cap as f64 / 32.0).floor() as u64;
         // we also set our table to the max allowed. TODO we may not want to use max allowed.
@@ -286,7 +320,7 @@ impl QPackEncoder {
             let acked_inserts_cnt = self.table.get_acked_inserts_cnt(); // we need to read it here because of borrowing problem.
             let can_be_blocked = self.blocked_streams.len() < self.max_blocked_streams as usize;
             {
-                let label = self.to_string();
+                let label = qlabel!(self);
                 // this is done in this way because otherwise it is complaining about mut borrow. TODO: look if we can do this better
                 let (e_s, e_d, found_value) = self.table.lookup(&name, &value);
                 if let Some(entry) = e_s {
@@ -618,6 +652,25 @@ mod tests {
         );
     }
 
+    // Only the entries inserted after record_inserts() are returned, once.
+    #[test]
+    fn test_record_inserts() {
+        let (mut encoder, _conn_c, _conn_s, _, _) = connect(false);
+        assert!(encoder.set_max_capacity(200).is_ok());
+        assert!(encoder
+            .insert_with_name_literal(b"a".to_vec(), b"1".to_vec())
+            .is_ok());
+        encoder.record_inserts();
+        assert!(encoder
+            .insert_with_name_ref(true, 4, vec![0x31, 0x32])
+            .is_ok());
+        assert_eq!(
+            encoder.take_inserts(),
+            vec![(b"content-length".to_vec(), vec![0x31, 0x32])]
+        );
+        assert!(encoder.take_inserts().is_empty());
+    }
+
     // test insert_with_name_literal which fails because there is not enough space in the table
     #[test]
     fn test_insert_with_name_literal_1() {
@@ -832,6 +885,45 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_header_block_encoder_without_dynamic_table() {
+        let (mut encoder, mut conn_c, mut conn_s, recv_stream_id, send_stream_id) = connect(false);
+        encoder.set_use_dynamic_table(false);
+
+        encoder.set_max_blocked_streams(100).unwrap();
+        encoder.set_max_capacity(200).unwrap();
+        assert_eq!(encoder.capacity(), 0);
+
+        // Only the stream type is sent on the encoder stream.
+        test_sent_instructions(
+            &mut encoder,
+            &mut conn_c,
+            &mut conn_s,
+            recv_stream_id,
+            send_stream_id,
+            &[0x02],
+        );
+
+        // A header that is not in the static table is encoded with a literal name.
+        let headers = vec![(String::from("my-header"), String::from("my-value"))];
+        let buf = encoder.encode_header_block(&headers, 1);
+        assert_eq!(
+            &buf[..],
+            &[
+                0x00, 0x00, 0x27, 0x02, 0x6d, 0x79, 0x2d, 0x68, 0x65, 0x61, 0x64, 0x65, 0x72, 0x08,
+                0x6d, 0x79, 0x2d, 0x76, 0x61, 0x6c, 0x75, 0x65,
+            ]
+        );
+        test_sent_instructions(
+            &mut encoder,
+            &mut conn_c,
+            &mut conn_s,
+            recv_stream_id,
+            send_stream_id,
+            &[],
+        );
+    }
+
     #[test]
     fn test_header_block_encoder_huffman() {
         let test_cases: [TestElement; 6] = [
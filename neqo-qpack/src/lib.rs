@@ -38,6 +38,7 @@ pub enum Error {
     NoMoreData,
     IntegerOverflow,
     WrongStreamCount,
+    HeaderListTooLarge,
 
     TransportError(neqo_transport::Error),
 }
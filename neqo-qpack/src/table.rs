@@ -58,6 +58,8 @@ pub struct HeaderTable {
     // The total number of inserts thus far.
     base: u64,
     acked_inserts_cnt: u64,
+    // The entries inserted since `take_inserts` was last called, if they are recorded.
+    inserts: Option<Vec<(Vec<u8>, Vec<u8>)>>,
 }
 
 impl HeaderTable {
@@ -73,9 +75,23 @@ impl HeaderTable {
             used: 0,
             base: 0,
             acked_inserts_cnt: if encoder { 0 } else { std::u64::MAX },
+            inserts: None,
         }
     }
 
+    pub fn record_inserts(&mut self) {
+        if self.inserts.is_none() {
+            self.inserts = Some(Vec::new());
+        }
+    }
+
+    pub fn take_inserts(&mut self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.inserts
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
     pub fn base(&self) -> u64 {
         self.base
     }
@@ -84,6 +100,10 @@ impl HeaderTable {
         self.capacity
     }
 
+    pub fn used(&self) -> u64 {
+        self.used
+    }
+
     pub fn set_capacity(&mut self, c: u64) {
         self.evict_to(c);
         self.capacity = c;
@@ -190,6 +210,9 @@ impl HeaderTable {
         }
         self.base += 1;
         self.used += entry.size();
+        if let Some(inserts) = &mut self.inserts {
+            inserts.push((entry.name.clone(), entry.value.clone()));
+        }
         self.dynamic.push_front(entry);
         Ok(())
     }
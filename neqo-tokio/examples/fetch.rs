@@ -0,0 +1,87 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Fetch a URL over HTTP/3, like neqo-client but without its hand-written event loop:
+//! `cargo run --example fetch -- http://127.0.0.1:12345/ ./test-fixture/db`
+
+use neqo_crypto::init_db;
+use neqo_http3::{Error, Http3Client};
+use neqo_tokio::Client;
+use neqo_transport::FixedConnectionIdManager;
+use std::cell::RefCell;
+use std::env;
+use std::net::ToSocketAddrs;
+use std::process::exit;
+use std::rc::Rc;
+use tokio::io::AsyncReadExt;
+use tokio::net::UdpSocket;
+use url::Url;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let mut args = env::args().skip(1);
+    let (url, db) = match (args.next(), args.next()) {
+        (Some(url), Some(db)) => (url, db),
+        _ => {
+            eprintln!("Usage: fetch <url> <db>");
+            exit(1);
+        }
+    };
+    init_db(db);
+
+    let url = Url::parse(&url).expect("a valid URL");
+    let host = url.host_str().unwrap_or("localhost");
+    let remote_addr = (host, url.port_or_known_default().unwrap_or(443))
+        .to_socket_addrs()
+        .expect("the host must resolve")
+        .next()
+        .expect("the host must have an address");
+    let socket = UdpSocket::bind(if remote_addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })
+    .await
+    .expect("a socket");
+    let local_addr = socket.local_addr().expect("a local address");
+
+    let client = Client::new(
+        Http3Client::new(
+            host,
+            &["h3-24"],
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(0))),
+            local_addr,
+            remote_addr,
+            128,
+            128,
+        )
+        .expect("a client"),
+        socket,
+    );
+
+    let request = async {
+        match client
+            .fetch("GET", url.scheme(), host, url.path(), &[])
+            .await
+        {
+            Ok(mut response) => {
+                println!("READ HEADERS: {:?}", response.headers);
+                let mut body = Vec::new();
+                match response.body.read_to_end(&mut body).await {
+                    Ok(_) => println!("READ: {}", String::from_utf8_lossy(&body)),
+                    Err(e) => eprintln!("Unable to read the response: {}", e),
+                }
+            }
+            Err(e) => eprintln!("The request failed: {}", e),
+        }
+        client.close(Error::HttpNoError.code(), "kthxbye!");
+    };
+    let (state, ()) = tokio::join!(client.run(), request);
+    match state {
+        Ok(state) => println!("{:?}", state),
+        Err(e) => eprintln!("Socket error: {}", e),
+    }
+}
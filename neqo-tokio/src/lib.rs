@@ -0,0 +1,365 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg_attr(feature = "deny-warnings", deny(warnings))]
+#![warn(clippy::use_self)]
+
+//! Drives an `Http3Client` on a Tokio `UdpSocket`.
+//!
+//! `Client::run` sends and receives datagrams and handles the timers of the connection;
+//! it must be polled for as long as the connection is used, e.g. with `join!`. Requests
+//! are made with `Client::fetch`, and the response body is read with `AsyncRead`. The
+//! client is not `Send`, so everything runs on one thread, e.g. in a `LocalSet`.
+
+use neqo_common::{matches, qdebug, qinfo, Datagram};
+use neqo_crypto::AuthenticationStatus;
+use neqo_http3::{Error, Header, Http3Client, Http3ClientEvent, Http3State, Output};
+use neqo_transport::stream_id::StreamId;
+use neqo_transport::{AppError, Error as TransportError};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::time::Instant;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::Notify;
+use tokio::time::sleep;
+
+type Res<T> = Result<T, Error>;
+
+// A future that calls `f` whenever it is polled.
+struct PollFn<F>(F);
+
+impl<T, F: FnMut(&mut Context) -> Poll<T> + Unpin> Future for PollFn<F> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        (self.0)(cx)
+    }
+}
+
+fn poll_fn<T, F: FnMut(&mut Context) -> Poll<T> + Unpin>(f: F) -> PollFn<F> {
+    PollFn(f)
+}
+
+// What the events of the client said about a request stream.
+#[derive(Default)]
+struct RequestStream {
+    header_ready: bool,
+    reset: Option<AppError>,
+    waker: Option<Waker>,
+}
+
+impl RequestStream {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct Shared {
+    client: Http3Client,
+    streams: HashMap<u64, RequestStream>,
+    // Tasks that wait for the connection to be established.
+    state_wakers: Vec<Waker>,
+}
+
+impl Shared {
+    fn closed(&self) -> bool {
+        matches!(
+            self.client.state(),
+            Http3State::Closing(_) | Http3State::Closed(_)
+        )
+    }
+
+    // Turn the events of the client into wakeups of the tasks that wait for them.
+    fn handle_events(&mut self) {
+        while let Some(event) = self.client.next_event() {
+            qdebug!("Client event {:?}", event);
+            match event {
+                Http3ClientEvent::HeaderReady { stream_id } => {
+                    if let Some(stream) = self.streams.get_mut(&stream_id) {
+                        stream.header_ready = true;
+                        stream.wake();
+                    }
+                }
+                Http3ClientEvent::DataReadable { stream_id } => {
+                    if let Some(stream) = self.streams.get_mut(&stream_id) {
+                        stream.wake();
+                    }
+                }
+                Http3ClientEvent::Reset { stream_id, error } => {
+                    if let Some(stream) = self.streams.get_mut(&stream_id) {
+                        stream.reset = Some(error);
+                        stream.wake();
+                    }
+                }
+                Http3ClientEvent::AuthenticationNeeded => {
+                    // As in neqo-client, the certificate is accepted once NSS has checked it.
+                    self.client
+                        .authenticated(AuthenticationStatus::Ok, Instant::now());
+                }
+                Http3ClientEvent::StateChange(state) => {
+                    qinfo!("Connection state {:?}", state);
+                    for waker in self.state_wakers.drain(..) {
+                        waker.wake();
+                    }
+                    if self.closed() {
+                        for stream in self.streams.values_mut() {
+                            stream.wake();
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Res<()>> {
+        match self.client.state() {
+            // As for `Http3Client::fetch`, requests can still be sent while the connection is
+            // going away; the server refuses those that it will not process.
+            Http3State::ZeroRtt | Http3State::Connected | Http3State::GoingAway => {
+                Poll::Ready(Ok(()))
+            }
+            Http3State::Initializing => {
+                self.state_wakers.push(cx.waker().clone());
+                Poll::Pending
+            }
+            Http3State::Closing(_) | Http3State::Closed(_) => {
+                Poll::Ready(Err(Error::TransportError(TransportError::ConnectionState)))
+            }
+        }
+    }
+
+    // Check for a stream error before the stream is used, so that the error is returned
+    // rather than "invalid stream".
+    fn stream_error(&self, stream_id: u64) -> Option<Error> {
+        match self.streams.get(&stream_id) {
+            Some(RequestStream {
+                reset: Some(error), ..
+            }) => Some(Error::from(*error)),
+            _ if self.closed() => Some(Error::TransportError(TransportError::ConnectionState)),
+            _ => None,
+        }
+    }
+
+    fn poll_headers(&mut self, stream_id: u64, cx: &mut Context) -> Poll<Res<(Vec<Header>, bool)>> {
+        if let Some(e) = self.stream_error(stream_id) {
+            return Poll::Ready(Err(e));
+        }
+        let stream = self.streams.get_mut(&stream_id).unwrap();
+        if stream.header_ready {
+            Poll::Ready(self.client.read_response_headers(StreamId(stream_id)))
+        } else {
+            stream.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    fn poll_read(
+        &mut self,
+        stream_id: u64,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<Res<(usize, bool)>> {
+        if let Some(e) = self.stream_error(stream_id) {
+            return Poll::Ready(Err(e));
+        }
+        match self
+            .client
+            .read_response_data(Instant::now(), StreamId(stream_id), buf)
+        {
+            Ok((0, false)) => {
+                self.streams.get_mut(&stream_id).unwrap().waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            res => Poll::Ready(res),
+        }
+    }
+}
+
+/// An HTTP/3 client that runs on a Tokio `UdpSocket`.
+#[derive(Clone)]
+pub struct Client {
+    shared: Rc<RefCell<Shared>>,
+    socket: Rc<UdpSocket>,
+    // Wakes `run` when a request or a read needs something to be sent.
+    notify: Rc<Notify>,
+}
+
+impl Client {
+    /// Use `client` to talk to its peer over `socket`. Nothing is sent until `run` is polled.
+    pub fn new(client: Http3Client, socket: UdpSocket) -> Self {
+        Self {
+            shared: Rc::new(RefCell::new(Shared {
+                client,
+                streams: HashMap::new(),
+                state_wakers: Vec::new(),
+            })),
+            socket: Rc::new(socket),
+            notify: Rc::new(Notify::new()),
+        }
+    }
+
+    /// Send and receive datagrams until the connection is closed, and return its final
+    /// state. This replaces the loop around `process_input` and `process_output`.
+    pub async fn run(&self) -> io::Result<Http3State> {
+        let local_addr = self.socket.local_addr()?;
+        let mut buf = vec![0; 2048];
+        loop {
+            let (datagrams, timeout) = {
+                let mut shared = self.shared.borrow_mut();
+                shared.client.process_http3(Instant::now());
+                shared.handle_events();
+                let mut datagrams = Vec::new();
+                let timeout = loop {
+                    match shared.client.process_output(Instant::now()) {
+                        Output::Datagram(dgram) => datagrams.push(dgram),
+                        Output::Callback(duration) => break Some(duration),
+                        Output::None => break None,
+                    }
+                };
+                (datagrams, timeout)
+            };
+            for dgram in datagrams {
+                self.socket.send_to(&dgram, dgram.destination()).await?;
+            }
+            let timeout = match timeout {
+                Some(timeout) => timeout,
+                None => {
+                    let mut shared = self.shared.borrow_mut();
+                    shared.handle_events();
+                    return Ok(shared.client.state());
+                }
+            };
+
+            tokio::select! {
+                res = self.socket.recv_from(&mut buf) => {
+                    let (sz, remote_addr) = res?;
+                    self.shared.borrow_mut().client.process_input(
+                        Datagram::new(remote_addr, local_addr, &buf[..sz]),
+                        Instant::now(),
+                    );
+                }
+                _ = sleep(timeout) => {
+                    self.shared.borrow_mut().client.process_timer(Instant::now());
+                }
+                _ = self.notify.notified() => {}
+            }
+        }
+    }
+
+    /// Wait until requests can be sent, i.e. the connection is established or 0-RTT is used,
+    /// as `Http3Client::fetch` requires.
+    pub async fn ready(&self) -> Res<()> {
+        poll_fn(|cx| self.shared.borrow_mut().poll_ready(cx)).await
+    }
+
+    /// Send a request without a body and wait for the response headers.
+    pub async fn fetch(
+        &self,
+        method: &str,
+        scheme: &str,
+        host: &str,
+        path: &str,
+        headers: &[Header],
+    ) -> Res<Response> {
+        self.ready().await?;
+        let stream_id = {
+            let mut shared = self.shared.borrow_mut();
+            let stream_id = shared.client.fetch(method, scheme, host, path, headers)?;
+            shared.client.stream_close_send(StreamId(stream_id))?;
+            shared.streams.insert(stream_id, RequestStream::default());
+            stream_id
+        };
+        self.notify.notify_one();
+
+        // The body is read by `Body`, which removes the stream when it is dropped.
+        let mut body = Body {
+            client: self.clone(),
+            stream_id,
+            fin: false,
+        };
+        let (headers, fin) =
+            poll_fn(|cx| self.shared.borrow_mut().poll_headers(stream_id, cx)).await?;
+        body.fin = fin;
+        Ok(Response { headers, body })
+    }
+
+    /// Close the connection; `run` returns once the CONNECTION_CLOSE has been sent.
+    pub fn close(&self, error: AppError, msg: &str) {
+        self.shared
+            .borrow_mut()
+            .client
+            .close_graceful(Instant::now(), error, msg);
+        self.notify.notify_one();
+    }
+
+    /// Access the `Http3Client`, e.g. for its statistics.
+    pub fn with_client<R>(&self, f: impl FnOnce(&mut Http3Client) -> R) -> R {
+        f(&mut self.shared.borrow_mut().client)
+    }
+}
+
+/// A response to `Client::fetch`.
+pub struct Response {
+    pub headers: Vec<Header>,
+    pub body: Body,
+}
+
+/// The body of a response. If it is dropped before the end, the request is cancelled.
+pub struct Body {
+    client: Client,
+    stream_id: u64,
+    fin: bool,
+}
+
+impl AsyncRead for Body {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        if self.fin {
+            return Poll::Ready(Ok(()));
+        }
+        let res = self.client.shared.borrow_mut().poll_read(
+            self.stream_id,
+            cx,
+            buf.initialize_unfilled(),
+        );
+        match res {
+            Poll::Ready(Ok((amount, fin))) => {
+                buf.advance(amount);
+                self.fin = fin;
+                // Reading may open the flow control window.
+                self.client.notify.notify_one();
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for Body {
+    fn drop(&mut self) {
+        let mut shared = self.client.shared.borrow_mut();
+        shared.streams.remove(&self.stream_id);
+        if !self.fin {
+            let _ = shared
+                .client
+                .stream_reset(StreamId(self.stream_id), Error::HttpRequestCancelled.code());
+            self.client.notify.notify_one();
+        }
+    }
+}
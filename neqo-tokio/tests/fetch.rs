@@ -0,0 +1,118 @@
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use neqo_common::{matches, Datagram};
+use neqo_http3::{Error, Http3Client, Http3Server, Http3ServerEvent, Http3State, Output};
+use neqo_tokio::Client;
+use neqo_transport::FixedConnectionIdManager;
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use test_fixture::*;
+use tokio::io::AsyncReadExt;
+use tokio::net::UdpSocket;
+use tokio::time::{sleep, timeout};
+
+// Larger than a datagram, so that the body is read in several parts.
+const RESPONSE_DATA: &[u8] = &[0x61; 5000];
+
+fn respond(server: &mut Http3Server) {
+    while let Some(event) = server.next_event() {
+        if let Http3ServerEvent::Headers { mut request, .. } = event {
+            request
+                .set_response(
+                    &[
+                        (String::from(":status"), String::from("200")),
+                        (
+                            String::from("content-length"),
+                            RESPONSE_DATA.len().to_string(),
+                        ),
+                    ],
+                    RESPONSE_DATA.to_vec(),
+                )
+                .unwrap();
+        }
+    }
+}
+
+// Run `server` on `socket`. This only returns if the socket fails.
+async fn serve(mut server: Http3Server, socket: UdpSocket) -> io::Result<()> {
+    let local_addr = socket.local_addr()?;
+    let mut buf = vec![0; 2048];
+    let mut dgram = None;
+    loop {
+        let delay = loop {
+            match server.process(dgram.take(), Instant::now()) {
+                Output::Datagram(d) => {
+                    socket.send_to(&d, d.destination()).await?;
+                }
+                Output::Callback(delay) => break delay,
+                Output::None => break Duration::from_secs(60),
+            }
+        };
+        if server.has_events() {
+            respond(&mut server);
+            continue;
+        }
+        tokio::select! {
+            res = socket.recv_from(&mut buf) => {
+                let (sz, remote_addr) = res?;
+                dgram = Some(Datagram::new(remote_addr, local_addr, &buf[..sz]));
+            }
+            _ = sleep(delay) => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn fetch() {
+    let server = default_http3_server();
+    let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server_socket.local_addr().unwrap();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client = Client::new(
+        Http3Client::new(
+            DEFAULT_SERVER_NAME,
+            DEFAULT_ALPN,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(3))),
+            socket.local_addr().unwrap(),
+            server_addr,
+            100,
+            100,
+        )
+        .unwrap(),
+        socket,
+    );
+
+    let request = async {
+        let mut response = client
+            .fetch("GET", "https", DEFAULT_SERVER_NAME, "/", &[])
+            .await
+            .unwrap();
+        assert!(response
+            .headers
+            .contains(&(String::from(":status"), String::from("200"))));
+        let mut body = Vec::new();
+        response.body.read_to_end(&mut body).await.unwrap();
+        assert_eq!(body, RESPONSE_DATA);
+        client.close(Error::HttpNoError.code(), "");
+    };
+
+    let state = timeout(Duration::from_secs(10), async {
+        tokio::select! {
+            (state, ()) = async { tokio::join!(client.run(), request) } => state.unwrap(),
+            res = serve(server, server_socket) => panic!("The server failed: {:?}", res),
+        }
+    })
+    .await
+    .expect("the fetch takes too long");
+    assert!(matches!(
+        state,
+        Http3State::Closing(_) | Http3State::Closed(_)
+    ));
+}
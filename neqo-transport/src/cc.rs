@@ -22,8 +22,37 @@ const INITIAL_WINDOW: usize = const_min(
 pub const MIN_CONG_WINDOW: usize = MAX_DATAGRAM_SIZE * 2;
 const PERSISTENT_CONG_THRESH: u32 = 3;
 
+/// The congestion control algorithms that a connection can use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CongestionControlAlgorithm {
+    NewReno,
+}
+
+impl CongestionControlAlgorithm {
+    /// The name of the algorithm, as used in logs and on the command line.
+    #[must_use]
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::NewReno => "newreno",
+        }
+    }
+}
+
+impl Default for CongestionControlAlgorithm {
+    fn default() -> Self {
+        Self::NewReno
+    }
+}
+
+impl Display for CongestionControlAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 #[derive(Debug)]
 pub struct CongestionControl {
+    algorithm: CongestionControlAlgorithm,
     congestion_window: usize, // = kInitialWindow
     bytes_in_flight: usize,
     congestion_recovery_start_time: Option<Instant>,
@@ -32,12 +61,7 @@ pub struct CongestionControl {
 
 impl Default for CongestionControl {
     fn default() -> Self {
-        Self {
-            congestion_window: INITIAL_WINDOW,
-            bytes_in_flight: 0,
-            congestion_recovery_start_time: None,
-            ssthresh: std::usize::MAX,
-        }
+        Self::new(CongestionControlAlgorithm::default())
     }
 }
 
@@ -52,13 +76,27 @@ impl Display for CongestionControl {
 }
 
 impl CongestionControl {
-    #[cfg(test)]
+    #[must_use]
+    pub fn new(algorithm: CongestionControlAlgorithm) -> Self {
+        Self {
+            algorithm,
+            congestion_window: INITIAL_WINDOW,
+            bytes_in_flight: 0,
+            congestion_recovery_start_time: None,
+            ssthresh: std::usize::MAX,
+        }
+    }
+
+    #[must_use]
+    pub fn algorithm(&self) -> CongestionControlAlgorithm {
+        self.algorithm
+    }
+
     #[must_use]
     pub fn cwnd(&self) -> usize {
         self.congestion_window
     }
 
-    #[cfg(test)]
     #[must_use]
     pub fn ssthresh(&self) -> usize {
         self.ssthresh
@@ -8,7 +8,7 @@
 
 use std::cell::RefCell;
 use std::cmp::{max, min, Ordering};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::fmt::{self, Debug};
@@ -18,13 +18,16 @@ use std::time::{Duration, Instant};
 
 use smallvec::SmallVec;
 
-use neqo_common::{hex, matches, qdebug, qerror, qinfo, qtrace, qwarn, Datagram, Decoder, Encoder};
+use neqo_common::{
+    hex, matches, qdebug, qerror, qinfo, qtrace, qwarn, Datagram, Decoder, Ecn, Encoder,
+};
 use neqo_crypto::agent::CertificateInfo;
 use neqo_crypto::{
-    Agent, AntiReplay, AuthenticationStatus, Client, HandshakeState, Record, SecretAgentInfo,
-    Server,
+    random, Agent, AntiReplay, AuthenticationStatus, Client, HandshakeState, Record,
+    SecretAgentInfo, Server,
 };
 
+use crate::cc::CongestionControlAlgorithm;
 use crate::crypto::Crypto;
 use crate::dump::*;
 use crate::events::{ConnectionEvent, ConnectionEvents};
@@ -56,6 +59,9 @@ const LOCAL_MAX_DATA: u64 = 0x3FFF_FFFF_FFFF_FFFF; // 2^62-1
 
 const LOCAL_IDLE_TIMEOUT: Duration = Duration::from_secs(60); // 1 minute
 
+/// The most datagrams that wait to be sent; the oldest is dropped to make room.
+const MAX_QUEUED_DATAGRAMS: usize = 10;
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 /// Client or Server.
 pub enum Role {
@@ -149,6 +155,22 @@ impl Path {
         self.local == d.destination() && self.remote == d.source()
     }
 
+    /// A path between other addresses.  It uses connection IDs that no other path used, so
+    /// that an observer can't link the two.
+    fn with_addresses(
+        local: SocketAddr,
+        remote: SocketAddr,
+        local_cid: ConnectionId,
+        remote_cid: ConnectionId,
+    ) -> Self {
+        Self {
+            local,
+            remote,
+            local_cids: vec![local_cid],
+            remote_cid,
+        }
+    }
+
     fn mtu(&self) -> usize {
         if self.local.is_ipv4() {
             1252
@@ -158,6 +180,51 @@ impl Path {
     }
 }
 
+/// A path that is being validated, which the connection moves to once a PATH_RESPONSE
+/// with the data of our PATH_CHALLENGE is received on it.
+#[derive(Debug)]
+struct PathValidation {
+    path: Path,
+    challenge: [u8; 8],
+    /// When the PATH_CHALLENGE was last sent.  It is sent again each PTO until `expiry`.
+    challenge_sent: Option<Instant>,
+    /// The data of a PATH_CHALLENGE received on the path, to be echoed on it.
+    response: Option<[u8; 8]>,
+    /// When the path is given up on.
+    expiry: Option<Instant>,
+    /// Whether the peer started using the path.  Until the path is validated, no more than
+    /// three times the bytes received on it are then sent on it.
+    limited: bool,
+    received: usize,
+    sent: usize,
+}
+
+impl PathValidation {
+    fn new(path: Path, limited: bool) -> Self {
+        let mut challenge = [0; 8];
+        challenge.copy_from_slice(&random(8));
+        Self {
+            path,
+            challenge,
+            challenge_sent: None,
+            response: None,
+            expiry: None,
+            limited,
+            received: 0,
+            sent: 0,
+        }
+    }
+
+    /// How many bytes may still be sent on the path.
+    fn send_budget(&self) -> usize {
+        if self.limited {
+            (3 * self.received).saturating_sub(self.sent)
+        } else {
+            usize::max_value()
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 /// Type returned from process() and `process_output()`. Users are required to
 /// call these repeatedly until `Callback` or `None` is returned.
@@ -297,6 +364,8 @@ pub struct Connection {
     cid_manager: CidMgr,
     /// Network paths.  Right now, this tracks at most one path, so it uses `Option`.
     path: Option<Path>,
+    /// A new path that is being validated, see `migrate()`.
+    path_validation: Option<PathValidation>,
     /// The connection IDs that we will accept.
     /// This includes any we advertise in NEW_CONNECTION_ID that haven't been bound to a path yet.
     /// During the handshake at the server, it also includes the randomized DCID pick by the client.
@@ -316,6 +385,15 @@ pub struct Connection {
     token: Option<Vec<u8>>,
     stats: Stats,
     tx_mode: TxMode,
+    /// Whether to send a PING when half of the idle timeout has passed without activity.
+    keep_alive: bool,
+    /// The idle timeout in force when an ack-eliciting packet was last sent.  That packet
+    /// serves as the keep-alive for this timeout, so no PING is needed until it changes.
+    keep_alive_pinged: Option<Instant>,
+    /// Datagrams waiting to be sent in DATAGRAM frames.
+    datagrams: VecDeque<Vec<u8>>,
+    /// The number of bytes that the application has read from all streams.
+    rx_data_retired: u64,
 }
 
 impl Debug for Connection {
@@ -330,6 +408,8 @@ impl Debug for Connection {
 
 impl Connection {
     /// Create a new QUIC connection with Client role.
+    /// `server_name` is only used for the TLS SNI and certificate checks; packets are sent to
+    /// `remote_addr`, which does not need to be an address of that name.
     pub fn new_client(
         server_name: &str,
         protocols: &[impl AsRef<str>],
@@ -424,6 +504,7 @@ impl Connection {
             },
             cid_manager,
             path,
+            path_validation: None,
             valid_cids: Vec::new(),
             tps: tphandler,
             zero_rtt_state: ZeroRttState::Init,
@@ -442,6 +523,10 @@ impl Connection {
             token: None,
             stats: Stats::default(),
             tx_mode: TxMode::Normal,
+            keep_alive: false,
+            keep_alive_pinged: None,
+            datagrams: VecDeque::new(),
+            rx_data_retired: 0,
         }
     }
 
@@ -460,6 +545,119 @@ impl Connection {
         }
     }
 
+    /// Set the connection flow control limit that is advertised to the peer, in bytes, i.e.
+    /// how much data the peer may send on all streams together before the application reads it.
+    /// The limit moves forward with MAX_DATA frames as the application reads data. The default
+    /// is 2^62-1. This can only be done before the connection starts.
+    pub fn set_max_data(&self, max_data: u64) -> Res<()> {
+        self.set_local_tparam(
+            tp_constants::INITIAL_MAX_DATA,
+            TransportParameter::Integer(max_data),
+        )
+    }
+
+    /// Set the stream flow control limit that is advertised to the peer, in bytes, i.e. how much
+    /// data the peer may send on a stream before it gets more credit. This applies to all types
+    /// of streams; the default is 65535 (`RX_STREAM_DATA_WINDOW`). This can only be done before
+    /// the connection starts.
+    pub fn set_max_stream_data(&self, max_stream_data: u64) -> Res<()> {
+        for key in &[
+            tp_constants::INITIAL_MAX_STREAM_DATA_BIDI_LOCAL,
+            tp_constants::INITIAL_MAX_STREAM_DATA_BIDI_REMOTE,
+            tp_constants::INITIAL_MAX_STREAM_DATA_UNI,
+        ] {
+            self.set_local_tparam(*key, TransportParameter::Integer(max_stream_data))?;
+        }
+        Ok(())
+    }
+
+    /// Allow the peer to send DATAGRAM frames of up to `size` bytes, including the frame type
+    /// and length. The default is 0, which means that the peer may not send any. This can only
+    /// be done before the connection starts.
+    pub fn set_max_datagram_frame_size(&self, size: u64) -> Res<()> {
+        self.set_local_tparam(
+            tp_constants::MAX_DATAGRAM_FRAME_SIZE,
+            TransportParameter::Integer(size),
+        )
+    }
+
+    /// Allow the peer to migrate the connection to a new address.  Migration is disabled by
+    /// default, which is advertised with the DISABLE_MIGRATION transport parameter.  This can
+    /// only be done before the connection starts.
+    pub fn set_allow_migration(&self, allow: bool) -> Res<()> {
+        self.set_local_tparam(tp_constants::DISABLE_MIGRATION, TransportParameter::Empty)?;
+        if allow {
+            self.tps
+                .borrow_mut()
+                .local
+                .remove(tp_constants::DISABLE_MIGRATION);
+        }
+        Ok(())
+    }
+
+    /// Queue `data` to be sent in a DATAGRAM frame. Datagrams are sent before new stream
+    /// data, but they are not retransmitted if they are lost. If too many are waiting to be
+    /// sent, the oldest one is dropped, which `Stats::datagrams_dropped_tx` counts. This fails
+    /// with `Error::TooMuchData` if the frame would be larger than the peer allows, which
+    /// includes a peer that does not support DATAGRAM frames.
+    pub fn send_datagram(&mut self, data: &[u8]) -> Res<()> {
+        let max = {
+            let tps = self.tps.borrow();
+            if tps.remote.is_none() && tps.remote_0rtt.is_none() {
+                return Err(Error::NotConnected);
+            }
+            tps.remote()
+                .get_integer(tp_constants::MAX_DATAGRAM_FRAME_SIZE)
+        };
+        if u64::try_from(Frame::datagram_len(data.len()))? > max {
+            return Err(Error::TooMuchData);
+        }
+        if self.datagrams.len() == MAX_QUEUED_DATAGRAMS {
+            qdebug!([self], "Datagram queue full, dropping the oldest");
+            self.datagrams.pop_front();
+            self.stats.datagrams_dropped_tx += 1;
+        }
+        self.datagrams.push_back(data.to_vec());
+        Ok(())
+    }
+
+    /// Select the congestion control algorithm.  This can only be done before
+    /// the connection starts.
+    pub fn set_congestion_control(&mut self, algorithm: CongestionControlAlgorithm) -> Res<()> {
+        if matches!(
+            (self.role(), self.state()),
+            (Role::Client, State::Init) | (Role::Server, State::WaitInitial)
+        ) {
+            qinfo!([self], "Using congestion control {}", algorithm);
+            self.loss_recovery.set_congestion_control(algorithm);
+            Ok(())
+        } else {
+            qerror!(
+                [self],
+                "Cannot set congestion control in state {:?}",
+                self.state
+            );
+            Err(Error::ConnectionState)
+        }
+    }
+
+    /// Get the congestion control algorithm in use.
+    pub fn congestion_control(&self) -> CongestionControlAlgorithm {
+        self.loss_recovery.congestion_control()
+    }
+
+    /// Whether the address of the client was validated with a Retry before the server accepted
+    /// the connection. This is always false for a client.
+    pub fn address_validated(&self) -> bool {
+        self.role == Role::Server
+            && self
+                .tps
+                .borrow()
+                .local
+                .get_bytes(tp_constants::ORIGINAL_CONNECTION_ID)
+                .is_some()
+    }
+
     /// Set the connection ID that was originally chosen by the client.
     pub(crate) fn original_connection_id(&mut self, odcid: &ConnectionId) {
         assert_eq!(self.role, Role::Server);
@@ -560,6 +758,20 @@ impl Connection {
         self.crypto.tls.info()
     }
 
+    /// The ALPN protocol that the server chose. A client knows it once it has the server's
+    /// handshake messages, i.e. from `AuthenticationNeeded` on, before the handshake is done.
+    pub fn alpn(&self) -> Option<String> {
+        match self.crypto.tls.info() {
+            Some(info) => info.alpn().cloned(),
+            None => self
+                .crypto
+                .tls
+                .preinfo()
+                .ok()
+                .and_then(|info| info.alpn().cloned()),
+        }
+    }
+
     /// Get the peer's certificate chain and other info.
     pub fn peer_certificate(&self) -> Option<CertificateInfo> {
         self.crypto.tls.peer_certificate()
@@ -587,6 +799,51 @@ impl Connection {
         &self.stats
     }
 
+    /// The local and remote addresses of the path that the connection uses, or `None` if
+    /// a server has not received a packet yet.
+    pub fn current_path(&self) -> Option<(SocketAddr, SocketAddr)> {
+        self.path.as_ref().map(|p| (p.local, p.remote))
+    }
+
+    /// The connection ID that the peer uses to address this endpoint on the current path,
+    /// or `None` if there is no path yet. It can be empty.
+    pub fn local_cid(&self) -> Option<&[u8]> {
+        self.path
+            .as_ref()
+            .and_then(|p| p.local_cids.first())
+            .map(|cid| &cid[..])
+    }
+
+    /// Enable or disable keep-alive.  When enabled, a PING is sent once half of the idle
+    /// timeout has passed without the timer being reset, so that an otherwise quiet
+    /// connection isn't closed.
+    pub fn set_keep_alive(&mut self, keep_alive: bool) {
+        self.keep_alive = keep_alive;
+    }
+
+    /// The time at which a keep-alive PING is due, if one is.
+    fn keep_alive_time(&self) -> Option<Instant> {
+        if !self.keep_alive || !matches!(self.state, State::Connected) {
+            return None;
+        }
+        let idle_time = self.idle_timeout.as_instant()?;
+        if self.keep_alive_pinged == Some(idle_time) {
+            return None;
+        }
+        Some(idle_time - LOCAL_IDLE_TIMEOUT / 2)
+    }
+
+    /// Get the current congestion window, in bytes.
+    pub fn congestion_window(&self) -> usize {
+        self.loss_recovery.cwnd()
+    }
+
+    /// Get the current slow start threshold, in bytes. This is `usize::MAX` until the first
+    /// congestion event.
+    pub fn slow_start_threshold(&self) -> usize {
+        self.loss_recovery.ssthresh()
+    }
+
     // This function wraps a call to another function and sets the connection state
     // properly if that call fails.
     fn capture_error<T>(&mut self, now: Instant, frame_type: FrameType, res: Res<T>) -> Res<T> {
@@ -624,6 +881,18 @@ impl Connection {
         let res = self.crypto.states.check_key_update(now);
         self.absorb_error(now, res);
 
+        if let Some(v) = &self.path_validation {
+            if v.expiry.map_or(false, |t| now >= t) {
+                qinfo!(
+                    [self],
+                    "Validation of path from {} to {} failed",
+                    v.path.local,
+                    v.path.remote
+                );
+                self.path_validation = None;
+            }
+        }
+
         if self.idle_timeout.expired(now) {
             qinfo!("idle timeout expired");
             self.set_state(State::Closed(ConnectionError::Transport(
@@ -655,7 +924,7 @@ impl Connection {
     fn next_delay(&mut self, now: Instant) -> Duration {
         self.loss_recovery_state = self.loss_recovery.get_timer();
 
-        let mut delays = SmallVec::<[_; 4]>::new();
+        let mut delays = SmallVec::<[_; 6]>::new();
 
         if let Some(lr_time) = self.loss_recovery_state.callback_time() {
             delays.push(lr_time);
@@ -669,10 +938,27 @@ impl Connection {
             delays.push(idle_time);
         }
 
+        if let Some(keep_alive_time) = self.keep_alive_time() {
+            delays.push(keep_alive_time);
+        }
+
         if let Some(key_update_time) = self.crypto.states.update_time() {
             delays.push(key_update_time);
         }
 
+        if let Some(v) = &self.path_validation {
+            if let Some(expiry) = v.expiry {
+                delays.push(expiry);
+            }
+            // A PATH_CHALLENGE that the amplification limit blocks is sent once more data
+            // is received on the path, so there is no point in waking up for it.
+            if let Some(sent) = v.challenge_sent {
+                if v.send_budget() >= v.path.mtu() {
+                    delays.push(sent + self.loss_recovery.pto());
+                }
+            }
+        }
+
         // Should always at least have idle timeout, once connected
         assert!(!delays.is_empty());
         let earliest = delays.into_iter().min().unwrap();
@@ -727,7 +1013,12 @@ impl Connection {
     }
 
     fn is_valid_cid(&self, cid: &ConnectionId) -> bool {
-        self.valid_cids.contains(cid) || self.path.iter().any(|p| p.local_cids.contains(cid))
+        self.valid_cids.contains(cid)
+            || self.path.iter().any(|p| p.local_cids.contains(cid))
+            || self
+                .path_validation
+                .iter()
+                .any(|v| v.path.local_cids.contains(cid))
     }
 
     fn is_valid_initial(&self, hdr: &PacketHdr) -> bool {
@@ -782,6 +1073,7 @@ impl Connection {
             token: token.to_vec(),
             odcid: odcid.clone(),
         });
+        self.stats.retry_dcid = Some(scid.to_vec());
         let lost_packets = self.loss_recovery.retry();
         self.handle_lost_packets(&lost_packets);
 
@@ -801,6 +1093,27 @@ impl Connection {
         let mut frames = Vec::new();
 
         qdebug!([self], "input {}", hex(&**d));
+        self.stats.bytes_rx += d.len();
+        match d.ecn() {
+            Ecn::NotEct => {}
+            Ecn::Ect0 => self.stats.ect0_rx += 1,
+            Ecn::Ect1 => self.stats.ect1_rx += 1,
+            Ecn::Ce => self.stats.ce_rx += 1,
+        }
+
+        // Once there is a path, datagrams from other addresses can only be used to validate
+        // a new path, see `input_probe()`.  Their source address may have been spoofed.
+        let mut on_path = self.path.as_ref().map_or(true, |p| p.received_on(&d));
+        if !on_path && !self.may_probe(&d) {
+            qinfo!(
+                [self],
+                "Dropping datagram from {} to {}, not on the current path",
+                d.source(),
+                d.destination()
+            );
+            self.stats.dropped_rx += 1;
+            return Ok(frames);
+        }
 
         // Handle each packet in the datagram
         while !slc.is_empty() {
@@ -820,7 +1133,17 @@ impl Connection {
             };
             self.stats.packets_rx += 1;
             match (&hdr.tipe, &self.state, &self.role) {
-                (PacketType::VN(_), State::WaitInitial, Role::Client) => {
+                (PacketType::VN(versions), State::WaitInitial, Role::Client) => {
+                    // A Version Negotiation packet that lists the version we use cannot be
+                    // genuine. Ignoring it also keeps a broken server from making us loop.
+                    if versions.contains(&QUIC_VERSION) {
+                        qinfo!([self], "Dropping Version Negotiation listing our version");
+                        self.stats.dropped_rx += 1;
+                        return Ok(frames);
+                    }
+                    // We only support one version, so there is nothing else to try.
+                    qinfo!([self], "No mutually supported version in {:x?}", versions);
+                    self.stats.vn_versions = versions.clone();
                     self.set_state(State::Closed(ConnectionError::Transport(
                         Error::VersionNegotiation,
                     )));
@@ -876,6 +1199,11 @@ impl Connection {
                         // Server has received a Handshake packet -> discard Initial keys and states
                         self.discard_keys(PNSpace::Initial);
                     }
+                    if !on_path && !matches!(hdr.tipe, PacketType::Short(..)) {
+                        qinfo!([self], "Dropping long header packet from another path");
+                        self.stats.dropped_rx += 1;
+                        return Ok(frames);
+                    }
                 }
                 State::Closing { .. } => {
                     // Don't bother processing the packet. Instead ask to get a
@@ -901,11 +1229,16 @@ impl Connection {
                 // OK, we have a valid packet.
                 self.idle_timeout.on_packet_received(now);
                 dump_packet(self, "-> RX", &hdr, &body);
+                if !on_path {
+                    on_path = self.input_probe(&d, &hdr, &body, now)?;
+                    if !on_path {
+                        continue;
+                    }
+                }
                 frames.extend(self.process_packet(&hdr, body, now)?);
                 if matches!(self.state, State::WaitInitial) {
                     self.start_handshake(hdr, &d)?;
                 }
-                self.process_migrations(&d)?;
             } else {
                 // Decryption failure, or not having keys is not fatal.
                 // If the state isn't available, or we can't decrypt the packet, drop
@@ -916,6 +1249,119 @@ impl Connection {
         Ok(frames)
     }
 
+    /// Whether a datagram from outside the current path can be used for path validation.
+    /// Only one path is validated at a time, so that a datagram from yet another address,
+    /// which may have been copied or spoofed, cannot cancel a validation.  A server starts
+    /// validating a new address of the client if it allows migration, but a client only
+    /// uses the path that it is migrating to.
+    fn may_probe(&self, d: &Datagram) -> bool {
+        if self.state != State::Connected {
+            return false;
+        }
+        match &self.path_validation {
+            Some(v) => v.path.received_on(d),
+            None => {
+                self.role == Role::Server
+                    && !self
+                        .tps
+                        .borrow()
+                        .local
+                        .get_empty(tp_constants::DISABLE_MIGRATION)
+            }
+        }
+    }
+
+    /// Handle a packet that arrived from outside the current path.  Its PATH_CHALLENGE and
+    /// PATH_RESPONSE frames are used to validate the path; a packet with other frames is only
+    /// processed if it completes validation, and then this returns true.
+    fn input_probe(
+        &mut self,
+        d: &Datagram,
+        hdr: &PacketHdr,
+        body: &[u8],
+        now: Instant,
+    ) -> Res<bool> {
+        let mut challenge = None;
+        let mut validated = false;
+        let mut probing = true;
+        let mut dec = Decoder::from(body);
+        while dec.remaining() > 0 {
+            match Frame::decode(&mut dec)? {
+                Frame::PathChallenge { data } => challenge = Some(data),
+                Frame::PathResponse { data } => {
+                    validated |= self
+                        .path_validation
+                        .as_ref()
+                        .map_or(false, |v| v.path.received_on(d) && v.challenge == data);
+                }
+                Frame::Padding => {}
+                _ => probing = false,
+            }
+        }
+
+        if validated {
+            let v = self.path_validation.take().unwrap();
+            qinfo!(
+                [self],
+                "Path from {} to {} validated, migrating",
+                v.path.local,
+                v.path.remote
+            );
+            self.path = Some(v.path);
+            return Ok(true);
+        }
+
+        let validating = self
+            .path_validation
+            .as_ref()
+            .map_or(false, |v| v.path.received_on(d));
+        if !validating {
+            // Only a server gets here for a path it isn't validating, see `may_probe()`.
+            let remote_cid = if let Some(cid) = self.take_remote_cid() {
+                cid
+            } else {
+                qinfo!(
+                    [self],
+                    "Dropping packet from {}, no connection ID to validate the path with",
+                    d.source()
+                );
+                self.stats.dropped_rx += 1;
+                return Ok(false);
+            };
+            self.valid_cids.retain(|cid| *cid != hdr.dcid);
+            let path =
+                Path::with_addresses(d.destination(), d.source(), hdr.dcid.clone(), remote_cid);
+            qinfo!(
+                [self],
+                "Validating path from {} to {}",
+                path.local,
+                path.remote
+            );
+            let mut v = PathValidation::new(path, true);
+            v.expiry = Some(now + 3 * self.loss_recovery.pto());
+            self.path_validation = Some(v);
+        }
+        let v = self.path_validation.as_mut().unwrap();
+        v.received += d.len();
+        if let Some(data) = challenge {
+            v.response = Some(data);
+        }
+
+        let space = hdr.tipe.space();
+        if !probing {
+            // The peer sends the packet again if this address turns out to be its own.
+            qinfo!(
+                [self],
+                "Dropping packet from {}, the path is not validated",
+                d.source()
+            );
+            self.stats.dropped_rx += 1;
+        } else if !self.acks[space].is_duplicate(hdr.pn) {
+            self.acks[space].set_received(now, hdr.pn, true);
+        }
+        Ok(false)
+    }
+
     /// Decrypt a packet and - if successful - return the body.
     fn decrypt_packet(
         &mut self,
@@ -1029,17 +1475,15 @@ impl Connection {
         Ok(())
     }
 
-    fn process_migrations(&self, d: &Datagram) -> Res<()> {
-        if self.path.iter().any(|p| p.received_on(&d)) {
-            Ok(())
-        } else {
-            // Right now, we don't support any form of migration.
-            // So generate an error if a packet is received on a new path.
-            Err(Error::InvalidMigration)
-        }
-    }
-
     fn output(&mut self, now: Instant) -> Option<Datagram> {
+        if let Some(mut v) = self.path_validation.take() {
+            let res = self.output_probe(&mut v, now);
+            let out = self.absorb_error(now, res).unwrap_or(None);
+            self.path_validation = Some(v);
+            if out.is_some() {
+                return out;
+            }
+        }
         if let Some(mut path) = self.path.take() {
             let res = self.output_path(&mut path, now);
             let out = self.absorb_error(now, res).unwrap_or(None);
@@ -1064,6 +1508,9 @@ impl Connection {
         for space in PNSpace::iter() {
             let mut encoder = Encoder::default();
             let mut tokens = Vec::new();
+            let mut keep_alive = *space == PNSpace::ApplicationData
+                && self.tx_mode == TxMode::Normal
+                && self.keep_alive_time().map_or(false, |t| now >= t);
 
             // Ensure we have tx crypto state for this epoch, or skip it.
             let tx = if let Some(tx_state) = self.crypto.states.tx(*space) {
@@ -1131,12 +1578,38 @@ impl Connection {
                         if frame.is_none() && self.tx_mode == TxMode::Normal {
                             frame = self.flow_mgr.borrow_mut().get_frame(*space, remaining);
                         }
+                        if frame.is_none() && *space == PNSpace::ApplicationData {
+                            if let Some(data) = self.datagrams.front() {
+                                let len = Frame::datagram_len(data.len());
+                                if len <= remaining {
+                                    let data = self.datagrams.pop_front().unwrap();
+                                    frame = Some((Frame::Datagram { data }, None));
+                                } else if out_bytes.is_empty()
+                                    && encoder.len() == 0
+                                    && len > path.mtu().saturating_sub(used)
+                                {
+                                    qwarn!(
+                                        [self],
+                                        "Datagram of {} bytes does not fit a packet",
+                                        len
+                                    );
+                                    self.datagrams.pop_front();
+                                    self.stats.datagrams_dropped_tx += 1;
+                                    continue;
+                                }
+                            }
+                        }
                         if frame.is_none() {
                             frame = self.send_streams.get_frame(*space, tx_mode, remaining)
                         }
                         if frame.is_none() && self.tx_mode == TxMode::Pto {
                             frame = Some((Frame::Ping, None));
                         }
+                        if frame.is_none() && keep_alive && !ack_eliciting && !tx.is_0rtt() {
+                            qdebug!("Sending keep-alive PING");
+                            keep_alive = false;
+                            frame = Some((Frame::Ping, None));
+                        }
 
                         if let Some((frame, token)) = frame {
                             ack_eliciting |= frame.ack_eliciting();
@@ -1208,6 +1681,9 @@ impl Connection {
 
             if self.tx_mode != TxMode::Pto && ack_eliciting {
                 self.idle_timeout.on_packet_sent(now);
+                if *space == PNSpace::ApplicationData && self.keep_alive {
+                    self.keep_alive_pinged = self.idle_timeout.as_instant();
+                }
             }
 
             let in_flight = match self.tx_mode {
@@ -1250,10 +1726,142 @@ impl Connection {
                 qdebug!([self], "pad Initial to max_datagram_size");
                 out_bytes.resize(path.mtu(), 0);
             }
+            self.stats.bytes_tx += out_bytes.len();
             Ok(Some(Datagram::new(path.local, path.remote, out_bytes)))
         }
     }
 
+    /// Build a datagram for a path that is being validated, with the PATH_CHALLENGE and
+    /// PATH_RESPONSE frames that are due on it.  Other frames are only sent on the current path.
+    fn output_probe(&mut self, v: &mut PathValidation, now: Instant) -> Res<Option<Datagram>> {
+        let pto = self.loss_recovery.pto();
+        let challenge_due = v.challenge_sent.map_or(true, |t| now >= t + pto);
+        if self.state != State::Connected || (!challenge_due && v.response.is_none()) {
+            return Ok(None);
+        }
+        let tx = if let Some(tx_state) = self.crypto.states.tx(PNSpace::ApplicationData) {
+            tx_state
+        } else {
+            return Ok(None);
+        };
+        let path = &v.path;
+        let hdr = PacketHdr::new(
+            0,
+            PacketType::Short(tx.key_phase()),
+            Some(self.version),
+            path.remote_cid.clone(),
+            path.local_cids.first().cloned(),
+            tx.next_pn(),
+        );
+
+        let mut encoder = Encoder::default();
+        if let Some(data) = v.response {
+            Frame::PathResponse { data }.marshal(&mut encoder);
+        }
+        if challenge_due {
+            Frame::PathChallenge { data: v.challenge }.marshal(&mut encoder);
+        }
+        let used = encoder.len() + hdr.overhead(tx.expansion(), path.mtu());
+        let budget = v.send_budget();
+        if used > budget {
+            qdebug!(
+                [self],
+                "Probe on path to {} blocked by amplification limit",
+                path.remote
+            );
+            return Ok(None);
+        }
+        // Fill the datagram with PADDING, which shows that the path can carry a full one, as
+        // far as the amplification limit allows.
+        encoder.encode(&vec![0; min(path.mtu(), budget) - used]);
+        v.response = None;
+        if challenge_due {
+            v.challenge_sent = Some(now);
+            v.expiry.get_or_insert(now + 3 * pto);
+        }
+
+        self.stats.packets_tx += 1;
+        let packet = encode_packet(tx, &hdr, &encoder);
+        self.loss_recovery.on_packet_sent(
+            PNSpace::ApplicationData,
+            hdr.pn,
+            SentPacket::new(now, true, Vec::new(), packet.len(), true),
+        );
+        dump_packet(self, "TX ->", &hdr, &encoder);
+        self.stats.bytes_tx += packet.len();
+        v.sent += packet.len();
+        Ok(Some(Datagram::new(v.path.local, v.path.remote, packet)))
+    }
+
+    /// Move the connection to a new local address.  This is only possible for a client
+    /// once the connection is established, and if the server allows it.  Packets are sent
+    /// from `local` after a PATH_CHALLENGE sent from it is answered; until then, the current
+    /// path is used.  The new path uses a connection ID that the server gave in a
+    /// NEW_CONNECTION_ID frame, and fails with `Error::InvalidMigration` if there is none.
+    pub fn migrate(&mut self, local: SocketAddr) -> Res<()> {
+        if self.role != Role::Client || self.state != State::Connected {
+            return Err(Error::ConnectionState);
+        }
+        if self
+            .tps
+            .borrow()
+            .remote()
+            .get_empty(tp_constants::DISABLE_MIGRATION)
+        {
+            return Err(Error::InvalidMigration);
+        }
+        let remote_cid = self.take_remote_cid().ok_or(Error::InvalidMigration)?;
+        let path = self.path.as_ref().unwrap();
+        qinfo!([self], "Migrating from {} to {}", path.local, local);
+        // The server addresses the new path with the connection ID that we gave it.
+        let local_cid = if self.valid_cids.is_empty() {
+            path.local_cids[0].clone()
+        } else {
+            self.valid_cids.remove(0)
+        };
+        let path = Path::with_addresses(local, path.remote, local_cid, remote_cid);
+        self.path_validation = Some(PathValidation::new(path, false));
+        Ok(())
+    }
+
+    /// Give the peer a spare connection ID in a NEW_CONNECTION_ID frame, for use on a new
+    /// path.  This is only needed if the server allows migration, and with zero-length
+    /// connection IDs there is nothing to give.
+    fn issue_connection_id(&mut self) {
+        let migration_disabled = {
+            let tps = self.tps.borrow();
+            let server_tps = if self.role == Role::Server {
+                &tps.local
+            } else {
+                tps.remote()
+            };
+            server_tps.get_empty(tp_constants::DISABLE_MIGRATION)
+        };
+        if migration_disabled || self.path.as_ref().unwrap().local_cids[0].is_empty() {
+            return;
+        }
+        let cid = self.cid_manager.borrow_mut().generate_cid();
+        let mut token = [0; 16];
+        token.copy_from_slice(&random(16));
+        self.flow_mgr
+            .borrow_mut()
+            .new_connection_id(1, cid.0.clone(), token);
+        self.valid_cids.push(cid);
+    }
+
+    /// Take an unused connection ID of the peer for a new path, from those it gave in
+    /// NEW_CONNECTION_ID frames.  A peer with a zero-length connection ID can't give any,
+    /// and then keeps using that.
+    fn take_remote_cid(&mut self) -> Option<ConnectionId> {
+        let current = &self.path.as_ref().unwrap().remote_cid;
+        if current.is_empty() {
+            return Some(current.clone());
+        }
+        let seq = *self.connection_ids.keys().min()?;
+        let (cid, _) = self.connection_ids.remove(&seq).unwrap();
+        Some(ConnectionId(cid))
+    }
+
     pub fn initiate_key_update(&mut self) -> Res<()> {
         // TODO(mt): this needs to be confirmed, not connected.
         if self.state == State::Connected {
@@ -1491,14 +2099,13 @@ impl Connection {
                 }
             }
             Frame::DataBlocked { data_limit } => {
-                // Should never happen since we set data limit to max
-                qwarn!(
+                // The peer has used up the limit set with `set_max_data`. It gets more credit
+                // once the application reads data, see `retire_rx_data`.
+                qdebug!(
                     [self],
                     "Received DataBlocked with data limit {}",
                     data_limit
                 );
-                // But if it does, open it up all the way
-                self.flow_mgr.borrow_mut().max_data(LOCAL_MAX_DATA);
             }
             Frame::StreamDataBlocked { stream_id, .. } => {
                 // TODO(agrover@mozilla.com): how should we be using
@@ -1557,12 +2164,24 @@ impl Connection {
                 );
                 self.set_state(State::Closed(error_code.into()));
             }
+            Frame::Datagram { data } => {
+                let max = self
+                    .tps
+                    .borrow()
+                    .local
+                    .get_integer(tp_constants::MAX_DATAGRAM_FRAME_SIZE);
+                if u64::try_from(Frame::datagram_len(data.len()))? > max {
+                    return Err(Error::ProtocolViolation);
+                }
+                self.events.datagram(data);
+            }
         };
 
         Ok(())
     }
 
     fn handle_lost_packets(&mut self, lost_packets: &[SentPacket]) {
+        self.stats.lost += lost_packets.len();
         for lost in lost_packets {
             for token in &lost.tokens {
                 qdebug!([self], "Lost: {:?}", token);
@@ -1667,6 +2286,7 @@ impl Connection {
         self.crypto.install_application_keys(now + pto)?;
         self.validate_odcid()?;
         self.set_initial_limits();
+        self.issue_connection_id();
         self.set_state(State::Connected);
         qinfo!([self], "Connection established");
         Ok(())
@@ -1755,7 +2375,8 @@ impl Connection {
 
             if stream_idx >= *next_stream_idx {
                 let recv_initial_max_stream_data = if stream_id.is_bidi() {
-                    if stream_idx > self.indexes.local_max_stream_bidi {
+                    // The limit is a number of streams, so the largest allowed index is one less.
+                    if stream_idx >= self.indexes.local_max_stream_bidi {
                         qwarn!(
                             [self],
                             "remote bidi stream create blocked, next={:?} max={:?}",
@@ -1774,7 +2395,7 @@ impl Connection {
                         .local
                         .get_integer(tp_constants::INITIAL_MAX_STREAM_DATA_BIDI_REMOTE)
                 } else {
-                    if stream_idx > self.indexes.local_max_stream_uni {
+                    if stream_idx >= self.indexes.local_max_stream_uni {
                         qwarn!(
                             [self],
                             "remote uni stream create blocked, next={:?} max={:?}",
@@ -1962,6 +2583,12 @@ impl Connection {
         self.send_streams.get_mut(stream_id.into())?.send(data)
     }
 
+    /// Whether any stream has data or a FIN that the peer has not acknowledged yet. This
+    /// includes data that flow control or the congestion window hold back.
+    pub fn has_unacked_stream_data(&self) -> bool {
+        self.send_streams.has_unacked_data()
+    }
+
     /// Bytes that stream_send() is guaranteed to accept for sending.
     /// i.e. that will not be blocked by flow credits or send buffer max
     /// capacity.
@@ -1990,9 +2617,36 @@ impl Connection {
             .ok_or_else(|| Error::InvalidStreamId)?;
 
         let rb = stream.read(data)?;
+        self.retire_rx_data(rb.0);
         Ok((rb.0 as usize, rb.1))
     }
 
+    /// Count data that the application has read, and send MAX_DATA once more than half of the
+    /// connection flow control limit has been read, like `maybe_send_flowc_update` for streams.
+    fn retire_rx_data(&mut self, amount: u64) {
+        self.rx_data_retired += amount;
+        let window = self
+            .tps
+            .borrow()
+            .local
+            .get_integer(tp_constants::INITIAL_MAX_DATA);
+        let mut flow_mgr = self.flow_mgr.borrow_mut();
+        let current = max(flow_mgr.rx_max_data(), window);
+        let new_max = min(self.rx_data_retired.saturating_add(window), LOCAL_MAX_DATA);
+        if new_max > current + window / 2 {
+            flow_mgr.max_data(new_max);
+        }
+    }
+
+    /// Check whether a stream has data or a fin that has not been read yet.
+    pub fn stream_readable(&self, stream_id: u64) -> Res<bool> {
+        let stream = self
+            .recv_streams
+            .get(&stream_id.into())
+            .ok_or_else(|| Error::InvalidStreamId)?;
+        Ok(stream.readable())
+    }
+
     /// Application is no longer interested in this stream.
     pub fn stream_stop_sending(&mut self, stream_id: u64, err: AppError) -> Res<()> {
         let stream = self
@@ -2443,6 +3097,30 @@ mod tests {
         }
     }
 
+    #[test]
+    fn current_path() {
+        fixture_init();
+        let local_addr = SocketAddr::new(loopback().ip(), 4433);
+        let mut client = Connection::new_client(
+            test_fixture::DEFAULT_SERVER_NAME,
+            test_fixture::DEFAULT_ALPN,
+            Rc::new(RefCell::new(FixedConnectionIdManager::new(3))),
+            local_addr,
+            loopback(),
+        )
+        .expect("create a client");
+        let mut server = default_server();
+        assert_eq!(client.current_path(), Some((local_addr, loopback())));
+        assert_eq!(client.local_cid().map(<[u8]>::len), Some(3));
+        assert_eq!(server.current_path(), None);
+        assert_eq!(server.local_cid(), None);
+
+        connect(&mut client, &mut server);
+        assert_eq!(client.current_path(), Some((local_addr, loopback())));
+        assert_eq!(server.current_path(), Some((loopback(), local_addr)));
+        assert_eq!(server.local_cid().map(<[u8]>::len), Some(5));
+    }
+
     #[test]
     fn test_no_alpn() {
         fixture_init();
@@ -2463,6 +3141,25 @@ mod tests {
         assert_error(&server, ConnectionError::Transport(Error::CryptoAlert(120)));
     }
 
+    #[test]
+    fn alpn_before_authentication() {
+        let mut client = default_client();
+        let mut server = default_server();
+        assert_eq!(client.alpn(), None);
+        let out = client.process(None, now());
+        let out = server.process(out.dgram(), now());
+        let out = client.process(out.dgram(), now());
+        let _ = server.process(out.dgram(), now());
+
+        assert_eq!(client.alpn(), Some(String::from("alpn")));
+        assert!(maybe_authenticate(&mut client));
+        let out = client.process(None, now());
+        let _ = server.process(out.dgram(), now());
+        assert_eq!(*client.state(), State::Connected);
+        assert_eq!(client.alpn(), Some(String::from("alpn")));
+        assert_eq!(server.alpn(), Some(String::from("alpn")));
+    }
+
     #[test]
     fn test_dup_server_flight1() {
         qdebug!("---- client: generate CH");
@@ -2543,6 +3240,283 @@ mod tests {
         ));
     }
 
+    // A datagram with a different source address is dropped, without closing the connection,
+    // until that address is validated.
+    #[test]
+    fn datagram_from_other_address() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[1, 2, 3]).unwrap();
+        let dgram = client.process(None, now()).dgram().unwrap();
+        let spoofed = Datagram::new(
+            "[::1]:9999".parse().unwrap(),
+            dgram.destination(),
+            &dgram[..],
+        );
+        let dropped = server.stats().dropped_rx;
+        server.process_input(spoofed, now());
+        assert_eq!(server.stats().dropped_rx, dropped + 1);
+        assert_eq!(*server.state(), State::Connected);
+        assert!(!server
+            .events()
+            .any(|e| matches!(e, ConnectionEvent::NewStream { .. })));
+
+        // The same packet from the right address is accepted.
+        server.process_input(dgram, now());
+        assert!(server
+            .events()
+            .any(|e| matches!(e, ConnectionEvent::NewStream { .. })));
+    }
+
+    // The client moves to a new address once the server has answered a PATH_CHALLENGE on it,
+    // and data then flows on the new path.
+    #[test]
+    fn migrate() {
+        let mut client = default_client();
+        let mut server = default_server();
+        server.set_allow_migration(true).unwrap();
+        connect(&mut client, &mut server);
+
+        let client_cid = client.local_cid().unwrap().to_vec();
+        let server_cid = server.local_cid().unwrap().to_vec();
+        let new_addr: SocketAddr = "[::1]:444".parse().unwrap();
+        assert_eq!(server.migrate(new_addr), Err(Error::ConnectionState));
+        client.migrate(new_addr).unwrap();
+        let probe = client.process(None, now()).dgram().unwrap();
+        assert_eq!(probe.source(), new_addr);
+
+        // The server answers on the new path, with a PATH_CHALLENGE of its own.
+        let probe = server.process(Some(probe), now()).dgram().unwrap();
+        assert_eq!(probe.destination(), new_addr);
+        assert_eq!(client.current_path(), Some((loopback(), loopback())));
+
+        // The PATH_RESPONSE moves the client to the new path.
+        let stream_id = client.stream_create(StreamType::BiDi).unwrap();
+        client.stream_send(stream_id, &[1, 2, 3]).unwrap();
+        let dgram = client.process(Some(probe), now()).dgram().unwrap();
+        assert_eq!(dgram.source(), new_addr);
+        assert_eq!(client.current_path(), Some((new_addr, loopback())));
+        assert_ne!(client.local_cid().unwrap(), &client_cid[..]);
+        assert_eq!(server.current_path(), Some((loopback(), loopback())));
+
+        // The client's PATH_RESPONSE moves the server too.
+        server.process_input(dgram, now());
+        assert_eq!(server.current_path(), Some((loopback(), new_addr)));
+        assert_ne!(server.local_cid().unwrap(), &server_cid[..]);
+        assert!(server
+            .events()
+            .any(|e| matches!(e, ConnectionEvent::NewStream { .. })));
+
+        server.stream_send(stream_id, &[4, 5, 6]).unwrap();
+        let dgram = server.process(None, now()).dgram().unwrap();
+        assert_eq!(dgram.destination(), new_addr);
+        client.process_input(dgram, now());
+        let mut buf = [0; 3];
+        assert_eq!(client.stream_recv(stream_id, &mut buf).unwrap(), (3, false));
+        assert_eq!(buf, [4, 5, 6]);
+    }
+
+    // A lost PATH_CHALLENGE is sent again after a PTO.
+    #[test]
+    fn migrate_lost_challenge() {
+        let mut client = default_client();
+        let mut server = default_server();
+        server.set_allow_migration(true).unwrap();
+        connect(&mut client, &mut server);
+
+        let new_addr: SocketAddr = "[::1]:444".parse().unwrap();
+        client.migrate(new_addr).unwrap();
+        let lost = client.process(None, now()).dgram().unwrap();
+        assert_eq!(lost.source(), new_addr);
+        let out = client.process(None, now()).dgram();
+        assert!(out.map_or(true, |d| d.source() != new_addr));
+
+        let later = now() + client.loss_recovery.pto();
+        let probe = client.process(None, later).dgram().unwrap();
+        assert_eq!(probe.source(), new_addr);
+        let probe = server.process(Some(probe), later).dgram().unwrap();
+        assert_eq!(probe.destination(), new_addr);
+        client.process_input(probe, later);
+        assert_eq!(client.current_path(), Some((new_addr, loopback())));
+    }
+
+    // Without a spare connection ID from the server, the client can't migrate.
+    #[test]
+    fn migrate_no_cid() {
+        let mut client = default_client();
+        let mut server = default_server();
+        server.set_allow_migration(true).unwrap();
+        connect(&mut client, &mut server);
+
+        client.migrate("[::1]:444".parse().unwrap()).unwrap();
+        assert_eq!(
+            client.migrate("[::1]:445".parse().unwrap()),
+            Err(Error::InvalidMigration)
+        );
+    }
+
+    #[test]
+    fn migrate_disabled() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let new_addr = "[::1]:444".parse().unwrap();
+        assert_eq!(client.migrate(new_addr), Err(Error::InvalidMigration));
+    }
+
+    // Until a new path is validated, a server sends no more than three times what it received
+    // on it, and a datagram from yet another address does not replace the validation.
+    #[test]
+    fn probe_amplification_limit() {
+        let mut client = default_client();
+        let mut server = default_server();
+        server.set_allow_migration(true).unwrap();
+        connect(&mut client, &mut server);
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[1, 2, 3]).unwrap();
+        let dgram = client.process(None, now()).dgram().unwrap();
+        let new_addr: SocketAddr = "[::1]:9999".parse().unwrap();
+        let moved = Datagram::new(new_addr, dgram.destination(), &dgram[..]);
+        let probe = server.process(Some(moved), now()).dgram().unwrap();
+        assert_eq!(probe.destination(), new_addr);
+        assert!(probe.len() <= 3 * dgram.len());
+
+        client.stream_send(stream_id, &[4, 5, 6]).unwrap();
+        let dgram = client.process(None, now()).dgram().unwrap();
+        let other_addr: SocketAddr = "[::1]:9998".parse().unwrap();
+        let moved = Datagram::new(other_addr, dgram.destination(), &dgram[..]);
+        let out = server.process(Some(moved), now()).dgram();
+        assert!(out.map_or(true, |d| d.destination() != other_addr));
+    }
+
+    #[test]
+    fn ecn_counts() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        let stream_id = client.stream_create(StreamType::UniDi).unwrap();
+        client.stream_send(stream_id, &[1]).unwrap();
+        let out = client.process(None, now());
+        let dgram = out.dgram().unwrap();
+        // Datagrams are counted even if their packets are duplicates.
+        server.process_input(dgram.clone().with_ecn(Ecn::Ce), now());
+        server.process_input(dgram.with_ecn(Ecn::Ect0), now());
+        assert_eq!(server.stats().ce_rx, 1);
+        assert_eq!(server.stats().ect0_rx, 1);
+        assert_eq!(server.stats().ect1_rx, 0);
+    }
+
+    #[test]
+    fn datagram() {
+        let mut client = default_client();
+        let mut server = default_server();
+        client.set_max_datagram_frame_size(100).unwrap();
+        server.set_max_datagram_frame_size(100).unwrap();
+        connect(&mut client, &mut server);
+
+        client.send_datagram(&[1, 2, 3]).unwrap();
+        let out = client.process(None, now());
+        server.process_input(out.dgram().unwrap(), now());
+        let datagram = |e| {
+            e == ConnectionEvent::Datagram {
+                data: vec![1, 2, 3],
+            }
+        };
+        assert!(server.events().any(datagram));
+
+        // The frame, with its type and length, must fit the limit.
+        assert_eq!(server.send_datagram(&[0; 98]), Err(Error::TooMuchData));
+        server.send_datagram(&[0; 97]).unwrap();
+        let out = server.process(None, now());
+        client.process_input(out.dgram().unwrap(), now());
+        let datagram = |e| matches!(e, ConnectionEvent::Datagram { data } if data.len() == 97);
+        assert!(client.events().any(datagram));
+    }
+
+    // Once the queue is full, the oldest datagram is dropped to make room.
+    #[test]
+    fn datagram_queue_full() {
+        let mut client = default_client();
+        let mut server = default_server();
+        server.set_max_datagram_frame_size(100).unwrap();
+        connect(&mut client, &mut server);
+
+        for i in 0..=MAX_QUEUED_DATAGRAMS {
+            client.send_datagram(&[u8::try_from(i).unwrap()]).unwrap();
+        }
+        assert_eq!(client.stats().datagrams_dropped_tx, 1);
+        let out = client.process(None, now());
+        server.process_input(out.dgram().unwrap(), now());
+        let received: Vec<_> = server
+            .events()
+            .filter_map(|e| match e {
+                ConnectionEvent::Datagram { data } => Some(data[0]),
+                _ => None,
+            })
+            .collect();
+        let expected: Vec<_> = (1..=MAX_QUEUED_DATAGRAMS)
+            .map(|i| u8::try_from(i).unwrap())
+            .collect();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn datagram_not_allowed() {
+        let mut client = default_client();
+        let mut server = default_server();
+        assert_eq!(client.send_datagram(&[1]), Err(Error::NotConnected));
+        connect(&mut client, &mut server);
+        assert_eq!(client.send_datagram(&[1]), Err(Error::TooMuchData));
+
+        // A peer that sends one anyway breaks the protocol.
+        client
+            .tps
+            .borrow_mut()
+            .remote
+            .as_mut()
+            .unwrap()
+            .set_integer(tp_constants::MAX_DATAGRAM_FRAME_SIZE, 100);
+        client.send_datagram(&[1]).unwrap();
+        let out = client.process(None, now());
+        server.process_input(out.dgram().unwrap(), now());
+        assert_error(
+            &server,
+            ConnectionError::Transport(Error::ProtocolViolation),
+        );
+    }
+
+    #[test]
+    fn peer_exceeds_stream_limit() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+
+        // Make the client ignore the limit and open one more stream than the server allows.
+        client.indexes.remote_max_stream_bidi = StreamIndex::new(LOCAL_STREAM_LIMIT_BIDI + 1);
+        let mut stream_id = 0;
+        for _ in 0..=LOCAL_STREAM_LIMIT_BIDI {
+            stream_id = client.stream_create(StreamType::BiDi).unwrap();
+        }
+        assert_eq!(client.stream_send(stream_id, &[0x00]).unwrap(), 1);
+
+        let out = client.process(None, now());
+        let out = server.process(out.dgram(), now());
+        assert_error(&server, ConnectionError::Transport(Error::StreamLimitError));
+
+        // The client is told why the connection was closed.
+        client.process(out.dgram(), now());
+        assert_error(
+            &client,
+            ConnectionError::Transport(Error::PeerError(Error::StreamLimitError.code())),
+        );
+    }
+
     #[test]
     fn resume() {
         let mut client = default_client();
@@ -2777,6 +3751,36 @@ mod tests {
         assert!(matches!(client.state(), State::Closed(_)));
     }
 
+    #[test]
+    fn idle_keep_alive() {
+        let mut client = default_client();
+        let mut server = default_server();
+        connect(&mut client, &mut server);
+        client.set_keep_alive(true);
+
+        let now = now();
+
+        // The client wants to be called back after half of the idle timeout.
+        let res = client.process(None, now);
+        assert_eq!(res, Output::Callback(LOCAL_IDLE_TIMEOUT / 2));
+
+        // At that point it sends a PING.
+        let now = now + LOCAL_IDLE_TIMEOUT / 2;
+        let out = client.process(None, now);
+        let frames = server.test_process_input(out.dgram().unwrap(), now);
+        assert_eq!(frames, vec![(Frame::Ping, PNSpace::ApplicationData)]);
+
+        // Only one PING is sent for each idle period.
+        assert!(client.process(None, now).dgram().is_none());
+
+        // The acknowledgment from the server resets the client's idle timer.
+        let now = now + Duration::from_secs(1);
+        let out = server.process(None, now);
+        client.process(out.dgram(), now);
+        client.process_timer(now + Duration::from_secs(59));
+        assert!(matches!(client.state(), State::Connected));
+    }
+
     #[test]
     fn idle_send_packet1() {
         let mut client = default_client();
@@ -2971,6 +3975,48 @@ mod tests {
         assert_eq!(*server.state(), State::Connected);
     }
 
+    // Create a Version Negotiation packet in response to the client's first packet.
+    fn version_negotiation_packet(client_initial: &[u8], versions: &[u32]) -> Vec<u8> {
+        // Skip the first byte and the version.
+        let mut dec = Decoder::from(&client_initial[5..]);
+        let dcid = dec.decode_vec(1).unwrap();
+        let scid = dec.decode_vec(1).unwrap();
+        let mut enc = Encoder::default();
+        enc.encode_byte(0x80);
+        enc.encode_uint(4, 0_u64);
+        enc.encode_vec(1, scid);
+        enc.encode_vec(1, dcid);
+        for v in versions {
+            enc.encode_uint(4, *v);
+        }
+        enc.into()
+    }
+
+    #[test]
+    fn version_negotiation_no_common_version() {
+        let mut client = default_client();
+        let initial = client.process(None, now()).dgram().unwrap();
+        let vn = version_negotiation_packet(&initial, &[0x1a2a_3a4a, 0xff00_0001]);
+        client.process_input(Datagram::new(loopback(), loopback(), vn), now());
+        assert_eq!(
+            *client.state(),
+            State::Closed(ConnectionError::Transport(Error::VersionNegotiation))
+        );
+        assert_eq!(client.stats().vn_versions, vec![0x1a2a_3a4a, 0xff00_0001]);
+    }
+
+    #[test]
+    fn version_negotiation_listing_current_version() {
+        let mut client = default_client();
+        let initial = client.process(None, now()).dgram().unwrap();
+        let vn = version_negotiation_packet(&initial, &[0x1a2a_3a4a, QUIC_VERSION]);
+        let dropped_before = client.stats().dropped_rx;
+        client.process_input(Datagram::new(loopback(), loopback(), vn), now());
+        assert_eq!(*client.state(), State::WaitInitial);
+        assert_eq!(client.stats().dropped_rx, dropped_before + 1);
+        assert!(client.stats().vn_versions.is_empty());
+    }
+
     #[test]
     fn set_local_tparam() {
         let client = default_client();
@@ -2983,6 +4029,83 @@ mod tests {
             .unwrap()
     }
 
+    #[test]
+    fn set_flow_control_limits() {
+        let mut client = default_client();
+        let mut server = default_server();
+        client.set_max_data(1000).unwrap();
+        client.set_max_stream_data(100).unwrap();
+        connect(&mut client, &mut server);
+
+        let remote = server.tps.borrow().remote().clone();
+        assert_eq!(remote.get_integer(tp_constants::INITIAL_MAX_DATA), 1000);
+        assert_eq!(
+            remote.get_integer(tp_constants::INITIAL_MAX_STREAM_DATA_BIDI_LOCAL),
+            100
+        );
+        assert_eq!(
+            remote.get_integer(tp_constants::INITIAL_MAX_STREAM_DATA_BIDI_REMOTE),
+            100
+        );
+        assert_eq!(
+            remote.get_integer(tp_constants::INITIAL_MAX_STREAM_DATA_UNI),
+            100
+        );
+
+        // The server can only send 100 bytes on a new stream.
+        let stream_id = server.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(server.stream_send(stream_id, &[0; 200]), Ok(100));
+
+        assert_eq!(client.set_max_data(2000), Err(Error::ConnectionState));
+        assert_eq!(client.set_max_stream_data(200), Err(Error::ConnectionState));
+    }
+
+    // MAX_DATA moves the limit set with `set_max_data` forward as the application reads data.
+    #[test]
+    fn max_data_update() {
+        let mut client = default_client();
+        let mut server = default_server();
+        client.set_max_data(1000).unwrap();
+        connect(&mut client, &mut server);
+
+        let stream_id = server.stream_create(StreamType::UniDi).unwrap();
+        assert_eq!(server.stream_send(stream_id, &[0; 2000]), Ok(1000));
+        let out = server.process(None, now());
+        client.process_input(out.dgram().unwrap(), now());
+
+        // Reading less than half of the limit does not send MAX_DATA.
+        let mut buf = [0; 1000];
+        assert_eq!(
+            client.stream_recv(stream_id, &mut buf[..400]),
+            Ok((400, false))
+        );
+        assert_eq!(client.flow_mgr.borrow().rx_max_data(), 0);
+        assert_eq!(client.stream_recv(stream_id, &mut buf), Ok((600, false)));
+        assert_eq!(client.flow_mgr.borrow().rx_max_data(), 2000);
+
+        let out = client.process(None, now());
+        server.process_input(out.dgram().unwrap(), now());
+        assert_eq!(server.stream_send(stream_id, &[0; 2000]), Ok(1000));
+    }
+
+    #[test]
+    fn set_congestion_control() {
+        let mut client = default_client();
+        let mut server = default_server();
+        client
+            .set_congestion_control(CongestionControlAlgorithm::NewReno)
+            .unwrap();
+        connect(&mut client, &mut server);
+        assert_eq!(
+            client.congestion_control(),
+            CongestionControlAlgorithm::NewReno
+        );
+        assert_eq!(
+            client.set_congestion_control(CongestionControlAlgorithm::NewReno),
+            Err(Error::ConnectionState)
+        );
+    }
+
     #[test]
     // If we send a stop_sending to the peer, we should not accept more data from the peer.
     fn do_not_accept_data_after_stop_sending() {
@@ -3445,6 +4568,7 @@ mod tests {
         // If we just triggered cong avoidance, these should be equal
         let cwnd1 = client.loss_recovery.cwnd();
         assert_eq!(cwnd1, client.loss_recovery.ssthresh());
+        assert_eq!(client.slow_start_threshold(), cwnd1);
 
         // Generate ACK for more received packets
         let (s_tx_dgram, _) = ack_bytes(&mut server, 0, c_tx_dgrams2, now);
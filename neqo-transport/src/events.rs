@@ -44,6 +44,8 @@ pub enum ConnectionEvent {
     /// This event invalidates all state in streams that has been created.
     /// Any data written to streams needs to be written again.
     ZeroRttRejected,
+    /// A DATAGRAM frame has been received.
+    Datagram { data: Vec<u8> },
 }
 
 #[derive(Debug, Default, Clone)]
@@ -126,6 +128,10 @@ impl ConnectionEvents {
         self.insert(ConnectionEvent::ZeroRttRejected);
     }
 
+    pub fn datagram(&self, data: Vec<u8>) {
+        self.insert(ConnectionEvent::Datagram { data });
+    }
+
     pub fn events(&self) -> impl Iterator<Item = ConnectionEvent> {
         self.events.replace(VecDeque::new()).into_iter()
     }
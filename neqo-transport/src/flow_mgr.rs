@@ -38,6 +38,9 @@ pub struct FlowMgr {
     used_data: u64,
     max_data: u64,
 
+    // The last limit that we sent in a MAX_DATA frame.
+    rx_max_data: u64,
+
     need_close_frame: bool,
 }
 
@@ -81,7 +84,28 @@ impl FlowMgr {
         self.from_conn.insert(mem::discriminant(&frame), frame);
     }
 
+    pub fn new_connection_id(
+        &mut self,
+        sequence_number: u64,
+        connection_id: Vec<u8>,
+        stateless_reset_token: [u8; 16],
+    ) {
+        let frame = Frame::NewConnectionId {
+            sequence_number,
+            retire_prior: 0,
+            connection_id,
+            stateless_reset_token,
+        };
+        self.from_conn.insert(mem::discriminant(&frame), frame);
+    }
+
+    /// The last limit that was sent in a MAX_DATA frame, or 0 if none was sent.
+    pub fn rx_max_data(&self) -> u64 {
+        self.rx_max_data
+    }
+
     pub fn max_data(&mut self, maximum_data: u64) {
+        self.rx_max_data = maximum_data;
         let frame = Frame::MaxData { maximum_data };
         self.from_conn.insert(mem::discriminant(&frame), frame);
     }
@@ -284,6 +308,23 @@ impl FlowMgr {
                     rs.maybe_send_flowc_update()
                 }
             }
+            // Resend MaxData if a later update hasn't replaced it.
+            Frame::MaxData { maximum_data } => {
+                if maximum_data == self.rx_max_data {
+                    self.max_data(maximum_data)
+                }
+            }
+            // Always resend NewConnectionId if lost
+            Frame::NewConnectionId {
+                sequence_number,
+                ref connection_id,
+                stateless_reset_token,
+                ..
+            } => self.new_connection_id(
+                sequence_number,
+                connection_id.clone(),
+                stateless_reset_token,
+            ),
             Frame::PathResponse { .. } => qinfo!("Path Response lost, not re-sent"),
             _ => qwarn!("Unexpected Flow frame {:?} lost, not re-sent", token),
         }
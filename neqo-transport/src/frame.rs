@@ -44,6 +44,8 @@ const FRAME_TYPE_PATH_RESPONSE: FrameType = 0x1b;
 const FRAME_TYPE_CONNECTION_CLOSE_TRANSPORT: FrameType = 0x1c;
 const FRAME_TYPE_CONNECTION_CLOSE_APPLICATION: FrameType = 0x1d;
 // const FRAME_TYPE_HANDSHAKE_DONE: FrameType = 0x1e;
+const FRAME_TYPE_DATAGRAM: FrameType = 0x30;
+const FRAME_TYPE_DATAGRAM_WITH_LEN: FrameType = 0x31;
 
 const STREAM_FRAME_BIT_FIN: u64 = 0x01;
 const STREAM_FRAME_BIT_LEN: u64 = 0x02;
@@ -191,6 +193,9 @@ pub enum Frame {
         frame_type: u64,
         reason_phrase: Vec<u8>,
     },
+    Datagram {
+        data: Vec<u8>,
+    },
 }
 
 impl Frame {
@@ -235,9 +240,16 @@ impl Frame {
             Self::ConnectionClose { error_code, .. } => {
                 FRAME_TYPE_CONNECTION_CLOSE_TRANSPORT + error_code.frame_type_bit()
             }
+            // A length is always included, so that other frames can follow.
+            Self::Datagram { .. } => FRAME_TYPE_DATAGRAM_WITH_LEN,
         }
     }
 
+    /// The size of a DATAGRAM frame that carries `len` bytes.
+    pub fn datagram_len(len: usize) -> usize {
+        1 + Encoder::varint_len(u64::try_from(len).unwrap()) + len
+    }
+
     /// Create a CRYPTO frame that fits the available space and its length.
     pub fn new_crypto(offset: u64, data: &[u8], space: usize) -> (Self, usize) {
         // Subtract the frame type and offset from available space.
@@ -439,6 +451,9 @@ impl Frame {
                 enc.encode_varint(*frame_type);
                 enc.encode_vvec(reason_phrase);
             }
+            Self::Datagram { data } => {
+                enc.encode_vvec(data);
+            }
         }
     }
 
@@ -510,6 +525,7 @@ impl Frame {
                 data.len(),
                 fin,
             )),
+            Self::Datagram { data } => Some(format!("Datagram {{ len: {} }}", data.len())),
             Self::Padding => None,
             _ => Some(format!("{:?}", self)),
         }
@@ -688,6 +704,13 @@ impl Frame {
                     reason_phrase: d!(dec.decode_vvec()).to_vec(), // TODO(mt) unnecessary copy
                 })
             }
+            // Without a length, the datagram runs to the end of the packet.
+            FRAME_TYPE_DATAGRAM => Ok(Self::Datagram {
+                data: dec.decode_remainder().to_vec(),
+            }),
+            FRAME_TYPE_DATAGRAM_WITH_LEN => Ok(Self::Datagram {
+                data: d!(dec.decode_vvec()).to_vec(),
+            }),
             _ => Err(Error::UnknownFrameType),
         }
     }
@@ -948,6 +971,19 @@ mod tests {
         enc_dec(&f, "1d80005678523403010203");
     }
 
+    #[test]
+    fn test_datagram() {
+        let f = Frame::Datagram {
+            data: vec![0x01, 0x02, 0x03],
+        };
+        enc_dec(&f, "3103010203");
+        assert_eq!(Frame::datagram_len(3), 5);
+
+        // Without a length, the rest of the packet is the datagram.
+        let enc = Encoder::from_hex("30010203");
+        assert_eq!(Frame::decode(&mut enc.as_decoder()).unwrap(), f);
+    }
+
     #[test]
     fn test_compare() {
         let f1 = Frame::Padding;
@@ -27,6 +27,7 @@ pub mod stream_id;
 mod tparams;
 mod tracking;
 
+pub use self::cc::CongestionControlAlgorithm;
 pub use self::connection::{
     Connection, ConnectionIdManager, FixedConnectionIdManager, Output, Role, State,
 };
@@ -15,7 +15,7 @@ use smallvec::SmallVec;
 
 use neqo_common::{qdebug, qinfo};
 
-use crate::cc::CongestionControl;
+use crate::cc::{CongestionControl, CongestionControlAlgorithm};
 use crate::crypto::CryptoRecoveryToken;
 use crate::flow_mgr::FlowControlRecoveryToken;
 use crate::send_stream::StreamRecoveryToken;
@@ -230,12 +230,20 @@ impl LossRecovery {
         }
     }
 
-    #[cfg(test)]
+    /// Replace the congestion controller.  This is only safe before any
+    /// packets have been sent.
+    pub fn set_congestion_control(&mut self, algorithm: CongestionControlAlgorithm) {
+        self.cc = CongestionControl::new(algorithm);
+    }
+
+    pub fn congestion_control(&self) -> CongestionControlAlgorithm {
+        self.cc.algorithm()
+    }
+
     pub fn cwnd(&self) -> usize {
         self.cc.cwnd()
     }
 
-    #[cfg(test)]
     pub fn ssthresh(&self) -> usize {
         self.cc.ssthresh()
     }
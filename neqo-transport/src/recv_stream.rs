@@ -483,6 +483,11 @@ impl RecvStream {
             .map_or(false, RxStreamOrderer::data_ready)
     }
 
+    /// Is there any data or a fin that the application has not read yet?
+    pub fn readable(&self) -> bool {
+        self.data_ready() || self.needs_to_inform_app_about_fin()
+    }
+
     pub fn read(&mut self, buf: &mut [u8]) -> Res<(u64, bool)> {
         let res = match &mut self.state {
             RecvStreamState::Recv { recv_buf, .. }
@@ -606,6 +606,18 @@ impl SendStream {
         matches!(self.state, SendStreamState::DataRecvd { .. } | SendStreamState::ResetRecvd)
     }
 
+    /// Whether data or a FIN was queued that the peer has not acknowledged yet.
+    pub fn has_unacked_data(&self) -> bool {
+        match &self.state {
+            SendStreamState::Send { send_buf } => send_buf.buffered() > 0,
+            SendStreamState::DataSent { .. } => true,
+            SendStreamState::Ready
+            | SendStreamState::DataRecvd { .. }
+            | SendStreamState::ResetSent
+            | SendStreamState::ResetRecvd => false,
+        }
+    }
+
     pub fn send(&mut self, buf: &[u8]) -> Res<usize> {
         if buf.is_empty() {
             qerror!("zero-length send on stream {}", self.stream_id.as_u64());
@@ -742,6 +754,10 @@ impl SendStreams {
         self.0.retain(|_, stream| !stream.is_terminal())
     }
 
+    pub fn has_unacked_data(&self) -> bool {
+        self.0.values().any(SendStream::has_unacked_data)
+    }
+
     pub(crate) fn get_frame(
         &mut self,
         space: PNSpace,
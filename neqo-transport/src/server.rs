@@ -204,6 +204,8 @@ pub struct Server {
     /// Whether a Retry packet will be sent in response to new
     /// Initial packets.
     retry: RetryToken,
+    /// Whether new connections allow the client to migrate.
+    allow_migration: bool,
 }
 
 impl Server {
@@ -232,6 +234,7 @@ impl Server {
             waiting: VecDeque::default(),
             timers: Timer::new(now, TIMER_GRANULARITY, TIMER_CAPACITY),
             retry: RetryToken::new(now)?,
+            allow_migration: false,
         })
     }
 
@@ -252,6 +255,12 @@ impl Server {
         self.retry.set_retry_required(require_retry);
     }
 
+    /// Allow clients of new connections to migrate to a new address, see
+    /// `Connection::set_allow_migration`.
+    pub fn set_allow_migration(&mut self, allow: bool) {
+        self.allow_migration = allow;
+    }
+
     fn remove_timer(&mut self, c: &StateRef) {
         let last = c.borrow().last_timer;
         self.timers.remove(last, |t| Rc::ptr_eq(t, c));
@@ -359,7 +368,11 @@ impl Server {
             &self.protocols,
             &self.anti_replay,
             cid_mgr.clone(),
-        );
+        )
+        .and_then(|c| {
+            c.set_allow_migration(self.allow_migration)?;
+            Ok(c)
+        });
         if let Ok(mut c) = sconn {
             if let Some(odcid) = odcid {
                 c.original_connection_id(&odcid);
@@ -13,8 +13,27 @@ pub struct Stats {
     pub packets_rx: usize,
     /// Total packets sent
     pub packets_tx: usize,
+    /// Total bytes received, including datagrams that were dropped
+    pub bytes_rx: usize,
+    /// Total bytes sent
+    pub bytes_tx: usize,
+    /// Packets declared lost, including those resent after a Retry or rejected 0-RTT
+    pub lost: usize,
     /// Duplicate packets received
     pub dups_rx: usize,
     /// Dropped datagrams, or parts thereof
     pub dropped_rx: usize,
+    /// Datagrams received with the ECT(0) codepoint
+    pub ect0_rx: usize,
+    /// Datagrams received with the ECT(1) codepoint
+    pub ect1_rx: usize,
+    /// Datagrams received with the CE codepoint
+    pub ce_rx: usize,
+    /// Datagrams dropped before they were sent, because the queue was full or they did not
+    /// fit a packet
+    pub datagrams_dropped_tx: usize,
+    /// The destination connection ID provided by the server in a Retry, if one was received
+    pub retry_dcid: Option<Vec<u8>>,
+    /// The versions that the server offered in a Version Negotiation packet, if it sent one
+    pub vn_versions: Vec<u32>,
 }
@@ -43,6 +43,7 @@ pub mod tp_constants {
         MAX_ACK_DELAY = 11,
         DISABLE_MIGRATION = 12,
         PREFERRED_ADDRESS = 13,
+        MAX_DATAGRAM_FRAME_SIZE = 0x20,
     }
 }
 
@@ -182,7 +183,8 @@ impl TransportParameters {
             | INITIAL_MAX_STREAM_DATA_BIDI_REMOTE
             | INITIAL_MAX_STREAM_DATA_UNI
             | INITIAL_MAX_STREAMS_BIDI
-            | INITIAL_MAX_STREAMS_UNI => 0,
+            | INITIAL_MAX_STREAMS_UNI
+            | MAX_DATAGRAM_FRAME_SIZE => 0,
             MAX_PACKET_SIZE => 65527,
             ACK_DELAY_EXPONENT => 3,
             MAX_ACK_DELAY => 25,
@@ -207,7 +209,8 @@ impl TransportParameters {
             | INITIAL_MAX_STREAMS_UNI
             | MAX_PACKET_SIZE
             | ACK_DELAY_EXPONENT
-            | MAX_ACK_DELAY => {
+            | MAX_ACK_DELAY
+            | MAX_DATAGRAM_FRAME_SIZE => {
                 self.set(tipe, TransportParameter::Integer(value));
             }
             _ => panic!("Transport parameter not known"),
@@ -245,6 +248,19 @@ impl TransportParameters {
         }
     }
 
+    pub fn get_empty(&self, tipe: u16) -> bool {
+        match tipe {
+            DISABLE_MIGRATION => {}
+            _ => panic!("Transport parameter not known or not type empty"),
+        }
+
+        match self.params.get(&tipe) {
+            None => false,
+            Some(TransportParameter::Empty) => true,
+            _ => panic!("Internal error"),
+        }
+    }
+
     /// Return true if the remembered transport parameters are OK for 0-RTT.
     /// Generally this means that any value that is currently in effect is greater than
     /// or equal to the promised value.
@@ -453,6 +469,7 @@ mod tests {
             INITIAL_MAX_STREAMS_BIDI,
             INITIAL_MAX_STREAMS_UNI,
             MAX_PACKET_SIZE,
+            MAX_DATAGRAM_FRAME_SIZE,
         ];
         for i in INTEGER_KEYS {
             tps_a.set(*i, TransportParameter::Integer(12));
@@ -77,7 +77,9 @@ fn single_client() {
     let mut server = default_server();
     let mut client = default_client();
 
-    connect(&mut client, &mut server);
+    let server_conn = connect(&mut client, &mut server);
+    assert!(client.stats().retry_dcid.is_none());
+    assert!(!server_conn.borrow().address_validated());
 }
 
 #[test]
@@ -95,6 +97,7 @@ fn retry() {
 
     let dgram = client.process(dgram, now()).dgram(); // Initial w/token
     assert!(dgram.is_some());
+    assert!(client.stats().retry_dcid.is_some());
     let dgram = server.process(dgram, now()).dgram(); // Initial, HS
     assert!(dgram.is_some());
     let _ = client.process(dgram, now()).dgram(); // Ingest, drop any ACK.
@@ -104,7 +107,9 @@ fn retry() {
     assert_eq!(*client.state(), State::Connected);
     let dgram = server.process(dgram, now()).dgram(); // (done)
     assert!(dgram.is_some()); // Note that this packet will be dropped...
-    connected_server(&mut server);
+    let server_conn = connected_server(&mut server);
+    assert!(server_conn.borrow().address_validated());
+    assert!(!client.address_validated());
 }
 
 // attempt a retry with 0-RTT, and have 0-RTT packets sent with the second ClientHello